@@ -0,0 +1,230 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::error::err;
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::request::Request;
+use crate::resp::RespValue;
+use crate::response::Response;
+use crate::slice::Slice;
+
+/// The only SASL mechanism offered.
+pub const PLAIN: &str = "PLAIN";
+
+/// Users and their passwords, from a file of `user:password` lines as
+/// memcached's `-Y` takes.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    users: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Compares in a time that depends on the lengths only.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Credentials {
+    pub fn load(path: &str) -> MyResult<Self> {
+        let mut s = String::new();
+        OpenOptions::new()
+            .read(true)
+            .open(path)?
+            .read_to_string(&mut s)?;
+        Credentials::parse(&s)
+    }
+
+    pub(crate) fn parse(s: &str) -> MyResult<Self> {
+        let mut credentials = Credentials::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            match line.find(':') {
+                Some(at) if at > 0 => {
+                    let (user, password) = (&line[..at], &line[at + 1..]);
+                    credentials
+                        .users
+                        .insert(user.as_bytes().to_vec(), password.as_bytes().to_vec());
+                }
+                _ => {
+                    return err(
+                        StatusCode::ConfigError,
+                        format!("line {} of the auth file is not user:password", i + 1),
+                    )
+                }
+            }
+        }
+        Ok(credentials)
+    }
+
+    pub fn check(&self, user: &[u8], password: &[u8]) -> bool {
+        match self.users.get(user) {
+            Some(expected) => same(expected, password),
+            None => false,
+        }
+    }
+
+    /// Checks the message of a PLAIN authentication: an authorization
+    /// identity, which is ignored, a user and a password, split by NULs.
+    pub fn check_plain(&self, message: &[u8]) -> bool {
+        let parts: Vec<&[u8]> = message.split(|b| *b == 0).collect();
+        match &parts[..] {
+            [_, user, password] => self.check(user, password),
+            _ => false,
+        }
+    }
+}
+
+/// Where a connection stands with authentication.
+pub struct Authenticator {
+    credentials: Arc<Credentials>,
+    authenticated: Cell<bool>,
+}
+
+impl Authenticator {
+    pub fn new(credentials: Arc<Credentials>) -> Self {
+        Authenticator {
+            credentials,
+            authenticated: Cell::new(false),
+        }
+    }
+
+    /// Answers the SASL requests, and refuses anything but `version` before
+    /// the connection has authenticated, as memcached. None lets `request`
+    /// through.
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        match request {
+            Request::SaslListMechs => Some(Response::Mechanisms(PLAIN.to_owned())),
+            Request::SaslAuth { mechanism, data } => {
+                let ok =
+                    &mechanism[..] == PLAIN.as_bytes() && self.credentials.check_plain(&data[..]);
+                self.authenticated.set(ok);
+                Some(if ok {
+                    Response::Authenticated
+                } else {
+                    Response::AuthError
+                })
+            }
+            Request::Resp(args) => self.check_resp(args).map(Response::Resp),
+            _ if self.authenticated.get() => None,
+            Request::Version => None,
+            _ => Some(Response::AuthError),
+        }
+    }
+
+    /// As `check`, for a Redis command: `AUTH <user> <password>`
+    /// authenticates, and nothing else is let through before it.
+    pub fn check_resp(&self, args: &[Slice]) -> Option<RespValue> {
+        let is_auth = match args.first() {
+            Some(name) => name[..].eq_ignore_ascii_case(b"auth"),
+            None => false,
+        };
+        if !is_auth {
+            return if self.authenticated.get() {
+                None
+            } else {
+                Some(RespValue::Error(
+                    "NOAUTH Authentication required.".to_owned(),
+                ))
+            };
+        }
+        match args {
+            [_, user, password] => {
+                let ok = self.credentials.check(&user[..], &password[..]);
+                self.authenticated.set(ok);
+                Some(if ok {
+                    RespValue::Simple("OK".to_owned())
+                } else {
+                    RespValue::Error("WRONGPASS invalid username-password pair".to_owned())
+                })
+            }
+            _ => Some(RespValue::error(
+                "wrong number of arguments for 'auth' command",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::request::GetterType;
+
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials::parse("alice:secret\n\nbob:a:b\r\n").unwrap();
+        assert!(credentials.check_plain(b"\0alice\0secret"));
+        assert!(credentials.check_plain(b"admin\0bob\0a:b"));
+        assert!(!credentials.check_plain(b"\0alice\0secre"));
+        assert!(!credentials.check_plain(b"\0carol\0secret"));
+        assert!(!credentials.check_plain(b"alice\0secret"));
+        assert!(Credentials::parse("alice\n").is_err());
+        assert!(Credentials::parse(":secret\n").is_err());
+    }
+
+    #[test]
+    fn test_authenticator() {
+        let credentials = Credentials::parse("alice:secret\n").unwrap();
+        let auth = Authenticator::new(Arc::new(credentials));
+        let get = Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("abc")],
+        };
+        let sasl_auth = |mechanism: &str, data: &str| Request::SaslAuth {
+            mechanism: Slice::from(mechanism),
+            data: Slice::from(data),
+        };
+
+        assert_eq!(Some(Response::AuthError), auth.check(&get));
+        assert_eq!(None, auth.check(&Request::Version));
+        assert_eq!(
+            Some(Response::Mechanisms("PLAIN".to_owned())),
+            auth.check(&Request::SaslListMechs)
+        );
+        assert_eq!(
+            Some(Response::AuthError),
+            auth.check(&sasl_auth("CRAM-MD5", "\0alice\0secret"))
+        );
+        assert_eq!(
+            Some(Response::Authenticated),
+            auth.check(&sasl_auth("PLAIN", "\0alice\0secret"))
+        );
+        assert_eq!(None, auth.check(&get));
+
+        // a failed attempt drops an earlier authentication
+        assert_eq!(
+            Some(Response::AuthError),
+            auth.check(&sasl_auth("PLAIN", "\0alice\0wrong"))
+        );
+        assert_eq!(Some(Response::AuthError), auth.check(&get));
+    }
+
+    #[test]
+    fn test_authenticator_resp() {
+        let credentials = Credentials::parse("alice:secret\n").unwrap();
+        let auth = Authenticator::new(Arc::new(credentials));
+        let args = |words: &[&str]| words.iter().map(|w| Slice::from(*w)).collect::<Vec<_>>();
+        let noauth = RespValue::Error("NOAUTH Authentication required.".to_owned());
+
+        assert_eq!(Some(noauth), auth.check_resp(&args(&["GET", "a"])));
+        match auth.check_resp(&args(&["AUTH", "alice", "wrong"])) {
+            Some(RespValue::Error(e)) => assert!(e.starts_with("WRONGPASS")),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match auth.check_resp(&args(&["AUTH", "secret"])) {
+            Some(RespValue::Error(_)) => {}
+            r => panic!("unexpected reply {:?}", r),
+        }
+        assert_eq!(
+            Some(Response::Resp(RespValue::Simple("OK".to_owned()))),
+            auth.check(&Request::Resp(args(&["auth", "alice", "secret"])))
+        );
+        assert_eq!(None, auth.check_resp(&args(&["GET", "a"])));
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashSet;
+use std::fs::copy;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::remove_dir_all;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bincode::deserialize_from;
+use bincode::serialize_into;
+use crc::crc32;
+use crc::crc32::Hasher32;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use sstable::TableReader;
+
+use crate::blob::BLOB_EXT;
+use crate::error::err;
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::options::Options;
+use crate::store::Store;
+
+/// Written ahead of every `BackupMeta`. Bumped when the meta changes in a way
+/// older code can't read.
+const BACKUP_META_VERSION: u32 = 1;
+const SHARED_DIR: &str = "shared";
+const PRIVATE_DIR: &str = "private";
+const META_DIR: &str = "meta";
+const TMP_DIR: &str = "tmp";
+
+/// A backed up file, by path relative to the work dir.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub crc: u32,
+}
+
+/// The files of one backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackupMeta {
+    pub id: u64,
+    pub created_at: u64,
+    /// Sstables and blob files. They never change once written, so all
+    /// backups share one copy of each.
+    pub shared_files: Vec<BackupFile>,
+    /// The manifests and WAL segments, copied for every backup.
+    pub private_files: Vec<BackupFile>,
+}
+
+/// Where a work dir is restored from at startup.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreSource {
+    /// A backup engine dir, and the backup to restore, the newest if None.
+    Backup(String, Option<u64>),
+    Checkpoint(String),
+}
+
+/// Keeps numbered backups of a store in a directory. Each backup copies only
+/// the sstables and blob files no earlier backup has.
+pub struct BackupEngine {
+    dir: PathBuf,
+}
+
+impl BackupEngine {
+    pub fn new<P: AsRef<Path>>(dir: P) -> MyResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub in &[SHARED_DIR, PRIVATE_DIR, META_DIR] {
+            create_dir_all(dir.join(sub))?;
+        }
+        Ok(BackupEngine { dir })
+    }
+
+    /// Backs up a checkpoint of `store` and returns the id of the backup.
+    pub fn create_backup(&self, store: &Store) -> MyResult<u64> {
+        let id = self.list_backups()?.last().map_or(1, |m| m.id + 1);
+        let checkpoint = self.dir.join(TMP_DIR);
+        if checkpoint.exists() {
+            remove_dir_all(&checkpoint)?;
+        }
+        store.checkpoint(&checkpoint)?;
+        let meta = self.copy_checkpoint(id, &checkpoint);
+        remove_dir_all(&checkpoint)?;
+        let meta = meta?;
+        self.write_meta(&meta)?;
+        info!(
+            "backup {} created, {} shared files, {} private files",
+            id,
+            meta.shared_files.len(),
+            meta.private_files.len()
+        );
+        Ok(id)
+    }
+
+    fn copy_checkpoint(&self, id: u64, checkpoint: &Path) -> MyResult<BackupMeta> {
+        let mut meta = BackupMeta {
+            id,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            shared_files: vec![],
+            private_files: vec![],
+        };
+        for rel in list_files(checkpoint)? {
+            let src = checkpoint.join(&rel);
+            let file = BackupFile {
+                name: rel.to_string_lossy().into_owned(),
+                size: src.metadata()?.len(),
+                crc: file_crc(&src)?,
+            };
+            if !is_shared(&rel) {
+                copy_file(&src, &self.private_dir(id).join(&rel))?;
+                meta.private_files.push(file);
+                continue;
+            }
+            let dst = self.dir.join(SHARED_DIR).join(&rel);
+            if !dst.exists() {
+                copy_file(&src, &dst)?;
+            } else if dst.metadata()?.len() != file.size {
+                return err(
+                    StatusCode::IOError,
+                    format!("backed up {} differs in size", file.name),
+                );
+            }
+            meta.shared_files.push(file);
+        }
+        Ok(meta)
+    }
+
+    fn private_dir(&self, id: u64) -> PathBuf {
+        self.dir.join(PRIVATE_DIR).join(id.to_string())
+    }
+
+    fn meta_path(&self, id: u64) -> PathBuf {
+        self.dir.join(META_DIR).join(id.to_string())
+    }
+
+    fn write_meta(&self, meta: &BackupMeta) -> MyResult<()> {
+        let path = self.meta_path(meta.id);
+        let tmp_path = path.with_extension("tmp");
+        let mut f = File::create(&tmp_path)?;
+        serialize_into(&mut f, &BACKUP_META_VERSION)?;
+        serialize_into(&mut f, meta)?;
+        f.sync_all()?;
+        rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Every complete backup, oldest first.
+    pub fn list_backups(&self) -> MyResult<Vec<BackupMeta>> {
+        let mut metas = vec![];
+        for entry in read_dir(self.dir.join(META_DIR))? {
+            let path = entry?.path();
+            // skips metas still being written
+            if path.extension().is_some() {
+                continue;
+            }
+            let mut f = File::open(&path)?;
+            let version: u32 = deserialize_from(&mut f)?;
+            if version > BACKUP_META_VERSION {
+                return err(
+                    StatusCode::NotSupport,
+                    format!("backup meta version {} is too new", version),
+                );
+            }
+            metas.push(deserialize_from::<_, BackupMeta>(&mut f)?);
+        }
+        metas.sort_by_key(|m| m.id);
+        Ok(metas)
+    }
+
+    /// Rebuilds the work dir of backup `id` in `work_dir`, which must not
+    /// exist or be empty. The restored files are checked against the
+    /// checksums taken at backup time, and removed again if any differs.
+    pub fn restore<P: AsRef<Path>>(&self, id: u64, work_dir: P) -> MyResult<()> {
+        let work_dir = work_dir.as_ref();
+        check_restore_dir(work_dir)?;
+        let meta = match self.list_backups()?.into_iter().find(|m| m.id == id) {
+            Some(meta) => meta,
+            None => return err(StatusCode::NotFound, format!("no backup {}", id)),
+        };
+        let shared = meta
+            .shared_files
+            .iter()
+            .map(|f| (self.dir.join(SHARED_DIR).join(&f.name), f));
+        let private = meta
+            .private_files
+            .iter()
+            .map(|f| (self.private_dir(id).join(&f.name), f));
+        let r = shared.chain(private).try_for_each(|(src, file)| {
+            let dst = work_dir.join(&file.name);
+            copy_file(&src, &dst)?;
+            if dst.metadata()?.len() != file.size || file_crc(&dst)? != file.crc {
+                return err(
+                    StatusCode::ChecksumError,
+                    format!("restored {} is corrupt", file.name),
+                );
+            }
+            Ok(())
+        });
+        finish_restore(work_dir, r)?;
+        info!("backup {} restored to {}", id, work_dir.display());
+        Ok(())
+    }
+
+    /// Deletes all but the newest `keep` backups, and the shared files only
+    /// they used.
+    pub fn purge_old_backups(&self, keep: usize) -> MyResult<()> {
+        let metas = self.list_backups()?;
+        if metas.len() <= keep {
+            return Ok(());
+        }
+        let (old, kept) = metas.split_at(metas.len() - keep);
+        for meta in old {
+            remove_file(self.meta_path(meta.id))?;
+            if self.private_dir(meta.id).exists() {
+                remove_dir_all(self.private_dir(meta.id))?;
+            }
+        }
+        let used = kept
+            .iter()
+            .flat_map(|m| m.shared_files.iter().map(|f| &f.name))
+            .collect::<HashSet<_>>();
+        for file in old.iter().flat_map(|m| m.shared_files.iter()) {
+            let path = self.dir.join(SHARED_DIR).join(&file.name);
+            if !used.contains(&file.name) && path.exists() {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restores `opt.work_dir` from `source` before the store is opened.
+pub fn restore_work_dir(source: &RestoreSource, opt: &Options) -> MyResult<()> {
+    match source {
+        RestoreSource::Backup(dir, id) => {
+            let engine = BackupEngine::new(dir)?;
+            let id = match id {
+                Some(id) => *id,
+                None => match engine.list_backups()?.last() {
+                    Some(meta) => meta.id,
+                    None => return err(StatusCode::NotFound, format!("no backups in {}", dir)),
+                },
+            };
+            engine.restore(id, &opt.work_dir)
+        }
+        RestoreSource::Checkpoint(dir) => restore_checkpoint(dir, opt),
+    }
+}
+
+/// Copies the checkpoint in `dir` to `opt.work_dir`, which must not exist or
+/// be empty. Checkpoints carry no checksums of their own, so every restored
+/// sstable is verified block by block instead.
+pub fn restore_checkpoint<P: AsRef<Path>>(dir: P, opt: &Options) -> MyResult<()> {
+    let (dir, work_dir) = (dir.as_ref(), Path::new(&opt.work_dir));
+    check_restore_dir(work_dir)?;
+    let r = list_files(dir)?.into_iter().try_for_each(|rel| {
+        let dst = work_dir.join(&rel);
+        copy_file(&dir.join(&rel), &dst)?;
+        if rel.extension().map_or(false, |x| x == "sst") {
+            TableReader::new(&dst, opt.get_table_opt().clone())?.verify()?;
+        }
+        Ok(())
+    });
+    finish_restore(work_dir, r)?;
+    info!(
+        "checkpoint {} restored to {}",
+        dir.display(),
+        work_dir.display()
+    );
+    Ok(())
+}
+
+fn check_restore_dir(work_dir: &Path) -> MyResult<()> {
+    if work_dir.exists() && read_dir(work_dir)?.next().is_some() {
+        return err!(StatusCode::InvalidArgument, "restore dir is not empty");
+    }
+    Ok(())
+}
+
+/// Leaves no half restored work dir behind when `r` failed.
+fn finish_restore(work_dir: &Path, r: MyResult<()>) -> MyResult<()> {
+    if r.is_err() && work_dir.exists() {
+        remove_dir_all(work_dir)?;
+    }
+    r
+}
+
+fn file_crc(path: &Path) -> MyResult<u32> {
+    let mut f = File::open(path)?;
+    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            return Ok(digest.sum32());
+        }
+        digest.write(&buf[..n]);
+    }
+}
+
+fn is_shared(path: &Path) -> bool {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some(ext) => ext == "sst" || ext == BLOB_EXT,
+        None => false,
+    }
+}
+
+/// Paths of the files under `root`, relative to it.
+fn list_files(root: &Path) -> MyResult<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let rel = dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(rel);
+            } else {
+                files.push(rel);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Copies through a temporary file, so an interrupted copy never leaves a
+/// file that looks complete.
+fn copy_file(src: &Path, dst: &Path) -> MyResult<()> {
+    if let Some(parent) = dst.parent() {
+        create_dir_all(parent)?;
+    }
+    let tmp = PathBuf::from(format!("{}.tmp", dst.display()));
+    copy(src, &tmp)?;
+    rename(&tmp, dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::options::WriteOptions;
+    use crate::request::{GetterType, Request, SetterType};
+    use crate::response::Response;
+    use crate::slice::Slice;
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    fn set(store: &Store, key: &str) -> MyResult<()> {
+        let payload = Slice::from(key);
+        store.apply(Request::Setter {
+            setter: SetterType::Set,
+            key: Slice::from(key),
+            flags: 0,
+            ttl: 0,
+            bytes: payload.len(),
+            payload,
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        Ok(())
+    }
+
+    fn keys(store: &Store, keys: &[&str]) -> MyResult<Vec<Slice>> {
+        let r = store.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: keys.iter().map(|k| Slice::from(*k)).collect(),
+        })?;
+        match r {
+            Response::Get(items) => Ok(items.into_iter().map(|item| item.key).collect()),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_backup() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let engine = BackupEngine::new(format!("{}-backup", opt.work_dir))?;
+        let store = Store::new(opt.clone())?;
+        set(&store, "a")?;
+        set(&store, "app:b")?;
+        assert_eq!(1, engine.create_backup(&store)?);
+        set(&store, "c")?;
+        assert_eq!(2, engine.create_backup(&store)?);
+        drop(store);
+
+        let backups = engine.list_backups()?;
+        assert_eq!(vec![1, 2], backups.iter().map(|m| m.id).collect::<Vec<_>>());
+        // the second backup reuses the files of the first
+        for file in &backups[0].shared_files {
+            assert!(backups[1].shared_files.contains(file));
+        }
+        assert!(backups[1].shared_files.len() > backups[0].shared_files.len());
+
+        let mut restored_opt = opt.clone();
+        restored_opt.work_dir = format!("{}-restored", opt.work_dir);
+        engine.restore(1, &restored_opt.work_dir)?;
+        assert!(engine.restore(1, &restored_opt.work_dir).is_err());
+        let restored = Store::new(restored_opt)?;
+        assert_eq!(
+            vec![Slice::from("a"), Slice::from("app:b")],
+            keys(&restored, &["a", "app:b", "c"])?
+        );
+
+        engine.purge_old_backups(1)?;
+        let backups = engine.list_backups()?;
+        assert_eq!(1, backups.len());
+        for file in &backups[0].shared_files {
+            assert!(engine.dir.join(SHARED_DIR).join(&file.name).exists());
+        }
+        assert!(!engine.private_dir(1).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_work_dir() -> MyResult<()> {
+        let opt = get_test_opt();
+        let backup_dir = format!("{}-backup", opt.work_dir);
+        let checkpoint_dir = format!("{}-checkpoint", opt.work_dir);
+        let store = Store::new(opt.clone())?;
+        set(&store, "a")?;
+        BackupEngine::new(&backup_dir)?.create_backup(&store)?;
+        store.checkpoint(&checkpoint_dir)?;
+        drop(store);
+
+        let sources = vec![
+            RestoreSource::Backup(backup_dir.clone(), None),
+            RestoreSource::Checkpoint(checkpoint_dir.clone()),
+        ];
+        for (i, source) in sources.iter().enumerate() {
+            let mut restored_opt = opt.clone();
+            restored_opt.work_dir = format!("{}-restored{}", opt.work_dir, i);
+            restore_work_dir(source, &restored_opt)?;
+            let restored = Store::new(restored_opt)?;
+            assert_eq!(vec![Slice::from("a")], keys(&restored, &["a"])?);
+        }
+
+        // a corrupt shared file fails the restore and leaves nothing behind
+        let shared = BackupEngine::new(&backup_dir)?.list_backups()?[0].shared_files[0].clone();
+        let path = Path::new(&backup_dir).join(SHARED_DIR).join(&shared.name);
+        let mut data = std::fs::read(&path)?;
+        data[0] ^= 0xff;
+        std::fs::write(&path, data)?;
+        let mut restored_opt = opt.clone();
+        restored_opt.work_dir = format!("{}-corrupt", opt.work_dir);
+        let r = restore_work_dir(&RestoreSource::Backup(backup_dir, Some(1)), &restored_opt);
+        assert_eq!(StatusCode::ChecksumError, r.unwrap_err().code);
+        assert!(!Path::new(&restored_opt.work_dir).exists());
+        Ok(())
+    }
+}
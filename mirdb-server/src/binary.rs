@@ -0,0 +1,532 @@
+use crate::error::MyResult;
+use crate::options::WriteOptions;
+use crate::parser_util::macros::IRResult;
+use crate::request::{GetterType, Request, SetterType, StatsType};
+use crate::response::Response;
+use crate::response::Writer;
+use crate::slice::Slice;
+use crate::store::MAX_KEY_LEN;
+use crate::store::TOO_LARGE;
+
+/// The first byte of a binary protocol request, which no text command
+/// starts with.
+pub const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+const HEADER_LEN: usize = 24;
+
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_REPLACE: u8 = 0x03;
+const OP_DELETE: u8 = 0x04;
+const OP_INCREMENT: u8 = 0x05;
+const OP_DECREMENT: u8 = 0x06;
+const OP_QUIT: u8 = 0x07;
+const OP_GETQ: u8 = 0x09;
+const OP_NOOP: u8 = 0x0a;
+const OP_VERSION: u8 = 0x0b;
+const OP_GETK: u8 = 0x0c;
+const OP_GETKQ: u8 = 0x0d;
+const OP_APPEND: u8 = 0x0e;
+const OP_PREPEND: u8 = 0x0f;
+const OP_STAT: u8 = 0x10;
+const OP_SETQ: u8 = 0x11;
+const OP_ADDQ: u8 = 0x12;
+const OP_REPLACEQ: u8 = 0x13;
+const OP_DELETEQ: u8 = 0x14;
+const OP_INCREMENTQ: u8 = 0x15;
+const OP_DECREMENTQ: u8 = 0x16;
+const OP_QUITQ: u8 = 0x17;
+const OP_APPENDQ: u8 = 0x19;
+const OP_PREPENDQ: u8 = 0x1a;
+const OP_GAT: u8 = 0x1d;
+const OP_GATQ: u8 = 0x1e;
+const OP_SASL_LIST_MECHS: u8 = 0x20;
+const OP_SASL_AUTH: u8 = 0x21;
+const OP_GATK: u8 = 0x23;
+const OP_GATKQ: u8 = 0x24;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_VALUE_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_NOT_STORED: u16 = 0x0005;
+const STATUS_NON_NUMERIC: u16 = 0x0006;
+const STATUS_AUTH_ERROR: u16 = 0x0020;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+/// Increments and decrements have the largest extras.
+const MAX_EXTRAS_LEN: usize = 20;
+
+/// The expiration of an increment or decrement that is not to create a
+/// missing key.
+const NO_CREATE: u32 = 0xffff_ffff;
+
+/// What the response to a binary request is framed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryHeader {
+    opcode: u8,
+    opaque: u32,
+    key: Slice,
+}
+
+impl BinaryHeader {
+    /// Quiet requests are only answered on errors, quiet gets on hits too.
+    fn is_quiet(&self) -> bool {
+        match self.opcode {
+            OP_GETQ | OP_GETKQ | OP_GATQ | OP_GATKQ | OP_SETQ | OP_ADDQ | OP_REPLACEQ
+            | OP_DELETEQ | OP_INCREMENTQ | OP_DECREMENTQ | OP_QUITQ | OP_APPENDQ | OP_PREPENDQ => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn echoes_key(&self) -> bool {
+        match self.opcode {
+            OP_GETK | OP_GETKQ | OP_GATK | OP_GATKQ => true,
+            _ => false,
+        }
+    }
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    let mut a = [0; 4];
+    a.copy_from_slice(&b[..4]);
+    u32::from_be_bytes(a)
+}
+
+fn be_u64(b: &[u8]) -> u64 {
+    let mut a = [0; 8];
+    a.copy_from_slice(&b[..8]);
+    u64::from_be_bytes(a)
+}
+
+/// The length of the body after the header at the start of `src`.
+pub fn body_len(src: &[u8]) -> usize {
+    be_u32(&src[8..]) as usize
+}
+
+/// Parses a binary request, which starts with `REQUEST_MAGIC`. Unknown
+/// opcodes parse as `Request::Error` and bodies shorter than their key and
+/// extras as `Request::Malformed`, once the whole packet is there. A body
+/// too long for a value of `max_item_size` bytes is not waited for: the
+/// header alone parses as `Request::TooLarge`, leaving the body to be
+/// skipped.
+pub fn parse(src: &[u8], max_item_size: usize) -> IRResult<(Request, BinaryHeader)> {
+    if src.len() < HEADER_LEN {
+        return IRResult::Incomplete(HEADER_LEN - src.len());
+    }
+    let key_len = u16::from_be_bytes([src[2], src[3]]) as usize;
+    let extras_len = src[4] as usize;
+    let body_len = body_len(src);
+    let mut header = BinaryHeader {
+        opcode: src[1],
+        opaque: be_u32(&src[12..]),
+        key: Slice::new(),
+    };
+    if body_len > max_item_size + MAX_KEY_LEN + MAX_EXTRAS_LEN {
+        return IRResult::Ok((&src[HEADER_LEN..], (Request::TooLarge, header)));
+    }
+    if src.len() < HEADER_LEN + body_len {
+        return IRResult::Incomplete(HEADER_LEN + body_len - src.len());
+    }
+    let (body, remaining) = src[HEADER_LEN..].split_at(body_len);
+    if extras_len + key_len > body_len {
+        return IRResult::Ok((remaining, (Request::Malformed, header)));
+    }
+    let (extras, body) = body.split_at(extras_len);
+    let (key, value) = body.split_at(key_len);
+    header.key = Slice::from(key);
+    let cas = be_u64(&src[16..]);
+    let request = to_request(&header, extras, value, cas).unwrap_or(Request::Error);
+    IRResult::Ok((remaining, (request, header)))
+}
+
+fn to_request(header: &BinaryHeader, extras: &[u8], value: &[u8], cas: u64) -> Option<Request> {
+    let key = header.key.clone();
+    let no_reply = header.is_quiet();
+    let keys = vec![key.clone()];
+    Some(match header.opcode {
+        // gets rather than get, for the cas unique of the response
+        OP_GET | OP_GETQ | OP_GETK | OP_GETKQ => Request::Getter {
+            getter: GetterType::Gets,
+            keys,
+        },
+        OP_GAT | OP_GATQ | OP_GATK | OP_GATKQ if extras.len() == 4 => Request::GetAndTouch {
+            getter: GetterType::Gets,
+            ttl: be_u32(extras),
+            keys,
+        },
+        OP_SET | OP_SETQ | OP_ADD | OP_ADDQ | OP_REPLACE | OP_REPLACEQ if extras.len() == 8 => {
+            let setter = match header.opcode {
+                OP_SET | OP_SETQ if cas != 0 => SetterType::Cas(cas),
+                OP_SET | OP_SETQ => SetterType::Set,
+                OP_ADD | OP_ADDQ => SetterType::Add,
+                _ => SetterType::Replace,
+            };
+            Request::Setter {
+                setter,
+                key,
+                flags: be_u32(extras),
+                ttl: be_u32(&extras[4..]),
+                bytes: value.len(),
+                payload: Slice::from(value),
+                no_reply,
+                write_opt: WriteOptions::default(),
+            }
+        }
+        OP_APPEND | OP_APPENDQ | OP_PREPEND | OP_PREPENDQ if extras.is_empty() => {
+            let setter = match header.opcode {
+                OP_APPEND | OP_APPENDQ => SetterType::Append,
+                _ => SetterType::Prepend,
+            };
+            Request::Setter {
+                setter,
+                key,
+                flags: 0,
+                ttl: 0,
+                bytes: value.len(),
+                payload: Slice::from(value),
+                no_reply,
+                write_opt: WriteOptions::default(),
+            }
+        }
+        OP_DELETE | OP_DELETEQ => Request::Deleter {
+            key,
+            no_reply,
+            write_opt: WriteOptions::default(),
+        },
+        OP_INCREMENT | OP_INCREMENTQ | OP_DECREMENT | OP_DECREMENTQ if extras.len() == 20 => {
+            let ttl = be_u32(&extras[16..]);
+            Request::Arith {
+                key,
+                decr: header.opcode == OP_DECREMENT || header.opcode == OP_DECREMENTQ,
+                delta: be_u64(extras),
+                initial: if ttl == NO_CREATE {
+                    None
+                } else {
+                    Some((be_u64(&extras[8..]), ttl))
+                },
+                no_reply,
+            }
+        }
+        OP_QUIT | OP_QUITQ => Request::Quit,
+        OP_NOOP => Request::Noop,
+        OP_VERSION => Request::Version,
+        OP_STAT if key.is_empty() => Request::Stats(StatsType::General),
+        OP_SASL_LIST_MECHS => Request::SaslListMechs,
+        // PLAIN takes a single step, so there is no OP_SASL_STEP
+        OP_SASL_AUTH => Request::SaslAuth {
+            mechanism: key,
+            data: Slice::from(value),
+        },
+        _ => return None,
+    })
+}
+
+fn write_packet(
+    writer: &mut Writer,
+    header: &BinaryHeader,
+    status: u16,
+    cas: u64,
+    extras: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> MyResult<()> {
+    let mut h = [0; HEADER_LEN];
+    h[0] = RESPONSE_MAGIC;
+    h[1] = header.opcode;
+    h[2..4].copy_from_slice(&(key.len() as u16).to_be_bytes());
+    h[4] = extras.len() as u8;
+    h[6..8].copy_from_slice(&status.to_be_bytes());
+    let body_len = (extras.len() + key.len() + value.len()) as u32;
+    h[8..12].copy_from_slice(&body_len.to_be_bytes());
+    h[12..16].copy_from_slice(&header.opaque.to_be_bytes());
+    h[16..24].copy_from_slice(&cas.to_be_bytes());
+    writer.write(&h)?;
+    writer.write(extras)?;
+    writer.write(key)?;
+    writer.write(value)
+}
+
+fn write_error(writer: &mut Writer, header: &BinaryHeader, status: u16, msg: &str) -> MyResult<()> {
+    write_packet(writer, header, status, 0, &[], &[], msg.as_bytes())
+}
+
+/// Writes `response` as the answer to the binary request `header` came
+/// with. Successes of quiet requests, and misses of quiet gets, write
+/// nothing.
+pub fn write_response(
+    header: &BinaryHeader,
+    response: &Response,
+    writer: &mut Writer,
+) -> MyResult<()> {
+    let quiet = header.is_quiet();
+    match response {
+        Response::Gets(items) => match items.first() {
+            Some((item, cas)) => {
+                let key = if header.echoes_key() {
+                    &item.key[..]
+                } else {
+                    &[][..]
+                };
+                let flags = item.flags.to_be_bytes();
+                write_packet(writer, header, STATUS_OK, *cas, &flags, key, &item.data[..])
+            }
+            None if quiet => Ok(()),
+            None => write_error(writer, header, STATUS_KEY_NOT_FOUND, "Not found"),
+        },
+        Response::Stored | Response::Deleted | Response::Touched | Response::Ok if quiet => Ok(()),
+        Response::Stored | Response::Deleted | Response::Touched | Response::Ok => {
+            write_packet(writer, header, STATUS_OK, 0, &[], &[], &[])
+        }
+        Response::Number(_) if quiet => Ok(()),
+        Response::Number(n) => {
+            write_packet(writer, header, STATUS_OK, 0, &[], &[], &n.to_be_bytes())
+        }
+        Response::Version(v) | Response::Mechanisms(v) => {
+            write_packet(writer, header, STATUS_OK, 0, &[], &[], v.as_bytes())
+        }
+        Response::Authenticated => {
+            write_packet(writer, header, STATUS_OK, 0, &[], &[], b"Authenticated")
+        }
+        Response::AuthError => write_error(writer, header, STATUS_AUTH_ERROR, "Auth failure"),
+        Response::Stats(v) => {
+            for (name, value) in v {
+                write_packet(
+                    writer,
+                    header,
+                    STATUS_OK,
+                    0,
+                    &[],
+                    name.as_bytes(),
+                    value.as_bytes(),
+                )?;
+            }
+            write_packet(writer, header, STATUS_OK, 0, &[], &[], &[])
+        }
+        Response::NotFound => write_error(writer, header, STATUS_KEY_NOT_FOUND, "Not found"),
+        Response::Exists => write_error(writer, header, STATUS_KEY_EXISTS, "Data exists for key"),
+        // as in memcached, an add of a present key and a replace of a missing
+        // one say why nothing was stored
+        Response::NotStored => match header.opcode {
+            OP_ADD | OP_ADDQ => {
+                write_error(writer, header, STATUS_KEY_EXISTS, "Data exists for key")
+            }
+            OP_REPLACE | OP_REPLACEQ => {
+                write_error(writer, header, STATUS_KEY_NOT_FOUND, "Not found")
+            }
+            _ => write_error(writer, header, STATUS_NOT_STORED, "Not stored"),
+        },
+        Response::ClientError(e) => match header.opcode {
+            OP_INCREMENT | OP_INCREMENTQ | OP_DECREMENT | OP_DECREMENTQ => {
+                write_error(writer, header, STATUS_NON_NUMERIC, e)
+            }
+            _ => write_error(writer, header, STATUS_INVALID_ARGUMENTS, e),
+        },
+        Response::Error => write_error(writer, header, STATUS_UNKNOWN_COMMAND, "Unknown command"),
+        Response::ServerError(e) if e == TOO_LARGE => {
+            write_error(writer, header, STATUS_VALUE_TOO_LARGE, "Too large")
+        }
+        Response::ServerError(e) => write_error(writer, header, STATUS_INTERNAL_ERROR, e),
+        _ => write_error(
+            writer,
+            header,
+            STATUS_INTERNAL_ERROR,
+            "Unsupported response",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use crate::options::MB;
+    use crate::response::BufferWriter;
+    use crate::response::GetRespItem;
+
+    use super::*;
+
+    fn request(opcode: u8, extras: &[u8], key: &[u8], value: &[u8], cas: u64) -> Vec<u8> {
+        let mut v = vec![REQUEST_MAGIC, opcode];
+        v.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        v.extend_from_slice(&[extras.len() as u8, 0, 0, 0]);
+        let body_len = (extras.len() + key.len() + value.len()) as u32;
+        v.extend_from_slice(&body_len.to_be_bytes());
+        v.extend_from_slice(&7u32.to_be_bytes());
+        v.extend_from_slice(&cas.to_be_bytes());
+        v.extend_from_slice(extras);
+        v.extend_from_slice(key);
+        v.extend_from_slice(value);
+        v
+    }
+
+    fn header(opcode: u8, key: &str) -> BinaryHeader {
+        BinaryHeader {
+            opcode,
+            opaque: 7,
+            key: Slice::from(key),
+        }
+    }
+
+    fn response(header: &BinaryHeader, response: Response) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        write_response(header, &response, &mut BufferWriter::new(&mut buf)).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_parse() {
+        let src = request(OP_GETK, &[], b"abc", &[], 0);
+        assert_eq!(IRResult::Incomplete(1), parse(&src[..src.len() - 1], MB));
+        assert_eq!(IRResult::Incomplete(4), parse(&src[..20], MB));
+        assert_eq!(
+            IRResult::Ok((
+                &[][..],
+                (
+                    Request::Getter {
+                        getter: GetterType::Gets,
+                        keys: vec![Slice::from("abc")],
+                    },
+                    header(OP_GETK, "abc")
+                )
+            )),
+            parse(&src, MB)
+        );
+
+        let mut extras = 3u32.to_be_bytes().to_vec();
+        extras.extend_from_slice(&60u32.to_be_bytes());
+        let mut src = request(OP_SETQ, &extras, b"abc", b"xyz", 5);
+        src.push(REQUEST_MAGIC);
+        assert_eq!(
+            IRResult::Ok((
+                &[REQUEST_MAGIC][..],
+                (
+                    Request::Setter {
+                        setter: SetterType::Cas(5),
+                        key: Slice::from("abc"),
+                        flags: 3,
+                        ttl: 60,
+                        bytes: 3,
+                        payload: Slice::from("xyz"),
+                        no_reply: true,
+                        write_opt: WriteOptions::default(),
+                    },
+                    header(OP_SETQ, "abc")
+                )
+            )),
+            parse(&src, MB)
+        );
+
+        let mut extras = 2u64.to_be_bytes().to_vec();
+        extras.extend_from_slice(&10u64.to_be_bytes());
+        extras.extend_from_slice(&NO_CREATE.to_be_bytes());
+        assert_eq!(
+            IRResult::Ok((
+                &[][..],
+                (
+                    Request::Arith {
+                        key: Slice::from("abc"),
+                        decr: true,
+                        delta: 2,
+                        initial: None,
+                        no_reply: false,
+                    },
+                    header(OP_DECREMENT, "abc")
+                )
+            )),
+            parse(&request(OP_DECREMENT, &extras, b"abc", &[], 0), MB)
+        );
+
+        assert_eq!(
+            IRResult::Ok((
+                &[][..],
+                (
+                    Request::SaslAuth {
+                        mechanism: Slice::from("PLAIN"),
+                        data: Slice::from("\0alice\0secret"),
+                    },
+                    header(OP_SASL_AUTH, "PLAIN")
+                )
+            )),
+            parse(
+                &request(OP_SASL_AUTH, &[], b"PLAIN", b"\0alice\0secret", 0),
+                MB
+            )
+        );
+
+        // a set without its extras, and an unknown opcode
+        for src in &[
+            request(OP_SET, &[], b"abc", b"xyz", 0),
+            request(0x42, &[], &[], &[], 0),
+        ] {
+            match parse(src, MB) {
+                IRResult::Ok((_, (Request::Error, _))) => {}
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        // a body shorter than its key
+        let mut src = request(OP_GET, &[], b"abc", &[], 0);
+        src[3] = 9;
+        match parse(&src, MB) {
+            IRResult::Ok((_, (Request::Malformed, _))) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // a body too long for any value is refused before it is read
+        let mut src = request(OP_SET, &extras, b"abc", b"xyz", 0);
+        src[8..12].copy_from_slice(&(2 * MB as u32).to_be_bytes());
+        assert_eq!(
+            IRResult::Ok((&src[HEADER_LEN..], (Request::TooLarge, header(OP_SET, "")))),
+            parse(&src, MB)
+        );
+    }
+
+    #[test]
+    fn test_write_response() {
+        let item = GetRespItem::new(Slice::from("abc"), Slice::from("xyz"), 3, 3);
+        let v = response(&header(OP_GETK, "abc"), Response::Gets(vec![(item, 9)]));
+        assert_eq!(&[RESPONSE_MAGIC, OP_GETK, 0, 3, 4, 0, 0, 0][..], &v[..8]);
+        assert_eq!(10u32.to_be_bytes(), v[8..12]);
+        assert_eq!(7u32.to_be_bytes(), v[12..16]);
+        assert_eq!(9u64.to_be_bytes(), v[16..24]);
+        assert_eq!(b"\0\0\0\x03abcxyz", &v[24..]);
+
+        // quiet gets miss silently, other gets answer the miss
+        assert!(response(&header(OP_GETQ, "abc"), Response::Gets(vec![])).is_empty());
+        let v = response(&header(OP_GET, "abc"), Response::Gets(vec![]));
+        assert_eq!(STATUS_KEY_NOT_FOUND.to_be_bytes(), v[6..8]);
+        assert_eq!(b"Not found", &v[24..]);
+
+        // quiet writes only answer errors
+        assert!(response(&header(OP_SETQ, "abc"), Response::Stored).is_empty());
+        let v = response(&header(OP_ADDQ, "abc"), Response::NotStored);
+        assert_eq!(STATUS_KEY_EXISTS.to_be_bytes(), v[6..8]);
+
+        let v = response(&header(OP_INCREMENT, "abc"), Response::Number(42));
+        assert_eq!(8u32.to_be_bytes(), v[8..12]);
+        assert_eq!(42u64.to_be_bytes(), v[24..]);
+        let v = response(
+            &header(OP_INCREMENT, "abc"),
+            Response::ClientError("non-numeric".to_owned()),
+        );
+        assert_eq!(STATUS_NON_NUMERIC.to_be_bytes(), v[6..8]);
+
+        let v = response(
+            &header(OP_STAT, ""),
+            Response::Stats(vec![("pid".to_owned(), "1".to_owned())]),
+        );
+        assert_eq!(2 * HEADER_LEN + 4, v.len());
+        assert_eq!(b"pid1", &v[24..28]);
+        assert_eq!(0u32.to_be_bytes(), v[36..40]);
+
+        let v = response(&header(OP_SASL_AUTH, "PLAIN"), Response::AuthError);
+        assert_eq!(STATUS_AUTH_ERROR.to_be_bytes(), v[6..8]);
+        assert_eq!(b"Auth failure", &v[24..]);
+    }
+}
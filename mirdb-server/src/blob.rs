@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use bincode::{deserialize, serialize};
+use crc::crc32;
+use crc::crc32::Hasher32;
+use integer_encoding::FixedInt;
+use serde::{Deserialize, Serialize};
+
+use crate::env::Env;
+use crate::env::EnvFile;
+use crate::env::SharedEnv;
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::utils::make_file_name;
+use crate::utils::read_lock;
+use crate::utils::write_lock;
+
+pub const BLOB_EXT: &str = "blob";
+
+/// First byte of a table value that points into a blob file. Inline values are
+/// bincode encoded `Option<StorePayload>`s, which always start with 0 or 1.
+const BLOB_VALUE_TAG: u8 = 0xb1;
+
+const BLOB_CRC_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BlobIndex {
+    pub file_number: usize,
+    pub offset: u64,
+    pub size: u32,
+}
+
+impl BlobIndex {
+    /// Bytes the record occupies in its blob file.
+    pub fn record_size(&self) -> u64 {
+        (BLOB_CRC_LEN + self.size as usize) as u64
+    }
+
+    pub fn encode(&self) -> MyResult<Vec<u8>> {
+        let mut buf = vec![BLOB_VALUE_TAG];
+        buf.extend(serialize(self)?);
+        Ok(buf)
+    }
+
+    /// Returns the blob index stored in a table value, or None for inline values.
+    pub fn decode(v: &[u8]) -> MyResult<Option<BlobIndex>> {
+        if v.first() != Some(&BLOB_VALUE_TAG) {
+            return Ok(None);
+        }
+        Ok(Some(deserialize(&v[1..])?))
+    }
+}
+
+pub fn blob_file_name(file_number: usize) -> String {
+    make_file_name(file_number, BLOB_EXT)
+}
+
+pub fn blob_file_number(file_name: &str) -> Option<usize> {
+    file_name.split('.').next().and_then(|x| x.parse().ok())
+}
+
+fn crc(v: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+    digest.write(v);
+    digest.sum32()
+}
+
+/// Appends `[u32 crc][value]` records to a new blob file.
+pub struct BlobWriter {
+    file_number: usize,
+    path: PathBuf,
+    w: BufWriter<Box<dyn EnvFile>>,
+    offset: u64,
+}
+
+impl BlobWriter {
+    pub fn new<T: AsRef<Path>>(env: &dyn Env, path: T, file_number: usize) -> MyResult<Self> {
+        let f = env.create(path.as_ref())?;
+        Ok(BlobWriter {
+            file_number,
+            path: path.as_ref().to_path_buf(),
+            w: BufWriter::new(f),
+            offset: 0,
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn add(&mut self, v: &[u8]) -> MyResult<BlobIndex> {
+        let mut buf = [0; BLOB_CRC_LEN];
+        crc(v).encode_fixed(&mut buf);
+        self.w.write_all(&buf)?;
+        self.w.write_all(v)?;
+        let idx = BlobIndex {
+            file_number: self.file_number,
+            offset: self.offset,
+            size: v.len() as u32,
+        };
+        self.offset += idx.record_size();
+        Ok(idx)
+    }
+
+    pub fn finish(mut self) -> MyResult<()> {
+        self.w.flush()?;
+        self.w.get_ref().sync()?;
+        Ok(())
+    }
+}
+
+/// Reads values out of blob files, keeping their file handles open.
+pub struct BlobStore {
+    env: SharedEnv,
+    dir: PathBuf,
+    files: RwLock<HashMap<usize, Arc<Box<dyn EnvFile>>>>,
+}
+
+impl BlobStore {
+    pub fn new<T: AsRef<Path>>(env: SharedEnv, dir: T) -> Self {
+        BlobStore {
+            env,
+            dir: dir.as_ref().to_path_buf(),
+            files: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn file(&self, file_number: usize) -> MyResult<Arc<Box<dyn EnvFile>>> {
+        if let Some(f) = read_lock(&self.files).get(&file_number) {
+            return Ok(f.clone());
+        }
+        let f = Arc::new(self.env.open(&self.dir.join(blob_file_name(file_number)))?);
+        write_lock(&self.files).insert(file_number, f.clone());
+        Ok(f)
+    }
+
+    pub fn get(&self, idx: &BlobIndex) -> MyResult<Vec<u8>> {
+        let f = self.file(idx.file_number)?;
+        let mut buf = vec![0; idx.record_size() as usize];
+        f.read_exact_at(idx.offset, &mut buf)?;
+        let v = buf.split_off(BLOB_CRC_LEN);
+        if u32::decode_fixed(&buf) != crc(&v) {
+            return err!(StatusCode::ChecksumError, "blob checksum error");
+        }
+        Ok(v)
+    }
+
+    pub fn evict(&self, file_number: usize) {
+        write_lock(&self.files).remove(&file_number);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    #[test]
+    fn test_blob() -> MyResult<()> {
+        let opt = get_test_opt();
+        let dir = Path::new(&opt.work_dir);
+        let mut w = BlobWriter::new(opt.env.as_ref(), dir.join(blob_file_name(3)), 3)?;
+        let values = (0..10)
+            .map(|i| format!("value{}", i).repeat(i + 1).into_bytes())
+            .collect::<Vec<_>>();
+        let indexes = values
+            .iter()
+            .map(|v| w.add(v))
+            .collect::<MyResult<Vec<_>>>()?;
+        w.finish()?;
+
+        let store = BlobStore::new(opt.env.clone(), dir);
+        for (v, idx) in values.iter().zip(&indexes) {
+            let encoded = idx.encode()?;
+            assert_eq!(Some(*idx), BlobIndex::decode(&encoded)?);
+            assert_eq!(v, &store.get(idx)?);
+        }
+        assert_eq!(None, BlobIndex::decode(&serialize(&Some(1))?)?);
+        assert_eq!(Some(3), blob_file_number(&blob_file_name(3)));
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+use crate::error::MyResult;
+use crate::error::StatusCode;
+
+struct TokenInner {
+    cancelled: AtomicBool,
+    parent: Option<CancellationToken>,
+}
+
+/// Shared flag that long-running background jobs poll at safe points.
+/// Cancelling a token also cancels every token derived from it with `child`.
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<TokenInner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                cancelled: AtomicBool::new(false),
+                parent: None,
+            }),
+        }
+    }
+
+    pub fn child(&self) -> Self {
+        CancellationToken {
+            inner: Arc::new(TokenInner {
+                cancelled: AtomicBool::new(false),
+                parent: Some(self.clone()),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.inner.cancelled.load(SeqCst) {
+            return true;
+        }
+        match &self.inner.parent {
+            Some(parent) => parent.is_cancelled(),
+            None => false,
+        }
+    }
+
+    pub fn check(&self) -> MyResult<()> {
+        if self.is_cancelled() {
+            return err!(StatusCode::Cancelled, "");
+        }
+        Ok(())
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let root = CancellationToken::new();
+        let a = root.child();
+        let b = root.child();
+        let c = a.child();
+
+        a.cancel();
+        assert!(a.is_cancelled());
+        assert!(c.is_cancelled());
+        assert!(!b.is_cancelled());
+        assert!(!root.is_cancelled());
+        assert_eq!(StatusCode::Cancelled, c.check().unwrap_err().code);
+
+        root.cancel();
+        assert!(b.is_cancelled());
+        assert!(b.check().is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// Counters of the commands a store served, named as in memcached's `stats`.
+#[derive(Default)]
+pub struct CommandMetrics {
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    cmd_touch: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    delete_hits: AtomicU64,
+    delete_misses: AtomicU64,
+    touch_hits: AtomicU64,
+    touch_misses: AtomicU64,
+    cas_hits: AtomicU64,
+    cas_misses: AtomicU64,
+    cas_badval: AtomicU64,
+}
+
+/// How a `cas` command went.
+pub enum CasOutcome {
+    Stored,
+    NotFound,
+    Exists,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandStats {
+    pub cmd_get: u64,
+    pub cmd_set: u64,
+    pub cmd_touch: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    pub delete_hits: u64,
+    pub delete_misses: u64,
+    pub touch_hits: u64,
+    pub touch_misses: u64,
+    pub cas_hits: u64,
+    pub cas_misses: u64,
+    pub cas_badval: u64,
+}
+
+fn hit_or_miss(hit: bool, hits: &AtomicU64, misses: &AtomicU64) {
+    if hit {
+        hits.fetch_add(1, Relaxed);
+    } else {
+        misses.fetch_add(1, Relaxed);
+    }
+}
+
+impl CommandMetrics {
+    pub fn new() -> Self {
+        CommandMetrics::default()
+    }
+
+    /// A key looked up by `get` or `gets`.
+    pub fn record_get(&self, hit: bool) {
+        self.cmd_get.fetch_add(1, Relaxed);
+        hit_or_miss(hit, &self.get_hits, &self.get_misses);
+    }
+
+    /// A key looked up and touched by `gat` or `gats`, which counts as a get
+    /// too.
+    pub fn record_touch(&self, hit: bool) {
+        self.cmd_touch.fetch_add(1, Relaxed);
+        hit_or_miss(hit, &self.touch_hits, &self.touch_misses);
+        self.record_get(hit);
+    }
+
+    pub fn record_set(&self) {
+        self.cmd_set.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_delete(&self, hit: bool) {
+        hit_or_miss(hit, &self.delete_hits, &self.delete_misses);
+    }
+
+    pub fn record_cas(&self, outcome: CasOutcome) {
+        let counter = match outcome {
+            CasOutcome::Stored => &self.cas_hits,
+            CasOutcome::NotFound => &self.cas_misses,
+            CasOutcome::Exists => &self.cas_badval,
+        };
+        counter.fetch_add(1, Relaxed);
+    }
+
+    /// Zeroes every counter, for `stats reset`.
+    pub fn reset(&self) {
+        for counter in &[
+            &self.cmd_get,
+            &self.cmd_set,
+            &self.cmd_touch,
+            &self.get_hits,
+            &self.get_misses,
+            &self.delete_hits,
+            &self.delete_misses,
+            &self.touch_hits,
+            &self.touch_misses,
+            &self.cas_hits,
+            &self.cas_misses,
+            &self.cas_badval,
+        ] {
+            counter.store(0, Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> CommandStats {
+        CommandStats {
+            cmd_get: self.cmd_get.load(Relaxed),
+            cmd_set: self.cmd_set.load(Relaxed),
+            cmd_touch: self.cmd_touch.load(Relaxed),
+            get_hits: self.get_hits.load(Relaxed),
+            get_misses: self.get_misses.load(Relaxed),
+            delete_hits: self.delete_hits.load(Relaxed),
+            delete_misses: self.delete_misses.load(Relaxed),
+            touch_hits: self.touch_hits.load(Relaxed),
+            touch_misses: self.touch_misses.load(Relaxed),
+            cas_hits: self.cas_hits.load(Relaxed),
+            cas_misses: self.cas_misses.load(Relaxed),
+            cas_badval: self.cas_badval.load(Relaxed),
+        }
+    }
+}
+
+impl CommandStats {
+    /// Hits over lookups of `get`s, 0 before the first one.
+    pub fn get_hit_rate(&self) -> f64 {
+        if self.cmd_get == 0 {
+            return 0.;
+        }
+        self.get_hits as f64 / self.cmd_get as f64
+    }
+
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        let pair = |name: &str, value: u64| (name.to_owned(), value.to_string());
+        vec![
+            pair("cmd_get", self.cmd_get),
+            pair("cmd_set", self.cmd_set),
+            pair("cmd_touch", self.cmd_touch),
+            pair("get_hits", self.get_hits),
+            pair("get_misses", self.get_misses),
+            (
+                "get_hit_rate".to_owned(),
+                format!("{:.2}", self.get_hit_rate()),
+            ),
+            pair("delete_hits", self.delete_hits),
+            pair("delete_misses", self.delete_misses),
+            pair("touch_hits", self.touch_hits),
+            pair("touch_misses", self.touch_misses),
+            pair("cas_hits", self.cas_hits),
+            pair("cas_misses", self.cas_misses),
+            pair("cas_badval", self.cas_badval),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_metrics() {
+        let metrics = CommandMetrics::new();
+        metrics.record_get(true);
+        metrics.record_get(false);
+        metrics.record_touch(true);
+        metrics.record_set();
+        metrics.record_delete(false);
+        metrics.record_cas(CasOutcome::Exists);
+        let stats = metrics.stats();
+        assert_eq!(3, stats.cmd_get);
+        assert_eq!(2, stats.get_hits);
+        assert_eq!(1, stats.touch_hits);
+        assert_eq!(1, stats.delete_misses);
+        assert_eq!(1, stats.cas_badval);
+        assert_eq!(0, stats.cas_hits);
+
+        let pairs = stats.to_pairs();
+        assert!(pairs.contains(&("cmd_set".to_owned(), "1".to_owned())));
+        assert!(pairs.contains(&("get_hit_rate".to_owned(), "0.67".to_owned())));
+
+        metrics.reset();
+        assert_eq!(CommandStats::default(), metrics.stats());
+    }
+}
@@ -0,0 +1,187 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use crate::error::MyResult;
+use crate::error::Status;
+use crate::error::StatusCode;
+
+struct QueueState<T, R> {
+    queue: VecDeque<(usize, T)>,
+    results: HashMap<usize, MyResult<R>>,
+    next_ticket: usize,
+    leader_active: bool,
+}
+
+/// Lets concurrent writers share one commit. Each writer enqueues its item;
+/// the writer at the head of the queue becomes the leader, takes every queued
+/// item (up to `max_batch`), commits them together and hands each follower its
+/// result.
+pub struct CommitQueue<T, R> {
+    state: Mutex<QueueState<T, R>>,
+    cond: Condvar,
+    max_batch: usize,
+}
+
+impl<T, R> CommitQueue<T, R> {
+    pub fn new(max_batch: usize) -> Self {
+        assert!(max_batch > 0);
+        CommitQueue {
+            state: Mutex::new(QueueState {
+                queue: VecDeque::new(),
+                results: HashMap::new(),
+                next_ticket: 0,
+                leader_active: false,
+            }),
+            cond: Condvar::new(),
+            max_batch,
+        }
+    }
+
+    /// Commits `item`, either as the leader of a batch by calling `f`, or as a
+    /// follower whose item was committed by another writer's `f`. `f` must
+    /// return one result per item, in order.
+    pub fn commit<F>(&self, item: T, f: F) -> MyResult<R>
+    where
+        F: FnOnce(Vec<T>) -> MyResult<Vec<R>>,
+    {
+        self.commit_pipelined(item, |items, _| f(items))
+    }
+
+    /// Like `commit`, but `f` may call the handoff it is given once the part
+    /// of the commit that must not overlap is done, letting the next batch's
+    /// leader start while `f` finishes.
+    pub fn commit_pipelined<F>(&self, item: T, f: F) -> MyResult<R>
+    where
+        F: FnOnce(Vec<T>, &dyn Fn()) -> MyResult<Vec<R>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push_back((ticket, item));
+
+        loop {
+            if let Some(r) = state.results.remove(&ticket) {
+                return r;
+            }
+            if !state.leader_active && state.queue.front().map(|x| x.0) == Some(ticket) {
+                break;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+
+        state.leader_active = true;
+        let n = ::std::cmp::min(state.queue.len(), self.max_batch);
+        let (tickets, items): (Vec<_>, Vec<_>) = state.queue.drain(..n).unzip();
+        drop(state);
+
+        let handed_off = Cell::new(false);
+        let handoff = || {
+            if !handed_off.replace(true) {
+                self.state.lock().unwrap().leader_active = false;
+                self.cond.notify_all();
+            }
+        };
+        let results = f(items, &handoff);
+
+        let mut state = self.state.lock().unwrap();
+        if !handed_off.get() {
+            state.leader_active = false;
+        }
+        self.cond.notify_all();
+        match results {
+            Ok(results) => {
+                assert_eq!(tickets.len(), results.len());
+                for (t, r) in tickets.into_iter().zip(results) {
+                    state.results.insert(t, Ok(r));
+                }
+                state.results.remove(&ticket).unwrap()
+            }
+            Err(e) => {
+                for t in tickets.into_iter().filter(|t| *t != ticket) {
+                    state
+                        .results
+                        .insert(t, Err(Status::new(StatusCode::WALError, &e.msg)));
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_group_commit() {
+        let queue = Arc::new(CommitQueue::new(16));
+        let commits = Arc::new(AtomicUsize::new(0));
+        let committed = Arc::new(Mutex::new(vec![]));
+
+        let threads = (0..8)
+            .map(|i| {
+                let queue = queue.clone();
+                let commits = commits.clone();
+                let committed = committed.clone();
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        let item = i * 100 + j;
+                        let r = queue.commit(item, |items| {
+                            commits.fetch_add(1, SeqCst);
+                            committed.lock().unwrap().extend(items.iter().cloned());
+                            Ok(items.iter().map(|x| x * 2).collect())
+                        });
+                        assert_eq!(Ok(item * 2), r);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let mut committed = committed.lock().unwrap().clone();
+        committed.sort();
+        assert_eq!((0..800).collect::<Vec<_>>(), committed);
+        assert!(commits.load(SeqCst) <= 800);
+    }
+
+    #[test]
+    fn test_pipelined() {
+        let queue = Arc::new(CommitQueue::new(1));
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let first = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                queue.commit_pipelined(1, |items, handoff| {
+                    handoff();
+                    // finishes only after the next leader has committed
+                    rx.recv().unwrap();
+                    Ok(items)
+                })
+            })
+        };
+        thread::sleep(::std::time::Duration::from_millis(50));
+        let r = queue.commit(2, |items| {
+            tx.send(()).unwrap();
+            Ok(items)
+        });
+        assert_eq!(Ok(2), r);
+        assert_eq!(Ok(1), first.join().unwrap());
+    }
+
+    #[test]
+    fn test_error() {
+        let queue: CommitQueue<usize, usize> = CommitQueue::new(4);
+        let r = queue.commit(1, |_| err!(StatusCode::IOError, "disk full"));
+        assert_eq!(StatusCode::IOError, r.unwrap_err().code);
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use crate::store::StorePayload;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    /// Drops the entry. Older versions of the key in lower levels stay
+    /// hidden behind a delete until the key reaches the bottom level.
+    Remove,
+    ChangeValue(StorePayload),
+}
+
+/// Looks at every value a compaction writes, deletes and expired values
+/// aside, and may drop or rewrite it.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &StorePayload) -> FilterDecision;
+}
+
+pub type SharedCompactionFilter = Arc<dyn CompactionFilter>;
+
+/// Removes keys starting with any of the given prefixes.
+pub struct RetiredPrefixFilter(pub Vec<Vec<u8>>);
+
+impl CompactionFilter for RetiredPrefixFilter {
+    fn filter(&self, key: &[u8], _value: &StorePayload) -> FilterDecision {
+        if self.0.iter().any(|p| key.starts_with(p)) {
+            FilterDecision::Remove
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::slice::Slice;
+
+    use super::*;
+
+    #[test]
+    fn test_retired_prefix_filter() {
+        let filter = RetiredPrefixFilter(vec![b"tmp:".to_vec(), b"old".to_vec()]);
+        let v = StorePayload::new(Slice::from("v"), 0, 0, 1, 0);
+        assert_eq!(FilterDecision::Remove, filter.filter(b"tmp:1", &v));
+        assert_eq!(FilterDecision::Remove, filter.filter(b"old", &v));
+        assert_eq!(FilterDecision::Keep, filter.filter(b"tmp", &v));
+        assert_eq!(FilterDecision::Keep, filter.filter(b"new:old", &v));
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+#[derive(Default)]
+struct Counters {
+    runs: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    micros: AtomicU64,
+    moves: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, bytes_read: u64, bytes_written: u64, elapsed: Duration) {
+        self.runs.fetch_add(1, Relaxed);
+        self.bytes_read.fetch_add(bytes_read, Relaxed);
+        self.bytes_written.fetch_add(bytes_written, Relaxed);
+        self.micros.fetch_add(elapsed.as_micros() as u64, Relaxed);
+    }
+
+    fn stats(&self) -> IoStats {
+        IoStats {
+            runs: self.runs.load(Relaxed),
+            bytes_read: self.bytes_read.load(Relaxed),
+            bytes_written: self.bytes_written.load(Relaxed),
+            micros: self.micros.load(Relaxed),
+            moves: self.moves.load(Relaxed),
+        }
+    }
+}
+
+/// Flush and compaction counters. Compactions are accounted to the level
+/// they write to.
+pub struct CompactionMetrics {
+    flush: Counters,
+    levels: Vec<Counters>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoStats {
+    pub runs: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub micros: u64,
+    /// Files moved down a level without being rewritten.
+    pub moves: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionStats {
+    pub flush: IoStats,
+    pub levels: Vec<IoStats>,
+}
+
+impl CompactionMetrics {
+    pub fn new(max_level: usize) -> Self {
+        CompactionMetrics {
+            flush: Counters::default(),
+            levels: (0..max_level).map(|_| Counters::default()).collect(),
+        }
+    }
+
+    pub fn record_flush(&self, bytes_written: u64, elapsed: Duration) {
+        self.flush.record(0, bytes_written, elapsed);
+    }
+
+    pub fn record_compaction(
+        &self,
+        output_level: usize,
+        bytes_read: u64,
+        bytes_written: u64,
+        elapsed: Duration,
+    ) {
+        self.levels[output_level].record(bytes_read, bytes_written, elapsed);
+    }
+
+    pub fn record_move(&self, output_level: usize, files: u64) {
+        self.levels[output_level].moves.fetch_add(files, Relaxed);
+    }
+
+    pub fn stats(&self) -> CompactionStats {
+        CompactionStats {
+            flush: self.flush.stats(),
+            levels: self.levels.iter().map(Counters::stats).collect(),
+        }
+    }
+}
+
+impl CompactionStats {
+    /// Bytes written by flushes and compactions per byte flushed.
+    pub fn write_amp(&self) -> f64 {
+        if self.flush.bytes_written == 0 {
+            return 0.;
+        }
+        let written =
+            self.flush.bytes_written + self.levels.iter().map(|x| x.bytes_written).sum::<u64>();
+        written as f64 / self.flush.bytes_written as f64
+    }
+
+    /// Flattens the stats into `compaction:`-prefixed name/value pairs.
+    /// Levels nothing was compacted into are left out.
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        let mut v = vec![
+            ("compaction:flushes".to_owned(), self.flush.runs.to_string()),
+            (
+                "compaction:flush_bytes_written".to_owned(),
+                self.flush.bytes_written.to_string(),
+            ),
+            (
+                "compaction:flush_micros".to_owned(),
+                self.flush.micros.to_string(),
+            ),
+        ];
+        for (i, level) in self.levels.iter().enumerate() {
+            if level.runs == 0 && level.moves == 0 {
+                continue;
+            }
+            let name = |x: &str| format!("compaction:l{}:{}", i, x);
+            v.push((name("compactions"), level.runs.to_string()));
+            v.push((name("bytes_read"), level.bytes_read.to_string()));
+            v.push((name("bytes_written"), level.bytes_written.to_string()));
+            v.push((name("micros"), level.micros.to_string()));
+            v.push((name("moves"), level.moves.to_string()));
+        }
+        v.push((
+            "compaction:write_amp".to_owned(),
+            format!("{:.2}", self.write_amp()),
+        ));
+        v
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let metrics = CompactionMetrics::new(4);
+        assert_eq!(0., metrics.stats().write_amp());
+
+        metrics.record_flush(100, Duration::from_micros(5));
+        metrics.record_flush(100, Duration::from_micros(5));
+        metrics.record_compaction(1, 200, 150, Duration::from_micros(20));
+        metrics.record_move(2, 1);
+        let stats = metrics.stats();
+        assert_eq!(2, stats.flush.runs);
+        assert_eq!(10, stats.flush.micros);
+        assert_eq!(
+            IoStats {
+                runs: 1,
+                bytes_read: 200,
+                bytes_written: 150,
+                micros: 20,
+                moves: 0,
+            },
+            stats.levels[1]
+        );
+        assert_eq!(1.75, stats.write_amp());
+
+        let pairs = stats.to_pairs();
+        assert!(pairs.contains(&("compaction:l1:bytes_read".to_owned(), "200".to_owned())));
+        assert!(pairs.contains(&("compaction:l2:moves".to_owned(), "1".to_owned())));
+        assert!(!pairs.iter().any(|(k, _)| k.starts_with("compaction:l3:")));
+        assert_eq!(
+            Some(&("compaction:write_amp".to_owned(), "1.75".to_owned())),
+            pairs.last()
+        );
+    }
+}
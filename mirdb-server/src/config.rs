@@ -1,32 +1,106 @@
 use std::fs::OpenOptions;
 use std::io::Read;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::sync::Arc;
 
 use serde::Deserialize;
 use toml;
 
+use sstable::ChecksumType;
+use sstable::EncryptionKey;
+use sstable::FixedPrefixExtractor;
+use sstable::SharedPrefixExtractor;
+use sstable::KEY_LEN;
+
+use crate::compaction_filter::RetiredPrefixFilter;
+use crate::compaction_filter::SharedCompactionFilter;
+use crate::env::PosixEnv;
+use crate::env::SharedEnv;
 use crate::error::err;
 use crate::error::MyResult;
 use crate::error::StatusCode;
-use crate::options::{Options, GB, KB, MB, TB};
+use crate::mem_env::MemEnv;
+use crate::options::{
+    CompactionStyle, MemtableRepType, Options, WALCompression, WALRecoveryMode, GB, KB, MB, TB,
+};
 use crate::parser_util::macros::{digit, space, usize_parser, IRResult};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub addr: String,
+    /// Where to also serve Redis clients, see `resp`. `addr` takes them too.
+    pub resp_addr: Option<String>,
+    /// `user:password` lines; binary clients must then authenticate with
+    /// SASL PLAIN before anything but `version`, Redis clients with `AUTH`.
+    pub auth_file: Option<String>,
 
     pub max_level: usize,
     pub work_dir: String,
+    pub env: Option<String>,
+    pub namespaces: Option<Vec<String>>,
     pub sst_max_size: String,
     pub mem_table_max_size: String,
     pub mem_table_max_height: usize,
     pub imm_mem_table_max_count: usize,
+    pub max_memtables_per_flush: Option<usize>,
+    pub memtable_rep: Option<String>,
+    pub memtable_hash_buckets: Option<usize>,
+    pub memtable_prefix_len: Option<usize>,
     pub block_size: String,
     pub block_restart_interval: usize,
 
+    pub compaction_style: Option<String>,
     pub l0_compaction_trigger: usize,
+    pub tiered_size_ratio: Option<usize>,
+    pub tiered_min_merge_width: Option<usize>,
+    pub max_subcompactions: Option<usize>,
+    pub intra_l0_min_files: Option<usize>,
+    pub seek_compaction_min_misses: Option<usize>,
+    pub max_bytes_for_level_base: Option<String>,
+    pub max_bytes_for_level_multiplier: Option<f64>,
+    pub dynamic_level_bytes: Option<bool>,
+    pub periodic_compaction_days: Option<u64>,
+    pub retired_prefixes: Option<Vec<String>>,
+    pub l0_slowdown_trigger: Option<usize>,
+    pub l0_stop_trigger: Option<usize>,
+    pub imm_slowdown_trigger: Option<usize>,
+    pub write_slowdown_ms: Option<usize>,
 
     pub thread_sleep_ms: usize,
+    pub write_lock_stripes: Option<usize>,
+    pub expiry_scan_interval_ms: Option<usize>,
+    pub secondary_refresh_interval_ms: Option<usize>,
+    pub rate_limit: Option<String>,
+    pub max_item_size: Option<String>,
+
+    pub prefix_stats_len: Option<usize>,
+
+    pub high_pri_pool_ratio: Option<f64>,
+    pub pin_index_blocks: Option<bool>,
+    pub row_cache_capacity: Option<usize>,
+
+    pub paranoid_checks: Option<bool>,
+    pub quarantine_orphan_files: Option<bool>,
+    pub manifest_max_edits: Option<usize>,
+
+    pub checksum_type: Option<String>,
+    pub encryption_key_file: Option<String>,
+
+    pub flush_on_close: Option<bool>,
+    pub wal_sync: Option<bool>,
+    pub wal_compression: Option<String>,
+    pub wal_buffer_size: Option<String>,
+    pub wal_recovery_mode: Option<String>,
+    pub wal_seg_max_size: Option<String>,
+    pub wal_preallocate: Option<bool>,
+    pub wal_recycle_num: Option<usize>,
+    pub wal_archive_dir: Option<String>,
+    pub wal_archive_max_files: Option<usize>,
+    pub wal_archive_ttl_secs: Option<u64>,
+
+    pub blob_min_size: Option<String>,
+    pub blob_gc_ratio: Option<f64>,
 }
 
 impl Config {
@@ -34,18 +108,287 @@ impl Config {
         let mut opt = Options::default();
         opt.max_level = self.max_level;
         opt.work_dir = self.work_dir.clone();
+        if let Some(env) = &self.env {
+            opt.env = parse_env(env)?;
+        }
+        if let Some(names) = &self.namespaces {
+            for name in names {
+                let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+                if name.is_empty() || !name.chars().all(valid) {
+                    return err(
+                        StatusCode::ConfigError,
+                        format!("invalid namespace: {:?}", name),
+                    );
+                }
+            }
+            opt.namespaces = names.clone();
+        }
         opt.sst_max_size = parse_size(self.sst_max_size.as_bytes())?;
         opt.mem_table_max_size = parse_size(self.mem_table_max_size.as_bytes())?;
         opt.mem_table_max_height = self.mem_table_max_height;
         opt.imm_mem_table_max_count = self.imm_mem_table_max_count;
+        if let Some(n) = self.max_memtables_per_flush {
+            opt.max_memtables_per_flush = n;
+        }
+        if let Some(rep) = &self.memtable_rep {
+            opt.memtable_rep = parse_memtable_rep(rep)?;
+        }
+        if let Some(n) = self.memtable_hash_buckets {
+            opt.memtable_hash_buckets = n;
+        }
+        if let Some(n) = self.memtable_prefix_len {
+            opt.memtable_prefix_len = n;
+        }
         opt.table_opt.block_size = parse_size(self.block_size.as_bytes())?;
         opt.table_opt.block_restart_interval = self.block_restart_interval;
+        if let Some(style) = &self.compaction_style {
+            opt.compaction_style = parse_compaction_style(style)?;
+        }
         opt.l0_compaction_trigger = self.l0_compaction_trigger;
+        if let Some(ratio) = self.tiered_size_ratio {
+            opt.tiered_size_ratio = ratio;
+        }
+        if let Some(n) = self.tiered_min_merge_width {
+            opt.tiered_min_merge_width = n;
+        }
+        if let Some(n) = self.max_subcompactions {
+            opt.max_subcompactions = n;
+        }
+        if let Some(n) = self.intra_l0_min_files {
+            opt.intra_l0_min_files = n;
+        }
+        if let Some(n) = self.seek_compaction_min_misses {
+            opt.seek_compaction_min_misses = n;
+        }
+        if let Some(size) = &self.max_bytes_for_level_base {
+            opt.max_bytes_for_level_base = parse_size(size.as_bytes())?;
+        }
+        if let Some(multiplier) = self.max_bytes_for_level_multiplier {
+            opt.max_bytes_for_level_multiplier = multiplier;
+        }
+        if let Some(dynamic) = self.dynamic_level_bytes {
+            opt.dynamic_level_bytes = dynamic;
+        }
+        if let Some(days) = self.periodic_compaction_days {
+            opt.periodic_compaction_secs = days * 24 * 3600;
+        }
+        if let Some(prefixes) = &self.retired_prefixes {
+            let prefixes = prefixes.iter().map(|p| p.as_bytes().to_vec()).collect();
+            let filter: SharedCompactionFilter = Arc::new(RetiredPrefixFilter(prefixes));
+            opt.compaction_filter = Some(filter);
+        }
+        if let Some(n) = self.l0_slowdown_trigger {
+            opt.l0_slowdown_trigger = n;
+        }
+        if let Some(n) = self.l0_stop_trigger {
+            opt.l0_stop_trigger = n;
+        }
+        if let Some(n) = self.imm_slowdown_trigger {
+            opt.imm_slowdown_trigger = n;
+        }
+        if let Some(ms) = self.write_slowdown_ms {
+            opt.write_slowdown_ms = ms;
+        }
         opt.thread_sleep_ms = self.thread_sleep_ms;
+        if let Some(n) = self.write_lock_stripes {
+            opt.write_lock_stripes = n;
+        }
+        if let Some(ms) = self.expiry_scan_interval_ms {
+            opt.expiry_scan_interval_ms = ms;
+        }
+        if let Some(ms) = self.secondary_refresh_interval_ms {
+            opt.secondary_refresh_interval_ms = ms;
+        }
+        if let Some(limit) = &self.rate_limit {
+            opt.rate_limit = parse_size(limit.as_bytes())?;
+        }
+        if let Some(size) = &self.max_item_size {
+            opt.max_item_size = parse_size(size.as_bytes())?;
+        }
+        if let Some(n) = self.prefix_stats_len {
+            if n > 0 {
+                let extractor: SharedPrefixExtractor = Arc::new(FixedPrefixExtractor(n));
+                opt.table_opt.prefix_extractor = Some(extractor);
+            }
+        }
+        if let Some(ratio) = self.high_pri_pool_ratio {
+            opt.table_opt.high_pri_pool_ratio = ratio;
+            opt.table_opt.cache_index_blocks = true;
+        }
+        if let Some(pin) = self.pin_index_blocks {
+            opt.table_opt.pin_index_blocks = pin;
+        }
+        opt.table_opt.reset_block_cache(opt.block_cache_capacity);
+        if let Some(n) = self.row_cache_capacity {
+            opt.row_cache_capacity = n;
+        }
+        if let Some(paranoid) = self.paranoid_checks {
+            opt.paranoid_checks = paranoid;
+        }
+        if let Some(quarantine) = self.quarantine_orphan_files {
+            opt.quarantine_orphan_files = quarantine;
+        }
+        if let Some(n) = self.manifest_max_edits {
+            opt.manifest_max_edits = n;
+        }
+        if let Some(checksum_type) = &self.checksum_type {
+            opt.table_opt.checksum_type = parse_checksum_type(checksum_type)?;
+        }
+        if let Some(path) = &self.encryption_key_file {
+            opt.table_opt.encryption_key = Some(Arc::new(read_encryption_key(path)?));
+        }
+        if let Some(flush) = self.flush_on_close {
+            opt.flush_on_close = flush;
+        }
+        if let Some(sync) = self.wal_sync {
+            opt.wal_sync = sync;
+        }
+        if let Some(compression) = &self.wal_compression {
+            opt.wal_compression = parse_wal_compression(compression)?;
+        }
+        if let Some(size) = &self.wal_buffer_size {
+            opt.wal_buffer_size = parse_size(size.as_bytes())?;
+        }
+        if let Some(mode) = &self.wal_recovery_mode {
+            opt.wal_recovery_mode = parse_wal_recovery_mode(mode)?;
+        }
+        if let Some(size) = &self.wal_seg_max_size {
+            opt.wal_seg_max_size = parse_size(size.as_bytes())?;
+        }
+        if let Some(preallocate) = self.wal_preallocate {
+            opt.wal_preallocate = preallocate;
+        }
+        if let Some(n) = self.wal_recycle_num {
+            opt.wal_recycle_num = n;
+        }
+        if let Some(dir) = &self.wal_archive_dir {
+            opt.wal_archive_dir = Some(dir.clone());
+        }
+        if let Some(n) = self.wal_archive_max_files {
+            opt.wal_archive_max_files = n;
+        }
+        if let Some(ttl) = self.wal_archive_ttl_secs {
+            opt.wal_archive_ttl_secs = ttl;
+        }
+        if let Some(size) = &self.blob_min_size {
+            opt.blob_min_size = parse_size(size.as_bytes())?;
+        }
+        if let Some(ratio) = self.blob_gc_ratio {
+            opt.blob_gc_ratio = ratio;
+        }
         Ok(opt)
     }
 }
 
+fn parse_checksum_type(s: &str) -> MyResult<ChecksumType> {
+    match s.to_lowercase().as_str() {
+        "crc32c" => Ok(ChecksumType::CRC32C),
+        "xxhash64" => Ok(ChecksumType::XXHash64),
+        _ => err(
+            StatusCode::ConfigError,
+            format!("unknown checksum type: {}", s),
+        ),
+    }
+}
+
+/// Reads a key written as 64 hex digits.
+fn read_encryption_key(path: &str) -> MyResult<EncryptionKey> {
+    let mut s = String::new();
+    OpenOptions::new()
+        .read(true)
+        .open(path)?
+        .read_to_string(&mut s)?;
+    let hex = s.trim().as_bytes();
+    let mut key = [0; KEY_LEN];
+    if hex.len() != KEY_LEN * 2 {
+        return err(
+            StatusCode::ConfigError,
+            format!(
+                "encryption key in {} is not {} hex digits",
+                path,
+                KEY_LEN * 2
+            ),
+        );
+    }
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let byte = std::str::from_utf8(pair)
+            .ok()
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+        match byte {
+            Some(byte) => key[i] = byte,
+            None => {
+                return err(
+                    StatusCode::ConfigError,
+                    format!("invalid encryption key in {}", path),
+                )
+            }
+        }
+    }
+    Ok(key)
+}
+
+fn parse_env(s: &str) -> MyResult<SharedEnv> {
+    match s.to_lowercase().as_str() {
+        "posix" => Ok(Arc::new(PosixEnv)),
+        "mem" => Ok(Arc::new(MemEnv::new())),
+        _ => err(StatusCode::ConfigError, format!("unknown env: {}", s)),
+    }
+}
+
+pub fn parse_addr(s: &str) -> MyResult<SocketAddr> {
+    match s.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => err(StatusCode::ConfigError, format!("invalid address: {}", s)),
+    }
+}
+
+fn parse_memtable_rep(s: &str) -> MyResult<MemtableRepType> {
+    match s.to_lowercase().as_str() {
+        "skip_list" => Ok(MemtableRepType::SkipList),
+        "hash_skip_list" => Ok(MemtableRepType::HashSkipList),
+        "vector" => Ok(MemtableRepType::Vector),
+        _ => err(
+            StatusCode::ConfigError,
+            format!("unknown memtable rep: {}", s),
+        ),
+    }
+}
+
+fn parse_compaction_style(s: &str) -> MyResult<CompactionStyle> {
+    match s.to_lowercase().as_str() {
+        "leveled" => Ok(CompactionStyle::Leveled),
+        "tiered" => Ok(CompactionStyle::Tiered),
+        _ => err(
+            StatusCode::ConfigError,
+            format!("unknown compaction style: {}", s),
+        ),
+    }
+}
+
+fn parse_wal_compression(s: &str) -> MyResult<WALCompression> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(WALCompression::None),
+        "snappy" => Ok(WALCompression::Snappy),
+        "zstd" => Ok(WALCompression::Zstd),
+        _ => err(
+            StatusCode::ConfigError,
+            format!("unknown wal compression: {}", s),
+        ),
+    }
+}
+
+fn parse_wal_recovery_mode(s: &str) -> MyResult<WALRecoveryMode> {
+    match s.to_lowercase().as_str() {
+        "strict" => Ok(WALRecoveryMode::Strict),
+        "point_in_time" => Ok(WALRecoveryMode::PointInTime),
+        "skip_corrupted" => Ok(WALRecoveryMode::SkipCorrupted),
+        _ => err(
+            StatusCode::ConfigError,
+            format!("unknown wal recovery mode: {}", s),
+        ),
+    }
+}
+
 fn to_size_unit(x: &[u8]) -> usize {
     match x {
         b"K" => KB,
@@ -101,37 +444,111 @@ mod test {
     fn test_parse() -> MyResult<()> {
         let toml_str = r#"
 addr = "0.0.0.0:12333"
+resp_addr = "127.0.0.1:6379"
+auth_file = "/etc/mirdb/users"
 
 max_level = 7
 work_dir = "/tmp/mirdbs"
+env = "mem"
+namespaces = ["sessions", "cache"]
 
 sst_max_size = "100M"
 mem_table_max_size = "4M"
 mem_table_max_height = 32
 
 imm_mem_table_max_count = 16
+max_memtables_per_flush = 8
+memtable_rep = "hash_skip_list"
+memtable_prefix_len = 4
 
 block_size = "4K"
 block_restart_interval = 16
 
+compaction_style = "tiered"
 l0_compaction_trigger = 4
+tiered_size_ratio = 20
+max_subcompactions = 4
+intra_l0_min_files = 6
+seek_compaction_min_misses = 50
+max_bytes_for_level_base = "64M"
+max_bytes_for_level_multiplier = 8.0
+dynamic_level_bytes = true
+periodic_compaction_days = 30
+retired_prefixes = ["tmp:"]
 
 thread_sleep_ms = 500
+write_lock_stripes = 64
+expiry_scan_interval_ms = 1000
+secondary_refresh_interval_ms = 200
+
+wal_seg_max_size = "16M"
+rate_limit = "32M"
+max_item_size = "2M"
+
+row_cache_capacity = 10000
+
+quarantine_orphan_files = false
+manifest_max_edits = 100
+
+encryption_key_file = "/tmp/mirdb_test_encryption_key"
 "#;
+        std::fs::write(
+            "/tmp/mirdb_test_encryption_key",
+            "ab".repeat(KEY_LEN) + "\n",
+        )?;
 
         let config: Config = toml::from_str(toml_str).unwrap();
         println!("{:#?}", config);
+        assert_eq!(
+            Some("127.0.0.1:6379"),
+            config.resp_addr.as_ref().map(|a| &a[..])
+        );
+        assert!(parse_addr(config.resp_addr.as_ref().unwrap()).is_ok());
+        assert!(parse_addr("127.0.0.1:63790").is_err());
+        assert_eq!(
+            Some("/etc/mirdb/users"),
+            config.auth_file.as_ref().map(|f| &f[..])
+        );
         let opt = config.to_options()?;
         assert_eq!(7, opt.max_level);
         assert_eq!("/tmp/mirdbs", opt.work_dir);
+        assert!(!opt.env.exists(Path::new("/tmp")));
+        assert_eq!(vec!["sessions", "cache"], opt.namespaces);
         assert_eq!(100 * MB, opt.sst_max_size);
         assert_eq!(4 * MB, opt.mem_table_max_size);
         assert_eq!(32, opt.mem_table_max_height);
         assert_eq!(16, opt.imm_mem_table_max_count);
+        assert_eq!(8, opt.max_memtables_per_flush);
+        assert_eq!(MemtableRepType::HashSkipList, opt.memtable_rep);
+        assert_eq!(4, opt.memtable_prefix_len);
         assert_eq!(4 * KB, opt.table_opt.block_size);
         assert_eq!(16, opt.table_opt.block_restart_interval);
+        assert_eq!(CompactionStyle::Tiered, opt.compaction_style);
         assert_eq!(4, opt.l0_compaction_trigger);
+        assert_eq!(20, opt.tiered_size_ratio);
+        assert_eq!(2, opt.tiered_min_merge_width);
+        assert_eq!(4, opt.max_subcompactions);
+        assert_eq!(6, opt.intra_l0_min_files);
+        assert_eq!(50, opt.seek_compaction_min_misses);
+        assert_eq!(64 * MB, opt.max_bytes_for_level_base);
+        assert_eq!(8., opt.max_bytes_for_level_multiplier);
+        assert!(opt.dynamic_level_bytes);
+        assert_eq!(30 * 24 * 3600, opt.periodic_compaction_secs);
+        assert!(opt.compaction_filter.is_some());
         assert_eq!(500, opt.thread_sleep_ms);
+        assert_eq!(64, opt.write_lock_stripes);
+        assert_eq!(1000, opt.expiry_scan_interval_ms);
+        assert_eq!(200, opt.secondary_refresh_interval_ms);
+        assert_eq!(16 * MB, opt.wal_seg_max_size);
+        assert_eq!(32 * MB, opt.rate_limit);
+        assert_eq!(2 * MB, opt.max_item_size);
+        assert_eq!(10000, opt.row_cache_capacity);
+        assert!(!opt.quarantine_orphan_files);
+        assert_eq!(100, opt.manifest_max_edits);
+        assert_eq!(
+            Some([0xab; KEY_LEN]),
+            opt.table_opt.encryption_key.map(|key| *key)
+        );
 
         Ok(())
     }
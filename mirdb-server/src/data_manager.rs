@@ -1,49 +1,151 @@
 use log::info;
+use log::warn;
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::iter;
+use std::iter::Peekable;
+use std::mem;
+use std::ops::Bound;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::Relaxed;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::SeqCst;
 use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
 use std::sync::RwLock;
 use std::sync::RwLockWriteGuard;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use bincode::deserialize;
 use bincode::serialize;
 use serde::Deserialize;
 use serde::Serialize;
 
+use sstable::PrefixStat;
 use sstable::SsIterator;
 use sstable::TableBuilder;
+use sstable::TableIter;
 use sstable::TableReader;
-
+use sstable::ValueHandle;
+
+use crate::blob::blob_file_number;
+use crate::blob::BlobIndex;
+use crate::blob::BlobWriter;
+use crate::blob::BLOB_EXT;
+use crate::cancel::CancellationToken;
+use crate::commit_queue::CommitQueue;
+use crate::compaction_filter::FilterDecision;
+use crate::compaction_stats::CompactionMetrics;
+use crate::compaction_stats::CompactionStats;
+use crate::env::create_table;
+use crate::env::open_table;
+use crate::env::Env;
+use crate::error::err;
 use crate::error::MyResult;
+use crate::error::Status;
+use crate::error::StatusCode;
+use crate::expiry::ExpiryIndex;
+use crate::file_number::FileNumbers;
+use crate::key_lock::StripedLock;
+use crate::manifest::Manifest;
 use crate::memtable::Memtable;
+use crate::memtable::MemtablesStats;
+use crate::memtable::TOMBSTONE;
 use crate::memtable_list::MemtableList;
+use crate::merge::decode_operands;
+use crate::merge::encode_operands;
+use crate::merge::merge_values;
+use crate::merge::resolve_value;
+use crate::merge::MergeOperand;
 use crate::merger::Merger;
+use crate::options::CompactionStyle;
 use crate::options::Options;
+use crate::options::WriteOptions;
+use crate::rate_limiter::RateLimiter;
+use crate::repair::LOST_DIR;
+use crate::row_cache::RowCache;
 use crate::slice::Slice;
+use crate::spill::spill_dir;
+use crate::spill::SpillBuffer;
+use crate::spill::SpillIter;
+use crate::sstable_builder::entries_to_sstable;
+use crate::sstable_builder::new_blob_writer;
+use crate::sstable_builder::open_sstable;
+use crate::sstable_reader::ApproximateSize;
+use crate::sstable_reader::LevelStats;
 use crate::sstable_reader::SstableReader;
+use crate::sstable_reader::TableChanges;
 use crate::store::StoreKey;
 use crate::store::StorePayload;
+use crate::thread_pool::ThreadPool;
 use crate::types::Table;
 use crate::utils::make_file_name;
 use crate::utils::read_lock;
 use crate::utils::to_str;
 use crate::utils::write_lock;
+use crate::wal::seg_paths;
+use crate::wal::LogEntry;
+use crate::wal::WALStats;
+use crate::wal::WALTail;
 use crate::wal::WAL;
 
+const MAX_COMMIT_BATCH: usize = 1024;
+// keys deleted per expiry scan round, so one round doesn't hog the writers
+const MAX_EXPIRY_BATCH: usize = 1024;
+
+/// Start and end, exclusive, of the keys a subcompaction merges.
+type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Lets subcompactions share the tables of a compaction. Tables are only
+/// read, like everywhere else the data manager hands them to other threads.
+struct Shared<T>(T);
+
+unsafe impl<T> Send for Shared<T> {}
+unsafe impl<T> Sync for Shared<T> {}
+
 pub struct DataManager {
     mut_: Arc<RwLock<Memtable<Slice, Slice>>>,
     imm_: Arc<RwLock<MemtableList<Slice, Slice>>>,
     readers_: Arc<RwLock<SstableReader>>,
+    /// Shared by the default keyspace and its namespaces.
     wal_: Arc<RwLock<WAL>>,
+    /// The keyspace in the WAL, empty for the default one.
+    namespace_: Slice,
+    /// The first WAL segment with writes in each immutable memtable, oldest
+    /// first, and then in the mutable one; None while it has none.
+    log_numbers_: Mutex<VecDeque<Option<usize>>>,
     opt_: Options,
-    next_file_number_: AtomicUsize,
-    last_compact_keys_: Vec<Vec<u8>>,
+    file_numbers_: FileNumbers,
+    shutdown_: CancellationToken,
+    compaction_token_: RwLock<CancellationToken>,
+    compaction_paused_: AtomicBool,
+    background_threads_: Mutex<Vec<JoinHandle<()>>>,
+    closed_: AtomicBool,
+    commit_queue_: CommitQueue<LogEntry, Option<Slice>>,
+    flush_lock_: Mutex<()>,
+    flush_signal_: (Mutex<()>, Condvar),
+    flushes_: AtomicU64,
+    compaction_metrics_: CompactionMetrics,
+    compaction_pool_: Option<ThreadPool>,
+    key_locks_: StripedLock,
+    expiry_: ExpiryIndex,
+    cas_: AtomicU64,
+    rate_limiter_: RateLimiter,
+    row_cache_: Option<RowCache>,
 }
 
 unsafe impl Sync for DataManager {}
@@ -51,16 +153,71 @@ unsafe impl Send for DataManager {}
 
 impl DataManager {
     pub fn new(opt: Options) -> MyResult<Arc<Self>> {
+        Self::with_namespaces(opt, &[])
+    }
+
+    /// Like `new`, with the WAL shared by the namespaces `names`, which are
+    /// opened next with `open_namespace`. No segment is dropped before each
+    /// of them has replayed its writes.
+    pub fn with_namespaces(opt: Options, names: &[String]) -> MyResult<Arc<Self>> {
+        Self::open(opt, Slice::new(), None, names)
+    }
+
+    /// Opens the namespace `name`, with its tables in `opt.work_dir` and its
+    /// writes logged to the WAL of `root`.
+    pub fn open_namespace(opt: Options, name: &str, root: &DataManager) -> MyResult<Arc<Self>> {
+        Self::open(opt, Slice::from(name), Some(root.wal_.clone()), &[])
+    }
+
+    fn open(
+        opt: Options,
+        namespace: Slice,
+        shared_wal: Option<Arc<RwLock<WAL>>>,
+        names: &[String],
+    ) -> MyResult<Arc<Self>> {
+        let had_manifest = opt.env.exists(&Manifest::gen_path(&opt));
         let readers_ = Arc::new(RwLock::new(SstableReader::new(opt.clone())?));
-        let next_file_number = {
-            let readers = read_lock(&readers_);
-            readers.manifest_builder().next_file_number()
+        // a manifest that is missing or lists nothing can't tell orphans
+        // from the tables it lost
+        let trust_manifest = had_manifest && !read_lock(&readers_).live_files().is_empty();
+        let file_numbers = {
+            let mut readers = write_lock(&readers_);
+            let file_numbers = FileNumbers::new(readers.manifest_builder().next_file_number());
+            readers
+                .manifest_builder_mut()
+                .set_file_numbers(file_numbers.clone());
+            file_numbers
         };
-        let mut dm = DataManager {
-            mut_: Arc::new(RwLock::new(Memtable::new(
-                opt.mem_table_max_size,
-                opt.mem_table_max_height,
-            ))),
+        if !trust_manifest {
+            // new tables mustn't take the numbers of the ones it lost
+            for path in opt.env.list(Path::new(&opt.work_dir))? {
+                let name = path.file_name().and_then(|x| x.to_str());
+                if let Some(n) = name.and_then(blob_file_number) {
+                    file_numbers.advance_to(n + 1);
+                }
+            }
+        }
+        let wal = match shared_wal {
+            Some(wal) => wal,
+            None => {
+                let keyspaces = iter::once(Slice::new())
+                    .chain(names.iter().map(|x| Slice::from(x.as_str())))
+                    .collect::<Vec<_>>();
+                let wal = WAL::with_file_numbers(opt.clone(), file_numbers.clone())?
+                    .with_keyspaces(&keyspaces);
+                Arc::new(RwLock::new(wal))
+            }
+        };
+        // flushed segments are gone, so the manifest knows of later writes
+        // than the WAL may
+        let flushed_sequence = read_lock(&readers_)
+            .manifest_builder()
+            .manifest()
+            .last_sequence;
+        raise_last_sequence(&mut write_lock(&wal), flushed_sequence);
+        let shutdown = CancellationToken::new();
+        let dm = DataManager {
+            mut_: Arc::new(RwLock::new(Memtable::with_options(&opt))),
             imm_: Arc::new(RwLock::new(MemtableList::new(
                 opt.clone(),
                 opt.imm_mem_table_max_count,
@@ -68,36 +225,142 @@ impl DataManager {
                 opt.mem_table_max_height,
             ))),
             readers_,
-            next_file_number_: AtomicUsize::new(next_file_number),
-            wal_: Arc::new(RwLock::new(WAL::new(opt.clone())?)),
+            wal_: wal,
+            namespace_: namespace,
+            log_numbers_: Mutex::new(VecDeque::from(vec![None])),
+            file_numbers_: file_numbers,
             opt_: opt.clone(),
-            last_compact_keys_: Vec::with_capacity(opt.max_level),
+            shutdown_: shutdown.clone(),
+            compaction_token_: RwLock::new(shutdown.child()),
+            compaction_paused_: AtomicBool::new(false),
+            background_threads_: Mutex::new(vec![]),
+            closed_: AtomicBool::new(false),
+            commit_queue_: CommitQueue::new(MAX_COMMIT_BATCH),
+            flush_lock_: Mutex::new(()),
+            flush_signal_: (Mutex::new(()), Condvar::new()),
+            flushes_: AtomicU64::new(0),
+            compaction_metrics_: CompactionMetrics::new(opt.max_level),
+            compaction_pool_: if opt.max_subcompactions > 1 {
+                Some(ThreadPool::new(opt.max_subcompactions))
+            } else {
+                None
+            },
+            key_locks_: StripedLock::new(opt.write_lock_stripes),
+            expiry_: ExpiryIndex::new(),
+            cas_: AtomicU64::new(0),
+            rate_limiter_: RateLimiter::new(opt.rate_limit),
+            row_cache_: if opt.row_cache_capacity > 0 {
+                Some(RowCache::new(opt.row_cache_capacity))
+            } else {
+                None
+            },
         };
+        if !dm.namespace_.is_empty() {
+            dm.redo_own_wal()?;
+        }
         dm.redo()?;
+        dm.cas_.store(read_lock(&dm.wal_).last_sequence(), SeqCst);
+        dm.purge_orphan_files(trust_manifest)?;
         Ok(Arc::new(dm))
     }
 
     pub fn background_thread(dma: Arc<Self>) {
         let dm = dma.clone();
-        let _ = thread::spawn(move || {
+        let major = thread::spawn(move || {
             let d = Duration::from_millis(dm.opt().thread_sleep_ms as u64);
-            loop {
-                dm.major_compaction().unwrap();
+            while !dm.shutdown_.is_cancelled() {
+                ignore_cancelled(dm.major_compaction()).unwrap();
                 thread::sleep(d);
             }
         });
         let dm = dma.clone();
-        let _ = thread::spawn(move || {
+        let minor = thread::spawn(move || {
             let d = Duration::from_millis(dm.opt().thread_sleep_ms as u64);
-            loop {
-                dm.minor_compaction().unwrap();
-                thread::sleep(d);
+            while !dm.shutdown_.is_cancelled() {
+                ignore_cancelled(dm.minor_compaction()).unwrap();
+                dm.wait_flush_signal(d);
             }
         });
+        let mut threads = dma.background_threads_.lock().unwrap();
+        threads.push(major);
+        threads.push(minor);
+        if dma.opt_.expiry_scan_interval_ms > 0 {
+            let dm = dma.clone();
+            threads.push(thread::spawn(move || {
+                let d = Duration::from_millis(dm.opt().expiry_scan_interval_ms as u64);
+                while !dm.shutdown_.is_cancelled() {
+                    if let Err(e) = dm.purge_expired() {
+                        warn!("expiry scan failed: {:?}", e);
+                    }
+                    thread::sleep(d);
+                }
+            }));
+        }
+    }
+
+    /// Cancels running background jobs at their next safe point and waits for
+    /// the background threads to exit.
+    pub fn shutdown(&self) {
+        self.shutdown_.cancel();
+        self.notify_flush_signal();
+        let threads = mem::replace(&mut *self.background_threads_.lock().unwrap(), vec![]);
+        for handle in threads {
+            let _ = handle.join();
+        }
+    }
+
+    /// Stops background jobs and, with `flush_on_close`, flushes every memtable
+    /// into level 0; otherwise they are left to be rebuilt from the WAL on the
+    /// next open. No files are written after this returns.
+    pub fn close(&self) -> MyResult<()> {
+        if self.closed_.swap(true, SeqCst) {
+            return Ok(());
+        }
+        self.shutdown();
+        write_lock(&self.wal_).sync()?;
+        if self.opt_.flush_on_close {
+            self.flush_memtables()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed_.load(SeqCst)
+    }
+
+    fn check_open(&self) -> MyResult<()> {
+        if self.is_closed() {
+            return err!(StatusCode::Closed, "db is closed");
+        }
+        Ok(())
+    }
+
+    /// Stops the running major compaction at its next safe point and keeps new
+    /// ones from starting until `resume_compaction` is called.
+    pub fn pause_compaction(&self) {
+        self.compaction_paused_.store(true, SeqCst);
+        read_lock(&self.compaction_token_).cancel();
+    }
+
+    pub fn resume_compaction(&self) {
+        let mut token = write_lock(&self.compaction_token_);
+        if token.is_cancelled() {
+            *token = self.shutdown_.child();
+        }
+        self.compaction_paused_.store(false, SeqCst);
+    }
+
+    /// Changes the bytes per second flushes and compactions may write.
+    pub fn set_rate_limit(&self, bytes_per_sec: usize) {
+        self.rate_limiter_.set_bytes_per_sec(bytes_per_sec);
+    }
+
+    pub fn is_compaction_paused(&self) -> bool {
+        self.compaction_paused_.load(SeqCst)
     }
 
     fn new_file_number(&self) -> usize {
-        self.next_file_number_.fetch_add(1, Relaxed)
+        self.file_numbers_.next()
     }
 
     pub fn opt(&self) -> &Options {
@@ -109,60 +372,242 @@ impl DataManager {
         readers.manifest_builder().to_string()
     }
 
-    pub fn redo(&mut self) -> MyResult<()> {
-        {
-            let mut wal = write_lock(&self.wal_);
+    /// Streams WAL entries from `from_sequence` on, for replication and CDC.
+    /// Those of every keyspace sharing the WAL are streamed, each naming its
+    /// namespace.
+    pub fn tail_wal(&self, from_sequence: u64) -> MyResult<WALTail> {
+        write_lock(&self.wal_).tail(from_sequence)
+    }
 
-            if wal.seg_count() == 0 {
-                return Ok(());
-            }
+    pub fn wal_stats(&self) -> WALStats {
+        read_lock(&self.wal_).metrics().stats()
+    }
 
-            info!("redoing...");
+    pub fn memtable_stats(&self) -> MemtablesStats {
+        let mutable = read_lock(&self.mut_).stats();
+        let immutables = read_lock(&self.imm_)
+            .tables_iter()
+            .map(|t| t.stats())
+            .collect();
+        MemtablesStats {
+            mutable,
+            immutables,
+            flushes: self.flushes_.load(SeqCst),
+        }
+    }
 
-            let work_dir = Path::new(&self.opt_.work_dir);
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_metrics_.stats()
+    }
 
-            let mut threads = Vec::with_capacity(wal.segs.len());
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        read_lock(&self.readers_).level_stats()
+    }
 
-            for seg in &wal.segs {
-                let opt = self.opt_.clone();
-                let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
-                let seg = seg.clone()?;
-                threads.push(thread::spawn(move || {
-                    info!("building sstable {:?}...", path);
-                    let st = time::SystemTime::now();
-                    let t = seg.build_sstable(&opt, &path).unwrap();
-                    info!(
-                        "build sstable {:?} cost: {}ms",
-                        path,
-                        st.elapsed().unwrap().as_millis()
-                    );
-                    t.map(|_| path)
-                }));
-            }
+    /// Estimated sstable data in each of `ranges`, from the tables' index
+    /// blocks and properties, without reading data blocks. What is still in
+    /// the memtables is not counted.
+    pub fn approximate_sizes(&self, ranges: &[Range<Vec<u8>>]) -> MyResult<Vec<ApproximateSize>> {
+        let readers = read_lock(&self.readers_);
+        ranges
+            .iter()
+            .map(|r| readers.approximate_size(&r.start, Some(&r.end)))
+            .collect()
+    }
 
-            let table_opt = self.opt_.get_table_opt();
+    /// Like `approximate_sizes` for one range; no `end` runs to the last key.
+    pub fn approximate_size(&self, start: &[u8], end: Option<&[u8]>) -> MyResult<ApproximateSize> {
+        read_lock(&self.readers_).approximate_size(start, end)
+    }
 
-            let readers = threads
-                .into_iter()
-                .map(|handle| handle.join().unwrap())
-                .filter(Option::is_some)
-                .map(Option::unwrap)
-                .map(|path| TableReader::new(&path, table_opt.clone()).unwrap())
-                .collect();
+    pub fn prefix_stats(&self) -> BTreeMap<Vec<u8>, PrefixStat> {
+        let readers = read_lock(&self.readers_);
+        readers.prefix_stats()
+    }
 
-            {
-                let mut readers_group = write_lock(&self.readers_);
-                readers_group.add_readers(0, readers)?;
+    /// Deletes the sstables and blob files of the work dir that the manifest
+    /// doesn't know about, left behind by flushes and compactions that
+    /// crashed before installing their outputs, along with stale temporary
+    /// files. With `quarantine_orphan_files` the tables and blob files are
+    /// moved to `lost/` instead. Unless `trust_manifest`, tables and blob
+    /// files are left alone: `repair` can rebuild a manifest from them. Only
+    /// safe before anything else runs against the work dir. Returns how many
+    /// files were set aside or deleted.
+    fn purge_orphan_files(&self, trust_manifest: bool) -> MyResult<usize> {
+        let work_dir = Path::new(&self.opt_.work_dir);
+        let live = read_lock(&self.readers_)
+            .live_files()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        // blob files share their number with the table they were written for
+        let table_numbers = live
+            .iter()
+            .filter(|name| name.ends_with(".sst"))
+            .filter_map(|name| blob_file_number(name))
+            .collect::<HashSet<_>>();
+
+        let env = self.opt_.env.as_ref();
+        let mut purged = 0;
+        let mut kept = 0;
+        for path in env.list(work_dir)? {
+            let name = match path.file_name().and_then(|x| x.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let orphan = match path.extension().and_then(|x| x.to_str()) {
+                Some("sst") => !live.contains(&name),
+                Some(BLOB_EXT) => {
+                    !live.contains(&name)
+                        && !blob_file_number(&name).map_or(false, |n| table_numbers.contains(&n))
+                }
+                _ => false,
+            };
+            if orphan && !trust_manifest {
+                kept += 1;
+            } else if orphan {
+                if self.opt_.quarantine_orphan_files {
+                    let lost = work_dir.join(LOST_DIR);
+                    env.create_dir_all(&lost)?;
+                    env.rename(&path, &lost.join(&name))?;
+                } else {
+                    env.remove_file(&path)?;
+                }
+                purged += 1;
+            } else if path == Manifest::gen_path(&self.opt_).with_extension("tmp") {
+                env.remove_file(&path)?;
+                purged += 1;
             }
+        }
 
-            for seg in &mut wal.segs {
-                seg.delete()?;
-            }
+        // no scan survives a restart
+        for path in env.list(&spill_dir(&self.opt_))? {
+            env.remove_file(&path)?;
+            purged += 1;
+        }
+        if purged > 0 {
+            info!("purged {} orphan files from {}", purged, self.opt_.work_dir);
+        }
+        if kept > 0 {
+            warn!(
+                "{} has {} tables and blob files no manifest lists, run repair to recover them",
+                self.opt_.work_dir, kept
+            );
+        }
+        Ok(purged)
+    }
+
+    /// Rebuilds the writes of this keyspace the WAL holds and the sstables
+    /// don't, those newer than the last one flushed, into level 0 sstables.
+    /// The segments then go once no other keyspace needs them.
+    pub fn redo(&self) -> MyResult<()> {
+        let mut wal = write_lock(&self.wal_);
+        self.replay_wal(&wal, &self.namespace_[..])?;
+        wal.set_unflushed(&self.namespace_, None)
+    }
+
+    /// Replays and drops the segments a namespace logged in its own work dir
+    /// before it shared the WAL of the default keyspace.
+    fn redo_own_wal(&self) -> MyResult<()> {
+        let env = self.opt_.env.as_ref();
+        if seg_paths(env, Path::new(&self.opt_.work_dir))?.is_empty() {
+            return Ok(());
+        }
+        let mut opt = self.opt_.clone();
+        opt.wal_recycle_num = 0;
+        let mut wal = WAL::with_file_numbers(opt, self.file_numbers_.clone())?;
+        // its sequence numbers were counted apart from the shared ones
+        raise_last_sequence(&mut write_lock(&self.wal_), wal.last_sequence());
+        // its records don't name the namespace
+        self.replay_wal(&wal, b"")?;
+        let n = wal.seg_count();
+        wal.truncate(n)
+    }
+
+    /// Builds level 0 sstables, one per segment, from the entries of
+    /// `namespace` in `wal` newer than the last write flushed.
+    fn replay_wal(&self, wal: &WAL, namespace: &[u8]) -> MyResult<()> {
+        if wal.seg_count() == 0 {
+            return Ok(());
+        }
+        let flushed_sequence = read_lock(&self.readers_)
+            .manifest_builder()
+            .manifest()
+            .last_sequence;
+
+        info!("redoing...");
+
+        let work_dir = Path::new(&self.opt_.work_dir);
+
+        let mut threads = Vec::with_capacity(wal.segs.len());
+
+        for seg in &wal.segs {
+            let opt = self.opt_.clone();
+            let seg = seg.clone()?;
+            let namespace = namespace.to_vec();
+            threads.push(thread::spawn(move || {
+                seg.to_memtable(&opt, &namespace, flushed_sequence)
+            }));
+        }
+
+        let tables = threads
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<MyResult<Vec<_>>>()?;
+
+        // merge operands may sit on values from older segments, so they
+        // are resolved oldest segment first before anything is written
+        for (i, table) in tables.iter().enumerate() {
+            let older = &tables[..i];
+            table.update_values(|k, v| {
+                resolve_value(&v[..], || {
+                    for t in older.iter().rev() {
+                        if let Some(x) = t.get(k) {
+                            return Ok(Some(x.clone()));
+                        }
+                    }
+                    read_lock(&self.readers_).get(k)
+                })
+            })?;
+        }
+
+        let mut threads = Vec::with_capacity(tables.len());
+
+        for table in tables {
+            let opt = self.opt_.clone();
+            let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
+            let seqs = table.sequence_range();
+            threads.push(thread::spawn(move || -> MyResult<_> {
+                info!("building sstable {:?}...", path);
+                let st = time::SystemTime::now();
+                let t = table.build_sstable(&opt, &path)?;
+                info!(
+                    "build sstable {:?} cost: {}ms",
+                    path,
+                    st.elapsed().unwrap().as_millis()
+                );
+                Ok(t.map(|_| (path, seqs)))
+            }));
         }
 
-        self.wal_ = Arc::new(RwLock::new(WAL::new(self.opt_.clone())?));
+        let table_opt = self.opt_.get_table_opt();
+
+        let paths = threads
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<MyResult<Vec<_>>>()?;
+        let readers = paths
+            .into_iter()
+            .flatten()
+            .map(|(path, seqs)| {
+                let reader = open_table(self.opt_.env.as_ref(), &path, table_opt.clone())?;
+                Ok((reader, seqs))
+            })
+            .collect::<MyResult<Vec<_>>>()?;
 
-        assert_eq!(0, read_lock(&self.wal_).seg_count());
+        {
+            let mut readers_group = write_lock(&self.readers_);
+            readers_group.add_flushed(readers)?;
+        }
 
         info!("redo done!");
 
@@ -170,231 +615,1369 @@ impl DataManager {
     }
 
     pub fn insert(&self, k: StoreKey, v: StorePayload) -> MyResult<Option<StorePayload>> {
-        self.insert_with_option(k, Some(v))
+        self.insert_opt(k, v, WriteOptions::default())
+    }
+
+    pub fn insert_opt(
+        &self,
+        k: StoreKey,
+        v: StorePayload,
+        write_opt: WriteOptions,
+    ) -> MyResult<Option<StorePayload>> {
+        if self.opt_.expiry_scan_interval_ms > 0 {
+            if let Some(t) = v.expires_at() {
+                self.expiry_.add(t, k.clone());
+            }
+        }
+        self.insert_with_option(k, Some(v), write_opt)
+    }
+
+    /// A cas unique for a new write. Items keep theirs across restarts, so
+    /// a run counts up from the last sequence number it opened with, and
+    /// writes get sequence numbers past the uniques handed out before them:
+    /// the last sequence persisted is at least any unique stored.
+    pub fn next_cas(&self) -> u64 {
+        self.cas_.fetch_add(1, SeqCst) + 1
+    }
+
+    /// Held by requests that read a key before writing it, so nothing else
+    /// writes the key in between.
+    pub fn lock_key(&self, k: &[u8]) -> MutexGuard<'_, ()> {
+        self.key_locks_.lock(k)
+    }
+
+    /// Deletes keys from the expiry index whose value has expired, up to
+    /// `MAX_EXPIRY_BATCH` of them. Returns how many were deleted.
+    pub fn purge_expired(&self) -> MyResult<usize> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut purged = 0;
+        for k in self.expiry_.take_expired(now, MAX_EXPIRY_BATCH) {
+            let _key_lock = self.lock_key(&k[..]);
+            // the key may have been written again since
+            if self.get_(&k)?.map_or(false, |p| p.is_expired()) {
+                self.insert_with_option(k, None, WriteOptions::default())?;
+                purged += 1;
+            }
+        }
+        if purged > 0 {
+            info!("deleted {} expired keys", purged);
+        }
+        Ok(purged)
     }
 
     fn insert_with_option(
         &self,
         k: StoreKey,
         v: Option<StorePayload>,
+        write_opt: WriteOptions,
     ) -> MyResult<Option<StorePayload>> {
         let encoded_v = serialize(&v)?;
-        let r = self.insert_(k, Slice::from(encoded_v))?;
+        let r = self.insert_(k, Slice::from(encoded_v), write_opt)?;
         Ok(r.and_then(|_| v))
     }
 
-    fn insert_(&self, k: Slice, v: Slice) -> MyResult<Option<Slice>> {
+    fn insert_(&self, k: Slice, v: Slice, write_opt: WriteOptions) -> MyResult<Option<Slice>> {
+        self.check_write_stall()?;
+        let entry = LogEntry::new(k, v).with_write_options(write_opt);
+        self.commit_queue_
+            .commit_pipelined(entry, |batch, handoff| self.write_batch(batch, handoff))
+    }
+
+    fn write_batch(
+        &self,
+        mut batch: Vec<LogEntry>,
+        handoff: &dyn Fn(),
+    ) -> MyResult<Vec<Option<Slice>>> {
         let mut wal = write_lock(&self.wal_);
-        wal.append(&k, &v)?;
+        // checked under the WAL lock so nothing is written once close has begun
+        self.check_open()?;
+        raise_last_sequence(&mut wal, self.cas_.load(SeqCst));
+        for entry in &mut batch {
+            entry.namespace = self.namespace_.clone();
+        }
+        wal.append_batch(&mut batch)?;
+        {
+            let mut log_numbers = self.log_numbers_.lock().unwrap();
+            let mutable = log_numbers.back_mut().expect("mutable memtable");
+            if mutable.is_none() {
+                *mutable = wal.log_number();
+                self.report_unflushed(&mut wal, &log_numbers)?;
+            }
+        }
+        let seg_full = wal.current_seg_size()? >= self.opt_.wal_seg_max_size;
 
-        let mut muttable = write_lock(&self.mut_);
-        let r = muttable.insert(k, v);
+        // taken before the WAL lock is released, so the memtable can't be
+        // rotated away between the append and the insert
+        let muttable = read_lock(&self.mut_);
+        drop(wal);
+        // the next batch is appended to the WAL while this one is inserted;
+        // sequence numbers keep the newest write of a key on top
+        handoff();
 
-        if wal.current_seg_size()? >= self.opt_.mem_table_max_size {
-            let copied = muttable.clone();
-            {
-                let mut immuttable = write_lock(&self.imm_);
-                immuttable.add(copied);
+        let keys = match &self.row_cache_ {
+            Some(_) => batch.iter().map(|e| e.key.clone()).collect(),
+            None => vec![],
+        };
+        let r = batch
+            .into_iter()
+            .map(|e| muttable.insert_shared(e.seq, e.key, e.value))
+            .collect();
+        if let Some(cache) = &self.row_cache_ {
+            for k in &keys {
+                cache.invalidate(k);
+            }
+        }
+
+        let full = seg_full || muttable.is_full();
+        drop(muttable);
+        if full {
+            self.wait_for_flush();
+            let mut wal = write_lock(&self.wal_);
+            let mut muttable = write_lock(&self.mut_);
+            // another writer may have rotated already
+            if muttable.is_full() || wal.current_seg_size()? >= self.opt_.wal_seg_max_size {
+                self.rotate_memtable(&mut wal, &mut muttable)?;
             }
-            muttable.clear();
-            wal.new_seg()?;
         }
 
         Ok(r)
     }
 
-    pub fn get<K: ?Sized>(&self, k: &K) -> MyResult<Option<StorePayload>>
-    where
-        K: Borrow<StoreKey>,
-    {
-        let k = k.borrow();
+    fn rotate_memtable(
+        &self,
+        wal: &mut WAL,
+        muttable: &mut Memtable<Slice, Slice>,
+    ) -> MyResult<()> {
+        let table = mem::replace(muttable, Memtable::with_options(&self.opt_));
+        {
+            let mut immuttable = write_lock(&self.imm_);
+            immuttable.add(table);
+        }
+        self.log_numbers_.lock().unwrap().push_back(None);
+        self.notify_flush_signal();
+        wal.new_seg()
+    }
 
-        let muttable = read_lock(&self.mut_);
-        let immuttable = read_lock(&self.imm_);
+    /// Tells `wal` the first segment with writes of this keyspace that
+    /// aren't flushed yet, from the `log_numbers_` of the memtables.
+    fn report_unflushed(
+        &self,
+        wal: &mut WAL,
+        log_numbers: &VecDeque<Option<usize>>,
+    ) -> MyResult<()> {
+        wal.set_unflushed(&self.namespace_, log_numbers.iter().find_map(|x| *x))
+    }
 
-        let mut r = muttable.get(k);
-        if r.is_none() {
-            r = immuttable.get(k);
+    /// Delays writes while flushes or level 0 compactions fall behind, and
+    /// rejects them once they are far behind, so reads don't have to look
+    /// through ever more memtables and level 0 files. Only applies while
+    /// background threads are there to catch up.
+    fn check_write_stall(&self) -> MyResult<()> {
+        if !self.background_running() {
+            return Ok(());
+        }
+        let l0 = read_lock(&self.readers_).get_readers(0).len();
+        let imm = read_lock(&self.imm_).table_count();
+        if l0 >= self.opt_.l0_stop_trigger || imm >= self.opt_.imm_mem_table_max_count {
+            return err(
+                StatusCode::WriteStall,
+                format!("{} level 0 files, {} immutable memtables", l0, imm),
+            );
+        }
+        if l0 >= self.opt_.l0_slowdown_trigger || imm >= self.opt_.imm_slowdown_trigger {
+            thread::sleep(Duration::from_millis(self.opt_.write_slowdown_ms as u64));
         }
+        Ok(())
+    }
 
-        if let Some(r) = r {
-            Ok(deserialize(r.borrow())?)
-        } else {
-            let readers = read_lock(&self.readers_);
-            let x: Option<Slice> = readers.get(k)?;
-            Ok(x.and_then(|x| deserialize(x.borrow()).unwrap()))
+    fn background_running(&self) -> bool {
+        !self.background_threads_.lock().unwrap().is_empty() && !self.shutdown_.is_cancelled()
+    }
+
+    /// Holds a writer back while the queue of immutable memtables is full and
+    /// the background flush is catching up. Without background threads the
+    /// queue is left to grow.
+    fn wait_for_flush(&self) {
+        let d = Duration::from_millis(self.opt_.thread_sleep_ms as u64);
+        while read_lock(&self.imm_).is_full() && self.background_running() {
+            self.wait_flush_signal(d);
         }
     }
 
-    pub fn remove<K>(&self, k: &K) -> MyResult<Option<StorePayload>>
-    where
-        K: Borrow<StoreKey>,
-    {
-        let r = self.get(k.borrow())?;
-        if r.is_some() {
-            self.insert_with_option(k.borrow().clone(), None)?;
+    fn wait_flush_signal(&self, timeout: Duration) {
+        let (lock, cond) = &self.flush_signal_;
+        let guard = lock.lock().unwrap();
+        let _ = cond.wait_timeout(guard, timeout).unwrap();
+    }
+
+    fn notify_flush_signal(&self) {
+        self.flush_signal_.1.notify_all();
+    }
+
+    fn flush_memtables(&self) -> MyResult<()> {
+        {
+            let mut wal = write_lock(&self.wal_);
+            let mut muttable = write_lock(&self.mut_);
+            if muttable.length() > 0 {
+                self.rotate_memtable(&mut wal, &mut muttable)?;
+            }
         }
-        Ok(r)
+        self.flush_immutables(&CancellationToken::new())
     }
 
-    fn minor_compaction(&self) -> MyResult<()> {
-        let imm = read_lock(&self.imm_);
-        let c = imm.table_count();
-        if c == 0 {
-            return Ok(());
+    /// Writes a copy of the db that another instance can open to `dir`, which
+    /// must not exist yet. The memtables are flushed first. Sstables and blob
+    /// files are hard linked, or copied where that fails, e.g. across
+    /// devices; the manifest and the WAL written since the flush are copied.
+    /// The WAL is copied by the default keyspace only, as its namespaces
+    /// share it. Writes and compactions wait while the files are collected.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> MyResult<()> {
+        self.check_open()?;
+        let dir = dir.as_ref();
+        let env = self.opt_.env.as_ref();
+        if env.exists(dir) {
+            return err!(StatusCode::InvalidArgument, "checkpoint dir exists");
         }
-        drop(imm);
+        self.flush_memtables()?;
 
         let mut wal = write_lock(&self.wal_);
-        let imm = read_lock(&self.imm_);
-
-        let mut iter = imm.tables_iter().rev();
+        let readers = read_lock(&self.readers_);
+        env.create_dir_all(dir)?;
         let work_dir = Path::new(&self.opt_.work_dir);
-        for _ in 0..c {
-            let memtable = iter.next().unwrap();
-            let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
-            if let Some((_, reader)) = memtable.build_sstable(&self.opt_, &path)? {
-                let mut readers = write_lock(&self.readers_);
-                readers.add(0, reader)?;
+        for file_name in readers.live_files() {
+            let (src, dst) = (work_dir.join(&file_name), dir.join(&file_name));
+            if env.hard_link(&src, &dst).is_err() {
+                env.copy(&src, &dst)?;
             }
-            wal.consume_seg()?;
         }
-        drop(imm);
-        drop(wal);
-        let mut imm = write_lock(&self.imm_);
-        for _ in 0..c {
-            imm.consume();
+        let manifest = Manifest::gen_path(&self.opt_);
+        env.copy(&manifest, &dir.join(manifest.file_name().unwrap()))?;
+        if self.namespace_.is_empty() {
+            wal.flush()?;
+            for seg in &wal.segs {
+                env.copy(seg.path(), &dir.join(seg.path().file_name().unwrap()))?;
+            }
         }
+        env.sync_dir(dir)?;
+        info!("checkpoint written to {}", dir.display());
         Ok(())
     }
 
-    pub fn major_compaction(&self) -> MyResult<()> {
-        let levels = {
-            let readers = read_lock(&self.readers_);
-            readers.compute_compaction_levels()
+    pub fn get<K: ?Sized>(&self, k: &K) -> MyResult<Option<StorePayload>>
+    where
+        K: Borrow<StoreKey>,
+    {
+        Ok(self.get_(k.borrow())?.filter(|p| !p.is_expired()))
+    }
+
+    /// Like `get`, but expired values are returned too.
+    fn get_(&self, k: &StoreKey) -> MyResult<Option<StorePayload>> {
+        let generation = match &self.row_cache_ {
+            Some(cache) => {
+                if let Some(r) = cache.get(k) {
+                    return Ok(r);
+                }
+                Some(cache.generation())
+            }
+            None => None,
         };
-        if !levels.is_empty() {
-            info!("size compaction: {:?}", levels);
-            self.size_compaction(levels)?;
-        } else {
-            self.seek_compaction()?;
+        let r = self.read_(k)?;
+        if let (Some(cache), Some(generation)) = (&self.row_cache_, generation) {
+            cache.insert(generation, k.clone(), r.clone());
         }
-        Ok(())
+        Ok(r)
     }
 
-    fn size_compaction(&self, levels: Vec<usize>) -> MyResult<()> {
-        // TODO: process all levels
-        let level = levels[0];
+    fn read_(&self, k: &StoreKey) -> MyResult<Option<StorePayload>> {
+        let muttable = read_lock(&self.mut_);
+        let immuttable = read_lock(&self.imm_);
 
-        if level >= self.opt_.max_level - 1 {
-            return Ok(());
+        // merge operands are collected newest first until a whole value
+        let mut ops = vec![];
+        let mut r = None;
+        let tables = ::std::iter::once(&*muttable).chain(immuttable.tables_iter());
+        for v in tables.filter_map(|t| t.get(k)) {
+            match decode_operands(v.borrow())? {
+                Some(x) => ops.push(x),
+                None => {
+                    r = Some(v.clone());
+                    break;
+                }
+            }
+        }
+        if r.is_none() {
+            let readers = read_lock(&self.readers_);
+            r = readers.get(k)?;
         }
 
-        let readers_group = read_lock(&self.readers_);
-        let readers = readers_group.get_readers(level);
+        let r = if ops.is_empty() {
+            r
+        } else {
+            let ops = ops.into_iter().rev().flatten().collect();
+            merge_values(r.as_ref().map(|x| &x[..]), ops)?
+        };
+        match r {
+            Some(r) => Ok(deserialize(r.borrow())?),
+            None => Ok(None),
+        }
+    }
 
-        let mut inputs0: Vec<&TableReader>;
+    /// Every live key with its value, in key order, as of one point in time.
+    /// See `range`.
+    pub fn scan(&self) -> MyResult<SpillIter> {
+        self.range(b"", None)
+    }
 
-        if level == 0 {
-            inputs0 = readers.iter().rev().collect();
-        } else {
-            let last_compact_key = self.last_compact_keys_.get(level);
+    /// The live keys in `[start, end)` with their values, in key order, as
+    /// of one point in time; no `end` runs to the last key. The range is
+    /// merged into a `SpillBuffer` under the read locks, so writes wait
+    /// until it is collected but not while it is consumed. Values are
+    /// bincode encoded `StorePayload`s.
+    pub fn range(&self, start: &[u8], end: Option<&[u8]>) -> MyResult<SpillIter> {
+        let mut buf = SpillBuffer::from_opt(&self.opt_, self.file_numbers_.clone());
+        self.scan_range(start, end, |k, p| {
+            buf.push(k, Slice::from(serialize(&p)?))?;
+            Ok(true)
+        })?;
+        buf.finish()
+    }
 
-            inputs0 = readers
-                .iter()
-                .filter(|reader| {
-                    last_compact_key.is_none() || reader.max_key() > last_compact_key.unwrap()
-                })
-                .collect();
+    /// Up to `limit` live keys starting with `prefix`, in key order, or all
+    /// of them with 0.
+    pub fn keys_with_prefix(&self, prefix: &[u8], limit: usize) -> MyResult<Vec<StoreKey>> {
+        let mut keys = vec![];
+        let end = prefix_end(prefix);
+        self.scan_range(prefix, end.as_deref(), |k, _| {
+            keys.push(k);
+            Ok(limit == 0 || keys.len() < limit)
+        })?;
+        Ok(keys)
+    }
 
-            if inputs0.is_empty() {
-                inputs0.push(&readers[0]);
-            }
+    /// Calls `f` with every live key in `[start, end)` and its value, in key
+    /// order, until it returns false; no `end` runs to the last key. The
+    /// memtables and sstables are only read from `start` on, under their read
+    /// locks, so `f` must not write.
+    pub fn scan_range<F>(&self, start: &[u8], end: Option<&[u8]>, mut f: F) -> MyResult<()>
+    where
+        F: FnMut(StoreKey, StorePayload) -> MyResult<bool>,
+    {
+        self.check_open()?;
+        if end.map_or(false, |end| start >= end) {
+            return Ok(());
         }
+        let muttable = read_lock(&self.mut_);
+        let immuttable = read_lock(&self.imm_);
+        let readers = read_lock(&self.readers_);
 
-        let (max, min) = inputs0.iter().fold((None, None), |a, b| {
-            if let (Some(max), Some(min)) = a {
-                (
-                    Some(::std::cmp::max(max, b.max_key())),
-                    Some(::std::cmp::min(min, b.min_key())),
-                )
+        let mut sources: Vec<ScanSource> = vec![];
+        for table in iter::once(&*muttable).chain(immuttable.tables_iter()) {
+            let entries = table.range(
+                Bound::Included(start),
+                end.map_or(Bound::Unbounded, Bound::Excluded),
+            );
+            sources.push(Box::new(
+                entries.map(|(k, v)| (k.clone(), ScanValue::Inline(v.clone()))),
+            ));
+        }
+        for level in 0..self.opt_.max_level {
+            let files = readers.get_readers(level).iter().filter(|r| {
+                r.max_key().as_slice() >= start
+                    && end.map_or(true, |end| r.min_key().as_slice() < end)
+            });
+            if level == 0 {
+                for reader in files.rev() {
+                    sources.push(Box::new(table_entries_from(reader, start)?));
+                }
             } else {
-                (Some(b.max_key()), Some(b.min_key()))
+                let entries = files
+                    .map(|r| table_entries_from(r, start))
+                    .collect::<MyResult<Vec<_>>>()?;
+                sources.push(Box::new(entries.into_iter().flatten()));
             }
-        });
-
-        let min_key = min.unwrap();
-        let max_key = max.unwrap();
-        let readers = readers_group.get_readers(level + 1);
-        let inputs1 = self.get_other_readers(&min_key, &max_key, readers);
-
-        let mut iters = vec![];
-
-        for reader in &inputs0 {
-            iters.push(reader.iter());
         }
 
-        for reader in &inputs1 {
-            iters.push(reader.iter());
+        let entries =
+            NewestFirstIter::new(sources).take_while(|(k, _)| end.map_or(true, |end| &k[..] < end));
+        for (k, values) in entries {
+            // merge operands are collected newest first until a whole value
+            let mut ops = vec![];
+            let mut r = None;
+            for v in values {
+                let v = v.resolve()?;
+                match decode_operands(&v[..])? {
+                    Some(x) => ops.push(x),
+                    None => {
+                        r = Some(v);
+                        break;
+                    }
+                }
+            }
+            let r = match r {
+                Some(v) => match BlobIndex::decode(&v[..])? {
+                    Some(idx) => Some(Slice::from(readers.get_blob(&idx)?)),
+                    None => Some(v),
+                },
+                None => None,
+            };
+            let r = if ops.is_empty() {
+                r
+            } else {
+                let ops = ops.into_iter().rev().flatten().collect();
+                merge_values(r.as_ref().map(|x| &x[..]), ops)?
+            };
+            let payload: Option<StorePayload> = match r {
+                Some(r) => deserialize(&r[..])?,
+                None => None,
+            };
+            match payload {
+                Some(p) if !p.is_expired() => {
+                    if !f(k, p)? {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `entries`, sorted by key without duplicates, straight into
+    /// level 0 sstables of about `sst_max_size` bytes each, bypassing the
+    /// memtable and the WAL. Meant for initial loads: the loaded values win
+    /// over older sstables but not over what is in the memtables. Nothing is
+    /// installed unless the whole input is written. Returns the number of
+    /// entries loaded.
+    pub fn bulk_load<I>(&self, entries: I) -> MyResult<usize>
+    where
+        I: IntoIterator<Item = (StoreKey, StorePayload)>,
+    {
+        self.check_open()?;
+        let work_dir = Path::new(&self.opt_.work_dir);
+        let mut entries = entries.into_iter().peekable();
+        let mut last: Option<StoreKey> = None;
+        let mut count = 0;
+        let mut failure: Option<Status> = None;
+        let mut paths = vec![];
+        let mut readers = vec![];
+
+        while failure.is_none() && entries.peek().is_some() {
+            let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
+            let mut bytes = 0;
+            let chunk = iter::from_fn(|| {
+                if bytes >= self.opt_.sst_max_size || failure.is_some() {
+                    return None;
+                }
+                let (k, v) = entries.next()?;
+                if last.as_ref().map_or(false, |last| &k <= last) {
+                    failure = Some(Status::new(
+                        StatusCode::InvalidArgument,
+                        &format!(
+                            "bulk load keys out of order at {}",
+                            String::from_utf8_lossy(&k[..])
+                        ),
+                    ));
+                    return None;
+                }
+                let v = match serialize(&Some(v)) {
+                    Ok(v) => Slice::from(v),
+                    Err(e) => {
+                        failure = Some(e.into());
+                        return None;
+                    }
+                };
+                bytes += k.len() + v.len();
+                count += 1;
+                last = Some(k.clone());
+                Some((k, v))
+            });
+            let table = entries_to_sstable(chunk, &self.opt_, &path);
+            paths.push(path);
+            match table {
+                Ok(Some((_, reader))) => readers.push(reader),
+                Ok(None) => {}
+                Err(e) => failure = Some(e),
+            }
+        }
+
+        if let Some(e) = failure {
+            drop(readers);
+            for path in paths {
+                for p in &[path.clone(), path.with_extension(BLOB_EXT)] {
+                    if self.opt_.env.exists(p) {
+                        self.opt_.env.remove_file(p)?;
+                    }
+                }
+            }
+            return Err(e);
+        }
+
+        write_lock(&self.readers_).add_readers(0, readers)?;
+        self.clear_row_cache();
+        Ok(count)
+    }
+
+    /// Installs the sstable at `path`, built elsewhere with `TableBuilder`,
+    /// as the newest version of its keys. Its blocks are verified and every
+    /// value must be a whole bincode encoded `Option<StorePayload>`. The file,
+    /// read through the env, is copied in under a new file number and goes
+    /// to the deepest level none of whose tables, nor those of the levels
+    /// above, overlap it. Memtables holding keys in its range are flushed
+    /// first. Returns the level it went to.
+    pub fn ingest_sstable<P: AsRef<Path>>(&self, path: P) -> MyResult<usize> {
+        self.check_open()?;
+        let env = self.opt_.env.as_ref();
+        let table_opt = self.opt_.get_table_opt();
+        let (min_key, max_key) = {
+            let reader = open_table(env, path.as_ref(), table_opt.clone())?;
+            if reader.verify()? == 0 {
+                return err!(StatusCode::InvalidArgument, "ingested sstable is empty");
+            }
+            for (k, v) in table_entries(&reader)? {
+                if decode_operands(&v[..])?.is_some() || BlobIndex::decode(&v[..])?.is_some() {
+                    return err(
+                        StatusCode::InvalidArgument,
+                        format!("bad ingested value at {}", String::from_utf8_lossy(&k[..])),
+                    );
+                }
+                deserialize::<Option<StorePayload>>(&v[..])?;
+            }
+            (reader.min_key().clone(), reader.max_key().clone())
+        };
+
+        let range = (Bound::Included(&min_key[..]), Bound::Included(&max_key[..]));
+        let in_memtables = {
+            let muttable = read_lock(&self.mut_);
+            let immuttable = read_lock(&self.imm_);
+            iter::once(&*muttable)
+                .chain(immuttable.tables_iter())
+                .any(|t| t.range(range.0, range.1).next().is_some())
+        };
+        if in_memtables {
+            self.flush_memtables()?;
+        }
+
+        let dst =
+            Path::new(&self.opt_.work_dir).join(make_file_name(self.new_file_number(), "sst"));
+        env.copy(path.as_ref(), &dst)?;
+        let installed = open_table(env, &dst, table_opt.clone()).and_then(|reader| {
+            let mut readers = write_lock(&self.readers_);
+            let level = self.ingest_level(&readers, &min_key, &max_key);
+            readers.add(level, reader)?;
+            self.clear_row_cache();
+            Ok(level)
+        });
+        if installed.is_err() {
+            env.remove_file(&dst)?;
+        }
+        installed
+    }
+
+    fn ingest_level(&self, readers: &SstableReader, min_key: &[u8], max_key: &[u8]) -> usize {
+        if self.opt_.compaction_style == CompactionStyle::Tiered {
+            return 0;
+        }
+        let mut level = 0;
+        for l in 0..self.opt_.max_level {
+            if readers.overlaps(l, min_key, max_key) {
+                break;
+            }
+            level = l;
+        }
+        level
+    }
+
+    /// Appends or prepends to the value of `k` by recording `op` in the
+    /// memtable, so the old value is only read when the memtable doesn't
+    /// have the key yet. Returns false when there is no value to merge into.
+    pub fn merge(&self, k: StoreKey, op: MergeOperand) -> MyResult<bool> {
+        self.merge_opt(k, op, WriteOptions::default())
+    }
+
+    pub fn merge_opt(
+        &self,
+        k: StoreKey,
+        op: MergeOperand,
+        write_opt: WriteOptions,
+    ) -> MyResult<bool> {
+        let current = read_lock(&self.mut_).get(&k).cloned();
+        let v = match current {
+            Some(current) => match merge_values(Some(&current[..]), vec![op])? {
+                Some(v) => v,
+                None => return Ok(false),
+            },
+            None => {
+                if self.get(&k)?.is_none() {
+                    return Ok(false);
+                }
+                encode_operands(&[op])?
+            }
+        };
+        self.insert_(k, v, write_opt)?;
+        Ok(true)
+    }
+
+    pub fn remove<K>(&self, k: &K) -> MyResult<Option<StorePayload>>
+    where
+        K: Borrow<StoreKey>,
+    {
+        self.remove_opt(k, WriteOptions::default())
+    }
+
+    pub fn remove_opt<K>(&self, k: &K, write_opt: WriteOptions) -> MyResult<Option<StorePayload>>
+    where
+        K: Borrow<StoreKey>,
+    {
+        let r = self.get(k.borrow())?;
+        if r.is_some() {
+            self.insert_with_option(k.borrow().clone(), None, write_opt)?;
+        }
+        Ok(r)
+    }
+
+    /// Deletes the keys that are there with one WAL write. Returns which
+    /// were, a key repeated counting as deleted the first time only.
+    pub fn remove_batch(&self, keys: &[StoreKey], write_opt: WriteOptions) -> MyResult<Vec<bool>> {
+        let deleted = Slice::from(serialize(&None::<StorePayload>)?);
+        let mut found = Vec::with_capacity(keys.len());
+        let mut batch = vec![];
+        for (i, k) in keys.iter().enumerate() {
+            let exists = !keys[..i].contains(k) && self.get(k)?.is_some();
+            if exists {
+                let entry = LogEntry::new(k.clone(), deleted.clone());
+                batch.push(entry.with_write_options(write_opt));
+            }
+            found.push(exists);
+        }
+        if !batch.is_empty() {
+            self.check_write_stall()?;
+            self.write_batch(batch, &|| {})?;
+        }
+        Ok(found)
+    }
+
+    /// For when values change other than by a write: loaded or ingested
+    /// tables, and compaction filters. Called once the new tables are in.
+    fn clear_row_cache(&self) {
+        if let Some(cache) = &self.row_cache_ {
+            cache.clear();
+        }
+    }
+
+    fn minor_compaction(&self) -> MyResult<()> {
+        self.rotate_pinning_memtable()?;
+        self.flush_immutables(&self.shutdown_)
+    }
+
+    /// Rotates the mutable memtable when its writes are all that keeps the
+    /// oldest segment of a WAL that has grown past the memtables of one
+    /// keyspace, so a keyspace written to now and then doesn't keep the
+    /// segments the others have flushed.
+    fn rotate_pinning_memtable(&self) -> MyResult<()> {
+        let max_segs = self.opt_.imm_mem_table_max_count + 1;
+        if read_lock(&self.wal_).seg_count() <= max_segs {
+            return Ok(());
+        }
+        let mut wal = write_lock(&self.wal_);
+        let mut muttable = write_lock(&self.mut_);
+        let pinning = {
+            let log_numbers = self.log_numbers_.lock().unwrap();
+            log_numbers.len() == 1
+                && log_numbers[0].is_some()
+                && log_numbers[0] == wal.first_log_number()
+        };
+        if pinning && wal.seg_count() > max_segs {
+            self.rotate_memtable(&mut wal, &mut muttable)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes immutable memtables oldest first. Only the bookkeeping at the
+    /// end of each table takes the WAL lock, so writes go on while the
+    /// sstable is built.
+    fn flush_immutables(&self, token: &CancellationToken) -> MyResult<()> {
+        // memtables must be consumed in the order they were written
+        let _flushing = self.flush_lock_.lock().unwrap();
+        let work_dir = Path::new(&self.opt_.work_dir);
+        while !token.is_cancelled() {
+            let memtables = read_lock(&self.imm_).oldest(self.opt_.max_memtables_per_flush.max(1));
+            if memtables.is_empty() {
+                break;
+            }
+            // older memtables are flushed already or resolved just before,
+            // so what is below the merge operands is in them or the sstables
+            for (i, memtable) in memtables.iter().enumerate() {
+                let older = &memtables[..i];
+                memtable.update_values(|k, v| {
+                    resolve_value(&v[..], || match older.iter().rev().find_map(|t| t.get(k)) {
+                        Some(v) => Ok(Some(v.clone())),
+                        None => read_lock(&self.readers_).get(k),
+                    })
+                })?;
+            }
+            let st = Instant::now();
+            let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
+            // newer memtables win
+            let mut merged = BTreeMap::new();
+            for memtable in &memtables {
+                merged.extend(memtable.iter());
+            }
+            // expired values only need to keep hiding what is below them
+            let entries = merged
+                .into_iter()
+                .inspect(|(k, v)| self.rate_limiter_.request(k.len() + v.len()))
+                .map(|(k, v)| match is_expired_value(v) {
+                    Ok(true) => (k, Slice::from(TOMBSTONE)),
+                    _ => (k, v.clone()),
+                });
+            if let Some((_, reader)) = entries_to_sstable(entries, &self.opt_, &path)? {
+                self.compaction_metrics_
+                    .record_flush(reader.size() as u64, st.elapsed());
+                let seqs = memtables.iter().filter_map(|t| t.sequence_range()).fold(
+                    None,
+                    |r, (min, max)| match r {
+                        Some((a, b)) => Some((::std::cmp::min(a, min), ::std::cmp::max(b, max))),
+                        None => Some((min, max)),
+                    },
+                );
+                write_lock(&self.readers_).add_flushed(vec![(reader, seqs)])?;
+            }
+            {
+                let mut wal = write_lock(&self.wal_);
+                let mut log_numbers = self.log_numbers_.lock().unwrap();
+                for _ in &memtables {
+                    log_numbers.pop_front();
+                    write_lock(&self.imm_).consume();
+                }
+                self.report_unflushed(&mut wal, &log_numbers)?;
+            }
+            self.flushes_.fetch_add(memtables.len() as u64, SeqCst);
+            self.notify_flush_signal();
+        }
+        Ok(())
+    }
+
+    pub fn major_compaction(&self) -> MyResult<()> {
+        self.check_open()?;
+        if self.is_compaction_paused() {
+            return Ok(());
+        }
+        let token = read_lock(&self.compaction_token_).clone();
+        if self.opt_.compaction_style == CompactionStyle::Tiered {
+            return self.tiered_compaction(&token);
+        }
+        let levels = {
+            let readers = read_lock(&self.readers_);
+            readers.compute_compaction_levels()
+        };
+        if !levels.is_empty() {
+            info!("size compaction: {:?}", levels);
+            self.size_compaction(levels, &token)?;
+        } else if !self.seek_compaction(&token)? {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            self.periodic_compaction(now.as_secs(), &token)?;
+        }
+        Ok(())
+    }
+
+    fn size_compaction(&self, levels: Vec<usize>, token: &CancellationToken) -> MyResult<()> {
+        // level 1 is too big to take level 0 before it is compacted itself
+        if levels[0] == 1 && levels.contains(&0) {
+            self.intra_l0_compaction(token)?;
+        }
+        // TODO: process all levels
+        self.compact_level(levels[0], token)
+    }
+
+    /// Merges the newest small files of level 0 into one level 0 file, in
+    /// their place, so newer files still shadow it. Nothing is dropped, older
+    /// versions of the keys may be below.
+    fn intra_l0_compaction(&self, token: &CancellationToken) -> MyResult<()> {
+        let min_files = self.opt_.intra_l0_min_files;
+        if min_files == 0 {
+            return Ok(());
+        }
+        let st = Instant::now();
+        let readers_group = read_lock(&self.readers_);
+        let runs = readers_group.get_readers(0);
+        let n = runs
+            .iter()
+            .rev()
+            .take_while(|r| r.size() < self.opt_.sst_max_size)
+            .count();
+        if n < min_files.max(2) {
+            return Ok(());
+        }
+        info!("intra level 0 compaction: {} of {} files", n, runs.len());
+
+        // newest first, so the merger keeps their values
+        let inputs = runs[runs.len() - n..].iter().rev().collect::<Vec<_>>();
+        let (new_readers, blob_garbage) =
+            self.merge_tables(&inputs, &readers_group, false, false, &(None, None), token)?;
+        self.compaction_metrics_.record_compaction(
+            0,
+            total_size(&inputs),
+            total_size(&new_readers),
+            st.elapsed(),
+        );
+        let file_names = inputs.iter().map(|x| x.file_name().clone()).collect();
+
+        drop(readers_group);
+
+        let mut readers_group = write_lock(&self.readers_);
+        readers_group.replace_files(0, &file_names, new_readers, &blob_garbage)?;
+        if self.opt_.compaction_filter.is_some() {
+            self.clear_row_cache();
+        }
+        Ok(())
+    }
+
+    /// Merges files of `level` with the files of `level + 1` they overlap.
+    /// Level 0 files overlap each other, so all of them go at once; deeper
+    /// levels give up one file per run, taken round robin through the key
+    /// space.
+    fn compact_level(&self, level: usize, token: &CancellationToken) -> MyResult<()> {
+        self.compact_file(level, None, token)
+    }
+
+    /// Like `compact_level`, but picks `file_name` from levels below 0.
+    fn compact_file(
+        &self,
+        level: usize,
+        file_name: Option<&str>,
+        token: &CancellationToken,
+    ) -> MyResult<()> {
+        if level >= self.opt_.max_level - 1 {
+            return Ok(());
         }
 
-        let mut merger = Merger::new(iters);
-
+        let st = Instant::now();
+        let readers_group = read_lock(&self.readers_);
+        let readers = readers_group.get_readers(level);
+        if readers.is_empty() {
+            return Ok(());
+        }
+
+        let inputs0: Vec<&TableReader> = if level == 0 {
+            // newest first, so the merger keeps their values
+            readers.iter().rev().collect()
+        } else {
+            let pointer = readers_group
+                .manifest_builder()
+                .manifest()
+                .compact_pointer(level);
+            let picked = file_name
+                .and_then(|f| readers.iter().find(|r| r.file_name() == f))
+                .or_else(|| {
+                    pointer.and_then(|p| {
+                        readers
+                            .iter()
+                            .find(|r| r.min_key().as_slice() > p.as_slice())
+                    })
+                })
+                .unwrap_or(&readers[0]);
+            vec![picked]
+        };
+
+        let (max, min) = inputs0.iter().fold((None, None), |a, b| {
+            if let (Some(max), Some(min)) = a {
+                (
+                    Some(::std::cmp::max(max, b.max_key())),
+                    Some(::std::cmp::min(min, b.min_key())),
+                )
+            } else {
+                (Some(b.max_key()), Some(b.min_key()))
+            }
+        });
+
+        let min_key = min.unwrap();
+        let max_key = max.unwrap().clone();
+        let readers = readers_group.get_readers(level + 1);
+        let inputs1 = self.get_other_readers(&min_key, &max_key, readers);
+        // a file that overlaps nothing below can move down as it is. Level 0
+        // is still rewritten into files of `sst_max_size`, and so is a file
+        // headed for the last level, where deletes and expired values are
+        // dropped for good; a compaction filter has to see every value.
+        if level > 0
+            && inputs1.is_empty()
+            && level + 2 < self.opt_.max_level
+            && self.opt_.compaction_filter.is_none()
+        {
+            let file_names = inputs0.iter().map(|x| x.file_name().clone()).collect();
+            drop(readers_group);
+            let mut readers_group = write_lock(&self.readers_);
+            readers_group
+                .manifest_builder_mut()
+                .set_compact_pointer(level, max_key);
+            readers_group.move_files(level, &file_names)?;
+            self.compaction_metrics_.record_move(level + 1, 1);
+            return Ok(());
+        }
+        // with no older version of these keys below the output level, deletes
+        // and expired values can go
+        let bottommost = (level + 2..self.opt_.max_level).all(|l| {
+            self.get_other_readers(&min_key, &max_key, readers_group.get_readers(l))
+                .is_empty()
+        });
+        let inputs = inputs0.iter().chain(&inputs1).cloned().collect::<Vec<_>>();
+        let ranges = if self.compaction_pool_.is_some() {
+            let keys = inputs.iter().map(|r| r.min_key().clone()).collect();
+            split_key_range(keys, self.opt_.max_subcompactions)
+        } else {
+            vec![(None, None)]
+        };
+        let (new_readers, blob_garbage) =
+            self.run_subcompactions(&inputs, &readers_group, &ranges, bottommost, token)?;
+        self.compaction_metrics_.record_compaction(
+            level + 1,
+            total_size(&inputs),
+            total_size(&new_readers),
+            st.elapsed(),
+        );
+
+        let mut changes = TableChanges::new();
+        changes.remove(
+            level,
+            inputs0.iter().map(|x| x.file_name().clone()).collect(),
+        );
+        changes.remove(
+            level + 1,
+            inputs1.iter().map(|x| x.file_name().clone()).collect(),
+        );
+        for reader in new_readers {
+            changes.add(level + 1, reader);
+        }
+        changes.add_blob_garbage(&blob_garbage);
+
+        drop(readers_group);
+
+        let mut readers_group = write_lock(&self.readers_);
+        // written with the compaction, so a restart picks up where it ended
+        if level > 0 {
+            readers_group
+                .manifest_builder_mut()
+                .set_compact_pointer(level, max_key);
+        }
+        readers_group.install(changes)?;
+        if self.opt_.compaction_filter.is_some() {
+            self.clear_row_cache();
+        }
+
+        Ok(())
+    }
+
+    /// Merges a window of similarly sized sorted runs of level 0 into one
+    /// run, once there are `l0_compaction_trigger` runs. Without such a
+    /// window the newest runs are merged until the trigger is met again.
+    fn tiered_compaction(&self, token: &CancellationToken) -> MyResult<()> {
+        let st = Instant::now();
+        let readers_group = read_lock(&self.readers_);
+        let runs = readers_group.get_readers(0);
+        let trigger = self.opt_.l0_compaction_trigger.max(2);
+        if runs.len() < trigger {
+            return Ok(());
+        }
+        let sizes = runs.iter().map(TableReader::size).collect::<Vec<_>>();
+        let (start, end) = pick_tiered_runs(
+            &sizes,
+            self.opt_.tiered_size_ratio,
+            self.opt_.tiered_min_merge_width,
+        )
+        .unwrap_or((
+            (sizes.len() + 1 - trigger).min(sizes.len() - 2),
+            sizes.len(),
+        ));
+        info!(
+            "tiered compaction: runs {}..{} of {}",
+            start,
+            end,
+            sizes.len()
+        );
+
+        // newest first, so the merger keeps their values
+        let inputs = runs[start..end].iter().rev().collect::<Vec<_>>();
+        let bottommost =
+            start == 0 && (1..self.opt_.max_level).all(|l| readers_group.get_readers(l).is_empty());
+        let (new_readers, blob_garbage) = self.merge_tables(
+            &inputs,
+            &readers_group,
+            false,
+            bottommost,
+            &(None, None),
+            token,
+        )?;
+        self.compaction_metrics_.record_compaction(
+            0,
+            total_size(&inputs),
+            total_size(&new_readers),
+            st.elapsed(),
+        );
+        let file_names = inputs.iter().map(|x| x.file_name().clone()).collect();
+
+        drop(readers_group);
+
+        let mut readers_group = write_lock(&self.readers_);
+        readers_group.replace_files(0, &file_names, new_readers, &blob_garbage)?;
+        if self.opt_.compaction_filter.is_some() {
+            self.clear_row_cache();
+        }
+        Ok(())
+    }
+
+    /// Merges each of `ranges` on its own thread of the compaction pool. If
+    /// any of them fails, what the others wrote is removed again.
+    fn run_subcompactions(
+        &self,
+        inputs: &[&TableReader],
+        readers_group: &SstableReader,
+        ranges: &[KeyRange],
+        drop_deletes: bool,
+        token: &CancellationToken,
+    ) -> MyResult<(Vec<TableReader>, HashMap<usize, u64>)> {
+        let pool = match &self.compaction_pool_ {
+            Some(pool) if ranges.len() > 1 => pool,
+            _ => {
+                return self.merge_tables(
+                    inputs,
+                    readers_group,
+                    true,
+                    drop_deletes,
+                    &(None, None),
+                    token,
+                )
+            }
+        };
+        info!("running {} subcompactions", ranges.len());
+        let shared = Shared((inputs, readers_group));
+        let shared = &shared;
+        let jobs = ranges
+            .iter()
+            .map(|range| {
+                move || {
+                    let (inputs, readers_group) = shared.0;
+                    Shared(self.merge_tables(
+                        inputs,
+                        readers_group,
+                        true,
+                        drop_deletes,
+                        range,
+                        token,
+                    ))
+                }
+            })
+            .collect();
+
+        let mut new_readers = vec![];
+        let mut blob_garbage = HashMap::new();
+        let mut error = None;
+        for result in pool.run_all(jobs) {
+            match result.0 {
+                Ok((readers, garbage)) => {
+                    new_readers.extend(readers);
+                    for (file_number, bytes) in garbage {
+                        *blob_garbage.entry(file_number).or_insert(0) += bytes;
+                    }
+                }
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        if let Some(e) = error {
+            let work_dir = Path::new(&self.opt_.work_dir);
+            let paths = new_readers
+                .iter()
+                .map(|r| work_dir.join(r.file_name()))
+                .collect();
+            drop(new_readers);
+            remove_table_files(self.opt_.env.as_ref(), paths)?;
+            return Err(e);
+        }
+        Ok((new_readers, blob_garbage))
+    }
+
+    /// Merges the keys of `inputs`, newest first, within `range` into new
+    /// tables. Output is cut at `sst_max_size` when `split` is set, and
+    /// deletes and expired values are left out when `drop_deletes` is.
+    /// Returns the tables and the blob garbage found on the way.
+    fn merge_tables(
+        &self,
+        inputs: &[&TableReader],
+        readers_group: &SstableReader,
+        split: bool,
+        drop_deletes: bool,
+        range: &KeyRange,
+        token: &CancellationToken,
+    ) -> MyResult<(Vec<TableReader>, HashMap<usize, u64>)> {
+        let (start, end) = range;
+        let mut dropped = 0;
+        let mut filtered = 0;
+        let iters = inputs
+            .iter()
+            .filter(|r| {
+                start.as_ref().map_or(true, |s| r.max_key() >= s)
+                    && end.as_ref().map_or(true, |e| r.min_key() < e)
+            })
+            .map(|r| range_iter(r, start.as_deref()))
+            .collect::<MyResult<Vec<_>>>()?;
+        let mut merger = Merger::new(iters).with_shadowed_tracking();
+
         let work_dir = Path::new(&self.opt_.work_dir);
 
         let table_opt = self.opt_.get_table_opt();
         let mut table = None;
+        let mut blob = None;
         let mut new_readers = vec![];
+        let mut blob_garbage = HashMap::new();
 
         while let Some((k, v)) = merger.next() {
+            if end.as_ref().map_or(false, |e| &k >= e) {
+                // shadowed values of `k` are the next range's garbage
+                merger.take_shadowed();
+                break;
+            }
+            add_blob_garbage(&mut blob_garbage, merger.take_shadowed())?;
+
+            if token.is_cancelled() {
+                let mut paths = new_readers
+                    .iter()
+                    .map(|r| work_dir.join(r.file_name()))
+                    .collect::<Vec<_>>();
+                if let Some(table_) = table.take() {
+                    paths.push(table_.path().clone());
+                }
+                drop(new_readers);
+                drop(blob);
+                remove_table_files(self.opt_.env.as_ref(), paths)?;
+                return err!(StatusCode::Cancelled, "compaction cancelled");
+            }
+
+            self.rate_limiter_.request(k.len() + v.len());
+
+            if drop_deletes && is_droppable(&v)? {
+                dropped += 1;
+                continue;
+            }
+            let v = if !drop_deletes && is_expired_value(&v)? {
+                TOMBSTONE.to_vec()
+            } else {
+                v
+            };
+
+            let v =
+                match self.filter_value(&k, v, readers_group, drop_deletes, &mut blob_garbage)? {
+                    Some(v) => v,
+                    None => {
+                        filtered += 1;
+                        continue;
+                    }
+                };
+
             if table.is_none() {
                 let path = work_dir.join(make_file_name(self.new_file_number(), "sst"));
-                table = Some(TableBuilder::new(&path, table_opt.clone())?);
+                table = Some(create_table(
+                    self.opt_.env.as_ref(),
+                    &path,
+                    table_opt.clone(),
+                )?);
             }
 
             let is_full = {
                 let table_ = table.as_mut().unwrap();
 
+                let v = self.relocate_blob(
+                    v,
+                    readers_group,
+                    table_.path(),
+                    &mut blob,
+                    &mut blob_garbage,
+                )?;
                 table_.add(&k, &v)?;
 
-                table_.total_size_estimate() >= self.opt_.sst_max_size
+                split && table_.total_size_estimate() >= self.opt_.sst_max_size
             };
 
             if !is_full {
                 continue;
             }
 
+            if let Some(blob_) = blob.take() {
+                blob_.finish()?;
+            }
             let mut table_ = table.take().unwrap();
             let path = &table_.path().clone();
             table_.flush()?;
-            let reader = TableReader::new(path, table_opt.clone())?;
+            let reader = open_sstable(path, &self.opt_)?;
             new_readers.push(reader);
         }
 
+        add_blob_garbage(&mut blob_garbage, merger.take_shadowed())?;
+
+        if let Some(blob_) = blob.take() {
+            blob_.finish()?;
+        }
         if let Some(mut table_) = table.take() {
             let path = &table_.path().clone();
             table_.flush()?;
-            let reader = TableReader::new(&path, table_opt.clone())?;
+            let reader = open_sstable(&path, &self.opt_)?;
             new_readers.push(reader);
         }
 
-        let file_names0 = inputs0.iter().map(|x| x.file_name().clone()).collect();
-        let file_names1 = inputs1.iter().map(|x| x.file_name().clone()).collect();
-
-        drop(readers_group);
+        if dropped > 0 {
+            info!("dropped {} deletes and expired values", dropped);
+        }
+        if filtered > 0 {
+            info!("compaction filter removed {} values", filtered);
+        }
 
-        let mut readers_group = write_lock(&self.readers_);
+        Ok((new_readers, blob_garbage))
+    }
 
-        readers_group.remove_by_file_names(level, &file_names0)?;
-        readers_group.remove_by_file_names(level + 1, &file_names1)?;
+    /// Hands a value to the compaction filter, if there is one. Returns
+    /// `None` for values to leave out. Removed values that may shadow older
+    /// versions of their key are turned into deletes instead.
+    fn filter_value(
+        &self,
+        k: &[u8],
+        v: Vec<u8>,
+        readers_group: &SstableReader,
+        drop_deletes: bool,
+        blob_garbage: &mut HashMap<usize, u64>,
+    ) -> MyResult<Option<Vec<u8>>> {
+        let filter = match &self.opt_.compaction_filter {
+            Some(filter) => filter,
+            None => return Ok(Some(v)),
+        };
+        if decode_operands(&v)?.is_some() {
+            return Ok(Some(v));
+        }
+        let idx = BlobIndex::decode(&v)?;
+        let payload: Option<StorePayload> = match &idx {
+            Some(idx) => deserialize(&readers_group.get_blob(idx)?)?,
+            None => deserialize(&v)?,
+        };
+        let payload = match payload {
+            Some(payload) => payload,
+            None => return Ok(Some(v)),
+        };
+        let new_v = match filter.filter(k, &payload) {
+            FilterDecision::Keep => return Ok(Some(v)),
+            FilterDecision::Remove if drop_deletes => None,
+            FilterDecision::Remove => Some(serialize(&None::<StorePayload>)?),
+            FilterDecision::ChangeValue(payload) => Some(serialize(&Some(payload))?),
+        };
+        if let Some(idx) = idx {
+            *blob_garbage.entry(idx.file_number).or_insert(0) += idx.record_size();
+        }
+        Ok(new_v)
+    }
 
-        readers_group.add_readers(level + 1, new_readers)?;
+    /// Moves a value out of a blob file that is mostly garbage into the blob
+    /// file of the table being written, so the old file can be deleted sooner.
+    fn relocate_blob(
+        &self,
+        v: Vec<u8>,
+        readers: &SstableReader,
+        table_path: &Path,
+        blob: &mut Option<BlobWriter>,
+        blob_garbage: &mut HashMap<usize, u64>,
+    ) -> MyResult<Vec<u8>> {
+        let idx = match BlobIndex::decode(&v)? {
+            Some(idx) => idx,
+            None => return Ok(v),
+        };
+        let relocate = readers.blob_file(idx.file_number).map_or(false, |meta| {
+            meta.garbage_ratio() >= self.opt_.blob_gc_ratio
+        });
+        if !relocate {
+            return Ok(v);
+        }
+        let value = readers.get_blob(&idx)?;
+        if blob.is_none() {
+            *blob = Some(new_blob_writer(&self.opt_, table_path)?);
+        }
+        let new_idx = blob.as_mut().unwrap().add(&value)?;
+        *blob_garbage.entry(idx.file_number).or_insert(0) += idx.record_size();
+        new_idx.encode()
+    }
 
-        Ok(())
+    /// Compacts a file gets keep looking into for keys it doesn't have, so
+    /// they find the key, or learn it's missing, in fewer files.
+    fn seek_compaction(&self, token: &CancellationToken) -> MyResult<bool> {
+        let picked = read_lock(&self.readers_).seek_compaction_file();
+        match picked {
+            Some((level, file_name)) => {
+                info!("seek compaction: {} in level {}", file_name, level);
+                self.compact_file(level, Some(&file_name), token)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
-    fn seek_compaction(&self) -> MyResult<()> {
-        Ok(())
+    /// Rewrites a file written more than `periodic_compaction_secs` before
+    /// `now`, dropping deletes and expired values if nothing below
+    /// holds their keys. Level 0 goes into level 1 as usual, deeper files are
+    /// rewritten in place, which includes the last level.
+    fn periodic_compaction(&self, now: u64, token: &CancellationToken) -> MyResult<bool> {
+        let secs = self.opt_.periodic_compaction_secs;
+        if secs == 0 {
+            return Ok(false);
+        }
+        let st = Instant::now();
+        let readers_group = read_lock(&self.readers_);
+        let (level, file_name) =
+            match readers_group.periodic_compaction_file(now.saturating_sub(secs)) {
+                Some(picked) => picked,
+                None => return Ok(false),
+            };
+        info!("periodic compaction: {} in level {}", file_name, level);
+        if level == 0 {
+            drop(readers_group);
+            self.compact_level(0, token)?;
+            return Ok(true);
+        }
+
+        let input = readers_group
+            .get_readers(level)
+            .iter()
+            .find(|r| r.file_name() == &file_name)
+            .unwrap();
+        let bottommost = (level + 1..self.opt_.max_level).all(|l| {
+            self.get_other_readers(
+                input.min_key(),
+                input.max_key(),
+                readers_group.get_readers(l),
+            )
+            .is_empty()
+        });
+        let inputs = vec![input];
+        let (new_readers, blob_garbage) = self.merge_tables(
+            &inputs,
+            &readers_group,
+            true,
+            bottommost,
+            &(None, None),
+            token,
+        )?;
+        self.compaction_metrics_.record_compaction(
+            level,
+            total_size(&inputs),
+            total_size(&new_readers),
+            st.elapsed(),
+        );
+        let mut file_names = HashSet::new();
+        file_names.insert(file_name);
+
+        drop(readers_group);
+
+        let mut readers_group = write_lock(&self.readers_);
+        readers_group.replace_files(level, &file_names, new_readers, &blob_garbage)?;
+        if self.opt_.compaction_filter.is_some() {
+            self.clear_row_cache();
+        }
+        Ok(true)
     }
 
     fn get_other_readers<'a>(
@@ -416,14 +1999,223 @@ impl DataManager {
         let mut immuttable = write_lock(&self.imm_);
         muttable.clear();
         immuttable.clear();
+        *self.log_numbers_.lock().unwrap() = VecDeque::from(vec![None]);
     }
 }
 
-#[cfg(test)]
+fn add_blob_garbage(
+    blob_garbage: &mut HashMap<usize, u64>,
+    shadowed: Vec<Vec<u8>>,
+) -> MyResult<()> {
+    for v in shadowed {
+        if let Some(idx) = BlobIndex::decode(&v)? {
+            *blob_garbage.entry(idx.file_number).or_insert(0) += idx.record_size();
+        }
+    }
+    Ok(())
+}
+
+/// Picks consecutive runs out of `sizes`, oldest first, to merge. Starting
+/// from the newest run, older runs join while each is at most `size_ratio`
+/// percent larger than the runs taken so far. Returns the first window of at
+/// least `min_width` runs.
+fn pick_tiered_runs(
+    sizes: &[usize],
+    size_ratio: usize,
+    min_width: usize,
+) -> Option<(usize, usize)> {
+    let min_width = min_width.max(2);
+    for end in (1..=sizes.len()).rev() {
+        let mut start = end - 1;
+        let mut total = sizes[start];
+        while start > 0 && sizes[start - 1] * 100 <= total * (100 + size_ratio) {
+            start -= 1;
+            total += sizes[start];
+        }
+        if end - start >= min_width {
+            return Some((start, end));
+        }
+    }
+    None
+}
+
+/// A value met by a scan. Values in sstables are only copied out once the
+/// scan needs them, so versions shadowed by newer ones are never copied.
+enum ScanValue<'a> {
+    Inline(Slice),
+    Handle(&'a TableReader, ValueHandle),
+}
+
+impl<'a> ScanValue<'a> {
+    fn resolve(self) -> MyResult<Slice> {
+        match self {
+            ScanValue::Inline(v) => Ok(v),
+            ScanValue::Handle(reader, h) => Ok(Slice::from(h.resolve(reader)?)),
+        }
+    }
+}
+
+type ScanSource<'a> = Box<dyn Iterator<Item = (Slice, ScanValue<'a>)> + 'a>;
+
+/// Merges sources that are each in key order and given newest first. Yields
+/// every key once, with its values from all sources holding it, newest first.
+struct NewestFirstIter<'a> {
+    sources: Vec<Peekable<ScanSource<'a>>>,
+}
+
+impl<'a> NewestFirstIter<'a> {
+    fn new(sources: Vec<ScanSource<'a>>) -> Self {
+        NewestFirstIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for NewestFirstIter<'a> {
+    type Item = (Slice, Vec<ScanValue<'a>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let k = self
+            .sources
+            .iter_mut()
+            .filter_map(|s| s.peek().map(|(k, _)| k.clone()))
+            .min()?;
+        let mut values = vec![];
+        for source in &mut self.sources {
+            if source.peek().map_or(false, |(x, _)| x == &k) {
+                values.push(source.next().unwrap().1);
+            }
+        }
+        Some((k, values))
+    }
+}
+
+fn table_entries(reader: &TableReader) -> MyResult<impl Iterator<Item = (Slice, Slice)> + '_> {
+    let mut iter = reader.iter()?;
+    Ok(iter::from_fn(move || SsIterator::next(&mut iter))
+        .map(|(k, v)| (Slice::from(k), Slice::from(v))))
+}
+
+/// The entries of `reader` from the first key at or after `start` on, with
+/// handles to their values.
+fn table_entries_from<'a>(
+    reader: &'a TableReader,
+    start: &[u8],
+) -> MyResult<impl Iterator<Item = (Slice, ScanValue<'a>)> + 'a> {
+    let mut iter = range_iter(reader, Some(start))?;
+    Ok(iter::from_fn(move || iter.next_handle())
+        .map(move |(k, h)| (Slice::from(k), ScanValue::Handle(reader, h))))
+}
+
+/// The smallest key above every key starting with `prefix`, or None when
+/// there is none, as for a prefix of 0xff bytes.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let i = prefix.iter().rposition(|x| *x != 0xff)?;
+    let mut end = prefix[..=i].to_vec();
+    end[i] += 1;
+    Some(end)
+}
+
+/// Splits the key space at up to `n - 1` of `keys`, spread evenly over them.
+/// The smallest key starts no range, as nothing is below it.
+fn split_key_range(mut keys: Vec<Vec<u8>>, n: usize) -> Vec<KeyRange> {
+    keys.sort();
+    keys.dedup();
+    if !keys.is_empty() {
+        keys.remove(0);
+    }
+    let parts = n.min(keys.len() + 1).max(1);
+    let bounds = (1..parts)
+        .map(|i| keys[i * keys.len() / parts].clone())
+        .collect::<Vec<_>>();
+    let mut ranges = vec![];
+    let mut start = None;
+    for bound in bounds {
+        ranges.push((start, Some(bound.clone())));
+        start = Some(bound);
+    }
+    ranges.push((start, None));
+    ranges
+}
+
+/// An iterator whose first `advance` lands on the first key at or after
+/// `start`.
+fn range_iter<'a>(reader: &'a TableReader, start: Option<&[u8]>) -> MyResult<TableIter<'a>> {
+    let mut iter = reader.iter()?;
+    if let Some(start) = start {
+        if start > reader.min_key().as_slice() {
+            iter.seek(start);
+            if iter.valid() {
+                iter.prev();
+            }
+        }
+    }
+    Ok(iter)
+}
+
+/// Deletes tables, and their blob files, written by a compaction that did not
+/// go through.
+fn remove_table_files(env: &dyn Env, paths: Vec<PathBuf>) -> MyResult<()> {
+    for path in paths {
+        let blob_path = path.with_extension(BLOB_EXT);
+        if env.exists(&blob_path) {
+            env.remove_file(&blob_path)?;
+        }
+        env.remove_file(&path)?;
+    }
+    Ok(())
+}
+
+fn total_size<R: Borrow<TableReader>>(readers: &[R]) -> u64 {
+    readers.iter().map(|r| r.borrow().size() as u64).sum()
+}
+
+/// Whether `v` is a whole value, kept in the table itself, whose ttl passed.
+fn is_expired_value(v: &[u8]) -> MyResult<bool> {
+    if decode_operands(v)?.is_some() || BlobIndex::decode(v)?.is_some() {
+        return Ok(false);
+    }
+    let payload: Option<StorePayload> = deserialize(v)?;
+    Ok(payload.map_or(false, |p| p.is_expired()))
+}
+
+/// Whether a value can be left out of a compaction into the last level that
+/// holds its key: a delete has nothing left to hide and an expired value is
+/// never read again. Values in blob files are kept rather than read back.
+fn is_droppable(v: &[u8]) -> MyResult<bool> {
+    if BlobIndex::decode(v)?.is_some() {
+        return Ok(false);
+    }
+    let payload: Option<StorePayload> = deserialize(v)?;
+    Ok(payload.map_or(true, |p| p.is_expired()))
+}
+
+fn ignore_cancelled(r: MyResult<()>) -> MyResult<()> {
+    match r {
+        Err(ref e) if e.code == StatusCode::Cancelled => Ok(()),
+        r => r,
+    }
+}
+
+/// Moves the sequence numbers `wal` hands out past `seq`.
+fn raise_last_sequence(wal: &mut WAL, seq: u64) {
+    if seq > wal.last_sequence() {
+        wal.set_last_sequence(seq);
+    }
+}
+
+#[cfg(test)]
 mod test {
     use std::collections::HashMap;
+    use std::fs::copy;
+    use std::fs::create_dir_all;
     use std::time;
 
+    use crate::compaction_filter::CompactionFilter;
+    use crate::env::list_ext;
+    use crate::merge::MergeKind;
+    use crate::options::MB;
+    use crate::repair::repair;
     use crate::test_utils::get_test_opt;
 
     use super::*;
@@ -448,6 +2240,7 @@ mod test {
         let mut opt = get_test_opt();
         opt.imm_mem_table_max_count = 3;
         opt.mem_table_max_size = 20;
+        opt.wal_seg_max_size = 20;
         opt.sst_max_size = 60;
         opt.l0_compaction_trigger = 1;
 
@@ -574,4 +2367,1149 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_rotate_on_wal_size() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.mem_table_max_size = MB;
+        opt.wal_seg_max_size = 64;
+        opt.imm_mem_table_max_count = 16;
+        opt.wal_preallocate = false;
+        let dm = DataManager::new(opt)?;
+        let data = get_data();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        assert!(!read_lock(&dm.mut_).is_full());
+        assert!(read_lock(&dm.imm_).table_count() > 0);
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_load() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.sst_max_size = 60;
+        let dm = DataManager::new(opt)?;
+        dm.insert(make_key(b"b".to_vec()), make_payload(b"new".to_vec()))?;
+
+        let mut data = get_data().into_iter().collect::<Vec<_>>();
+        data.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(data.len(), dm.bulk_load(data.clone())?);
+        assert!(read_lock(&dm.readers_).get_readers(0).len() > 1);
+        for (k, v) in &data {
+            let expected = if k == &b"b".to_vec() {
+                make_payload(b"new".to_vec())
+            } else {
+                v.clone()
+            };
+            assert_eq!(Some(expected), dm.get(k)?);
+        }
+
+        // unsorted input installs nothing
+        let files = read_lock(&dm.readers_).get_readers(0).len();
+        let unsorted = vec![
+            (make_key(b"y".to_vec()), make_payload(b"y".to_vec())),
+            (make_key(b"x".to_vec()), make_payload(b"x".to_vec())),
+        ];
+        let r = dm.bulk_load(unsorted);
+        assert_eq!(StatusCode::InvalidArgument, r.unwrap_err().code);
+        assert_eq!(files, read_lock(&dm.readers_).get_readers(0).len());
+        assert_eq!(None, dm.get(&make_key(b"y".to_vec()))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_orphan_files() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.quarantine_orphan_files = false;
+        let work_dir = Path::new(&opt.work_dir).to_path_buf();
+        let data = get_data();
+        {
+            let dm = DataManager::new(opt.clone())?;
+            for (k, v) in &data {
+                dm.insert(k.clone(), v.clone())?;
+            }
+            dm.close()?;
+        }
+        let orphans = vec![
+            work_dir.join(make_file_name(9999, "sst")),
+            work_dir.join(make_file_name(9999, BLOB_EXT)),
+        ];
+        let temps = vec![
+            Manifest::gen_path(&opt).with_extension("tmp"),
+            spill_dir(&opt).join("00000001.spill"),
+        ];
+        create_dir_all(spill_dir(&opt))?;
+        for path in orphans.iter().chain(&temps) {
+            std::fs::write(path, b"junk")?;
+        }
+
+        let dm = DataManager::new(opt.clone())?;
+        for path in orphans.iter().chain(&temps) {
+            assert!(!path.exists());
+        }
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        assert_eq!(0, dm.purge_orphan_files(true)?);
+        dm.close()?;
+        drop(dm);
+
+        opt.quarantine_orphan_files = true;
+        std::fs::write(&orphans[0], b"junk")?;
+        let dm = DataManager::new(opt)?;
+        assert!(!orphans[0].exists());
+        assert!(work_dir
+            .join(LOST_DIR)
+            .join(orphans[0].file_name().unwrap())
+            .exists());
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_tables_without_manifest() -> MyResult<()> {
+        let opt = get_test_opt();
+        let work_dir = Path::new(&opt.work_dir).to_path_buf();
+        let data = get_data();
+        {
+            let dm = DataManager::new(opt.clone())?;
+            for (k, v) in &data {
+                dm.insert(k.clone(), v.clone())?;
+            }
+            dm.close()?;
+        }
+        let before = list_ext(opt.env.as_ref(), &work_dir, "sst")?;
+        assert!(!before.is_empty());
+        std::fs::remove_file(Manifest::gen_path(&opt))?;
+
+        let dm = DataManager::new(opt.clone())?;
+        dm.close()?;
+        drop(dm);
+        for path in &before {
+            assert!(path.exists());
+        }
+        assert!(list_ext(opt.env.as_ref(), &work_dir.join(LOST_DIR), "sst")?.is_empty());
+
+        repair(&opt)?;
+        let dm = DataManager::new(opt)?;
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingest_sstable() -> MyResult<()> {
+        let opt = get_test_opt();
+        let dm = DataManager::new(opt.clone())?;
+        dm.insert(make_key(b"b".to_vec()), make_payload(b"old".to_vec()))?;
+
+        let ext = get_test_opt();
+        let path = Path::new(&ext.work_dir).join("ext.sst");
+        let entries = vec![
+            (make_key(b"a".to_vec()), make_payload(b"a".to_vec())),
+            (make_key(b"b".to_vec()), make_payload(b"b".to_vec())),
+        ];
+        let encoded = entries
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), Slice::from(serialize(&Some(v.clone()))?))))
+            .collect::<MyResult<Vec<_>>>()?;
+        entries_to_sstable(encoded.into_iter(), &ext, &path)?;
+
+        // the memtable is flushed, and level 0 then holds "b"
+        assert_eq!(0, dm.ingest_sstable(&path)?);
+        for (k, v) in &entries {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        assert!(path.exists());
+
+        let other = Path::new(&ext.work_dir).join("other.sst");
+        let entries = vec![(make_key(b"x".to_vec()), make_payload(b"x".to_vec()))];
+        let encoded = vec![(
+            entries[0].0.clone(),
+            Slice::from(serialize(&Some(entries[0].1.clone()))?),
+        )];
+        entries_to_sstable(encoded.into_iter(), &ext, &other)?;
+        assert_eq!(opt.max_level - 1, dm.ingest_sstable(&other)?);
+        assert_eq!(Some(entries[0].1.clone()), dm.get(&entries[0].0)?);
+
+        // merge operands can't be ingested
+        let bad = Path::new(&ext.work_dir).join("bad.sst");
+        let op = MergeOperand::new(MergeKind::Append, make_payload(b"y".to_vec()));
+        entries_to_sstable(
+            vec![(make_key(b"y".to_vec()), encode_operands(&[op])?)].into_iter(),
+            &ext,
+            &bad,
+        )?;
+        let files = read_lock(&dm.readers_).live_files();
+        let r = dm.ingest_sstable(&bad);
+        assert_eq!(StatusCode::InvalidArgument, r.unwrap_err().code);
+        assert_eq!(files, read_lock(&dm.readers_).live_files());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merged_flush() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.max_memtables_per_flush = 3;
+        let dm = DataManager::new(opt.clone())?;
+        let (a, b, c) = (
+            make_key(b"a".to_vec()),
+            make_key(b"b".to_vec()),
+            make_key(b"c".to_vec()),
+        );
+        dm.insert(a.clone(), make_payload(vec![1]))?;
+        dm.insert(b.clone(), make_payload(vec![1]))?;
+        dm.merge(
+            a.clone(),
+            MergeOperand::new(MergeKind::Append, make_payload(vec![2])),
+        )?;
+        dm.remove(&b)?;
+        dm.insert(c.clone(), make_payload(vec![1]))?;
+        dm.insert(c.clone(), make_payload(vec![2]))?;
+        let memtables = read_lock(&dm.imm_).table_count();
+        assert!(memtables > 3);
+
+        dm.minor_compaction()?;
+        assert_eq!(0, read_lock(&dm.imm_).table_count());
+        let readers = read_lock(&dm.readers_);
+        assert_eq!((memtables + 2) / 3, readers.get_readers(0).len());
+        drop(readers);
+        assert_eq!(memtables as u64, dm.memtable_stats().flushes);
+
+        let expected = |dm: &DataManager| -> MyResult<()> {
+            assert_eq!(Slice::from(vec![1, 2]), dm.get(&a)?.unwrap().data);
+            assert_eq!(None, dm.get(&b)?);
+            assert_eq!(Some(make_payload(vec![2])), dm.get(&c)?);
+            Ok(())
+        };
+        expected(&dm)?;
+        dm.clear_memtables();
+        expected(&dm)?;
+        expected(&DataManager::new(opt)?)
+    }
+
+    #[test]
+    fn test_file_metas() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt.clone())?;
+        for k in &[b"b", b"a", b"c"] {
+            dm.insert(make_key(k.to_vec()), make_payload(vec![1]))?;
+        }
+        dm.minor_compaction()?;
+        let l0 = read_lock(&dm.readers_).get_file_metas(0).to_vec();
+        assert_eq!(3, l0.len());
+        for (fm, reader) in l0.iter().zip(read_lock(&dm.readers_).get_readers(0)) {
+            assert_eq!(reader.file_name(), &fm.file_name);
+            assert_eq!(reader.min_key(), &fm.smallest_key);
+            assert_eq!(reader.max_key(), &fm.largest_key);
+            assert_eq!(reader.size() as u64, fm.file_size);
+            assert_eq!(fm.smallest_seq, fm.largest_seq);
+        }
+        assert!(l0[0].largest_seq < l0[1].smallest_seq);
+        assert!(l0[1].largest_seq < l0[2].smallest_seq);
+        assert_eq!(
+            l0[2].largest_seq,
+            read_lock(&dm.readers_)
+                .manifest_builder()
+                .manifest()
+                .last_sequence
+        );
+
+        dm.major_compaction()?;
+        let check = |dm: &DataManager| {
+            let readers = read_lock(&dm.readers_);
+            assert!(readers.get_readers(0).is_empty());
+            let l1 = readers.get_file_metas(1);
+            assert!(!l1.is_empty());
+            for (fm, reader) in l1.iter().zip(readers.get_readers(1)) {
+                assert_eq!(reader.file_name(), &fm.file_name);
+                assert_eq!(l0[0].smallest_seq, fm.smallest_seq);
+                assert_eq!(l0[2].largest_seq, fm.largest_seq);
+            }
+            assert_eq!(b"a".to_vec(), l1[0].smallest_key);
+            assert_eq!(b"c".to_vec(), l1[l1.len() - 1].largest_key);
+        };
+        check(&dm);
+        drop(dm);
+        check(&DataManager::new(opt)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbering_resumes_after_flush() -> MyResult<()> {
+        let opt = get_test_opt();
+        let (last_sequence, next_file_number) = {
+            let dm = DataManager::new(opt.clone())?;
+            dm.insert(make_key(b"a".to_vec()), make_payload(vec![1]))?;
+            dm.insert(make_key(b"b".to_vec()), make_payload(vec![1]))?;
+            dm.flush_memtables()?;
+            assert!(read_lock(&dm.wal_)
+                .segs
+                .iter()
+                .all(|s| s.iter().unwrap().next().is_none()));
+            let last_sequence = read_lock(&dm.wal_).last_sequence();
+            assert!(last_sequence > 0);
+            (last_sequence, dm.file_numbers_.peek())
+        };
+
+        let dm = DataManager::new(opt)?;
+        assert_eq!(last_sequence, read_lock(&dm.wal_).last_sequence());
+        assert!(dm.file_numbers_.peek() >= next_file_number);
+        dm.insert(make_key(b"c".to_vec()), make_payload(vec![1]))?;
+        assert_eq!(last_sequence + 1, read_lock(&dm.wal_).last_sequence());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cas_resumes_after_restart() -> MyResult<()> {
+        let opt = get_test_opt();
+        let cas = {
+            let dm = DataManager::new(opt.clone())?;
+            // uniques handed out without a write of their own still count
+            for _ in 0..10 {
+                dm.next_cas();
+            }
+            let cas = dm.next_cas();
+            dm.insert(make_key(b"a".to_vec()), make_payload(vec![1]))?;
+            dm.flush_memtables()?;
+            cas
+        };
+
+        let dm = DataManager::new(opt)?;
+        assert!(dm.next_cas() > cas);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_wal() -> MyResult<()> {
+        let opt = get_test_opt();
+        let names = vec!["app".to_owned()];
+        let mut ns_opt = opt.clone();
+        let ns_dir = Path::new(&opt.work_dir).join("app");
+        opt.env.create_dir_all(&ns_dir)?;
+        ns_opt.work_dir = ns_dir.to_string_lossy().into_owned();
+        let a = make_key(b"a".to_vec());
+        {
+            let dm = DataManager::with_namespaces(opt.clone(), &names)?;
+            let app = DataManager::open_namespace(ns_opt.clone(), "app", &dm)?;
+            dm.insert(a.clone(), make_payload(vec![1]))?;
+            app.insert(a.clone(), make_payload(vec![2]))?;
+            assert!(seg_paths(opt.env.as_ref(), &ns_dir)?.is_empty());
+
+            // the namespace hasn't flushed its write in the first segment
+            dm.flush_memtables()?;
+            dm.insert(a.clone(), make_payload(vec![3]))?;
+            dm.flush_memtables()?;
+            assert_eq!(3, read_lock(&dm.wal_).seg_count());
+        }
+
+        // flushed writes aren't replayed over newer ones
+        let dm = DataManager::with_namespaces(opt, &names)?;
+        assert_eq!(Some(make_payload(vec![3])), dm.get(&a)?);
+        assert_eq!(3, read_lock(&dm.wal_).seg_count());
+        let app = DataManager::open_namespace(ns_opt, "app", &dm)?;
+        assert_eq!(Some(make_payload(vec![2])), app.get(&a)?);
+        assert_eq!(0, read_lock(&dm.wal_).seg_count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_table_changes() -> MyResult<()> {
+        let opt = get_test_opt();
+        let dm = DataManager::new(opt.clone())?;
+        let (a, b) = (make_key(b"a".to_vec()), make_key(b"b".to_vec()));
+        dm.insert(a.clone(), make_payload(vec![1]))?;
+        dm.insert(b.clone(), make_payload(vec![2]))?;
+        dm.minor_compaction()?;
+        let old = read_lock(&dm.readers_).get_file_metas(0)[0].clone();
+
+        // a rewrite of the oldest file, into level 1
+        let work_dir = Path::new(&opt.work_dir);
+        let path = work_dir.join(make_file_name(dm.new_file_number(), "sst"));
+        copy(work_dir.join(&old.file_name), &path)?;
+        let mut changes = TableChanges::new();
+        let mut removed = HashSet::new();
+        removed.insert(old.file_name.clone());
+        changes.remove(0, removed);
+        changes.add(1, open_sstable(&path, &opt)?);
+        write_lock(&dm.readers_).install(changes)?;
+        assert!(!work_dir.join(&old.file_name).exists());
+
+        let check = |dm: &DataManager| -> MyResult<()> {
+            let readers = read_lock(&dm.readers_);
+            assert!(readers
+                .get_file_metas(0)
+                .iter()
+                .all(|fm| fm.file_name != old.file_name));
+            let l1 = readers.get_file_metas(1);
+            assert_eq!(1, l1.len());
+            assert_eq!(path.file_name().unwrap().to_str().unwrap(), l1[0].file_name);
+            assert_eq!(
+                (old.smallest_seq, old.largest_seq),
+                (l1[0].smallest_seq, l1[0].largest_seq)
+            );
+            drop(readers);
+            assert_eq!(Some(make_payload(vec![1])), dm.get(&a)?);
+            assert_eq!(Some(make_payload(vec![2])), dm.get(&b)?);
+            Ok(())
+        };
+        check(&dm)?;
+        drop(dm);
+        check(&DataManager::new(opt)?)
+    }
+
+    #[test]
+    fn test_compact_level() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.sst_max_size = 60;
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt.clone())?;
+        let data = get_data();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        dm.minor_compaction()?;
+        dm.major_compaction()?;
+        let level_files = |dm: &DataManager, level| {
+            read_lock(&dm.readers_)
+                .get_readers(level)
+                .iter()
+                .map(|r| r.file_name().clone())
+                .collect::<Vec<_>>()
+        };
+        let l1 = level_files(&dm, 1);
+        assert!(l1.len() > 2);
+        assert!(level_files(&dm, 0).is_empty());
+        let second_max_key = read_lock(&dm.readers_).get_readers(1)[1].max_key().clone();
+
+        // one file at a time, round robin
+        let token = CancellationToken::new();
+        dm.compact_level(1, &token)?;
+        assert_eq!(l1[1..].to_vec(), level_files(&dm, 1));
+        dm.compact_level(1, &token)?;
+        assert_eq!(l1[2..].to_vec(), level_files(&dm, 1));
+        // level 2 was empty, so the files moved down without a rewrite
+        assert_eq!(l1[..2].to_vec(), level_files(&dm, 2));
+
+        let stats = dm.compaction_stats();
+        assert!(stats.flush.runs > 0);
+        assert_eq!(1, stats.levels[1].runs);
+        assert_eq!(0, stats.levels[2].runs);
+        assert_eq!(2, stats.levels[2].moves);
+        assert!(stats.write_amp() > 1.);
+
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        let dm = DataManager::new(opt)?;
+        assert_eq!(l1[..2].to_vec(), level_files(&dm, 2));
+        // the next compaction goes on from where the last one ended
+        assert_eq!(
+            Some(&second_max_key),
+            read_lock(&dm.readers_)
+                .manifest_builder()
+                .manifest()
+                .compact_pointer(1)
+        );
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_key_range() {
+        let keys = |x: &[u8]| x.iter().map(|k| vec![*k]).collect::<Vec<_>>();
+        assert_eq!(vec![(None, None)], split_key_range(keys(b"a"), 4));
+        assert_eq!(vec![(None, None)], split_key_range(keys(b"abc"), 1));
+        assert_eq!(
+            vec![(None, Some(vec![b'b'])), (Some(vec![b'b']), None)],
+            split_key_range(keys(b"baa"), 4)
+        );
+        assert_eq!(
+            vec![
+                (None, Some(vec![b'd'])),
+                (Some(vec![b'd']), Some(vec![b'f'])),
+                (Some(vec![b'f']), None)
+            ],
+            split_key_range(keys(b"abcdefg"), 3)
+        );
+    }
+
+    #[test]
+    fn test_subcompactions() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.sst_max_size = 60;
+        opt.l0_compaction_trigger = 1;
+        opt.max_subcompactions = 3;
+        let dm = DataManager::new(opt.clone())?;
+        let data = (0..50u32)
+            .map(|i| {
+                (
+                    make_key(i.to_be_bytes().to_vec()),
+                    make_payload(vec![1; 20]),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        dm.remove(&data[7].0)?;
+        dm.minor_compaction()?;
+        dm.major_compaction()?;
+        dm.compact_level(1, &CancellationToken::new())?;
+
+        let readers = read_lock(&dm.readers_);
+        assert!(readers.get_readers(0).is_empty());
+        for level in 1..3 {
+            let files = readers.get_readers(level);
+            for pair in files.windows(2) {
+                assert!(pair[0].max_key() < pair[1].min_key());
+            }
+        }
+        drop(readers);
+        for (i, (k, v)) in data.iter().enumerate() {
+            let expected = if i == 7 { None } else { Some(v.clone()) };
+            assert_eq!(expected, dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt)?;
+        let mut expected = get_data().into_iter().collect::<BTreeMap<_, _>>();
+        for (k, v) in &expected {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        dm.minor_compaction()?;
+        dm.major_compaction()?;
+        for (k, v) in &expected {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        dm.minor_compaction()?;
+
+        let a = make_key(b"a".to_vec());
+        let b = make_key(b"b".to_vec());
+        let c = make_key(b"c".to_vec());
+        dm.insert(a.clone(), make_payload(vec![1]))?;
+        dm.remove(&b)?;
+        dm.merge(
+            c.clone(),
+            MergeOperand::new(MergeKind::Append, make_payload(vec![2])),
+        )?;
+        dm.insert(
+            make_key(b"x".to_vec()),
+            StorePayload::new(Slice::from("x"), 0, 1, 1, 1),
+        )?;
+        expected.insert(a, make_payload(vec![1]));
+        expected.remove(&b);
+        let mut appended = vec![b'c'; 20];
+        appended.push(2);
+        expected.insert(c.clone(), dm.get(&c)?.unwrap());
+        assert_eq!(Slice::from(appended), expected[&c].data);
+
+        let scanned = dm
+            .scan()?
+            .map(|r| r.and_then(|(k, v)| Ok((k, deserialize(&v[..])?))))
+            .collect::<MyResult<Vec<(StoreKey, StorePayload)>>>()?;
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), scanned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_with_prefix() -> MyResult<()> {
+        let dm = DataManager::new(get_test_opt())?;
+        for k in &["a", "user:1", "user:2", "user:3", "users"] {
+            dm.insert(Slice::from(*k), make_payload(vec![1]))?;
+        }
+        dm.minor_compaction()?;
+        dm.insert(Slice::from("user:0"), make_payload(vec![1]))?;
+        dm.remove(&Slice::from("user:2"))?;
+
+        let keys = |prefix: &str, limit| -> MyResult<Vec<String>> {
+            Ok(dm
+                .keys_with_prefix(prefix.as_bytes(), limit)?
+                .into_iter()
+                .map(|k| String::from_utf8_lossy(&k[..]).into_owned())
+                .collect())
+        };
+        assert_eq!(vec!["user:0", "user:1", "user:3"], keys("user:", 0)?);
+        assert_eq!(vec!["user:0", "user:1"], keys("user:", 2)?);
+        assert_eq!(5, keys("", 0)?.len());
+        assert!(keys("z", 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_range() -> MyResult<()> {
+        let dm = DataManager::new(get_test_opt())?;
+        for k in &["a", "b", "c", "d", "e"] {
+            dm.insert(Slice::from(*k), make_payload(k.as_bytes().to_vec()))?;
+        }
+        dm.minor_compaction()?;
+        dm.insert(Slice::from("bb"), make_payload(vec![1]))?;
+        dm.remove(&Slice::from("c"))?;
+
+        let keys = |start: &[u8], end: Option<&[u8]>| -> MyResult<Vec<Slice>> {
+            dm.range(start, end)?.map(|r| r.map(|(k, _)| k)).collect()
+        };
+        let expected = vec![Slice::from("b"), Slice::from("bb"), Slice::from("d")];
+        assert_eq!(expected, keys(b"b", Some(&b"e"[..]))?);
+        assert_eq!(2, keys(b"d", None)?.len());
+        assert!(keys(b"x", None)?.is_empty());
+        assert!(keys(b"e", Some(&b"b"[..]))?.is_empty());
+
+        let mut seen = vec![];
+        dm.scan_range(b"bb", None, |k, _| {
+            seen.push(k);
+            Ok(seen.len() < 2)
+        })?;
+        assert_eq!(vec![Slice::from("bb"), Slice::from("d")], seen);
+
+        assert_eq!(Some(b"ab".to_vec()), prefix_end(b"aa\xff"));
+        assert_eq!(None, prefix_end(b"\xff\xff"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_bytes_for_levels() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.max_level = 4;
+        opt.max_bytes_for_level_base = 10;
+        opt.max_bytes_for_level_multiplier = 4.;
+        let dm = DataManager::new(opt.clone())?;
+        let key = |i: u32| make_key(i.to_be_bytes().to_vec());
+        dm.bulk_load((0..100).map(|i| (key(i), make_payload(vec![1; 20]))))?;
+        let token = CancellationToken::new();
+        dm.compact_level(0, &token)?;
+        dm.compact_level(1, &token)?;
+        let size = read_lock(&dm.readers_).get_readers(2)[0].size() as f64;
+        assert!(size > 160.);
+        assert_eq!(
+            vec![10., 10., 40., 160.],
+            read_lock(&dm.readers_).max_bytes_for_levels()
+        );
+        drop(dm);
+
+        // levels above the bottom one are sized from it
+        opt.dynamic_level_bytes = true;
+        let dm = DataManager::new(opt)?;
+        assert_eq!(
+            vec![10., size / 4., 40., 160.],
+            read_lock(&dm.readers_).max_bytes_for_levels()
+        );
+        assert_eq!(vec![2], read_lock(&dm.readers_).compute_compaction_levels());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pick_tiered_runs() {
+        assert_eq!(None, pick_tiered_runs(&[10], 1, 2));
+        assert_eq!(Some((0, 3)), pick_tiered_runs(&[10, 10, 10], 1, 2));
+        // the large old run stays out of the merge
+        assert_eq!(Some((1, 4)), pick_tiered_runs(&[100, 10, 10, 10], 1, 2));
+        assert_eq!(None, pick_tiered_runs(&[100, 10, 1], 1, 2));
+        assert_eq!(Some((0, 3)), pick_tiered_runs(&[100, 10, 1], 1000, 2));
+        // windows ending at older runs are tried too
+        assert_eq!(Some((0, 2)), pick_tiered_runs(&[10, 10, 1], 1, 2));
+        assert_eq!(None, pick_tiered_runs(&[10, 10, 1], 1, 3));
+    }
+
+    #[test]
+    fn test_approximate_sizes() -> MyResult<()> {
+        let dm = DataManager::new(get_test_opt())?;
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        dm.bulk_load((0..1000).map(|i| (make_key(key(i)), make_payload(vec![1; 100]))))?;
+
+        let sizes =
+            dm.approximate_sizes(&[key(0)..key(1000), key(0)..key(500), key(2000)..key(3000)])?;
+        assert_eq!(1000, sizes[0].keys);
+        assert!(sizes[0].bytes > 0);
+        // about half, give or take a block
+        assert!(sizes[1].keys > 400 && sizes[1].keys < 600);
+        assert!(sizes[1].bytes < sizes[0].bytes);
+        assert_eq!(ApproximateSize::default(), sizes[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tiered_compaction() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.compaction_style = CompactionStyle::Tiered;
+        opt.l0_compaction_trigger = 4;
+        opt.tiered_size_ratio = 100;
+        let dm = DataManager::new(opt)?;
+        let key = |i: u32| make_key(i.to_be_bytes().to_vec());
+        dm.bulk_load((0..200).map(|i| (key(i), make_payload(vec![1; 20]))))?;
+        dm.insert(key(1), make_payload(vec![2]))?;
+        dm.remove(&key(2))?;
+        dm.insert(key(3), make_payload(vec![3]))?;
+        dm.minor_compaction()?;
+        let old_run = read_lock(&dm.readers_).get_readers(0)[0]
+            .file_name()
+            .clone();
+        assert_eq!(4, read_lock(&dm.readers_).get_readers(0).len());
+
+        dm.major_compaction()?;
+        let readers = read_lock(&dm.readers_);
+        let runs = readers.get_readers(0);
+        // the large bulk loaded run is left alone and still sits below the
+        // merged small runs
+        assert_eq!(2, runs.len());
+        assert_eq!(&old_run, runs[0].file_name());
+        drop(readers);
+        assert_eq!(Some(make_payload(vec![2])), dm.get(&key(1))?);
+        assert_eq!(None, dm.get(&key(2))?);
+        assert_eq!(Some(make_payload(vec![3])), dm.get(&key(3))?);
+        assert_eq!(Some(make_payload(vec![1; 20])), dm.get(&key(4))?);
+
+        // merging nothing below the trigger
+        dm.major_compaction()?;
+        assert_eq!(2, read_lock(&dm.readers_).get_readers(0).len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_intra_l0_compaction() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.intra_l0_min_files = 3;
+        let dm = DataManager::new(opt.clone())?;
+        let (a, b) = (make_key(b"a".to_vec()), make_key(b"b".to_vec()));
+        dm.insert(a.clone(), make_payload(vec![1]))?;
+        dm.insert(b.clone(), make_payload(vec![1]))?;
+        dm.minor_compaction()?;
+        let l0_files = |dm: &DataManager| read_lock(&dm.readers_).get_readers(0).len();
+        assert_eq!(2, l0_files(&dm));
+
+        // level 1 goes first, too few files to merge
+        let token = CancellationToken::new();
+        dm.size_compaction(vec![1, 0], &token)?;
+        assert_eq!(2, l0_files(&dm));
+
+        dm.insert(a.clone(), make_payload(vec![2]))?;
+        dm.remove(&b)?;
+        dm.minor_compaction()?;
+        assert_eq!(4, l0_files(&dm));
+        dm.size_compaction(vec![1, 0], &token)?;
+        assert_eq!(1, l0_files(&dm));
+        assert!(read_lock(&dm.readers_).get_readers(1).is_empty());
+        assert_eq!(1, dm.compaction_stats().levels[0].runs);
+        // the delete is kept, as there may be older values below
+        assert!(read_lock(&dm.readers_).get(&b)?.is_some());
+        assert_eq!(Some(make_payload(vec![2])), dm.get(&a)?);
+        assert_eq!(None, dm.get(&b)?);
+
+        let dm = DataManager::new(opt)?;
+        assert_eq!(1, l0_files(&dm));
+        assert_eq!(Some(make_payload(vec![2])), dm.get(&a)?);
+        assert_eq!(None, dm.get(&b)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_compaction() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 100;
+        opt.seek_compaction_min_misses = 3;
+        let dm = DataManager::new(opt)?;
+        let (a, b, c) = (
+            make_key(b"a".to_vec()),
+            make_key(b"b".to_vec()),
+            make_key(b"c".to_vec()),
+        );
+        dm.bulk_load(vec![
+            (a.clone(), make_payload(vec![1])),
+            (c.clone(), make_payload(vec![3])),
+        ])?;
+        let level_files = |dm: &DataManager, level| {
+            read_lock(&dm.readers_)
+                .get_readers(level)
+                .iter()
+                .map(|r| (r.file_name().clone(), r.get_seek_miss_count()))
+                .collect::<Vec<_>>()
+        };
+        let miss = |n| -> MyResult<()> {
+            for _ in 0..n {
+                assert_eq!(None, dm.get(&b)?);
+            }
+            Ok(())
+        };
+
+        miss(2)?;
+        dm.major_compaction()?;
+        assert_eq!(1, level_files(&dm, 0).len());
+        miss(1)?;
+        dm.major_compaction()?;
+        assert!(level_files(&dm, 0).is_empty());
+        let l1 = level_files(&dm, 1);
+        assert_eq!(1, l1.len());
+        assert_eq!(0, l1[0].1);
+
+        // moved down as it is, and starts counting again
+        miss(3)?;
+        dm.major_compaction()?;
+        assert!(level_files(&dm, 1).is_empty());
+        assert_eq!(vec![(l1[0].0.clone(), 0)], level_files(&dm, 2));
+        assert_eq!(Some(make_payload(vec![1])), dm.get(&a)?);
+        assert_eq!(Some(make_payload(vec![3])), dm.get(&c)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_periodic_compaction() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.periodic_compaction_secs = 3600;
+        let dm = DataManager::new(opt.clone())?;
+        let old_file = make_file_name(dm.new_file_number(), "sst");
+        let path = Path::new(&opt.work_dir).join(&old_file);
+        let mut tb = create_table(opt.env.as_ref(), &path, opt.get_table_opt().clone())?;
+        let expired = StorePayload::new(Slice::from("x"), 0, 1, 1, 1);
+        tb.add(b"a", &serialize(&make_payload(vec![1]))?)?;
+        tb.add(b"b", TOMBSTONE)?;
+        tb.add(b"x", &serialize(&expired)?)?;
+        tb.flush()?;
+        write_lock(&dm.readers_).add(1, open_sstable(&path, &opt)?)?;
+
+        let token = CancellationToken::new();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(!dm.periodic_compaction(now, &token)?);
+
+        // rewritten in place, without what nothing below needs
+        assert!(dm.periodic_compaction(now + 4000, &token)?);
+        {
+            let readers = read_lock(&dm.readers_);
+            let l1 = readers.get_readers(1);
+            assert_eq!(1, l1.len());
+            assert_ne!(&old_file, l1[0].file_name());
+            assert_eq!(1, l1[0].properties().num_entries);
+        }
+
+        // level 0 goes down
+        let b = make_key(b"b".to_vec());
+        dm.insert(b.clone(), make_payload(vec![2]))?;
+        dm.minor_compaction()?;
+        assert!(dm.periodic_compaction(now + 4000, &token)?);
+        assert!(read_lock(&dm.readers_).get_readers(0).is_empty());
+        assert_eq!(2, read_lock(&dm.readers_).get_readers(1).len());
+        assert_eq!(
+            Some(make_payload(vec![1])),
+            dm.get(&make_key(b"a".to_vec()))?
+        );
+        assert_eq!(Some(make_payload(vec![2])), dm.get(&b)?);
+        Ok(())
+    }
+
+    struct TestFilter;
+
+    impl CompactionFilter for TestFilter {
+        fn filter(&self, key: &[u8], _value: &StorePayload) -> FilterDecision {
+            match key {
+                b"a" => FilterDecision::Remove,
+                b"b" => FilterDecision::ChangeValue(make_payload(vec![b'B'; 2])),
+                _ => FilterDecision::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        opt.compaction_filter = Some(Arc::new(TestFilter));
+        let dm = DataManager::new(opt)?;
+        let data = get_data();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        dm.minor_compaction()?;
+        let a = make_key(b"a".to_vec());
+        let b = make_key(b"b".to_vec());
+        assert_eq!(data.get(&a).cloned(), dm.get(&a)?);
+
+        dm.major_compaction()?;
+        assert_eq!(None, dm.get(&a)?);
+        assert_eq!(Some(make_payload(vec![b'B'; 2])), dm.get(&b)?);
+        for (k, v) in &data {
+            if k != &a && k != &b {
+                assert_eq!(Some(v.clone()), dm.get(k)?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_values() -> MyResult<()> {
+        let dm = DataManager::new(get_test_opt())?;
+        let a = make_key(b"a".to_vec());
+        dm.insert(a.clone(), make_payload(vec![1; 20]))?;
+        dm.minor_compaction()?;
+        dm.insert(a.clone(), StorePayload::new(Slice::from("a"), 0, 1, 1, 1))?;
+        assert_eq!(None, dm.get(&a)?);
+        assert!(!dm.merge(
+            a.clone(),
+            MergeOperand::new(MergeKind::Append, make_payload(vec![2]))
+        )?);
+
+        // flushed as a delete, which keeps hiding the older value
+        dm.minor_compaction()?;
+        assert_eq!(
+            Some(Slice::from(TOMBSTONE)),
+            read_lock(&dm.readers_).get(&a)?
+        );
+        assert_eq!(None, dm.get(&a)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_expired() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.expiry_scan_interval_ms = 1;
+        let dm = DataManager::new(opt)?;
+        let a = make_key(b"a".to_vec());
+        let b = make_key(b"b".to_vec());
+        dm.insert(a.clone(), make_payload(vec![1; 20]))?;
+        dm.minor_compaction()?;
+        dm.insert(a.clone(), StorePayload::new(Slice::from("a"), 0, 1, 1, 1))?;
+        dm.insert(b.clone(), StorePayload::new(Slice::from("b"), 0, 1, 1, 1))?;
+        // written again since, so it stays
+        dm.insert(b.clone(), make_payload(vec![2]))?;
+        assert_eq!(2, dm.expiry_.len());
+
+        assert_eq!(1, dm.purge_expired()?);
+        assert_eq!(0, dm.expiry_.len());
+        assert_eq!(
+            Some(Slice::from(TOMBSTONE)),
+            read_lock(&dm.mut_).get(&a).cloned()
+        );
+        assert_eq!(Some(make_payload(vec![2])), dm.get(&b)?);
+        assert_eq!(0, dm.purge_expired()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_at_bottom_level() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt)?;
+        let data = get_data();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+        }
+        let deleted = make_key(b"a".to_vec());
+        dm.remove(&deleted)?;
+        let expired = make_key(b"x".to_vec());
+        dm.insert(
+            expired.clone(),
+            StorePayload::new(Slice::from("x"), 0, 1, 1, 1),
+        )?;
+        dm.minor_compaction()?;
+        assert!(read_lock(&dm.readers_).get(&deleted)?.is_some());
+
+        dm.major_compaction()?;
+        assert!(read_lock(&dm.readers_).get_readers(0).is_empty());
+        assert_eq!(None, read_lock(&dm.readers_).get(&deleted)?);
+        assert_eq!(None, read_lock(&dm.readers_).get(&expired)?);
+        for (k, v) in &data {
+            if k != &deleted {
+                assert_eq!(Some(v.clone()), dm.get(k)?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_stall() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        opt.l0_slowdown_trigger = 1;
+        opt.l0_stop_trigger = 3;
+        opt.imm_mem_table_max_count = 64;
+        opt.thread_sleep_ms = 10;
+        let dm = DataManager::new(opt)?;
+        dm.pause_compaction();
+        DataManager::background_thread(dm.clone());
+
+        let insert = |i: u32| dm.insert(make_key(i.to_be_bytes().to_vec()), make_payload(vec![1]));
+        let mut stall = None;
+        for i in 0..1000 {
+            if let Err(e) = insert(i) {
+                stall = Some(e);
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(StatusCode::WriteStall, stall.unwrap().code);
+
+        // writes are taken again once compaction catches up
+        dm.resume_compaction();
+        let mut accepted = false;
+        for _ in 0..500 {
+            if insert(1000).is_ok() {
+                accepted = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(accepted);
+        dm.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn test_pause_compaction() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt)?;
+        for (k, v) in get_data() {
+            dm.insert(k, v)?;
+        }
+        dm.minor_compaction()?;
+        let info = dm.info();
+
+        dm.pause_compaction();
+        assert!(dm.is_compaction_paused());
+        dm.major_compaction()?;
+        assert_eq!(info, dm.info());
+
+        dm.resume_compaction();
+        assert!(!dm.is_compaction_paused());
+        dm.major_compaction()?;
+        assert_ne!(info, dm.info());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_values() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.blob_min_size = 1;
+        opt.l0_compaction_trigger = 1;
+        let dm = DataManager::new(opt)?;
+        let data = get_data();
+        for _ in 0..2 {
+            for (k, v) in &data {
+                dm.insert(k.clone(), v.clone())?;
+            }
+            dm.minor_compaction()?;
+        }
+        assert_eq!(
+            data.len() * 2,
+            read_lock(&dm.readers_)
+                .manifest_builder()
+                .manifest()
+                .blob_files
+                .len()
+        );
+
+        dm.major_compaction()?;
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        // the overwritten values are garbage and their blob files are gone
+        assert_eq!(
+            data.len(),
+            read_lock(&dm.readers_)
+                .manifest_builder()
+                .manifest()
+                .blob_files
+                .len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_flush() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.imm_mem_table_max_count = 2;
+        opt.thread_sleep_ms = 10;
+        let dm = DataManager::new(opt)?;
+        DataManager::background_thread(dm.clone());
+
+        let data = get_data();
+        for (k, v) in &data {
+            dm.insert(k.clone(), v.clone())?;
+            assert!(read_lock(&dm.imm_).table_count() <= 2);
+        }
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+
+        dm.shutdown();
+        dm.flush_memtables()?;
+        assert_eq!(0, read_lock(&dm.imm_).table_count());
+        let stats = dm.memtable_stats();
+        assert_eq!(0, stats.mutable.entries);
+        assert!(stats.immutables.is_empty());
+        assert!(stats.flushes > 0);
+        for (k, v) in &data {
+            assert_eq!(Some(v.clone()), dm.get(k)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_row_cache() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.row_cache_capacity = 16;
+        let dm = DataManager::new(opt)?;
+        let k = make_key(b"hot".to_vec());
+        let (v1, v2) = (make_payload(b"1".to_vec()), make_payload(b"2".to_vec()));
+        dm.insert(k.clone(), v1.clone())?;
+        dm.flush_memtables()?;
+
+        let cache = dm.row_cache_.as_ref().unwrap();
+        assert_eq!(Some(v1.clone()), dm.get(&k)?);
+        assert_eq!(Some(v1), dm.get(&k)?);
+        assert_eq!(1, cache.hits());
+
+        dm.insert(k.clone(), v2.clone())?;
+        assert_eq!(Some(v2), dm.get(&k)?);
+        dm.remove(&k)?;
+        assert_eq!(None, dm.get(&k)?);
+        assert_eq!(None, dm.get(&k)?);
+        // the get in remove hit too
+        assert_eq!(3, cache.hits());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_options() -> MyResult<()> {
+        let opt = get_test_opt();
+        let (a, b) = (make_key(b"a".to_vec()), make_key(b"b".to_vec()));
+        let v = make_payload(b"1".to_vec());
+        {
+            let dm = DataManager::new(opt.clone())?;
+            let nowal = WriteOptions {
+                disable_wal: true,
+                ..WriteOptions::default()
+            };
+            dm.insert_opt(a.clone(), v.clone(), nowal)?;
+            assert_eq!(0, dm.wal_stats().syncs);
+            let sync = WriteOptions {
+                sync: true,
+                ..WriteOptions::default()
+            };
+            dm.insert_opt(b.clone(), v.clone(), sync)?;
+            assert_eq!(1, dm.wal_stats().syncs);
+            assert_eq!(Some(v.clone()), dm.get(&a)?);
+        }
+        // reopened without a flush, only the logged write is left
+        let dm = DataManager::new(opt)?;
+        assert_eq!(None, dm.get(&a)?);
+        assert_eq!(Some(v), dm.get(&b)?);
+        Ok(())
+    }
 }
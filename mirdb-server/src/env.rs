@@ -0,0 +1,323 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::ops::Deref;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use memmap::Mmap;
+
+use sstable::Options as TableOptions;
+use sstable::RandomAccess;
+use sstable::TableBuilder;
+use sstable::TableReader;
+use sstable::WritableFile;
+
+use crate::error::MyResult;
+
+/// A file opened through an `Env`.
+pub trait EnvFile: Read + Write + Seek + Send + Sync {
+    fn size(&self) -> io::Result<u64>;
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> io::Result<usize>;
+    fn read_exact_at(&self, mut offset: u64, mut dst: &mut [u8]) -> io::Result<()> {
+        while !dst.is_empty() {
+            match self.read_at(offset, dst)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    offset += n as u64;
+                    dst = &mut dst[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+    fn set_len(&self, size: u64) -> io::Result<()>;
+    /// Waits until the data written so far, and the file size, are on disk.
+    fn sync(&self) -> io::Result<()>;
+    /// Reserves `size` bytes for the file, a hint an env may ignore.
+    fn allocate(&self, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The whole contents of a file, see `Env::read_all`.
+pub type FileData = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// Keeps a file locked until dropped, see `Env::lock_file`.
+pub type FileLock = Box<dyn Send + Sync>;
+
+/// The file system the WAL, the manifest, sstables and blob files live in.
+pub trait Env: Send + Sync {
+    /// Opens a file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+    /// Opens a file for reading and writing, creating it if it's missing.
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+    /// Creates an empty file, truncating one already at `path`.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>>;
+    fn read_all(&self, path: &Path) -> io::Result<FileData> {
+        let mut buf = vec![];
+        self.open(path)?.read_to_end(&mut buf)?;
+        Ok(Box::new(buf))
+    }
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Makes `to` another name for the file at `from`; `to` must not exist.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Copies the file at `from` to a new file at `to`, and syncs it.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut dst = self.create(to)?;
+        let n = io::copy(&mut self.open(from)?, &mut dst)?;
+        dst.sync()?;
+        Ok(n)
+    }
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn exists(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// The files directly in `dir`, sorted; none if `dir` doesn't exist.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    /// Makes the files created in or renamed into `dir` survive a power
+    /// loss, not just their contents.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    /// Locks `path`, creating it if it's missing, against other users of the
+    /// same files. Fails with `WouldBlock` if it's already locked.
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock>;
+}
+
+pub type SharedEnv = Arc<dyn Env>;
+
+/// The files of `env` in `dir` with the extension `ext`, sorted. Their names
+/// are zero padded numbers, so this is also creation order.
+pub fn list_ext(env: &dyn Env, dir: &Path, ext: &str) -> MyResult<Vec<PathBuf>> {
+    Ok(env
+        .list(dir)?
+        .into_iter()
+        .filter(|p| p.extension().map_or(false, |e| e == ext))
+        .collect())
+}
+
+/// Syncs the directory holding `path`. See `Env::sync_dir`.
+pub fn sync_parent_dir(env: &dyn Env, path: &Path) -> MyResult<()> {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => env.sync_dir(dir)?,
+        _ => env.sync_dir(Path::new("."))?,
+    }
+    Ok(())
+}
+
+/// Lets sstables read through an `Env`.
+struct TableFile(Box<dyn EnvFile>);
+
+impl RandomAccess for TableFile {
+    fn read_at(&self, offset: usize, dst: &mut [u8]) -> sstable::MyResult<usize> {
+        Ok(self.0.read_at(offset as u64, dst)?)
+    }
+}
+
+impl WritableFile for Box<dyn EnvFile> {
+    fn sync(&mut self) -> io::Result<()> {
+        EnvFile::sync(&**self)
+    }
+}
+
+pub fn open_table(env: &dyn Env, path: &Path, opt: TableOptions) -> MyResult<TableReader> {
+    let file = env.open(path)?;
+    let size = file.size()? as usize;
+    Ok(TableReader::from_file(
+        Box::new(TableFile(file)),
+        size,
+        path,
+        opt,
+    )?)
+}
+
+pub fn create_table(env: &dyn Env, path: &Path, opt: TableOptions) -> MyResult<TableBuilder> {
+    let file = env.create(path)?;
+    Ok(TableBuilder::from_file(Box::new(file), path, opt))
+}
+
+/// The local file system.
+pub struct PosixEnv;
+
+impl EnvFile for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, dst, offset)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        fs2::FileExt::allocate(self, size)
+    }
+}
+
+impl Env for PosixEnv {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        Ok(Box::new(f))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(f))
+    }
+
+    fn read_all(&self, path: &Path) -> io::Result<FileData> {
+        let f = File::open(path)?;
+        // mapping an empty file fails
+        if f.metadata()?.len() == 0 {
+            return Ok(Box::new(vec![]));
+        }
+        Ok(Box::new(unsafe { Mmap::map(&f)? }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut paths = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        if cfg!(unix) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock> {
+        let f = OpenOptions::new().create(true).write(true).open(path)?;
+        fs2::FileExt::try_lock_exclusive(&f)?;
+        // closing the file releases the lock
+        Ok(Box::new(f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    #[test]
+    fn test_posix_env() -> MyResult<()> {
+        let opt = get_test_opt();
+        let env = PosixEnv;
+        let dir = Path::new(&opt.work_dir).join("env");
+        env.create_dir_all(&dir)?;
+        assert!(env.list(&dir.join("missing"))?.is_empty());
+
+        let a = dir.join("00000002.log");
+        let mut f = env.create(&a)?;
+        f.write_all(b"hello world")?;
+        f.sync()?;
+        assert_eq!(11, f.size()?);
+        let mut buf = [0; 5];
+        assert_eq!(5, f.read_at(6, &mut buf)?);
+        assert_eq!(b"world", &buf);
+        f.set_len(5)?;
+        drop(f);
+        assert_eq!(b"hello", &env.read_all(&a)?[..]);
+
+        let b = dir.join("00000001.log");
+        env.rename(&a, &b)?;
+        env.open_writable(&a)?;
+        env.sync_dir(&dir)?;
+        assert_eq!(vec![b.clone(), a.clone()], list_ext(&env, &dir, "log")?);
+        assert!(env.read_all(&a)?.is_empty());
+
+        let c = dir.join("00000003.log");
+        env.hard_link(&b, &c)?;
+        assert!(env.hard_link(&b, &c).is_err());
+        let d = dir.join("00000004.log");
+        assert_eq!(5, env.copy(&b, &d)?);
+        env.open_writable(&b)?.write_all(b"H")?;
+        assert_eq!(b"Hello", &env.read_all(&c)?[..]);
+        assert_eq!(b"hello", &env.read_all(&d)?[..]);
+        env.remove_file(&c)?;
+        env.remove_file(&d)?;
+
+        env.remove_file(&b)?;
+        assert!(!env.exists(&b));
+        assert_eq!(vec![a], env.list(&dir)?);
+        assert!(env.is_dir(&dir));
+
+        let lock = env.lock_file(&dir.join("LOCK"))?;
+        drop(lock);
+        env.lock_file(&dir.join("LOCK"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_through_env() -> MyResult<()> {
+        let opt = get_test_opt();
+        let env = PosixEnv;
+        let path = Path::new(&opt.work_dir).join("00000001.sst");
+        let mut tb = create_table(&env, &path, opt.get_table_opt().clone())?;
+        tb.add(b"a", b"1")?;
+        tb.add(b"b", b"2")?;
+        tb.flush()?;
+        let reader = open_table(&env, &path, opt.get_table_opt().clone())?;
+        assert_eq!(2, reader.verify()?);
+        Ok(())
+    }
+}
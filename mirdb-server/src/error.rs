@@ -16,6 +16,10 @@ pub enum StatusCode {
     ChecksumError,
     SnapError,
     ConfigError,
+    Cancelled,
+    Closed,
+    InvalidArgument,
+    WriteStall,
 }
 
 #[derive(Debug, PartialEq)]
@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::slice::Slice;
+
+/// Keys written with a ttl, ordered by when they expire, for the background
+/// expiry scan. Only kept in memory: keys written before the last restart
+/// are left to compaction. Entries may be stale, as keys are overwritten or
+/// deleted without being taken out.
+pub struct ExpiryIndex {
+    entries: Mutex<BTreeSet<(u64, Slice)>>,
+}
+
+impl ExpiryIndex {
+    pub fn new() -> Self {
+        ExpiryIndex {
+            entries: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn add(&self, expires_at: u64, key: Slice) {
+        self.entries.lock().unwrap().insert((expires_at, key));
+    }
+
+    /// Removes and returns up to `limit` keys expiring at or before `now`,
+    /// soonest first.
+    pub fn take_expired(&self, now: u64, limit: usize) -> Vec<Slice> {
+        let mut entries = self.entries.lock().unwrap();
+        let expired = entries
+            .iter()
+            .take_while(|(t, _)| *t <= now)
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in &expired {
+            entries.remove(entry);
+        }
+        expired.into_iter().map(|(_, k)| k).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_take_expired() {
+        let index = ExpiryIndex::new();
+        index.add(30, Slice::from("c"));
+        index.add(10, Slice::from("a"));
+        index.add(20, Slice::from("b"));
+        index.add(20, Slice::from("b"));
+        assert_eq!(3, index.len());
+
+        assert!(index.take_expired(5, 10).is_empty());
+        assert_eq!(vec![Slice::from("a")], index.take_expired(25, 1));
+        assert_eq!(vec![Slice::from("b")], index.take_expired(25, 10));
+        assert_eq!(1, index.len());
+    }
+}
@@ -0,0 +1,448 @@
+#![cfg(test)]
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+use crate::env::Env;
+use crate::env::EnvFile;
+use crate::env::FileData;
+use crate::env::FileLock;
+use crate::env::SharedEnv;
+
+/// What a file held when it was last synced.
+type Synced = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Default)]
+struct State {
+    /// Files written through the env, with their synced contents.
+    files: HashMap<PathBuf, Synced>,
+    /// Files created since their dir was last synced.
+    unsynced_dir_entries: HashSet<PathBuf>,
+    written: u64,
+    /// Where the writes to each file began, whenever writing moved on to
+    /// another file.
+    file_starts: Vec<u64>,
+    last_written: Option<PathBuf>,
+    kill_at: Option<u64>,
+    killed: bool,
+    fail_sync: bool,
+}
+
+fn injected(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("injected fault: {}", msg))
+}
+
+/// Wraps another env to simulate crashes: `crash` drops everything that
+/// wasn't synced, `kill_at` fails every write from the given byte on, as if
+/// the process died there, and `set_fail_sync` makes fsync fail. Renames,
+/// links and deletes are taken as durable right away.
+#[derive(Clone)]
+pub struct FaultInjectionEnv {
+    inner: SharedEnv,
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultInjectionEnv {
+    pub fn new(inner: SharedEnv) -> Self {
+        FaultInjectionEnv {
+            inner,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Bytes written through the env so far.
+    pub fn written(&self) -> u64 {
+        self.state().written
+    }
+
+    /// The byte counts at which writing moved on to another file.
+    pub fn file_starts(&self) -> Vec<u64> {
+        self.state().file_starts.clone()
+    }
+
+    /// Kills the env once `n` bytes in total have been written: the write
+    /// reaching it is cut short, and everything that changes a file fails
+    /// from then on.
+    pub fn kill_at(&self, n: u64) {
+        self.state().kill_at = Some(n);
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.state().killed
+    }
+
+    pub fn set_fail_sync(&self, fail: bool) {
+        self.state().fail_sync = fail;
+    }
+
+    /// Puts every file back the way it was when last synced, removes files
+    /// whose dir wasn't synced after they were created, and kills the env.
+    pub fn crash(&self) -> io::Result<()> {
+        let mut state = self.state();
+        state.killed = true;
+        for (path, synced) in &state.files {
+            if !self.inner.exists(path) {
+                continue;
+            }
+            if state.unsynced_dir_entries.contains(path) {
+                self.inner.remove_file(path)?;
+                continue;
+            }
+            let mut f = self.inner.create(path)?;
+            f.write_all(&synced.lock().unwrap())?;
+            f.sync()?;
+        }
+        Ok(())
+    }
+
+    fn check_alive(&self) -> io::Result<()> {
+        if self.state().killed {
+            return Err(injected("killed"));
+        }
+        Ok(())
+    }
+
+    fn wrap(&self, path: &Path, file: Box<dyn EnvFile>, synced: Vec<u8>) -> Box<dyn EnvFile> {
+        let synced = Arc::new(Mutex::new(synced));
+        self.state()
+            .files
+            .insert(path.to_path_buf(), synced.clone());
+        Box::new(FaultFile {
+            env: self.clone(),
+            path: path.to_path_buf(),
+            file,
+            synced,
+        })
+    }
+}
+
+struct FaultFile {
+    env: FaultInjectionEnv,
+    path: PathBuf,
+    file: Box<dyn EnvFile>,
+    synced: Synced,
+}
+
+impl Read for FaultFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FaultFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = {
+            let mut state = self.env.state();
+            if state.killed {
+                return Err(injected("killed"));
+            }
+            if state.last_written.as_ref() != Some(&self.path) {
+                let written = state.written;
+                state.file_starts.push(written);
+                state.last_written = Some(self.path.clone());
+            }
+            let n = match state.kill_at {
+                Some(at) if state.written + buf.len() as u64 >= at => {
+                    state.killed = true;
+                    at.saturating_sub(state.written) as usize
+                }
+                _ => buf.len(),
+            };
+            if n == 0 {
+                return Err(injected("killed"));
+            }
+            state.written += n as u64;
+            n
+        };
+        self.file.write(&buf[..n])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FaultFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl EnvFile for FaultFile {
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> io::Result<usize> {
+        self.file.read_at(offset, dst)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        self.env.check_alive()?;
+        self.file.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.env.check_alive()?;
+        if self.env.state().fail_sync {
+            return Err(injected("fsync failed"));
+        }
+        self.file.sync()?;
+        let mut contents = vec![0; self.file.size()? as usize];
+        self.file.read_exact_at(0, &mut contents)?;
+        *self.synced.lock().unwrap() = contents;
+        Ok(())
+    }
+
+    fn allocate(&self, size: u64) -> io::Result<()> {
+        self.file.allocate(size)
+    }
+}
+
+impl Env for FaultInjectionEnv {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.inner.open(path)
+    }
+
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check_alive()?;
+        let existed = self.inner.exists(path);
+        let file = self.inner.open_writable(path)?;
+        let synced = match self.state().files.get(path) {
+            Some(synced) => synced.lock().unwrap().clone(),
+            // there from before the env was wrapped, so durable
+            None if existed => self.inner.read_all(path)?.to_vec(),
+            None => vec![],
+        };
+        if !existed {
+            self.state().unsynced_dir_entries.insert(path.to_path_buf());
+        }
+        Ok(self.wrap(path, file, synced))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        self.check_alive()?;
+        if !self.inner.exists(path) {
+            self.state().unsynced_dir_entries.insert(path.to_path_buf());
+        }
+        let file = self.inner.create(path)?;
+        Ok(self.wrap(path, file, vec![]))
+    }
+
+    fn read_all(&self, path: &Path) -> io::Result<FileData> {
+        self.inner.read_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_alive()?;
+        self.inner.rename(from, to)?;
+        let mut state = self.state();
+        state.unsynced_dir_entries.remove(from);
+        state.unsynced_dir_entries.remove(to);
+        if let Some(synced) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), synced);
+        }
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_alive()?;
+        self.inner.hard_link(from, to)?;
+        let mut state = self.state();
+        if let Some(synced) = state.files.get(from).cloned() {
+            state.files.insert(to.to_path_buf(), synced);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.check_alive()?;
+        self.inner.remove_file(path)?;
+        let mut state = self.state();
+        state.files.remove(path);
+        state.unsynced_dir_entries.remove(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.is_dir(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        self.check_alive()?;
+        self.inner.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        self.check_alive()?;
+        if self.state().fail_sync {
+            return Err(injected("fsync failed"));
+        }
+        self.inner.sync_dir(dir)?;
+        self.state()
+            .unsynced_dir_entries
+            .retain(|p| p.parent() != Some(dir));
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock> {
+        self.inner.lock_file(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::data_manager::DataManager;
+    use crate::error::MyResult;
+    use crate::mem_env::MemEnv;
+    use crate::options::Options;
+    use crate::slice::Slice;
+    use crate::store::StorePayload;
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    const KEYS: usize = 4;
+
+    fn key(i: usize) -> Slice {
+        Slice::from(format!("key{}", i))
+    }
+
+    fn value(i: usize) -> Slice {
+        Slice::from(format!("value{}", i).repeat(i + 1))
+    }
+
+    /// Writes `KEYS` keys in order and closes the db, stopping at the first
+    /// failure. Returns how many writes were acknowledged.
+    fn workload(opt: &Options) -> usize {
+        let dm = match DataManager::new(opt.clone()) {
+            Ok(dm) => dm,
+            Err(_) => return 0,
+        };
+        for i in 0..KEYS {
+            let p = StorePayload::new(value(i), 0, 0, value(i).len(), 0);
+            if dm.insert(key(i), p).is_err() {
+                return i;
+            }
+        }
+        let _ = dm.close();
+        KEYS
+    }
+
+    /// Opens the db as left by the crash: it must open, keep every
+    /// acknowledged write, and hold a prefix of the writes made.
+    fn check_recovery(opt: &Options, acked: usize) -> MyResult<usize> {
+        let dm = DataManager::new(opt.clone())?;
+        let present = (0..KEYS)
+            .map(|i| dm.get(&key(i)))
+            .collect::<MyResult<Vec<_>>>()?;
+        let recovered = present.iter().take_while(|p| p.is_some()).count();
+        assert!(present[recovered..].iter().all(Option::is_none));
+        assert!(
+            recovered >= acked,
+            "{} acked, {} recovered",
+            acked,
+            recovered
+        );
+        for (i, p) in present.iter().take(recovered).enumerate() {
+            assert_eq!(value(i), p.as_ref().unwrap().data);
+        }
+        dm.close()?;
+        Ok(recovered)
+    }
+
+    fn crash_opt(wal_sync: bool) -> (Options, MemEnv, FaultInjectionEnv) {
+        let mut opt = get_test_opt();
+        opt.work_dir = format!("{}/crash", opt.work_dir);
+        opt.wal_sync = wal_sync;
+        let base = MemEnv::new();
+        let fault = FaultInjectionEnv::new(Arc::new(base.clone()));
+        opt.env = Arc::new(fault.clone());
+        (opt, base, fault)
+    }
+
+    /// Kills the process right where writing moves on to another file in a
+    /// clean run, a byte after that, and halfway through the file, then
+    /// checks what survives. This covers every WAL segment, manifest, table
+    /// and blob file written.
+    #[test]
+    fn test_crash_recovery() -> MyResult<()> {
+        let (opt, _, fault) = crash_opt(true);
+        assert_eq!(KEYS, workload(&opt));
+        let mut starts = fault.file_starts();
+        starts.push(fault.written());
+
+        for w in starts.windows(2) {
+            for kill_at in &[w[0], w[0] + 1, (w[0] + w[1]) / 2] {
+                let (mut opt, base, fault) = crash_opt(true);
+                fault.kill_at(*kill_at);
+                let acked = workload(&opt);
+                assert!(fault.is_killed());
+                fault.crash()?;
+                opt.env = Arc::new(base);
+                check_recovery(&opt, acked)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsynced_writes_dropped() -> MyResult<()> {
+        let (mut opt, base, fault) = crash_opt(false);
+        let dm = DataManager::new(opt.clone())?;
+        for i in 0..KEYS {
+            let p = StorePayload::new(value(i), 0, 0, value(i).len(), 0);
+            dm.insert(key(i), p)?;
+        }
+        fault.crash()?;
+        drop(dm);
+        opt.env = Arc::new(base);
+        assert!(check_recovery(&opt, 0)? < KEYS);
+
+        // closing syncs the WAL and flushes the memtables
+        let (mut opt, base, fault) = crash_opt(false);
+        assert_eq!(KEYS, workload(&opt));
+        fault.crash()?;
+        opt.env = Arc::new(base);
+        assert_eq!(KEYS, check_recovery(&opt, KEYS)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_fsync() -> MyResult<()> {
+        let (mut opt, base, fault) = crash_opt(true);
+        let dm = DataManager::new(opt.clone())?;
+        let p = StorePayload::new(value(0), 0, 0, value(0).len(), 0);
+        dm.insert(key(0), p)?;
+        fault.set_fail_sync(true);
+        let p = StorePayload::new(value(1), 0, 0, value(1).len(), 0);
+        assert!(dm.insert(key(1), p).is_err());
+        fault.crash()?;
+        drop(dm);
+        opt.env = Arc::new(base);
+        assert_eq!(1, check_recovery(&opt, 1)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Arc;
+
+/// Hands out the numbers of wal segments, tables and blob files. Shared by
+/// everything that creates files in the work dir so names never collide.
+#[derive(Clone, Default)]
+pub struct FileNumbers {
+    next: Arc<AtomicUsize>,
+}
+
+impl FileNumbers {
+    pub fn new(next: usize) -> Self {
+        FileNumbers {
+            next: Arc::new(AtomicUsize::new(next)),
+        }
+    }
+
+    pub fn next(&self) -> usize {
+        self.next.fetch_add(1, SeqCst)
+    }
+
+    /// The number the next call to `next` returns.
+    pub fn peek(&self) -> usize {
+        self.next.load(SeqCst)
+    }
+
+    /// Makes sure numbers below `n` are never handed out.
+    pub fn advance_to(&self, n: usize) {
+        let mut cur = self.next.load(SeqCst);
+        while cur < n {
+            match self.next.compare_exchange(cur, n, SeqCst, SeqCst) {
+                Ok(_) => return,
+                Err(x) => cur = x,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_numbers() {
+        let numbers = FileNumbers::new(3);
+        let shared = numbers.clone();
+        assert_eq!(3, numbers.next());
+        assert_eq!(4, shared.next());
+        numbers.advance_to(10);
+        numbers.advance_to(7);
+        assert_eq!(10, shared.peek());
+        assert_eq!(10, numbers.next());
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+/// The smallest size class holds items of up to this many bytes, each next
+/// one twice as many.
+const MIN_CLASS_SIZE: usize = 64;
+/// `stats sizes` rounds item sizes up to a multiple of this, as memcached.
+const SIZE_GRANULARITY: usize = 32;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ClassStats {
+    number: u64,
+    /// When the oldest item was written.
+    oldest: u64,
+}
+
+/// Live items by size, for `stats items` and `stats sizes`. There are no
+/// slabs, so the classes of `stats items` are powers of two of item sizes,
+/// an item's size being its key's length plus its value's.
+#[derive(Debug, Default)]
+pub struct ItemStats {
+    now: u64,
+    classes: BTreeMap<usize, ClassStats>,
+    sizes: BTreeMap<usize, u64>,
+}
+
+/// The class of items of `size` bytes, 1 for the smallest.
+fn size_class(size: usize) -> usize {
+    let mut class = 1;
+    while size > MIN_CLASS_SIZE << (class - 1) {
+        class += 1;
+    }
+    class
+}
+
+impl ItemStats {
+    /// Ages are counted up to `now`.
+    pub fn new(now: u64) -> Self {
+        ItemStats {
+            now,
+            ..ItemStats::default()
+        }
+    }
+
+    pub fn add(&mut self, size: usize, created_at: u64) {
+        let class = self
+            .classes
+            .entry(size_class(size))
+            .or_insert_with(|| ClassStats {
+                number: 0,
+                oldest: created_at,
+            });
+        class.number += 1;
+        class.oldest = class.oldest.min(created_at);
+        let rounded = (size + SIZE_GRANULARITY - 1) / SIZE_GRANULARITY * SIZE_GRANULARITY;
+        *self.sizes.entry(rounded).or_insert(0) += 1;
+    }
+
+    pub fn items_pairs(&self) -> Vec<(String, String)> {
+        let mut v = vec![];
+        for (class, stats) in &self.classes {
+            let age = self.now.saturating_sub(stats.oldest);
+            let pair =
+                |name: &str, value: u64| (format!("items:{}:{}", class, name), value.to_string());
+            v.push(pair("number", stats.number));
+            v.push(pair("age", age));
+            v.push(pair("evicted", 0));
+        }
+        v
+    }
+
+    pub fn sizes_pairs(&self) -> Vec<(String, String)> {
+        self.sizes
+            .iter()
+            .map(|(size, count)| (size.to_string(), count.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_item_stats() {
+        assert_eq!(1, size_class(0));
+        assert_eq!(1, size_class(64));
+        assert_eq!(2, size_class(65));
+        assert_eq!(3, size_class(256));
+
+        let mut stats = ItemStats::new(100);
+        stats.add(10, 90);
+        stats.add(40, 50);
+        stats.add(100, 99);
+        let pair = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+        assert_eq!(
+            vec![
+                pair("items:1:number", "2"),
+                pair("items:1:age", "50"),
+                pair("items:1:evicted", "0"),
+                pair("items:2:number", "1"),
+                pair("items:2:age", "1"),
+                pair("items:2:evicted", "0"),
+            ],
+            stats.items_pairs()
+        );
+        assert_eq!(
+            vec![pair("32", "1"), pair("64", "1"), pair("128", "1")],
+            stats.sizes_pairs()
+        );
+    }
+}
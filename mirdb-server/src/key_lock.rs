@@ -0,0 +1,63 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+/// Mutexes picked by key hash. Requests that read a key before writing it
+/// hold the key's stripe, so they don't interleave with other writes of the
+/// key, while writes of keys on other stripes go on in parallel.
+pub struct StripedLock {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl StripedLock {
+    pub fn new(stripes: usize) -> Self {
+        StripedLock {
+            stripes: (0..stripes.max(1)).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)].lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let lock = Arc::new(StripedLock::new(16));
+        let other = (0..100u8)
+            .map(|i| vec![i])
+            .find(|k| lock.stripe(k) != lock.stripe(b"a"))
+            .unwrap();
+
+        let guard = lock.lock(b"a");
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let st = Instant::now();
+                drop(lock.lock(b"a"));
+                st.elapsed()
+            })
+        };
+        // other stripes are not held up
+        drop(lock.lock(&other));
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        assert!(t.join().unwrap() >= Duration::from_millis(50));
+    }
+}
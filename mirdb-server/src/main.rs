@@ -8,21 +8,29 @@ use std::net::SocketAddr;
 use std::net::{TcpListener, TcpStream};
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
+use std::thread;
 
 use clap::App;
 use clap::Arg;
+use clap::SubCommand;
 use env_logger;
 use futures::{future, Future};
 use tokio::prelude::*;
 use tokio_proto::TcpServer;
 use tokio_service::{NewService, Service};
 
+use crate::auth::{Authenticator, Credentials};
+use crate::backup::restore_work_dir;
+use crate::backup::RestoreSource;
+use crate::error::err;
 use crate::error::MyResult;
+use crate::error::StatusCode;
 use crate::options::Options;
 use crate::parser::parse;
 use crate::proto::Proto;
 use crate::request::Request;
 use crate::response::Response;
+use crate::secondary::Secondary;
 use crate::store::Store;
 use crate::thread_pool::ThreadPool;
 use crate::utils::to_str;
@@ -36,16 +44,40 @@ mod request;
 mod response;
 #[macro_use]
 mod parser_util;
+mod auth;
+mod backup;
+mod binary;
+mod blob;
+mod cancel;
+mod command_stats;
+mod commit_queue;
+mod compaction_filter;
+mod compaction_stats;
 mod config;
 mod data_manager;
+mod env;
+mod expiry;
+mod fault_env;
+mod file_number;
+mod item_stats;
+mod key_lock;
 mod manifest;
+mod mem_env;
 mod memtable;
 mod memtable_list;
+mod memtable_rep;
+mod merge;
 mod merger;
 mod options;
 mod parser;
 mod proto;
+mod rate_limiter;
+mod repair;
+mod resp;
+mod row_cache;
+mod secondary;
 mod slice;
+mod spill;
 mod sstable_builder;
 mod sstable_reader;
 mod store;
@@ -54,13 +86,26 @@ mod thread_pool;
 mod types;
 mod wal;
 
+/// What requests are served from: the store itself, or a secondary
+/// following another process's store.
+#[derive(Clone)]
+enum Backend {
+    Primary(Arc<Store>),
+    Secondary(Arc<Secondary>),
+}
+
 pub struct Server {
-    store: Arc<Store>,
+    backend: Backend,
+    /// None when clients need not authenticate.
+    auth: Option<Authenticator>,
 }
 
 impl Server {
-    fn new(store: Arc<Store>) -> Self {
-        Server { store }
+    fn new(backend: Backend, credentials: Option<Arc<Credentials>>) -> Self {
+        Server {
+            backend,
+            auth: credentials.map(Authenticator::new),
+        }
     }
 }
 
@@ -71,25 +116,41 @@ impl Service for Server {
     type Future = Box<Future<Item = Response, Error = io::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        Box::new(future::done(match self.store.apply(req) {
+        if let Some(response) = self.auth.as_ref().and_then(|auth| auth.check(&req)) {
+            return Box::new(future::ok(response));
+        }
+        let r = match &self.backend {
+            Backend::Primary(store) => store.apply(req),
+            Backend::Secondary(secondary) => secondary.apply(req),
+        };
+        Box::new(future::done(match r {
             Ok(response) => Ok(response),
             Err(e) => Ok(Response::ServerError(e.msg)),
         }))
     }
 }
 
-pub fn serve<T>(addr: SocketAddr, new_service: T)
+pub fn serve<T>(addr: SocketAddr, max_item_size: usize, new_service: T)
 where
     T: NewService<Request = Request, Response = Response, Error = io::Error>
         + Send
         + Sync
         + 'static,
 {
-    TcpServer::new(Proto, addr).serve(new_service);
+    TcpServer::new(Proto { max_item_size }, addr).serve(new_service);
 }
 
 fn main() -> MyResult<()> {
-    env_logger::init();
+    // Without RUST_LOG the logger lets everything through and the max level
+    // alone filters, so `verbosity` can raise it at runtime.
+    let mut logger = env_logger::Builder::from_default_env();
+    if std::env::var_os("RUST_LOG").is_none() {
+        logger.filter_level(log::LevelFilter::Trace);
+        logger.init();
+        log::set_max_level(log::LevelFilter::Error);
+    } else {
+        logger.init();
+    }
 
     let matches = App::new("MirDB")
         .version("0.0.1")
@@ -103,16 +164,102 @@ fn main() -> MyResult<()> {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("restore-backup")
+                .long("restore-backup")
+                .value_name("DIR")
+                .help("Restores the work dir from a backup in DIR before serving")
+                .takes_value(true)
+                .conflicts_with("restore-checkpoint"),
+        )
+        .arg(
+            Arg::with_name("backup-id")
+                .long("backup-id")
+                .value_name("ID")
+                .help("The backup to restore, the newest by default")
+                .takes_value(true)
+                .requires("restore-backup"),
+        )
+        .arg(
+            Arg::with_name("restore-checkpoint")
+                .long("restore-checkpoint")
+                .value_name("DIR")
+                .help("Restores the work dir from the checkpoint in DIR before serving")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("secondary")
+                .long("secondary")
+                .help("Serves reads from the work dir of a primary running in another process")
+                .conflicts_with_all(&["restore-backup", "restore-checkpoint"]),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuilds the manifest from the sstables in the work dir and exits"),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Prints the manifests, checks the files they list and exits"),
+        )
         .get_matches();
 
     let conf_path = matches.value_of("config").unwrap_or("default.conf");
     let conf = config::from_path(conf_path)?;
 
-    let addr = conf.addr.parse().unwrap();
+    let addr = config::parse_addr(&conf.addr)?;
     let opt = conf.to_options()?;
 
-    let store = Store::new(opt.clone())?;
-    let store = Arc::new(store);
+    if matches.subcommand_matches("repair").is_some() {
+        for stats in Store::repair(&opt)? {
+            println!(
+                "recovered {} tables with {} entries and {} blob files, {} tables lost",
+                stats.tables, stats.entries, stats.blob_files, stats.lost
+            );
+        }
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("manifest").is_some() {
+        let mut problems = 0;
+        for (opt, manifest) in Store::manifests(&opt)? {
+            println!("{}:\n{}", opt.work_dir, manifest);
+            for problem in manifest.check_files(&opt)? {
+                println!("error: {}", problem);
+                problems += 1;
+            }
+        }
+        if problems > 0 {
+            return err(
+                StatusCode::NotFound,
+                format!("{} files missing or of the wrong size", problems),
+            );
+        }
+        return Ok(());
+    }
+
+    let restore = if let Some(dir) = matches.value_of("restore-backup") {
+        let id = match matches.value_of("backup-id") {
+            Some(id) => match id.parse() {
+                Ok(id) => Some(id),
+                Err(_) => return err(StatusCode::InvalidArgument, "bad backup id"),
+            },
+            None => None,
+        };
+        Some(RestoreSource::Backup(dir.to_owned(), id))
+    } else {
+        matches
+            .value_of("restore-checkpoint")
+            .map(|dir| RestoreSource::Checkpoint(dir.to_owned()))
+    };
+    if let Some(source) = restore {
+        restore_work_dir(&source, &opt)?;
+    }
+
+    let backend = if matches.is_present("secondary") {
+        Backend::Secondary(Secondary::new(opt.clone())?)
+    } else {
+        Backend::Primary(Arc::new(Store::new(opt.clone())?))
+    };
 
     println!(
         "{}",
@@ -127,7 +274,28 @@ Welcome to MirDB!
         .trim_matches('\n')
     );
 
-    serve(addr, move || Ok(Server::new(store.clone())));
+    let credentials = match &conf.auth_file {
+        Some(path) => Some(Arc::new(Credentials::load(path)?)),
+        None => None,
+    };
+    if let Some(resp_addr) = &conf.resp_addr {
+        let store = match &backend {
+            Backend::Primary(store) => store.clone(),
+            Backend::Secondary(_) => {
+                return err(
+                    StatusCode::InvalidArgument,
+                    "resp_addr is not supported by a secondary",
+                )
+            }
+        };
+        let resp_addr = config::parse_addr(resp_addr)?;
+        let credentials = credentials.clone();
+        thread::spawn(move || resp::serve(resp_addr, store, credentials));
+    }
+
+    serve(addr, opt.max_item_size, move || {
+        Ok(Server::new(backend.clone(), credentials.clone()))
+    });
 
     Ok(())
 }
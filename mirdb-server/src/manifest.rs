@@ -1,23 +1,74 @@
+use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::fmt;
-use std::fs::File;
-use std::io::Read;
+use std::io::Cursor;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::mem;
 use std::path::Path;
 use std::path::PathBuf;
 
+use bincode::deserialize;
 use bincode::deserialize_from;
 use bincode::serialize;
+use crc::crc32;
+use crc::crc32::Hasher32;
+use integer_encoding::FixedInt;
+use log::info;
+use log::warn;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::blob::blob_file_name;
+use crate::env::open_table;
+use crate::env::sync_parent_dir;
+use crate::env::EnvFile;
+use crate::error::err;
 use crate::error::MyResult;
+use crate::error::Status;
+use crate::error::StatusCode;
+use crate::file_number::FileNumbers;
 use crate::options::Options;
+use crate::sstable_reader::table_reader_to_file_meta;
 
 const MANIFEST_FILENAME: &str = "MANIFEST";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// u32 length and u32 crc of a record
+const RECORD_HEADER_LEN: usize = 8;
+
+/// What the manifest knows of an sstable, enough to tell which files may
+/// hold a key without opening them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct FileMeta {
     pub file_name: String,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+    pub file_size: u64,
+    /// Sequences of the oldest and newest write in the file; zero for files
+    /// not written from memtables, such as ingested ones.
+    pub smallest_seq: u64,
+    pub largest_seq: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobFileMeta {
+    pub file_number: usize,
+    pub total_bytes: u64,
+    pub garbage_bytes: u64,
+}
+
+impl BlobFileMeta {
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.;
+        }
+        self.garbage_bytes as f64 / self.total_bytes as f64
+    }
+
+    pub fn is_obsolete(&self) -> bool {
+        self.garbage_bytes >= self.total_bytes
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +85,11 @@ impl LevelMeta {
         self.file_metas.push(file_meta);
     }
 
+    fn sort_by_smallest_key(&mut self) {
+        self.file_metas
+            .sort_by(|a, b| a.smallest_key.cmp(&b.smallest_key));
+    }
+
     pub fn remove_file_meta_by_file_name(&mut self, file_name: String) {
         let mut set = HashSet::with_capacity(1);
         set.insert(file_name);
@@ -44,17 +100,174 @@ impl LevelMeta {
         self.file_metas
             .retain(|x| !file_names.contains(&x.file_name));
     }
+
+    /// Puts `file_metas` where the first of `file_names` was, keeping the
+    /// order of the other files.
+    pub fn replace_file_metas(&mut self, file_names: &HashSet<String>, file_metas: Vec<FileMeta>) {
+        let pos = self
+            .file_metas
+            .iter()
+            .position(|x| file_names.contains(&x.file_name))
+            .unwrap_or_else(|| self.file_metas.len());
+        self.remove_file_meta_by_file_names(file_names);
+        self.file_metas.splice(pos..pos, file_metas);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
     pub level_metas: Vec<LevelMeta>,
+    pub blob_files: BTreeMap<usize, BlobFileMeta>,
+    /// Next unallocated file number when the manifest was written. Covers
+    /// numbers handed out to files that are not in the manifest (yet).
+    pub next_file_number: usize,
+    /// Sequence of the newest write flushed into the sstables.
+    pub last_sequence: u64,
+    /// Per level, the largest key of the last compaction out of it; the
+    /// next one starts after it.
+    pub compact_pointers: BTreeMap<usize, Vec<u8>>,
+}
+
+/// One change to the manifest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Edit {
+    AddFile(usize, FileMeta),
+    RemoveFiles(usize, Vec<String>),
+    ReplaceFiles(usize, Vec<String>, Vec<FileMeta>),
+    MoveFiles(usize, usize, Vec<String>),
+    AddBlobFile(BlobFileMeta),
+    AddBlobGarbage(usize, u64),
+    NextFileNumber(usize),
+    LastSequence(u64),
+    CompactPointer(usize, Vec<u8>),
+}
+
+/// The changes written to the manifest at once, replayed in order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VersionEdit {
+    pub edits: Vec<Edit>,
+}
+
+/// The manifest file is a log of these, starting with a snapshot.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Snapshot(Manifest),
+    Edit(VersionEdit),
+}
+
+fn crc(v: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+    digest.write(v);
+    digest.sum32()
+}
+
+fn write_record<W: Write>(w: &mut W, record: &Record) -> MyResult<()> {
+    let data = serialize(record)?;
+    let mut header = [0; RECORD_HEADER_LEN];
+    (data.len() as u32).encode_fixed(&mut header[..4]);
+    crc(&data).encode_fixed(&mut header[4..]);
+    w.write_all(&header)?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+/// The records of a manifest log. A bad record at the end is what's left of
+/// an append cut short by a crash and is ignored; anywhere else it is
+/// corruption.
+fn read_records(data: &[u8]) -> MyResult<Vec<Record>> {
+    let mut records = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let record = if rest.len() < RECORD_HEADER_LEN {
+            None
+        } else {
+            let len = u32::decode_fixed(&rest[..4]) as usize;
+            rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+                .filter(|v| u32::decode_fixed(&rest[4..RECORD_HEADER_LEN]) == crc(v))
+                .and_then(|v| deserialize(v).ok())
+                .map(|r| (r, RECORD_HEADER_LEN + len))
+        };
+        match record {
+            Some((r, len)) => {
+                records.push(r);
+                offset += len;
+            }
+            None if rest.iter().all(|b| *b == 0) || rest.len() < RECORD_HEADER_LEN => break,
+            None => {
+                let len = u32::decode_fixed(&rest[..4]) as usize;
+                if RECORD_HEADER_LEN + len < rest.len() {
+                    return err!(StatusCode::ChecksumError, "manifest is corrupted");
+                }
+                warn!("ignoring a torn record at the end of the manifest");
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Manifests from before the log of version edits are one bincode encoded
+/// snapshot, of one of these layouts: with file numbers, with blob files, or
+/// naming just the tables. `load` still reads them. They name the tables of
+/// each level and nothing more of them.
+#[derive(Serialize, Deserialize)]
+struct LegacyManifest {
+    level_metas: Vec<Vec<String>>,
+    blob_files: BTreeMap<usize, BlobFileMeta>,
+    next_file_number: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlobManifest {
+    level_metas: Vec<Vec<String>>,
+    blob_files: BTreeMap<usize, BlobFileMeta>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OldestManifest {
+    level_metas: Vec<Vec<String>>,
+}
+
+/// `data` decoded as a `T`, if that takes all of it.
+fn decode_whole<T: DeserializeOwned>(data: &[u8]) -> Option<T> {
+    let mut cursor = Cursor::new(data);
+    let t = deserialize_from(&mut cursor).ok()?;
+    if cursor.position() as usize == data.len() {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// The layouts are told apart by which one takes all of `data`. A log never
+/// does, its first record header reads as far too many levels.
+fn read_legacy(data: &[u8]) -> Option<LegacyManifest> {
+    decode_whole::<LegacyManifest>(data)
+        .or_else(|| {
+            decode_whole::<BlobManifest>(data).map(|m| LegacyManifest {
+                level_metas: m.level_metas,
+                blob_files: m.blob_files,
+                next_file_number: 0,
+            })
+        })
+        .or_else(|| {
+            decode_whole::<OldestManifest>(data).map(|m| LegacyManifest {
+                level_metas: m.level_metas,
+                blob_files: BTreeMap::new(),
+                next_file_number: 0,
+            })
+        })
 }
 
 impl Manifest {
     fn new(opt: &Options) -> Self {
         Manifest {
             level_metas: Vec::with_capacity(opt.max_level),
+            blob_files: BTreeMap::new(),
+            next_file_number: 0,
+            last_sequence: 0,
+            compact_pointers: BTreeMap::new(),
         }
     }
 
@@ -73,30 +286,120 @@ impl Manifest {
                 }
             }
         }
+        if let Some(n) = self.blob_files.keys().next_back() {
+            if m.is_none() || *n > m.unwrap() {
+                m = Some(*n);
+            }
+        }
         if let Some(m_) = m {
-            m_ + 1
+            ::std::cmp::max(m_ + 1, self.next_file_number)
         } else {
-            0
+            self.next_file_number
         }
     }
 
+    /// Names of the sstables and blob files in the manifest.
+    pub fn live_files(&self) -> Vec<String> {
+        let tables = self
+            .level_metas
+            .iter()
+            .flat_map(|lm| lm.file_metas.iter().map(|fm| fm.file_name.clone()));
+        let blobs = self.blob_files.keys().map(|n| blob_file_name(*n));
+        tables.chain(blobs).collect()
+    }
+
     pub fn gen_path(opt: &Options) -> PathBuf {
         let p = Path::new(&opt.work_dir);
         p.join(MANIFEST_FILENAME)
     }
 
+    /// Replays the manifest log of `opt.work_dir`. A manifest from before the
+    /// log is converted, and replaced by a log with the next flush.
     pub fn load(opt: &Options) -> MyResult<Self> {
         let p = Manifest::gen_path(opt);
-        if !p.exists() {
+        if !opt.env.exists(&p) {
             return Ok(Manifest::new(opt));
         }
-        let f = File::open(&p)?;
-        Ok(deserialize_from(f)?)
+        let data = opt.env.read_all(&p)?;
+        if let Some(legacy) = read_legacy(&data) {
+            return Manifest::from_legacy(opt, legacy);
+        }
+        let mut manifest = Manifest::new(opt);
+        for record in read_records(&data)? {
+            match record {
+                Record::Snapshot(m) => manifest = m,
+                Record::Edit(edit) => manifest.apply(&edit),
+            }
+        }
+        Ok(manifest)
     }
 
-    pub fn flush<T: Write>(&self, w: &mut T) -> MyResult<()> {
-        w.write_all(&serialize(self)?)?;
-        Ok(())
+    /// Fills in the metas a legacy manifest lacks from the tables themselves.
+    fn from_legacy(opt: &Options, legacy: LegacyManifest) -> MyResult<Self> {
+        info!("converting the legacy manifest of {}", opt.work_dir);
+        let mut manifest = Manifest::new(opt);
+        let work_dir = Path::new(&opt.work_dir);
+        let (env, table_opt) = (opt.env.as_ref(), opt.get_table_opt());
+        for (level, file_names) in legacy.level_metas.into_iter().enumerate() {
+            manifest.ensure_level(level);
+            for file_name in file_names {
+                let path = work_dir.join(&file_name);
+                let reader = open_table(env, &path, table_opt.clone()).map_err(|mut e| {
+                    e.msg = format!("{} converting the legacy manifest, run repair", e.msg);
+                    e
+                })?;
+                manifest.level_metas[level]
+                    .push_file_meta(table_reader_to_file_meta(&reader, (0, 0)));
+            }
+            manifest.sort_level(level);
+        }
+        manifest.blob_files = legacy.blob_files;
+        manifest.next_file_number = legacy.next_file_number;
+        Ok(manifest)
+    }
+
+    pub fn apply(&mut self, edit: &VersionEdit) {
+        let set = |names: &Vec<String>| names.iter().cloned().collect::<HashSet<_>>();
+        for e in &edit.edits {
+            match e {
+                Edit::AddFile(level, meta) => self.add_file_meta(*level, meta.clone()),
+                Edit::RemoveFiles(level, names) => {
+                    self.remove_file_meta_by_file_names(*level, &set(names))
+                }
+                Edit::ReplaceFiles(level, names, metas) => {
+                    self.replace_file_metas(*level, &set(names), metas.clone())
+                }
+                Edit::MoveFiles(from, to, names) => self.move_file_metas(*from, *to, &set(names)),
+                Edit::AddBlobFile(meta) => {
+                    self.blob_files.insert(meta.file_number, meta.clone());
+                }
+                Edit::AddBlobGarbage(file_number, bytes) => {
+                    self.add_blob_garbage(*file_number, *bytes);
+                }
+                Edit::NextFileNumber(n) => self.next_file_number = *n,
+                Edit::LastSequence(seq) => self.last_sequence = *seq,
+                Edit::CompactPointer(level, key) => {
+                    self.compact_pointers.insert(*level, key.clone());
+                }
+            }
+        }
+    }
+
+    /// Accounts `bytes` of `file_number` as garbage and returns the blob
+    /// file's meta once nothing in it is referenced anymore.
+    pub fn add_blob_garbage(&mut self, file_number: usize, bytes: u64) -> Option<BlobFileMeta> {
+        let obsolete = match self.blob_files.get_mut(&file_number) {
+            Some(meta) => {
+                meta.garbage_bytes += bytes;
+                meta.is_obsolete()
+            }
+            None => false,
+        };
+        if obsolete {
+            self.blob_files.remove(&file_number)
+        } else {
+            None
+        }
     }
 
     fn ensure_level(&mut self, level: usize) {
@@ -105,9 +408,18 @@ impl Manifest {
         }
     }
 
+    /// Files of level 0 are kept in the order they were added, newest last,
+    /// and those of other levels by their smallest key.
+    fn sort_level(&mut self, level: usize) {
+        if level != 0 {
+            self.level_metas[level].sort_by_smallest_key();
+        }
+    }
+
     pub fn add_file_meta(&mut self, level: usize, file_meta: FileMeta) {
         self.ensure_level(level);
         self.level_metas[level].push_file_meta(file_meta);
+        self.sort_level(level);
     }
 
     pub fn remove_file_meta_by_file_name(&mut self, level: usize, file_name: String) {
@@ -120,6 +432,65 @@ impl Manifest {
         self.level_metas[level].remove_file_meta_by_file_names(file_names)
     }
 
+    pub fn replace_file_metas(
+        &mut self,
+        level: usize,
+        file_names: &HashSet<String>,
+        file_metas: Vec<FileMeta>,
+    ) {
+        self.ensure_level(level);
+        self.level_metas[level].replace_file_metas(file_names, file_metas);
+        self.sort_level(level);
+    }
+
+    /// Moves the metas of `file_names` from level `from` to level `to`.
+    pub fn move_file_metas(&mut self, from: usize, to: usize, file_names: &HashSet<String>) {
+        self.ensure_level(::std::cmp::max(from, to));
+        let (moved, kept) = self.level_metas[from]
+            .file_metas
+            .drain(..)
+            .partition(|x| file_names.contains(&x.file_name));
+        self.level_metas[from].file_metas = kept;
+        self.level_metas[to].file_metas.extend(moved);
+        self.sort_level(to);
+    }
+
+    /// What is wrong with the files the manifest lists: missing ones, and
+    /// ones of another size than recorded.
+    pub fn check_files(&self, opt: &Options) -> MyResult<Vec<String>> {
+        let env = opt.env.as_ref();
+        let work_dir = Path::new(&opt.work_dir);
+        let mut problems = vec![];
+        let mut check = |file_name: String, expected: u64| -> MyResult<()> {
+            let path = work_dir.join(&file_name);
+            if !env.exists(&path) {
+                problems.push(format!("{} is missing", file_name));
+                return Ok(());
+            }
+            let size = env.open(&path)?.size()?;
+            if size != expected {
+                problems.push(format!(
+                    "{} has {} bytes, {} expected",
+                    file_name, size, expected
+                ));
+            }
+            Ok(())
+        };
+        for lm in &self.level_metas {
+            for fm in &lm.file_metas {
+                check(fm.file_name.clone(), fm.file_size)?;
+            }
+        }
+        for meta in self.blob_files.values() {
+            check(blob_file_name(meta.file_number), meta.total_bytes)?;
+        }
+        Ok(problems)
+    }
+
+    pub fn compact_pointer(&self, level: usize) -> Option<&Vec<u8>> {
+        self.compact_pointers.get(&level)
+    }
+
     pub fn file_metas(&self, level: usize) -> Option<&Vec<FileMeta>> {
         if self.level_metas.len() <= level {
             None
@@ -129,9 +500,17 @@ impl Manifest {
     }
 }
 
+/// Keeps the manifest and logs its changes. Each flush appends the changes
+/// made since the last one as a single `VersionEdit` record, and every
+/// `manifest_max_edits` edits, as well as on the first flush after opening,
+/// the log is rewritten as one snapshot record.
 pub struct ManifestBuilder {
     opt: Options,
     manifest_: Manifest,
+    file_numbers: Option<FileNumbers>,
+    pending: VersionEdit,
+    log: Option<Box<dyn EnvFile>>,
+    logged_edits: usize,
 }
 
 impl ManifestBuilder {
@@ -139,6 +518,10 @@ impl ManifestBuilder {
         Ok(ManifestBuilder {
             opt: opt.clone(),
             manifest_: Manifest::load(&opt)?,
+            file_numbers: None,
+            pending: VersionEdit::default(),
+            log: None,
+            logged_edits: 0,
         })
     }
 
@@ -148,28 +531,133 @@ impl ManifestBuilder {
 
     pub fn add_file_meta(&mut self, level: usize, file_meta: FileMeta) {
         assert!(level < self.opt.max_level);
+        self.pending
+            .edits
+            .push(Edit::AddFile(level, file_meta.clone()));
         self.manifest_.add_file_meta(level, file_meta)
     }
 
     pub fn remove_file_meta_by_file_name(&mut self, level: usize, file_name: String) {
-        assert!(level < self.opt.max_level);
-        self.manifest_
-            .remove_file_meta_by_file_name(level, file_name)
+        let mut set = HashSet::with_capacity(1);
+        set.insert(file_name);
+        self.remove_file_meta_by_file_names(level, &set)
     }
 
     pub fn remove_file_meta_by_file_names(&mut self, level: usize, file_names: &HashSet<String>) {
         assert!(level < self.opt.max_level);
+        self.pending
+            .edits
+            .push(Edit::RemoveFiles(level, sorted(file_names)));
         self.manifest_
             .remove_file_meta_by_file_names(level, file_names)
     }
 
+    pub fn replace_file_metas(
+        &mut self,
+        level: usize,
+        file_names: &HashSet<String>,
+        file_metas: Vec<FileMeta>,
+    ) {
+        assert!(level < self.opt.max_level);
+        self.pending.edits.push(Edit::ReplaceFiles(
+            level,
+            sorted(file_names),
+            file_metas.clone(),
+        ));
+        self.manifest_
+            .replace_file_metas(level, file_names, file_metas)
+    }
+
+    pub fn move_file_metas(&mut self, from: usize, to: usize, file_names: &HashSet<String>) {
+        assert!(from < self.opt.max_level && to < self.opt.max_level);
+        self.pending
+            .edits
+            .push(Edit::MoveFiles(from, to, sorted(file_names)));
+        self.manifest_.move_file_metas(from, to, file_names)
+    }
+
+    pub fn add_blob_file(&mut self, meta: BlobFileMeta) {
+        self.pending.edits.push(Edit::AddBlobFile(meta.clone()));
+        self.manifest_.blob_files.insert(meta.file_number, meta);
+    }
+
+    /// Accounts `bytes` of `file_number` as garbage and returns the blob file's
+    /// meta once nothing in it is referenced anymore.
+    pub fn add_blob_garbage(&mut self, file_number: usize, bytes: u64) -> Option<BlobFileMeta> {
+        self.pending
+            .edits
+            .push(Edit::AddBlobGarbage(file_number, bytes));
+        self.manifest_.add_blob_garbage(file_number, bytes)
+    }
+
+    pub fn set_last_sequence(&mut self, seq: u64) {
+        self.pending.edits.push(Edit::LastSequence(seq));
+        self.manifest_.last_sequence = seq;
+    }
+
+    pub fn set_compact_pointer(&mut self, level: usize, key: Vec<u8>) {
+        self.pending
+            .edits
+            .push(Edit::CompactPointer(level, key.clone()));
+        self.manifest_.compact_pointers.insert(level, key);
+    }
+
+    pub fn blob_file(&self, file_number: usize) -> Option<&BlobFileMeta> {
+        self.manifest_.blob_files.get(&file_number)
+    }
+
     pub fn manifest(&self) -> &Manifest {
         &self.manifest_
     }
 
-    pub fn flush(&self) -> MyResult<()> {
-        let mut file_ = File::create(Manifest::gen_path(&self.opt))?;
-        self.manifest_.flush(&mut file_)
+    /// Records the allocator's next number with every flush of the manifest.
+    pub fn set_file_numbers(&mut self, file_numbers: FileNumbers) {
+        self.file_numbers = Some(file_numbers);
+    }
+
+    /// Makes the changes so far durable, as one record appended to the log,
+    /// so a crash keeps either all of them or none.
+    pub fn flush(&mut self) -> MyResult<()> {
+        if let Some(file_numbers) = &self.file_numbers {
+            let n = file_numbers.peek();
+            if n != self.manifest_.next_file_number {
+                self.pending.edits.push(Edit::NextFileNumber(n));
+                self.manifest_.next_file_number = n;
+            }
+        }
+        let edit = mem::replace(&mut self.pending, VersionEdit::default());
+        let log = match &mut self.log {
+            Some(log) if self.logged_edits < self.opt.manifest_max_edits => log,
+            _ => return self.write_snapshot(),
+        };
+        if edit.edits.is_empty() {
+            return Ok(());
+        }
+        write_record(log, &Record::Edit(edit))?;
+        log.sync()?;
+        self.logged_edits += 1;
+        Ok(())
+    }
+
+    /// Writes the whole manifest to a temporary file and renames it into
+    /// place, so a crash leaves either the old or the new log, never a torn
+    /// one.
+    fn write_snapshot(&mut self) -> MyResult<()> {
+        self.log = None;
+        let path = Manifest::gen_path(&self.opt);
+        let tmp_path = path.with_extension("tmp");
+        let env = self.opt.env.as_ref();
+        let mut file_ = env.create(&tmp_path)?;
+        write_record(&mut file_, &Record::Snapshot(self.manifest_.clone()))?;
+        file_.sync()?;
+        env.rename(&tmp_path, &path)?;
+        sync_parent_dir(env, &path)?;
+
+        let mut log = env.open_writable(&path)?;
+        log.seek(SeekFrom::End(0))?;
+        self.log = Some(log);
+        self.logged_edits = 0;
+        Ok(())
     }
 
     pub fn next_file_number(&self) -> usize {
@@ -177,6 +665,58 @@ impl ManifestBuilder {
     }
 }
 
+fn sorted(file_names: &HashSet<String>) -> Vec<String> {
+    let mut v = file_names.iter().cloned().collect::<Vec<_>>();
+    v.sort();
+    v
+}
+
+/// Every file with its key range and sequences, unlike the summary of
+/// `ManifestBuilder`.
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = |k: &[u8]| String::from_utf8_lossy(k).into_owned();
+        writeln!(f, "Next file number: {}", self.next_file_number())?;
+        writeln!(f, "Last sequence: {}", self.last_sequence)?;
+        for (i, lm) in self.level_metas.iter().enumerate() {
+            let bytes = lm.file_metas.iter().map(|fm| fm.file_size).sum::<u64>();
+            writeln!(
+                f,
+                "Level{} ({} files, {} bytes):",
+                i,
+                lm.file_metas.len(),
+                bytes
+            )?;
+            for fm in &lm.file_metas {
+                writeln!(
+                    f,
+                    "\t{}: {} bytes, keys {:?}..{:?}, seqs {}..{}",
+                    fm.file_name,
+                    fm.file_size,
+                    key(&fm.smallest_key),
+                    key(&fm.largest_key),
+                    fm.smallest_seq,
+                    fm.largest_seq
+                )?;
+            }
+            if let Some(pointer) = self.compact_pointer(i) {
+                writeln!(f, "\tcompact pointer: {:?}", key(pointer))?;
+            }
+        }
+        writeln!(f, "Blob files ({}):", self.blob_files.len())?;
+        for meta in self.blob_files.values() {
+            writeln!(
+                f,
+                "\t{}: {}/{} garbage bytes",
+                blob_file_name(meta.file_number),
+                meta.garbage_bytes,
+                meta.total_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for ManifestBuilder {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -200,6 +740,221 @@ impl fmt::Display for ManifestBuilder {
             }
             writeln!(f)?;
         }
+        writeln!(f, "Blob files ({}):", self.manifest_.blob_files.len())?;
+        for meta in self.manifest_.blob_files.values() {
+            writeln!(
+                f,
+                "\t{}: {}/{} garbage bytes",
+                meta.file_number, meta.garbage_bytes, meta.total_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::env::create_table;
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    fn meta(file_name: &str) -> FileMeta {
+        FileMeta {
+            file_name: file_name.to_string(),
+            ..FileMeta::default()
+        }
+    }
+
+    fn names(m: &Manifest, level: usize) -> Vec<String> {
+        m.file_metas(level)
+            .unwrap()
+            .iter()
+            .map(|fm| fm.file_name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_edit_log() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.manifest_max_edits = 3;
+        let mut mb = ManifestBuilder::new(opt.clone())?;
+        mb.add_file_meta(0, meta("00000001.sst"));
+        mb.flush()?;
+        let snapshot_len = opt.env.open(&Manifest::gen_path(&opt))?.size()?;
+
+        mb.add_file_meta(0, meta("00000002.sst"));
+        mb.add_file_meta(0, meta("00000003.sst"));
+        mb.set_last_sequence(7);
+        mb.flush()?;
+        let mut set = HashSet::new();
+        set.insert("00000001.sst".to_string());
+        mb.move_file_metas(0, 1, &set);
+        mb.flush()?;
+        // nothing changed, nothing logged
+        mb.flush()?;
+        let log_len = opt.env.open(&Manifest::gen_path(&opt))?.size()?;
+        assert!(log_len > snapshot_len);
+
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000002.sst", "00000003.sst"], names(&m, 0));
+        assert_eq!(vec!["00000001.sst"], names(&m, 1));
+        assert_eq!(7, m.last_sequence);
+
+        // the flush after manifest_max_edits edits rewrites the log
+        mb.remove_file_meta_by_file_name(0, "00000002.sst".to_string());
+        mb.flush()?;
+        mb.add_file_meta(0, meta("00000004.sst"));
+        mb.flush()?;
+        assert!(opt.env.open(&Manifest::gen_path(&opt))?.size()? < log_len);
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000003.sst", "00000004.sst"], names(&m, 0));
+        assert_eq!(vec!["00000001.sst"], names(&m, 1));
+        assert_eq!(7, m.last_sequence);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_and_corrupted_log() -> MyResult<()> {
+        let opt = get_test_opt();
+        let path = Manifest::gen_path(&opt);
+        let mut mb = ManifestBuilder::new(opt.clone())?;
+        mb.add_file_meta(0, meta("00000001.sst"));
+        mb.flush()?;
+        mb.add_file_meta(0, meta("00000002.sst"));
+        mb.flush()?;
+        let len = opt.env.open(&path)?.size()?;
+        mb.add_file_meta(0, meta("00000003.sst"));
+        mb.flush()?;
+        let full_len = opt.env.open(&path)?.size()?;
+
+        // an append cut short loses just the last edit
+        opt.env.open_writable(&path)?.set_len(full_len - 1)?;
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000001.sst", "00000002.sst"], names(&m, 0));
+
+        // a bad record followed by others is corruption
+        opt.env.open_writable(&path)?.set_len(full_len)?;
+        let data = opt.env.read_all(&path)?.to_vec();
+        let mut f = opt.env.open_writable(&path)?;
+        f.seek(SeekFrom::Start(len - 1))?;
+        f.write_all(&[!data[len as usize - 1]])?;
+        let r = Manifest::load(&opt);
+        assert_eq!(StatusCode::ChecksumError, r.unwrap_err().code);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_files() -> MyResult<()> {
+        let opt = get_test_opt();
+        let work_dir = Path::new(&opt.work_dir);
+        let mut mb = ManifestBuilder::new(opt.clone())?;
+        for (file_name, size) in &[("00000001.sst", 3), ("00000002.sst", 4)] {
+            opt.env
+                .create(&work_dir.join(file_name))?
+                .write_all(b"abc")?;
+            let mut fm = meta(file_name);
+            fm.file_size = *size;
+            fm.smallest_key = b"a".to_vec();
+            fm.largest_key = b"c".to_vec();
+            mb.add_file_meta(1, fm);
+        }
+        mb.add_blob_file(BlobFileMeta {
+            file_number: 3,
+            total_bytes: 10,
+            garbage_bytes: 0,
+        });
+        mb.flush()?;
+
+        let m = Manifest::load(&opt)?;
+        assert_eq!(
+            vec![
+                "00000002.sst has 3 bytes, 4 expected".to_string(),
+                format!("{} is missing", blob_file_name(3)),
+            ],
+            m.check_files(&opt)?
+        );
+        let dump = m.to_string();
+        assert!(dump.contains("00000001.sst: 3 bytes, keys \"a\"..\"c\", seqs 0..0"));
+        assert!(dump.contains("Level1 (2 files, 7 bytes):"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy() -> MyResult<()> {
+        let opt = get_test_opt();
+        let work_dir = Path::new(&opt.work_dir);
+        let tables = [
+            ("00000001.sst", ["a", "c"]),
+            ("00000002.sst", ["d", "f"]),
+            ("00000003.sst", ["b", "c"]),
+        ];
+        for (file_name, keys) in &tables {
+            let path = work_dir.join(file_name);
+            let mut tb = create_table(opt.env.as_ref(), &path, opt.get_table_opt().clone())?;
+            for k in keys {
+                tb.add(k.as_bytes(), b"v")?;
+            }
+            tb.flush()?;
+        }
+        let path = Manifest::gen_path(&opt);
+        let level_metas = vec![
+            vec!["00000003.sst".to_owned()],
+            vec!["00000002.sst".to_owned(), "00000001.sst".to_owned()],
+        ];
+        let mut blob_files = BTreeMap::new();
+        blob_files.insert(
+            5,
+            BlobFileMeta {
+                file_number: 5,
+                total_bytes: 10,
+                garbage_bytes: 0,
+            },
+        );
+
+        let oldest = OldestManifest {
+            level_metas: level_metas.clone(),
+        };
+        opt.env.create(&path)?.write_all(&serialize(&oldest)?)?;
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000003.sst"], names(&m, 0));
+        assert_eq!(vec!["00000001.sst", "00000002.sst"], names(&m, 1));
+        let fm = &m.file_metas(1).unwrap()[0];
+        assert_eq!(b"a".to_vec(), fm.smallest_key);
+        assert_eq!(b"c".to_vec(), fm.largest_key);
+        assert_eq!(4, m.next_file_number());
+
+        let blob = BlobManifest {
+            level_metas: level_metas.clone(),
+            blob_files: blob_files.clone(),
+        };
+        opt.env.create(&path)?.write_all(&serialize(&blob)?)?;
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000001.sst", "00000002.sst"], names(&m, 1));
+        assert!(m.blob_files.contains_key(&5));
+        assert_eq!(6, m.next_file_number());
+
+        let legacy = LegacyManifest {
+            level_metas,
+            blob_files,
+            next_file_number: 9,
+        };
+        opt.env.create(&path)?.write_all(&serialize(&legacy)?)?;
+        let mut mb = ManifestBuilder::new(opt.clone())?;
+        assert_eq!(9, mb.next_file_number());
+
+        // the next flush writes the log
+        mb.flush()?;
+        assert!(read_legacy(&opt.env.read_all(&path)?).is_none());
+        let m = Manifest::load(&opt)?;
+        assert_eq!(vec!["00000001.sst", "00000002.sst"], names(&m, 1));
+        assert!(m.blob_files.contains_key(&5));
+        assert_eq!(9, m.next_file_number());
+
+        // a table gone
+        opt.env.remove_file(&work_dir.join("00000001.sst"))?;
+        opt.env.create(&path)?.write_all(&serialize(&oldest)?)?;
+        assert!(Manifest::load(&opt).unwrap_err().msg.contains("run repair"));
         Ok(())
     }
 }
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+
+use crate::env::Env;
+use crate::env::EnvFile;
+use crate::env::FileData;
+use crate::env::FileLock;
+use crate::utils::read_lock;
+use crate::utils::write_lock;
+
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+#[derive(Default)]
+struct Fs {
+    files: HashMap<PathBuf, Contents>,
+    dirs: HashSet<PathBuf>,
+    locks: HashSet<PathBuf>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// Keeps every file in memory, so nothing survives the process; reopening a
+/// work dir with a clone of the same env finds its files again. Handy for
+/// tests, and for running the server as a pure cache.
+#[derive(Clone, Default)]
+pub struct MemEnv {
+    fs: Arc<Mutex<Fs>>,
+}
+
+impl MemEnv {
+    pub fn new() -> Self {
+        MemEnv::default()
+    }
+
+    fn fs(&self) -> MutexGuard<Fs> {
+        self.fs.lock().unwrap()
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Contents> {
+        match self.fs().files.get(path) {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn open_file(&self, contents: Contents, writable: bool) -> Box<dyn EnvFile> {
+        Box::new(MemFile {
+            contents,
+            pos: 0,
+            writable,
+        })
+    }
+}
+
+struct MemFile {
+    contents: Contents,
+    pos: u64,
+    writable: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is opened read only",
+            ));
+        }
+        let mut contents = write_lock(&self.contents);
+        let start = self.pos as usize;
+        if contents.len() < start + buf.len() {
+            contents.resize(start + buf.len(), 0);
+        }
+        contents[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => offset(self.size()?, n),
+            SeekFrom::Current(n) => offset(self.pos, n),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )),
+        }
+    }
+}
+
+fn offset(base: u64, n: i64) -> Option<u64> {
+    if n >= 0 {
+        base.checked_add(n as u64)
+    } else {
+        base.checked_sub(n.wrapping_neg() as u64)
+    }
+}
+
+impl EnvFile for MemFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(read_lock(&self.contents).len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> io::Result<usize> {
+        let contents = read_lock(&self.contents);
+        let start = (offset as usize).min(contents.len());
+        let n = dst.len().min(contents.len() - start);
+        dst[..n].copy_from_slice(&contents[start..start + n]);
+        Ok(n)
+    }
+
+    fn set_len(&self, size: u64) -> io::Result<()> {
+        write_lock(&self.contents).resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Env for MemEnv {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        Ok(self.open_file(self.file(path)?, false))
+    }
+
+    fn open_writable(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let contents = self
+            .fs()
+            .files
+            .entry(path.to_path_buf())
+            .or_insert_with(Contents::default)
+            .clone();
+        Ok(self.open_file(contents, true))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn EnvFile>> {
+        let contents = Contents::default();
+        self.fs().files.insert(path.to_path_buf(), contents.clone());
+        Ok(self.open_file(contents, true))
+    }
+
+    fn read_all(&self, path: &Path) -> io::Result<FileData> {
+        let contents = self.file(path)?;
+        let data = read_lock(&contents).clone();
+        Ok(Box::new(data))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.fs();
+        match fs.files.remove(from) {
+            Some(contents) => {
+                fs.files.insert(to.to_path_buf(), contents);
+                Ok(())
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut fs = self.fs();
+        if fs.files.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        match fs.files.get(from).cloned() {
+            Some(contents) => {
+                fs.files.insert(to.to_path_buf(), contents);
+                Ok(())
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.fs().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let fs = self.fs();
+        fs.files.contains_key(path) || fs.dirs.contains(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.fs().dirs.contains(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = self
+            .fs()
+            .files
+            .keys()
+            .filter(|p| p.parent() == Some(dir))
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut fs = self.fs();
+        for ancestor in dir.ancestors() {
+            fs.dirs.insert(ancestor.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<FileLock> {
+        let mut fs = self.fs();
+        fs.files
+            .entry(path.to_path_buf())
+            .or_insert_with(Contents::default);
+        if !fs.locks.insert(path.to_path_buf()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(Box::new(MemLock {
+            fs: self.fs.clone(),
+            path: path.to_path_buf(),
+        }))
+    }
+}
+
+struct MemLock {
+    fs: Arc<Mutex<Fs>>,
+    path: PathBuf,
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.fs.lock().unwrap().locks.remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bincode::serialize;
+
+    use crate::data_manager::DataManager;
+    use crate::error::MyResult;
+    use crate::slice::Slice;
+    use crate::sstable_builder::entries_to_sstable;
+    use crate::store::StorePayload;
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    #[test]
+    fn test_mem_env() -> MyResult<()> {
+        let env = MemEnv::new();
+        let dir = Path::new("/mem/db");
+        env.create_dir_all(dir)?;
+        assert!(env.is_dir(Path::new("/mem")));
+        assert!(!Path::new("/mem").exists());
+
+        let a = dir.join("00000001.log");
+        let mut f = env.create(&a)?;
+        f.write_all(b"hello world")?;
+        f.seek(SeekFrom::Start(6))?;
+        f.write_all(b"there")?;
+        assert_eq!(b"hello there", &env.read_all(&a)?[..]);
+        f.set_len(5)?;
+        assert_eq!(5, env.open(&a)?.size()?);
+        assert!(env.open(&a)?.write_all(b"x").is_err());
+
+        let mut buf = vec![];
+        env.open_writable(&a)?.read_to_end(&mut buf)?;
+        assert_eq!(b"hello", &buf[..]);
+
+        let b = dir.join("00000002.log");
+        env.rename(&a, &b)?;
+        env.create(&dir.join("sub").join("00000003.log"))?;
+        assert_eq!(vec![b.clone()], env.list(dir)?);
+        let c = dir.join("00000004.log");
+        env.hard_link(&b, &c)?;
+        assert!(env.hard_link(&b, &c).is_err());
+        let d = dir.join("00000005.log");
+        assert_eq!(5, env.copy(&b, &d)?);
+        env.open_writable(&b)?.write_all(b"H")?;
+        assert_eq!(b"Hello", &env.read_all(&c)?[..]);
+        assert_eq!(b"hello", &env.read_all(&d)?[..]);
+        env.remove_file(&c)?;
+        env.remove_file(&d)?;
+
+        env.remove_file(&b)?;
+        assert!(env.open(&b).is_err());
+        assert!(env.remove_file(&b).is_err());
+
+        let lock = env.lock_file(&dir.join("LOCK"))?;
+        assert!(env.lock_file(&dir.join("LOCK")).is_err());
+        drop(lock);
+        env.lock_file(&dir.join("LOCK"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_manager_in_mem_env() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.work_dir = format!("{}/mem", opt.work_dir);
+        opt.env = Arc::new(MemEnv::new());
+        opt.blob_min_size = 4;
+        {
+            let dm = DataManager::new(opt.clone())?;
+            for key in &["a", "b", "c"] {
+                let p = StorePayload::new(Slice::from(key.repeat(8)), 0, 0, 8, 0);
+                dm.insert(Slice::from(*key), p)?;
+            }
+            dm.close()?;
+        }
+        assert!(!Path::new(&opt.work_dir).exists());
+        assert!(!opt.env.list(Path::new(&opt.work_dir))?.is_empty());
+
+        let dm = DataManager::new(opt.clone())?;
+        for key in &["a", "b", "c"] {
+            let p = dm.get(&Slice::from(*key))?.unwrap();
+            assert_eq!(Slice::from(key.repeat(8)), p.data);
+        }
+
+        let mut checkpoint_opt = opt.clone();
+        checkpoint_opt.work_dir = format!("{}-checkpoint", opt.work_dir);
+        dm.checkpoint(&checkpoint_opt.work_dir)?;
+        assert!(!Path::new(&checkpoint_opt.work_dir).exists());
+        let checkpoint = DataManager::new(checkpoint_opt)?;
+        for key in &["a", "b", "c"] {
+            let p = checkpoint.get(&Slice::from(*key))?.unwrap();
+            assert_eq!(Slice::from(key.repeat(8)), p.data);
+        }
+
+        let mut ext = opt.clone();
+        ext.blob_min_size = 0;
+        let path = Path::new(&format!("{}-ext", opt.work_dir)).join("ext.sst");
+        let p = StorePayload::new(Slice::from("d".repeat(8)), 0, 0, 8, 0);
+        let encoded = vec![(Slice::from("d"), Slice::from(serialize(&Some(p.clone()))?))];
+        entries_to_sstable(encoded.into_iter(), &ext, &path)?;
+        dm.ingest_sstable(&path)?;
+        assert!(!path.exists());
+        assert_eq!(Some(p), dm.get(&Slice::from("d"))?);
+        Ok(())
+    }
+}
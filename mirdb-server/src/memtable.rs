@@ -1,91 +1,249 @@
 use std::borrow::Borrow;
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
 
-use skip_list::SkipList;
-use skip_list::SkipListIter;
+use skip_list::ConcurrentSkipList;
 use sstable::TableBuilder;
 use sstable::TableReader;
 
 use crate::error::MyResult;
+use crate::memtable_rep::MemtableRep;
+use crate::memtable_rep::Rep;
 use crate::options::Options;
 use crate::slice::Slice;
-use crate::sstable_builder::skiplist_to_sstable;
+use crate::sstable_builder::entries_to_sstable;
+use crate::types::ApproximateSize;
+use crate::types::PrefixHash;
 use crate::types::Table;
 
-#[derive(Clone)]
+/// Values are stored with the sequence number of the write, so concurrent
+/// writers can insert in any order and the newest write of a key still wins.
 pub struct Memtable<K: Ord + Clone, V: Clone> {
     max_size_: usize,
-    size_: usize,
-    map_: SkipList<K, V>,
+    size_: AtomicUsize,
+    rep_: Rep<K, V>,
 }
 
+/// Sequence of inserts made through `Table::insert`, which win over any
+/// sequenced write.
+pub(crate) const UNSEQUENCED: u64 = ::std::u64::MAX;
+
 impl<K: Ord + Clone, V: Clone> Memtable<K, V> {
     pub fn new(max_size: usize, max_height: usize) -> Self {
-        let map = SkipList::new(max_height);
+        Self::with_rep(max_size, Rep::SkipList(ConcurrentSkipList::new(max_height)))
+    }
+
+    pub fn with_rep(max_size: usize, rep: Rep<K, V>) -> Self {
         Memtable {
             max_size_: max_size,
-            size_: 0,
-            map_: map,
+            size_: AtomicUsize::new(0),
+            rep_: rep,
         }
     }
 
-    pub fn iter(&self) -> SkipListIter<K, V> {
-        self.map_.iter()
+    /// A memtable of `mem_table_max_size` in the representation the options
+    /// ask for.
+    pub fn with_options(opt: &Options) -> Self {
+        Self::with_rep(opt.mem_table_max_size, Rep::with_options(opt))
+    }
+}
+
+impl<K: Ord + Clone + PrefixHash, V: Clone> Memtable<K, V> {
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.rep_.iter().map(|(k, (_, v))| (k, v))
+    }
+
+    /// Entries with keys between `start` and `end`, in order.
+    pub fn range<'a, Q: ?Sized>(
+        &'a self,
+        start: Bound<&Q>,
+        end: Bound<&'a Q>,
+    ) -> impl Iterator<Item = (&'a K, &'a V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        self.rep_.range(start, end).map(|(k, (_, v))| (k, v))
     }
 
     pub fn length(&self) -> usize {
-        self.map_.length()
+        self.rep_.length()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for Memtable<K, V> {
+    fn clone(&self) -> Self {
+        Memtable {
+            max_size_: self.max_size_,
+            size_: AtomicUsize::new(self.size_.load(SeqCst)),
+            rep_: self.rep_.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone + ApproximateSize + PrefixHash, V: Clone + ApproximateSize> Memtable<K, V> {
+    /// Inserts through a shared reference, so several writers can fill the
+    /// memtable at once. The value is only kept if `seq` is newer than the
+    /// stored one. Returns the value that lost, if the key was present.
+    pub fn insert_shared(&self, seq: u64, k: K, v: V) -> Option<V> {
+        let key_size = k.approximate_size();
+        self.size_.fetch_add(v.approximate_size(), SeqCst);
+        let r = self.rep_.insert(seq, k, v).map(|(_, v)| v);
+        match &r {
+            // the key is already counted
+            Some(old) => self.size_.fetch_sub(old.approximate_size(), SeqCst),
+            None => self.size_.fetch_add(key_size, SeqCst),
+        };
+        r
+    }
+
+    /// Replaces every value for which `f` returns a new one, keeping its
+    /// sequence number.
+    pub fn update_values<F>(&self, mut f: F) -> MyResult<()>
+    where
+        F: FnMut(&K, &V) -> MyResult<Option<V>>,
+    {
+        for (k, (seq, v)) in self.rep_.iter() {
+            if let Some(v) = f(k, v)? {
+                self.insert_shared(*seq, k.clone(), v);
+            }
+        }
+        Ok(())
+    }
+
+    /// The sequences of the oldest and the newest write in the memtable.
+    pub fn sequence_range(&self) -> Option<(u64, u64)> {
+        self.rep_
+            .iter()
+            .map(|(_, (seq, _))| *seq)
+            .filter(|seq| *seq != UNSEQUENCED)
+            .fold(None, |r, seq| match r {
+                Some((min, max)) => Some((::std::cmp::min(min, seq), ::std::cmp::max(max, seq))),
+                None => Some((seq, seq)),
+            })
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        let r = self.rep_.remove(k).map(|(_, v)| v);
+        if let Some(v) = &r {
+            self.size_
+                .fetch_sub(k.approximate_size() + v.approximate_size(), SeqCst);
+        }
+        r
+    }
+}
+
+/// What a deleted key maps to: a bincode encoded `None::<StorePayload>`.
+pub(crate) const TOMBSTONE: &[u8] = &[0];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemtableStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub deletes: usize,
+}
+
+impl MemtableStats {
+    /// Flattens the stats into `memtable:<name>:`-prefixed name/value pairs.
+    pub fn to_pairs(&self, name: &str) -> Vec<(String, String)> {
+        vec![
+            (
+                format!("memtable:{}:entries", name),
+                self.entries.to_string(),
+            ),
+            (format!("memtable:{}:bytes", name), self.bytes.to_string()),
+            (
+                format!("memtable:{}:deletes", name),
+                self.deletes.to_string(),
+            ),
+        ]
+    }
+}
+
+/// Stats of the mutable memtable and of the immutable ones waiting to be
+/// flushed, newest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemtablesStats {
+    pub mutable: MemtableStats,
+    pub immutables: Vec<MemtableStats>,
+    pub flushes: u64,
+}
+
+impl MemtablesStats {
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        let mut v = self.mutable.to_pairs("mut");
+        for (i, stats) in self.immutables.iter().enumerate() {
+            v.extend(stats.to_pairs(&format!("imm{}", i)));
+        }
+        v.push((
+            "memtable:imm_count".to_owned(),
+            self.immutables.len().to_string(),
+        ));
+        v.push(("memtable:flushes".to_owned(), self.flushes.to_string()));
+        v
     }
 }
 
 impl Memtable<Slice, Slice> {
+    /// Walks the whole table to count its tombstones.
+    pub fn stats(&self) -> MemtableStats {
+        MemtableStats {
+            entries: self.length(),
+            bytes: self.size(),
+            deletes: self.iter().filter(|(_, v)| &v[..] == TOMBSTONE).count(),
+        }
+    }
+
     pub fn build_sstable(
         &self,
         opt: &Options,
         path: &Path,
     ) -> MyResult<Option<(String, TableReader)>> {
-        skiplist_to_sstable(&self.map_, opt, path)
+        entries_to_sstable(self.iter(), opt, path)
     }
 }
 
-impl<K: Ord + Clone, V: Clone> Table<K, V> for Memtable<K, V> {
+impl<K: Ord + Clone + ApproximateSize + PrefixHash, V: Clone + ApproximateSize> Table<K, V>
+    for Memtable<K, V>
+{
     fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + PrefixHash,
     {
-        self.map_.get(k)
-    }
-
-    fn get_mut<Q: ?Sized>(&self, k: &Q) -> Option<&mut V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
-        self.map_.get_mut(k)
+        self.rep_.get(k).map(|(_, v)| v)
     }
 
     fn insert(&mut self, k: K, v: V) -> Option<V> {
-        self.map_.insert(k, v)
+        self.insert_shared(UNSEQUENCED, k, v)
     }
 
     fn clear(&mut self) {
-        self.size_ = 0;
-        self.map_.clear()
+        *self.size_.get_mut() = 0;
+        self.rep_.clear()
     }
 
     #[inline]
     fn is_full(&self) -> bool {
-        false
+        self.size() >= self.max_size_
     }
 
     fn size(&self) -> usize {
-        unimplemented!()
+        self.size_.load(SeqCst)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use bincode::serialize;
+
+    use crate::store::StorePayload;
+
     use super::*;
 
     #[test]
@@ -111,4 +269,113 @@ mod test {
         assert_eq!(Some(&Slice::from("b")), table.get(&Slice::from("b")));
         assert_eq!(Some(&Slice::from("c")), table.get(&Slice::from("c")));
     }
+
+    #[test]
+    fn test_range() {
+        let mut table = Memtable::new(1 << 20, 10);
+        for k in &["a", "b", "c", "d", "e"] {
+            table.insert(Slice::from(*k), Slice::from(k.repeat(2)));
+        }
+        let r = table
+            .range(Bound::Included(&b"b"[..]), Bound::Excluded(&b"d"[..]))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Slice::from("b"), Slice::from("bb")),
+                (Slice::from("c"), Slice::from("cc"))
+            ],
+            r
+        );
+        assert_eq!(
+            2,
+            table
+                .range(Bound::Excluded(&b"c"[..]), Bound::Unbounded)
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_update_values() -> MyResult<()> {
+        let mut table = Memtable::new(1 << 20, 10);
+        table.insert(1, 10);
+        table.insert(2, 20);
+        table.update_values(|k, v| Ok(if *k == 2 { Some(v + 1) } else { None }))?;
+        assert_eq!(Some(&10), table.get(&1));
+        assert_eq!(Some(&21), table.get(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_size() {
+        let mut table = Memtable::new(10, 10);
+        table.insert(Slice::from("a"), Slice::from("abc"));
+        assert_eq!(4, table.size());
+        table.insert(Slice::from("a"), Slice::from("ab"));
+        assert_eq!(3, table.size());
+        table.insert(Slice::from("bb"), Slice::from("bbb"));
+        assert_eq!(8, table.size());
+        assert!(!table.is_full());
+        table.insert(Slice::from("c"), Slice::from("c"));
+        assert!(table.is_full());
+        assert_eq!(Some(Slice::from("ab")), table.remove(&Slice::from("a")));
+        assert_eq!(None, table.remove(&Slice::from("a")));
+        assert_eq!(7, table.size());
+        assert!(!table.is_full());
+        table.clear();
+        assert_eq!(0, table.size());
+    }
+
+    #[test]
+    fn test_stats() -> MyResult<()> {
+        let tombstone = Slice::from(serialize(&None::<StorePayload>)?);
+        assert_eq!(TOMBSTONE, &tombstone[..]);
+
+        let mut table = Memtable::new(1 << 20, 10);
+        table.insert(Slice::from("a"), Slice::from("abc"));
+        table.insert(Slice::from("b"), tombstone.clone());
+        table.insert(Slice::from("c"), tombstone.clone());
+        table.insert(Slice::from("c"), Slice::from("c"));
+        assert_eq!(
+            MemtableStats {
+                entries: 3,
+                bytes: 8,
+                deletes: 1,
+            },
+            table.stats()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_shared() {
+        let table = Arc::new(Memtable::new(1 << 20, 12));
+        let threads = (0..4)
+            .map(|i| {
+                let table = table.clone();
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        let k = format!("k{}-{:03}", i, j);
+                        table.insert_shared(j, Slice::from(k.as_str()), Slice::from("v"));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(400, table.length());
+        assert_eq!(400 * 7, table.size());
+        assert_eq!(Some(&Slice::from("v")), table.get(&Slice::from("k3-099")));
+
+        // an older write that comes in late doesn't replace a newer one
+        let k = Slice::from("k0-000");
+        table.insert_shared(7, k.clone(), Slice::from("new"));
+        assert_eq!(
+            Some(Slice::from("old")),
+            table.insert_shared(6, k.clone(), Slice::from("old"))
+        );
+        assert_eq!(Some(&Slice::from("new")), table.get(&k));
+        assert_eq!(400 * 7 + 2, table.size());
+    }
 }
@@ -1,18 +1,24 @@
 use std::borrow::Borrow;
-use std::collections::linked_list;
 use std::collections::LinkedList;
+use std::sync::Arc;
 
 use crate::error::MyResult;
 use crate::memtable::Memtable;
+use crate::memtable::UNSEQUENCED;
 use crate::options::Options;
+use crate::types::ApproximateSize;
+use crate::types::PrefixHash;
 use crate::types::Table;
 
+/// Queue of immutable memtables, newest first. Tables are shared, so the
+/// background flush can build an sstable from the oldest one without holding
+/// the list locked.
 #[derive(Clone)]
 pub struct MemtableList<K: Ord + Clone, V: Clone> {
     max_table_count_: usize,
     per_table_max_size_: usize,
     per_table_max_height_: usize,
-    tables_: LinkedList<Memtable<K, V>>,
+    tables_: LinkedList<Arc<Memtable<K, V>>>,
     opt_: Options,
 }
 
@@ -34,15 +40,21 @@ impl<K: Ord + Clone, V: Clone> MemtableList<K, V> {
     }
 
     pub fn add(&mut self, table: Memtable<K, V>) {
-        self.tables_.push_front(table);
+        self.tables_.push_front(Arc::new(table));
     }
 
-    pub fn consume(&mut self) -> Option<Memtable<K, V>> {
+    /// The next tables to flush, up to `n`, oldest first. They are left in
+    /// the list so they stay readable until their sstable is in place.
+    pub fn oldest(&self, n: usize) -> Vec<Arc<Memtable<K, V>>> {
+        self.tables_.iter().rev().take(n).cloned().collect()
+    }
+
+    pub fn consume(&mut self) -> Option<Arc<Memtable<K, V>>> {
         self.tables_.pop_back()
     }
 
-    pub fn tables_iter(&self) -> linked_list::Iter<Memtable<K, V>> {
-        self.tables_.iter()
+    pub fn tables_iter(&self) -> impl DoubleEndedIterator<Item = &Memtable<K, V>> {
+        self.tables_.iter().map(|t| &**t)
     }
 
     pub fn table_count(&self) -> usize {
@@ -50,11 +62,13 @@ impl<K: Ord + Clone, V: Clone> MemtableList<K, V> {
     }
 }
 
-impl<K: Ord + Clone, V: Clone> Table<K, V> for MemtableList<K, V> {
+impl<K: Ord + Clone + ApproximateSize + PrefixHash, V: Clone + ApproximateSize> Table<K, V>
+    for MemtableList<K, V>
+{
     fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: Ord + PrefixHash,
     {
         for table in &self.tables_ {
             let r = table.get(k);
@@ -65,33 +79,19 @@ impl<K: Ord + Clone, V: Clone> Table<K, V> for MemtableList<K, V> {
         None
     }
 
-    fn get_mut<Q: ?Sized>(&self, k: &Q) -> Option<&mut V>
-    where
-        K: Borrow<Q>,
-        Q: Ord,
-    {
-        for table in &self.tables_ {
-            let r = table.get_mut(k);
-            if r.is_some() {
-                return r;
-            }
-        }
-        None
-    }
-
     fn insert(&mut self, k: K, v: V) -> Option<V> {
         assert!(!self.is_full());
 
-        if self.tables_.is_empty() {
-            self.tables_.push_back(Memtable::new(
+        if self.tables_.iter().all(|t| t.is_full()) {
+            self.tables_.push_back(Arc::new(Memtable::new(
                 self.per_table_max_size_,
                 self.per_table_max_height_,
-            ));
+            )));
         }
 
-        for table in &mut self.tables_ {
+        for table in &self.tables_ {
             if !table.is_full() {
-                return table.insert(k, v);
+                return table.insert_shared(UNSEQUENCED, k, v);
             }
         }
 
@@ -99,9 +99,6 @@ impl<K: Ord + Clone, V: Clone> Table<K, V> for MemtableList<K, V> {
     }
 
     fn clear(&mut self) {
-        for table in &mut self.tables_ {
-            table.clear();
-        }
         self.tables_.clear();
     }
 
@@ -110,6 +107,6 @@ impl<K: Ord + Clone, V: Clone> Table<K, V> for MemtableList<K, V> {
     }
 
     fn size(&self) -> usize {
-        unimplemented!()
+        self.tables_.iter().map(|t| t.size()).sum()
     }
 }
@@ -0,0 +1,494 @@
+use std::borrow::Borrow;
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use skip_list::ConcurrentSkipList;
+
+use crate::options::MemtableRepType;
+use crate::options::Options;
+use crate::types::PrefixHash;
+
+/// A memtable value with the sequence number of its write.
+pub type Sequenced<V> = (u64, V);
+
+pub type RepIter<'a, K, V> = Box<dyn Iterator<Item = (&'a K, &'a Sequenced<V>)> + 'a>;
+
+/// Storage behind a memtable. Of two writes to a key, the one with the higher
+/// sequence number wins, the later one on a tie.
+pub trait MemtableRep<K, V> {
+    /// Returns the write that lost, when the representation knows of it.
+    fn insert(&self, seq: u64, k: K, v: V) -> Option<Sequenced<V>>;
+
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash;
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash;
+
+    fn iter<'a>(&'a self) -> RepIter<'a, K, V>;
+
+    fn range<'a, Q: ?Sized>(&'a self, start: Bound<&Q>, end: Bound<&'a Q>) -> RepIter<'a, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord;
+
+    /// Number of distinct keys.
+    fn length(&self) -> usize;
+
+    fn clear(&mut self);
+}
+
+fn wins<V>(old: &Sequenced<V>, new: &Sequenced<V>) -> bool {
+    new.0 >= old.0
+}
+
+impl<K: Ord + Clone, V: Clone> MemtableRep<K, V> for ConcurrentSkipList<K, Sequenced<V>> {
+    fn insert(&self, seq: u64, k: K, v: V) -> Option<Sequenced<V>> {
+        self.insert_if(k, (seq, v), wins)
+    }
+
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        ConcurrentSkipList::get(self, k)
+    }
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        ConcurrentSkipList::remove(self, k)
+    }
+
+    fn iter<'a>(&'a self) -> RepIter<'a, K, V> {
+        Box::new(ConcurrentSkipList::iter(self))
+    }
+
+    fn range<'a, Q: ?Sized>(&'a self, start: Bound<&Q>, end: Bound<&'a Q>) -> RepIter<'a, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        Box::new(ConcurrentSkipList::range(self, start, end))
+    }
+
+    fn length(&self) -> usize {
+        ConcurrentSkipList::length(self)
+    }
+
+    fn clear(&mut self) {
+        ConcurrentSkipList::clear(self)
+    }
+}
+
+/// Skip lists in buckets picked by a hash of the key prefix, so point lookups
+/// and inserts only walk a short list. Ordered iteration merges the buckets.
+pub struct HashSkipListRep<K, V> {
+    prefix_len_: usize,
+    buckets_: Vec<ConcurrentSkipList<K, Sequenced<V>>>,
+}
+
+impl<K, V> HashSkipListRep<K, V> {
+    pub fn new(bucket_count: usize, prefix_len: usize, max_height: usize) -> Self {
+        assert!(bucket_count > 0);
+        HashSkipListRep {
+            prefix_len_: prefix_len,
+            buckets_: (0..bucket_count)
+                .map(|_| ConcurrentSkipList::new(max_height))
+                .collect(),
+        }
+    }
+
+    fn bucket<Q: ?Sized + PrefixHash>(&self, k: &Q) -> usize {
+        (k.prefix_hash(self.prefix_len_) % self.buckets_.len() as u64) as usize
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for HashSkipListRep<K, V> {
+    fn clone(&self) -> Self {
+        HashSkipListRep {
+            prefix_len_: self.prefix_len_,
+            buckets_: self.buckets_.clone(),
+        }
+    }
+}
+
+impl<K: Ord + Clone + PrefixHash, V: Clone> MemtableRep<K, V> for HashSkipListRep<K, V> {
+    fn insert(&self, seq: u64, k: K, v: V) -> Option<Sequenced<V>> {
+        self.buckets_[self.bucket(&k)].insert_if(k, (seq, v), wins)
+    }
+
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        self.buckets_[self.bucket(k)].get(k)
+    }
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        let b = self.bucket(k);
+        self.buckets_[b].remove(k)
+    }
+
+    fn iter<'a>(&'a self) -> RepIter<'a, K, V> {
+        Box::new(MergedIter::new(
+            self.buckets_
+                .iter()
+                .map(|b| Box::new(b.iter()) as RepIter<K, V>),
+        ))
+    }
+
+    fn range<'a, Q: ?Sized>(&'a self, start: Bound<&Q>, end: Bound<&'a Q>) -> RepIter<'a, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        Box::new(MergedIter::new(
+            self.buckets_
+                .iter()
+                .map(|b| Box::new(b.range(start, end)) as RepIter<K, V>),
+        ))
+    }
+
+    fn length(&self) -> usize {
+        self.buckets_.iter().map(|b| b.length()).sum()
+    }
+
+    fn clear(&mut self) {
+        for b in &mut self.buckets_ {
+            b.clear();
+        }
+    }
+}
+
+/// Merges iterators over disjoint sets of keys into one ordered iterator.
+struct MergedIter<'a, K, V> {
+    iters: Vec<Peekable<RepIter<'a, K, V>>>,
+}
+
+impl<'a, K, V> MergedIter<'a, K, V> {
+    fn new<I: Iterator<Item = RepIter<'a, K, V>>>(iters: I) -> Self {
+        MergedIter {
+            iters: iters.map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a, K: Ord, V> Iterator for MergedIter<'a, K, V> {
+    type Item = (&'a K, &'a Sequenced<V>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let i = self
+            .iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, it)| it.peek().map(|(k, _)| (i, *k)))
+            .min_by(|a, b| a.1.cmp(b.1))?
+            .0;
+        self.iters[i].next()
+    }
+}
+
+type Entry<K, V> = (K, Sequenced<V>);
+
+/// Writes are appended to a vector and only sorted when the table is read,
+/// which suits bulk loads that are read after the memtable is rotated.
+/// Every write is kept until the table is cleared, so overwrites take memory.
+pub struct VectorRep<K, V> {
+    // boxed, so references handed out stay valid while the vector grows
+    entries_: Mutex<Vec<Box<Entry<K, V>>>>,
+    // the newest write of each key in key order, rebuilt after writes
+    sorted_: Mutex<Option<Arc<Vec<*const Entry<K, V>>>>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for VectorRep<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for VectorRep<K, V> {}
+
+impl<K: Ord, V> VectorRep<K, V> {
+    pub fn new() -> Self {
+        VectorRep {
+            entries_: Mutex::new(vec![]),
+            sorted_: Mutex::new(None),
+        }
+    }
+
+    fn sorted(&self) -> Arc<Vec<*const Entry<K, V>>> {
+        let mut sorted = self.sorted_.lock().unwrap();
+        if let Some(s) = &*sorted {
+            return s.clone();
+        }
+        let entries = self.entries_.lock().unwrap();
+        let mut s = entries
+            .iter()
+            .map(|e| &**e as *const Entry<K, V>)
+            .collect::<Vec<_>>();
+        // stable, so writes to a key stay in insertion order
+        s.sort_by(|a, b| unsafe { (**a).0.cmp(&(**b).0) });
+        let mut newest: Vec<*const Entry<K, V>> = Vec::with_capacity(s.len());
+        for e in s {
+            match newest.last_mut() {
+                Some(last) if unsafe { (**last).0 == (*e).0 } => {
+                    if unsafe { wins(&(**last).1, &(*e).1) } {
+                        *last = e;
+                    }
+                }
+                _ => newest.push(e),
+            }
+        }
+        let s = Arc::new(newest);
+        *sorted = Some(s.clone());
+        s
+    }
+
+    fn search<Q: ?Sized>(&self, k: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let sorted = self.sorted();
+        sorted
+            .binary_search_by(|e| unsafe { (**e).0.borrow().cmp(k) })
+            .ok()
+            .map(|i| unsafe { &*sorted[i] })
+    }
+}
+
+impl<K: Ord, V> Default for VectorRep<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for VectorRep<K, V> {
+    fn clone(&self) -> Self {
+        VectorRep {
+            entries_: Mutex::new(self.entries_.lock().unwrap().clone()),
+            sorted_: Mutex::new(None),
+        }
+    }
+}
+
+impl<K: Ord, V> MemtableRep<K, V> for VectorRep<K, V> {
+    fn insert(&self, seq: u64, k: K, v: V) -> Option<Sequenced<V>> {
+        self.entries_.lock().unwrap().push(Box::new((k, (seq, v))));
+        // a read sorting meanwhile holds this lock until it is done, so its
+        // result is dropped here rather than kept without the new write
+        *self.sorted_.lock().unwrap() = None;
+        None
+    }
+
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        self.search(k).map(|e| &e.1)
+    }
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        let newest = self.search(k)? as *const Entry<K, V>;
+        *self.sorted_.get_mut().unwrap() = None;
+        let entries = self.entries_.get_mut().unwrap();
+        let mut r = None;
+        let mut i = 0;
+        while i < entries.len() {
+            if entries[i].0.borrow() != k {
+                i += 1;
+                continue;
+            }
+            let e = entries.remove(i);
+            if &*e as *const Entry<K, V> == newest {
+                r = Some(e.1);
+            }
+        }
+        r
+    }
+
+    fn iter<'a>(&'a self) -> RepIter<'a, K, V> {
+        let sorted = self.sorted();
+        Box::new((0..sorted.len()).map(move |i| {
+            let e = unsafe { &*sorted[i] };
+            (&e.0, &e.1)
+        }))
+    }
+
+    fn range<'a, Q: ?Sized>(&'a self, start: Bound<&Q>, end: Bound<&'a Q>) -> RepIter<'a, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let sorted = self.sorted();
+        let from = match start {
+            Bound::Included(s) => sorted.partition_point(|e| unsafe { (**e).0.borrow() < s }),
+            Bound::Excluded(s) => sorted.partition_point(|e| unsafe { (**e).0.borrow() <= s }),
+            Bound::Unbounded => 0,
+        };
+        Box::new(
+            (from..sorted.len())
+                .map(move |i| {
+                    let e = unsafe { &*sorted[i] };
+                    (&e.0, &e.1)
+                })
+                .take_while(move |(k, _)| match end {
+                    Bound::Included(e) => (*k).borrow() <= e,
+                    Bound::Excluded(e) => (*k).borrow() < e,
+                    Bound::Unbounded => true,
+                }),
+        )
+    }
+
+    fn length(&self) -> usize {
+        self.sorted().len()
+    }
+
+    fn clear(&mut self) {
+        self.entries_.get_mut().unwrap().clear();
+        *self.sorted_.get_mut().unwrap() = None;
+    }
+}
+
+/// The representation picked by `Options::memtable_rep`.
+pub enum Rep<K, V> {
+    SkipList(ConcurrentSkipList<K, Sequenced<V>>),
+    HashSkipList(HashSkipListRep<K, V>),
+    Vector(VectorRep<K, V>),
+}
+
+impl<K: Ord, V> Rep<K, V> {
+    pub fn with_options(opt: &Options) -> Self {
+        match opt.memtable_rep {
+            MemtableRepType::SkipList => {
+                Rep::SkipList(ConcurrentSkipList::new(opt.mem_table_max_height))
+            }
+            MemtableRepType::HashSkipList => Rep::HashSkipList(HashSkipListRep::new(
+                opt.memtable_hash_buckets,
+                opt.memtable_prefix_len,
+                opt.mem_table_max_height,
+            )),
+            MemtableRepType::Vector => Rep::Vector(VectorRep::new()),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for Rep<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Rep::SkipList(rep) => Rep::SkipList(rep.clone()),
+            Rep::HashSkipList(rep) => Rep::HashSkipList(rep.clone()),
+            Rep::Vector(rep) => Rep::Vector(rep.clone()),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $rep:ident => $e:expr) => {
+        match $self {
+            Rep::SkipList($rep) => $e,
+            Rep::HashSkipList($rep) => $e,
+            Rep::Vector($rep) => $e,
+        }
+    };
+}
+
+impl<K: Ord + Clone + PrefixHash, V: Clone> MemtableRep<K, V> for Rep<K, V> {
+    fn insert(&self, seq: u64, k: K, v: V) -> Option<Sequenced<V>> {
+        dispatch!(self, rep => MemtableRep::insert(rep, seq, k, v))
+    }
+
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        dispatch!(self, rep => MemtableRep::get(rep, k))
+    }
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<Sequenced<V>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + PrefixHash,
+    {
+        dispatch!(self, rep => MemtableRep::remove(rep, k))
+    }
+
+    fn iter<'a>(&'a self) -> RepIter<'a, K, V> {
+        dispatch!(self, rep => MemtableRep::iter(rep))
+    }
+
+    fn range<'a, Q: ?Sized>(&'a self, start: Bound<&Q>, end: Bound<&'a Q>) -> RepIter<'a, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        dispatch!(self, rep => MemtableRep::range(rep, start, end))
+    }
+
+    fn length(&self) -> usize {
+        dispatch!(self, rep => MemtableRep::length(rep))
+    }
+
+    fn clear(&mut self) {
+        dispatch!(self, rep => MemtableRep::clear(rep))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reps() -> Vec<Rep<u32, u32>> {
+        vec![
+            Rep::SkipList(ConcurrentSkipList::new(10)),
+            Rep::HashSkipList(HashSkipListRep::new(4, 4, 10)),
+            Rep::Vector(VectorRep::new()),
+        ]
+    }
+
+    #[test]
+    fn test_reps() {
+        for mut rep in reps() {
+            for k in (0..20).rev() {
+                rep.insert(1, k, k * 10);
+            }
+            rep.insert(2, 3, 31);
+            // an older write doesn't replace a newer one
+            rep.insert(0, 3, 0);
+            assert_eq!(20, rep.length());
+            assert_eq!(Some(&(2, 31)), rep.get(&3));
+            assert_eq!(None, rep.get(&20));
+
+            let keys = rep.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+            assert_eq!((0..20).collect::<Vec<_>>(), keys);
+            let keys = rep
+                .range(Bound::Excluded(&5), Bound::Included(&8))
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>();
+            assert_eq!(vec![6, 7, 8], keys);
+
+            assert_eq!(Some((2, 31)), rep.remove(&3));
+            assert_eq!(None, rep.get(&3));
+            assert_eq!(19, rep.length());
+            rep.clear();
+            assert_eq!(0, rep.length());
+            assert_eq!(0, rep.iter().count());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+
+use crate::error::MyResult;
+use crate::slice::Slice;
+use crate::store::StorePayload;
+
+/// First byte of a memtable value that holds merge operands rather than a
+/// whole value. Whole values are bincode encoded `Option<StorePayload>`s,
+/// which always start with 0 or 1. Operands never reach an sstable: they are
+/// folded into the value below them before a memtable is flushed.
+const MERGE_VALUE_TAG: u8 = 0xa1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MergeKind {
+    Append,
+    Prepend,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeOperand {
+    pub kind: MergeKind,
+    pub payload: StorePayload,
+}
+
+impl MergeOperand {
+    pub fn new(kind: MergeKind, payload: StorePayload) -> Self {
+        MergeOperand { kind, payload }
+    }
+
+    /// Appends or prepends the operand's data. As in memcached, the item
+    /// keeps its flags and expiry; only the cas unique is the operand's.
+    pub fn apply(&self, mut base: StorePayload) -> StorePayload {
+        let sp = &self.payload;
+        match self.kind {
+            MergeKind::Append => base.data.extend(&sp.data),
+            MergeKind::Prepend => {
+                let mut data = sp.data.clone();
+                data.extend(&base.data);
+                base.data = data;
+            }
+        }
+        base.bytes += sp.bytes;
+        base.cas = sp.cas;
+        base
+    }
+}
+
+pub fn encode_operands(ops: &[MergeOperand]) -> MyResult<Slice> {
+    let mut buf = vec![MERGE_VALUE_TAG];
+    buf.extend(serialize(ops)?);
+    Ok(Slice::from(buf))
+}
+
+/// Returns the operands stored in a memtable value, oldest first, or None for
+/// whole values.
+pub fn decode_operands(v: &[u8]) -> MyResult<Option<Vec<MergeOperand>>> {
+    if v.first() != Some(&MERGE_VALUE_TAG) {
+        return Ok(None);
+    }
+    Ok(Some(deserialize(&v[1..])?))
+}
+
+/// Stacks `ops` on top of the value `older`. The result is again an operand
+/// list when `older` is one. None when there is no live value to merge into.
+pub fn merge_values(older: Option<&[u8]>, mut ops: Vec<MergeOperand>) -> MyResult<Option<Slice>> {
+    let older = match older {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if let Some(mut older_ops) = decode_operands(older)? {
+        older_ops.append(&mut ops);
+        return Ok(Some(encode_operands(&older_ops)?));
+    }
+    let base: Option<StorePayload> = deserialize(older)?;
+    match base {
+        Some(base) if !base.is_expired() => {
+            let merged = ops.iter().fold(base, |base, op| op.apply(base));
+            Ok(Some(Slice::from(serialize(&Some(merged))?)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The value to store when `value` is written over `older`: operands stack on
+/// top of what is there, anything else replaces it. None when operands have
+/// nothing to merge into.
+pub fn stack_value(older: Option<&Slice>, value: Slice) -> MyResult<Option<Slice>> {
+    let older = match older {
+        Some(older) => older,
+        None => return Ok(Some(value)),
+    };
+    match decode_operands(&value[..])? {
+        Some(ops) => merge_values(Some(&older[..]), ops),
+        None => Ok(Some(value)),
+    }
+}
+
+/// Folds the operands in `v`, if any, into the whole value below them,
+/// which `base` looks up. A missing base leaves a tombstone. None when `v` is
+/// a whole value already.
+pub fn resolve_value<F>(v: &[u8], base: F) -> MyResult<Option<Slice>>
+where
+    F: FnOnce() -> MyResult<Option<Slice>>,
+{
+    let ops = match decode_operands(v)? {
+        Some(ops) => ops,
+        None => return Ok(None),
+    };
+    let base = base()?;
+    match merge_values(base.as_ref().map(|x| &x[..]), ops)? {
+        Some(merged) => Ok(Some(merged)),
+        None => Ok(Some(Slice::from(serialize(&None::<StorePayload>)?))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload(data: &str) -> StorePayload {
+        StorePayload::new(Slice::from(data), 1, 0, data.len(), 0)
+    }
+
+    #[test]
+    fn test_merge() -> MyResult<()> {
+        let base = Slice::from(serialize(&Some(payload("b")))?);
+        let append = MergeOperand::new(MergeKind::Append, payload("c"));
+        let prepend = MergeOperand::new(MergeKind::Prepend, payload("a"));
+
+        let ops = encode_operands(&[append.clone()])?;
+        assert_eq!(Some(vec![append.clone()]), decode_operands(&ops[..])?);
+        assert_eq!(None, decode_operands(&base[..])?);
+
+        // operands stack on operands
+        let stacked = merge_values(Some(&ops[..]), vec![prepend.clone()])?.unwrap();
+        assert_eq!(
+            Some(vec![append.clone(), prepend.clone()]),
+            decode_operands(&stacked[..])?
+        );
+
+        let merged = resolve_value(&stacked[..], || Ok(Some(base.clone())))?.unwrap();
+        let merged: Option<StorePayload> = deserialize(&merged[..])?;
+        assert_eq!(Some(payload("abc")), merged);
+
+        let tombstone = Slice::from(serialize(&None::<StorePayload>)?);
+        assert_eq!(
+            None,
+            merge_values(Some(&tombstone[..]), vec![append.clone()])?
+        );
+        let expired = StorePayload::new(Slice::from("b"), 1, 1, 1, 1);
+        let expired = Slice::from(serialize(&Some(expired))?);
+        assert_eq!(
+            None,
+            merge_values(Some(&expired[..]), vec![append.clone()])?
+        );
+        assert_eq!(Some(tombstone), resolve_value(&ops[..], || Ok(None))?);
+
+        // the flags and expiry are the item's, whatever the operand has
+        let op = MergeOperand::new(
+            MergeKind::Append,
+            StorePayload::new(Slice::from("c"), 7, 100, 1, 5),
+        );
+        let merged = op.apply(payload("b"));
+        assert_eq!((1, 0, 0), (merged.flags, merged.ttl, merged.created_at));
+        assert_eq!(None, resolve_value(&base[..], || Ok(None))?);
+        Ok(())
+    }
+}
@@ -5,11 +5,29 @@ use sstable::TableIter;
 pub struct Merger<'a> {
     iters: Vec<TableIter<'a>>,
     i: Option<usize>,
+    track_shadowed: bool,
+    shadowed: Vec<Vec<u8>>,
 }
 
 impl<'a> Merger<'a> {
     pub fn new(iters: Vec<TableIter<'a>>) -> Self {
-        Self { iters, i: None }
+        Self {
+            iters,
+            i: None,
+            track_shadowed: false,
+            shadowed: vec![],
+        }
+    }
+
+    /// Keeps the values of entries dropped by `advance` because an earlier
+    /// iterator has the same key, to be collected with `take_shadowed`.
+    pub fn with_shadowed_tracking(mut self) -> Self {
+        self.track_shadowed = true;
+        self
+    }
+
+    pub fn take_shadowed(&mut self) -> Vec<Vec<u8>> {
+        ::std::mem::replace(&mut self.shadowed, vec![])
     }
 }
 
@@ -46,6 +64,10 @@ impl<'a> SsIterator for Merger<'a> {
                 } else if pk_ < &nk {
                     let iter = &mut self.iters[i];
                     iter.prev();
+                } else if self.track_shadowed {
+                    if let Some(v) = self.iters[i].current_v() {
+                        self.shadowed.push(v);
+                    }
                 }
             } else {
                 pk = Some(nk);
@@ -145,7 +167,7 @@ mod test {
         t.add("c".as_bytes(), "0".as_bytes())?;
         t.flush()?;
         let t = TableReader::new(path, opt.clone())?;
-        ts.push(t.iter());
+        ts.push(t.iter()?);
         let path = Path::new("/tmp/test_merger1");
         let mut t = TableBuilder::new(path, opt.clone())?;
         t.add("c".as_bytes(), "c".as_bytes())?;
@@ -153,7 +175,7 @@ mod test {
         t.add("e".as_bytes(), "0".as_bytes())?;
         t.flush()?;
         let t = TableReader::new(path, opt.clone())?;
-        ts.push(t.iter());
+        ts.push(t.iter()?);
         let path = Path::new("/tmp/test_merger2");
         let mut t = TableBuilder::new(path, opt.clone())?;
         t.add("a".as_bytes(), "a".as_bytes())?;
@@ -161,7 +183,7 @@ mod test {
         t.add("f".as_bytes(), "f".as_bytes())?;
         t.flush()?;
         let t = TableReader::new(path, opt.clone())?;
-        ts.push(t.iter());
+        ts.push(t.iter()?);
         ts.reverse();
         let mut m = Merger::new(ts);
         while let Some((k, v)) = m.next() {
@@ -170,4 +192,38 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_shadowed() -> MyResult<()> {
+        let opt = Options::default();
+        let opt = opt.get_table_opt();
+        let path0 = Path::new("/tmp/test_merger_shadowed0");
+        let mut t = TableBuilder::new(path0, opt.clone())?;
+        t.add("a".as_bytes(), "new".as_bytes())?;
+        t.add("b".as_bytes(), "b".as_bytes())?;
+        t.flush()?;
+        let path1 = Path::new("/tmp/test_merger_shadowed1");
+        let mut t = TableBuilder::new(path1, opt.clone())?;
+        t.add("a".as_bytes(), "old".as_bytes())?;
+        t.add("c".as_bytes(), "c".as_bytes())?;
+        t.flush()?;
+        let t0 = TableReader::new(path0, opt.clone())?;
+        let t1 = TableReader::new(path1, opt.clone())?;
+
+        let mut m = Merger::new(vec![t0.iter(), t1.iter()]).with_shadowed_tracking();
+        let mut kvs = vec![];
+        while let Some(kv) = m.next() {
+            kvs.push(kv);
+        }
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), b"new".to_vec()),
+                (b"b".to_vec(), b"b".to_vec()),
+                (b"c".to_vec(), b"c".to_vec()),
+            ],
+            kvs
+        );
+        assert_eq!(vec![b"old".to_vec()], m.take_shadowed());
+        Ok(())
+    }
 }
@@ -1,5 +1,10 @@
 use sstable::Options as TableOptions;
 use std::path::Path;
+use std::sync::Arc;
+
+use crate::compaction_filter::SharedCompactionFilter;
+use crate::env::PosixEnv;
+use crate::env::SharedEnv;
 
 pub const KB: usize = 1 << 10;
 pub const MB: usize = KB * KB;
@@ -9,19 +14,196 @@ pub const TB: usize = KB * GB;
 const BLOCK_MAX_SIZE: usize = 4 * KB;
 const BLOCK_CACHE_CAPACITY: usize = 8 * MB;
 const WRITE_BUFFER_SIZE: usize = 4 * MB;
+const SCAN_SPILL_THRESHOLD: usize = 64 * MB;
+const BLOB_GC_RATIO: f64 = 0.5;
+const WAL_RECYCLE_NUM: usize = 4;
+const WAL_SEG_MAX_SIZE: usize = 4 * MB;
+const MEMTABLE_HASH_BUCKETS: usize = 1024;
+const MEMTABLE_PREFIX_LEN: usize = 8;
+const WRITE_LOCK_STRIPES: usize = 256;
+const TIERED_SIZE_RATIO: usize = 1;
+const TIERED_MIN_MERGE_WIDTH: usize = 2;
+const MANIFEST_MAX_EDITS: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WALCompression {
+    None = 0,
+    Snappy = 1,
+    Zstd = 2,
+}
+
+pub fn int_to_wal_compression(i: u32) -> Option<WALCompression> {
+    match i {
+        0 => Some(WALCompression::None),
+        1 => Some(WALCompression::Snappy),
+        2 => Some(WALCompression::Zstd),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WALRecoveryMode {
+    /// Any corrupt record fails recovery.
+    Strict,
+    /// Replay stops at the first corrupt record.
+    PointInTime,
+    /// Corrupt records are skipped.
+    SkipCorrupted,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MemtableRepType {
+    SkipList,
+    /// Skip lists bucketed by key prefix, for point lookups on many prefixes.
+    HashSkipList,
+    /// Unsorted until read, for bulk loads.
+    Vector,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompactionStyle {
+    /// Files move down through levels of growing size.
+    Leveled,
+    /// Sorted runs of similar size are merged in level 0, which writes less
+    /// at the cost of more files to read.
+    Tiered,
+}
+
+/// How durable a single write is.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct WriteOptions {
+    /// Syncs the WAL before the write is acknowledged, even without
+    /// `wal_sync`.
+    pub sync: bool,
+    /// Leaves the write out of the WAL, for data that can be lost in a
+    /// crash, e.g. cached values. It is durable only once its memtable is
+    /// flushed, and secondaries and WAL tails don't see it until then.
+    pub disable_wal: bool,
+}
 
 #[derive(Clone)]
 pub struct Options {
     pub max_level: usize,
     pub work_dir: String,
+    /// The file system `work_dir` is in. With a `MemEnv` the server runs as
+    /// a pure cache. Checkpoints, backups and ingested sstables are read and
+    /// written on the local file system regardless.
+    pub env: SharedEnv,
+    /// Keyspaces besides the default one, each with its own memtables and
+    /// levels. Keys are addressed to one as `<name>:<key>`. They share the
+    /// WAL of the default keyspace, whose records name their keyspace, and
+    /// a segment is kept until every keyspace has flushed its writes in it.
+    /// A keyspace whose unflushed writes are all that keeps the oldest of
+    /// more than `imm_mem_table_max_count + 1` segments is flushed early.
+    pub namespaces: Vec<String>,
     pub sst_max_size: usize,
     pub mem_table_max_size: usize,
     pub mem_table_max_height: usize,
     pub imm_mem_table_max_count: usize,
+    /// Immutable memtables waiting together are flushed into one sstable,
+    /// up to this many, so bursts of writes leave fewer level 0 files.
+    pub max_memtables_per_flush: usize,
+    pub memtable_rep: MemtableRepType,
+    pub memtable_hash_buckets: usize,
+    pub memtable_prefix_len: usize,
 
+    pub compaction_style: CompactionStyle,
     pub l0_compaction_trigger: usize,
+    /// Tiered compaction merges consecutive runs while the next older run is
+    /// at most `tiered_size_ratio` percent larger than the runs picked so
+    /// far, once at least `tiered_min_merge_width` runs qualify.
+    pub tiered_size_ratio: usize,
+    pub tiered_min_merge_width: usize,
+    /// Splits a leveled compaction into up to this many key ranges merged on
+    /// their own threads.
+    pub max_subcompactions: usize,
+    /// While level 1 is compacted ahead of level 0, the newest level 0 files
+    /// are merged into one once at least this many pile up, so reads go
+    /// through fewer files in the meantime; 0 turns this off.
+    pub intra_l0_min_files: usize,
+    /// A file is compacted once gets looked for this many keys in it that
+    /// weren't there, or one per 16K of the file if that's more; 0 turns
+    /// seek compaction off.
+    pub seek_compaction_min_misses: usize,
+    /// Level 1 may hold this many bytes before it is compacted, each deeper
+    /// level `max_bytes_for_level_multiplier` times as many.
+    pub max_bytes_for_level_base: usize,
+    pub max_bytes_for_level_multiplier: f64,
+    /// Sizes the levels above the deepest non-empty one from the data it
+    /// holds instead, a multiplier apart, so most data sits at the bottom
+    /// however much there is. No level gets less than
+    /// `max_bytes_for_level_base`.
+    pub dynamic_level_bytes: bool,
+    /// Files written longer ago than this are compacted even if nothing else
+    /// would pick them, so expired values and deletes are eventually dropped
+    /// from key ranges no longer written to; 0 turns this off.
+    pub periodic_compaction_secs: u64,
+    pub compaction_filter: Option<SharedCompactionFilter>,
+    /// Writes are delayed by `write_slowdown_ms` once level 0 has this many
+    /// files or this many immutable memtables wait to be flushed, and
+    /// rejected at the stop triggers. The immutable memtable stop trigger is
+    /// `imm_mem_table_max_count`.
+    pub l0_slowdown_trigger: usize,
+    pub l0_stop_trigger: usize,
+    pub imm_slowdown_trigger: usize,
+    pub write_slowdown_ms: usize,
 
     pub thread_sleep_ms: usize,
+    /// Starts no flush, compaction or expiry threads with the store, for
+    /// tests that drive them by hand.
+    pub disable_background_jobs: bool,
+    /// Writes that read the key first lock one of this many stripes, picked
+    /// by key hash.
+    pub write_lock_stripes: usize,
+    /// How often keys written with a ttl are checked for expiry and deleted,
+    /// 0 leaves expired values to compaction.
+    pub expiry_scan_interval_ms: usize,
+    /// How often a secondary instance catches up with its primary.
+    pub secondary_refresh_interval_ms: usize,
+    /// Bytes per second flushes and compactions may write, 0 for no limit.
+    pub rate_limit: usize,
+    /// Values longer than this are refused with `SERVER_ERROR object too
+    /// large for cache`.
+    pub max_item_size: usize,
+
+    pub scan_spill_threshold: usize,
+
+    pub block_cache_capacity: usize,
+    /// How many recently read keys to keep the values of, misses included,
+    /// in front of the memtables and sstables; 0 turns the row cache off.
+    pub row_cache_capacity: usize,
+
+    pub paranoid_checks: bool,
+    /// Orphan sstables and blob files found at startup are moved to `lost/`
+    /// rather than deleted. On by default.
+    pub quarantine_orphan_files: bool,
+    /// Edits appended to the manifest before it is rewritten as a snapshot.
+    pub manifest_max_edits: usize,
+
+    pub flush_on_close: bool,
+
+    /// fsync the WAL once per group commit.
+    pub wal_sync: bool,
+    pub wal_compression: WALCompression,
+    /// Bytes of WAL records buffered before they are written out, 0 writes
+    /// every batch through. Buffered records are written on `wal_sync`,
+    /// segment rotation and close.
+    pub wal_buffer_size: usize,
+    pub wal_recovery_mode: WALRecoveryMode,
+    /// The memtable is rotated once its WAL segment grows past this, even if
+    /// the memtable itself isn't full, which bounds replay after a crash.
+    pub wal_seg_max_size: usize,
+    pub wal_preallocate: bool,
+    /// How many consumed WAL segments to keep around for reuse.
+    pub wal_recycle_num: usize,
+
+    /// Consumed WAL segments are moved here instead of being deleted.
+    pub wal_archive_dir: Option<String>,
+    pub wal_archive_max_files: usize,
+    pub wal_archive_ttl_secs: u64,
+
+    pub blob_min_size: usize,
+    pub blob_gc_ratio: f64,
 
     pub table_opt: TableOptions,
 }
@@ -41,14 +223,67 @@ impl Default for Options {
         Options {
             max_level: 7,
             work_dir: "/tmp/mirdb".into(),
+            env: Arc::new(PosixEnv),
+            namespaces: vec![],
             sst_max_size: MB * 100,
             mem_table_max_size: MB * 4,
             mem_table_max_height: 1 << 5,
             imm_mem_table_max_count: 1 << 4,
+            max_memtables_per_flush: 4,
+            memtable_rep: MemtableRepType::SkipList,
+            memtable_hash_buckets: MEMTABLE_HASH_BUCKETS,
+            memtable_prefix_len: MEMTABLE_PREFIX_LEN,
 
+            compaction_style: CompactionStyle::Leveled,
             l0_compaction_trigger: 4,
+            tiered_size_ratio: TIERED_SIZE_RATIO,
+            tiered_min_merge_width: TIERED_MIN_MERGE_WIDTH,
+            max_subcompactions: 1,
+            intra_l0_min_files: 4,
+            seek_compaction_min_misses: 100,
+            max_bytes_for_level_base: 10 * MB,
+            max_bytes_for_level_multiplier: 10.,
+            dynamic_level_bytes: false,
+            periodic_compaction_secs: 0,
+            compaction_filter: None,
+            l0_slowdown_trigger: 8,
+            l0_stop_trigger: 12,
+            imm_slowdown_trigger: 1 << 3,
+            write_slowdown_ms: 1,
 
             thread_sleep_ms: 500,
+            disable_background_jobs: false,
+            write_lock_stripes: WRITE_LOCK_STRIPES,
+            expiry_scan_interval_ms: 0,
+            secondary_refresh_interval_ms: 1000,
+            rate_limit: 0,
+            max_item_size: MB,
+
+            scan_spill_threshold: SCAN_SPILL_THRESHOLD,
+
+            block_cache_capacity: BLOCK_CACHE_CAPACITY,
+            row_cache_capacity: 0,
+
+            paranoid_checks: false,
+            quarantine_orphan_files: true,
+            manifest_max_edits: MANIFEST_MAX_EDITS,
+
+            flush_on_close: true,
+
+            wal_sync: false,
+            wal_compression: WALCompression::Snappy,
+            wal_buffer_size: 0,
+            wal_recovery_mode: WALRecoveryMode::PointInTime,
+            wal_seg_max_size: WAL_SEG_MAX_SIZE,
+            wal_preallocate: true,
+            wal_recycle_num: WAL_RECYCLE_NUM,
+
+            wal_archive_dir: None,
+            wal_archive_max_files: 0,
+            wal_archive_ttl_secs: 0,
+
+            blob_min_size: 0,
+            blob_gc_ratio: BLOB_GC_RATIO,
 
             table_opt,
         }
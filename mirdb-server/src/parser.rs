@@ -1,7 +1,12 @@
-use crate::parser_util::macros::{digit, space, u32_parser, usize_parser, IRResult};
+use crate::options::WriteOptions;
+use crate::parser_util::macros::{digit, space, u32_parser, u64_parser, usize_parser, IRResult};
+use crate::request::CompactionCommand;
 use crate::request::GetterType;
+use crate::request::MetaCommand;
+use crate::request::MetaFlags;
 use crate::request::Request;
 use crate::request::SetterType;
+use crate::request::StatsType;
 use crate::slice::Slice;
 
 gen_parser!(key_parser<&[u8], &[u8]>, is_not!(b" \t\r\n\0"));
@@ -11,6 +16,11 @@ gen_parser!(
     alt!(tag!(b"gets") | tag!(b"get"))
 );
 
+gen_parser!(
+    get_and_touch_name_parser<&[u8]>,
+    alt!(tag!(b"gats") | tag!(b"gat"))
+);
+
 gen_parser!(
     setter_name_parser<&[u8]>,
     alt!(tag!(b"set") | tag!(b"add") | tag!(b"replace") | tag!(b"append") | tag!(b"prepend"))
@@ -19,12 +29,29 @@ gen_parser!(
 gen_parser!(
     getter<Request>,
     chain!(
-        getter: getter_name_parser
+        getter: map_opt!(getter_name_parser, to_getter_type)
             >> space
             >> keys: split!(space, key_parser)
             >> tag!(b"\r\n")
             >> (Request::Getter {
-                getter: to_getter_type(getter),
+                getter,
+                keys: keys.into_iter().map(Slice::from).collect(),
+            })
+    )
+);
+
+gen_parser!(
+    get_and_touch<Request>,
+    chain!(
+        getter: map_opt!(get_and_touch_name_parser, to_getter_type)
+            >> space
+            >> ttl: u32_parser
+            >> space
+            >> keys: split!(space, key_parser)
+            >> tag!(b"\r\n")
+            >> (Request::GetAndTouch {
+                getter,
+                ttl,
                 keys: keys.into_iter().map(Slice::from).collect(),
             })
     )
@@ -34,29 +61,41 @@ fn unwrap_no_reply(x: Option<&[u8]>) -> bool {
     x.is_some()
 }
 
-fn to_getter_type(x: &[u8]) -> GetterType {
+gen_parser!(
+    write_option_parser<&[u8]>,
+    alt!(tag!(b"sync") | tag!(b"nowal"))
+);
+
+fn to_write_options(x: Option<&[u8]>) -> WriteOptions {
+    WriteOptions {
+        sync: x == Some(&b"sync"[..]),
+        disable_wal: x == Some(&b"nowal"[..]),
+    }
+}
+
+fn to_getter_type(x: &[u8]) -> Option<GetterType> {
     match x {
-        b"get" => GetterType::Get,
-        b"gets" => GetterType::Gets,
-        _ => panic!(format!("unknown getter {:?}", x)),
+        b"get" | b"gat" => Some(GetterType::Get),
+        b"gets" | b"gats" => Some(GetterType::Gets),
+        _ => None,
     }
 }
 
-fn to_setter_type(x: &[u8]) -> SetterType {
+fn to_setter_type(x: &[u8]) -> Option<SetterType> {
     match x {
-        b"set" => SetterType::Set,
-        b"add" => SetterType::Add,
-        b"replace" => SetterType::Replace,
-        b"append" => SetterType::Append,
-        b"prepend" => SetterType::Prepend,
-        _ => panic!(format!("unknown setter {:?}", x)),
+        b"set" => Some(SetterType::Set),
+        b"add" => Some(SetterType::Add),
+        b"replace" => Some(SetterType::Replace),
+        b"append" => Some(SetterType::Append),
+        b"prepend" => Some(SetterType::Prepend),
+        _ => None,
     }
 }
 
 gen_parser!(
     setter<Request>,
     chain!(
-        setter: setter_name_parser
+        setter: map_opt!(setter_name_parser, to_setter_type)
             >> space
             >> key: key_parser
             >> space
@@ -67,55 +106,414 @@ gen_parser!(
             >> bytes: usize_parser
             >> opt!(space)
             >> no_reply: opt!(tag!(b"noreply"))
+            >> opt!(space)
+            >> write_opt: opt!(write_option_parser)
             >> tag!(b"\r\n")
             >> payload: take_at_least!(bytes, b"\r\n")
             >> tag!(b"\r\n")
             >> (Request::Setter {
-                setter: to_setter_type(setter),
+                setter,
                 key: Slice::from(key),
                 flags,
                 ttl,
                 bytes,
                 payload: Slice::from(payload),
                 no_reply: unwrap_no_reply(no_reply),
+                write_opt: to_write_options(write_opt),
             })
     )
 );
 
+gen_parser!(
+    cas<Request>,
+    chain!(
+        tag!(b"cas")
+            >> space
+            >> key: key_parser
+            >> space
+            >> flags: u32_parser
+            >> space
+            >> ttl: u32_parser
+            >> space
+            >> bytes: usize_parser
+            >> space
+            >> unique: u64_parser
+            >> opt!(space)
+            >> no_reply: opt!(tag!(b"noreply"))
+            >> opt!(space)
+            >> write_opt: opt!(write_option_parser)
+            >> tag!(b"\r\n")
+            >> payload: take_at_least!(bytes, b"\r\n")
+            >> tag!(b"\r\n")
+            >> (Request::Setter {
+                setter: SetterType::Cas(unique),
+                key: Slice::from(key),
+                flags,
+                ttl,
+                bytes,
+                payload: Slice::from(payload),
+                no_reply: unwrap_no_reply(no_reply),
+                write_opt: to_write_options(write_opt),
+            })
+    )
+);
+
+/// The words after `delete`: keys, then maybe `noreply` and a write option.
+/// A lone word is always a key.
+fn to_deleter(mut words: Vec<&[u8]>) -> Request {
+    let mut write_opt = None;
+    if words.len() > 1 && [&b"sync"[..], b"nowal"].contains(&words[words.len() - 1]) {
+        write_opt = words.pop();
+    }
+    let mut no_reply = None;
+    if words.len() > 1 && words[words.len() - 1] == &b"noreply"[..] {
+        no_reply = words.pop();
+    }
+    let no_reply = unwrap_no_reply(no_reply);
+    let write_opt = to_write_options(write_opt);
+    if words.len() == 1 {
+        Request::Deleter {
+            key: Slice::from(words[0]),
+            no_reply,
+            write_opt,
+        }
+    } else {
+        Request::MultiDeleter {
+            keys: words.into_iter().map(Slice::from).collect(),
+            no_reply,
+            write_opt,
+        }
+    }
+}
+
 gen_parser!(
     deleter<Request>,
     chain!(
         tag!(b"delete")
+            >> space
+            >> words: split!(space, key_parser)
+            >> tag!(b"\r\n")
+            >> (to_deleter(words))
+    )
+);
+
+gen_parser!(
+    scan<Request>,
+    chain!(
+        tag!(b"scan")
+            >> space
+            >> start: key_parser
+            >> space
+            >> end: key_parser
+            >> space
+            >> limit: usize_parser
+            >> tag!(b"\r\n")
+            >> (Request::Scan {
+                start: Slice::from(start),
+                end: Slice::from(end),
+                limit,
+            })
+    )
+);
+
+gen_parser!(
+    keys<Request>,
+    chain!(
+        tag!(b"keys")
+            >> space
+            >> prefix: key_parser
+            >> space
+            >> limit: usize_parser
+            >> tag!(b"\r\n")
+            >> (Request::Keys {
+                prefix: Slice::from(prefix),
+                limit,
+            })
+    )
+);
+
+gen_parser!(
+    arith_name_parser<&[u8]>,
+    alt!(tag!(b"incr") | tag!(b"decr"))
+);
+
+gen_parser!(
+    arith<Request>,
+    chain!(
+        name: arith_name_parser
             >> space
             >> key: key_parser
+            >> space
+            >> delta: u64_parser
             >> opt!(space)
             >> no_reply: opt!(tag!(b"noreply"))
             >> tag!(b"\r\n")
-            >> (Request::Deleter {
+            >> (Request::Arith {
                 key: Slice::from(key),
+                decr: name == &b"decr"[..],
+                delta,
+                initial: None,
                 no_reply: unwrap_no_reply(no_reply),
             })
     )
 );
 
+gen_parser!(
+    meta_flags_parser<Vec<&[u8]>>,
+    chain!(space >> flags: split!(space, key_parser) >> (flags))
+);
+
+fn to_meta_flags(x: Option<Vec<&[u8]>>) -> MetaFlags {
+    let flags = x.unwrap_or_default();
+    MetaFlags(
+        flags
+            .into_iter()
+            .map(|f| (f[0], Slice::from(&f[1..])))
+            .collect(),
+    )
+}
+
+fn to_meta_command(x: &[u8]) -> Option<MetaCommand> {
+    match x {
+        b"mg" => Some(MetaCommand::Get),
+        b"md" => Some(MetaCommand::Delete),
+        b"ma" => Some(MetaCommand::Arith),
+        _ => None,
+    }
+}
+
+gen_parser!(
+    meta_name_parser<&[u8]>,
+    alt!(tag!(b"mg") | tag!(b"md") | tag!(b"ma"))
+);
+
+gen_parser!(
+    meta<Request>,
+    chain!(
+        command: map_opt!(meta_name_parser, to_meta_command)
+            >> space
+            >> key: key_parser
+            >> flags: opt!(meta_flags_parser)
+            >> opt!(space)
+            >> tag!(b"\r\n")
+            >> (Request::Meta {
+                command,
+                key: Slice::from(key),
+                flags: to_meta_flags(flags),
+            })
+    )
+);
+
+gen_parser!(
+    meta_set<Request>,
+    chain!(
+        tag!(b"ms")
+            >> space
+            >> key: key_parser
+            >> space
+            >> bytes: usize_parser
+            >> flags: opt!(meta_flags_parser)
+            >> opt!(space)
+            >> tag!(b"\r\n")
+            >> payload: take_at_least!(bytes, b"\r\n")
+            >> tag!(b"\r\n")
+            >> (Request::Meta {
+                command: MetaCommand::Set(Slice::from(payload)),
+                key: Slice::from(key),
+                flags: to_meta_flags(flags),
+            })
+    )
+);
+
+gen_parser!(
+    meta_noop<Request>,
+    chain!(tag!(b"mn") >> tag!(b"\r\n") >> (Request::MetaNoop))
+);
+
 gen_parser!(
     info<Request>,
     chain!(tag!(b"info") >> tag!(b"\r\n") >> (Request::Info))
 );
 
+gen_parser!(
+    quit<Request>,
+    chain!(tag!(b"quit") >> tag!(b"\r\n") >> (Request::Quit))
+);
+
+gen_parser!(
+    version<Request>,
+    chain!(tag!(b"version") >> tag!(b"\r\n") >> (Request::Version))
+);
+
+gen_parser!(
+    verbosity<Request>,
+    chain!(
+        tag!(b"verbosity")
+            >> space
+            >> level: u32_parser
+            >> opt!(space)
+            >> no_reply: opt!(tag!(b"noreply"))
+            >> tag!(b"\r\n")
+            >> (Request::Verbosity {
+                level,
+                no_reply: unwrap_no_reply(no_reply),
+            })
+    )
+);
+
+fn to_stats_type(x: &[u8]) -> Option<StatsType> {
+    match x {
+        b"prefixes" => Some(StatsType::Prefixes),
+        b"wal" => Some(StatsType::Wal),
+        b"memtable" => Some(StatsType::Memtable),
+        b"compaction" => Some(StatsType::Compaction),
+        b"items" => Some(StatsType::Items),
+        b"sizes" => Some(StatsType::Sizes),
+        b"reset" => Some(StatsType::Reset),
+        _ => None,
+    }
+}
+
+gen_parser!(
+    stats_type_parser<&[u8]>,
+    alt!(
+        tag!(b"prefixes")
+            | tag!(b"wal")
+            | tag!(b"memtable")
+            | tag!(b"compaction")
+            | tag!(b"items")
+            | tag!(b"sizes")
+            | tag!(b"reset")
+    )
+);
+
+gen_parser!(
+    stats<Request>,
+    chain!(
+        tag!(b"stats")
+            >> space
+            >> stats_type: map_opt!(stats_type_parser, to_stats_type)
+            >> tag!(b"\r\n")
+            >> (Request::Stats(stats_type))
+    )
+);
+
+gen_parser!(
+    general_stats<Request>,
+    chain!(tag!(b"stats") >> tag!(b"\r\n") >> (Request::Stats(StatsType::General)))
+);
+
 gen_parser!(
     major_compaction<Request>,
     chain!(tag!(b"major_compaction") >> tag!(b"\r\n") >> (Request::MajorCompaction))
 );
 
+fn to_compaction_command(x: &[u8]) -> Option<CompactionCommand> {
+    match x {
+        b"pause" => Some(CompactionCommand::Pause),
+        b"resume" => Some(CompactionCommand::Resume),
+        _ => None,
+    }
+}
+
+gen_parser!(
+    compaction_command_parser<&[u8]>,
+    alt!(tag!(b"pause") | tag!(b"resume"))
+);
+
+gen_parser!(
+    compaction<Request>,
+    chain!(
+        tag!(b"compaction")
+            >> space
+            >> command: map_opt!(compaction_command_parser, to_compaction_command)
+            >> tag!(b"\r\n")
+            >> (Request::Compaction(command))
+    )
+);
+
+gen_parser!(
+    compaction_rate_limit<Request>,
+    chain!(
+        tag!(b"compaction")
+            >> space
+            >> tag!(b"rate_limit")
+            >> space
+            >> bytes_per_sec: usize_parser
+            >> tag!(b"\r\n")
+            >> (Request::Compaction(CompactionCommand::RateLimit(bytes_per_sec)))
+    )
+);
+
+/// The commands `parse` knows, to tell a malformed one from an unknown one.
+const COMMANDS: &[&[u8]] = &[
+    b"get",
+    b"gets",
+    b"gat",
+    b"gats",
+    b"set",
+    b"add",
+    b"replace",
+    b"append",
+    b"prepend",
+    b"cas",
+    b"delete",
+    b"scan",
+    b"keys",
+    b"incr",
+    b"decr",
+    b"mg",
+    b"ms",
+    b"md",
+    b"ma",
+    b"mn",
+    b"info",
+    b"quit",
+    b"version",
+    b"verbosity",
+    b"stats",
+    b"major_compaction",
+    b"compaction",
+];
+
+/// Whether a line `parse` failed on starts with a command it knows.
+pub fn is_known_command(line: &[u8]) -> bool {
+    let name = line
+        .split(|c| *c == b' ' || *c == b'\r')
+        .next()
+        .unwrap_or(b"");
+    COMMANDS.contains(&name)
+}
+
 gen_parser!(
     parse<Request>,
-    alt!(getter | setter | deleter | info | major_compaction)
+    alt!(
+        getter
+            | get_and_touch
+            | setter
+            | cas
+            | deleter
+            | scan
+            | keys
+            | arith
+            | meta
+            | meta_set
+            | meta_noop
+            | info
+            | quit
+            | version
+            | verbosity
+            | stats
+            | general_stats
+            | major_compaction
+            | compaction_rate_limit
+            | compaction
+    )
 );
 
 #[cfg(test)]
 mod test {
-    use crate::request::{GetterType, Request, SetterType};
+    use crate::request::{GetterType, Request, SetterType, StatsType};
 
     use super::*;
 
@@ -191,6 +589,28 @@ mod test {
                 }
             ))
         );
+        assert_eq!(
+            parse(b"gat 60 abc def\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::GetAndTouch {
+                    getter: GetterType::Get,
+                    ttl: 60,
+                    keys: vec![Slice::from("abc"), Slice::from("def")],
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"gats 0 abc\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::GetAndTouch {
+                    getter: GetterType::Gets,
+                    ttl: 0,
+                    keys: vec![Slice::from("abc")],
+                }
+            ))
+        );
         assert_eq!(parse(b"set abc 1 0 7\r\n"), IRResult::Incomplete(7));
         assert_eq!(parse(b"set abc   1 0 7\r\n"), IRResult::Incomplete(7));
         assert_eq!(parse(b"set abc   1 0 7\r\na"), IRResult::Incomplete(6));
@@ -206,6 +626,7 @@ mod test {
                     bytes: 7,
                     payload: Slice::from("\"a b c\""),
                     no_reply: false,
+                    write_opt: WriteOptions::default(),
                 }
             ))
         );
@@ -221,6 +642,7 @@ mod test {
                     bytes: 7,
                     payload: Slice::from("\"a b c\""),
                     no_reply: false,
+                    write_opt: WriteOptions::default(),
                 }
             ))
         );
@@ -236,6 +658,26 @@ mod test {
                     bytes: 7,
                     payload: Slice::from("\"a b c\""),
                     no_reply: true,
+                    write_opt: WriteOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"set abc 1 0 7 noreply nowal\r\n\"a b c\"\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Setter {
+                    setter: SetterType::Set,
+                    key: Slice::from("abc"),
+                    flags: 1,
+                    ttl: 0,
+                    bytes: 7,
+                    payload: Slice::from("\"a b c\""),
+                    no_reply: true,
+                    write_opt: WriteOptions {
+                        sync: false,
+                        disable_wal: true,
+                    },
                 }
             ))
         );
@@ -251,6 +693,7 @@ mod test {
                     bytes: 6,
                     payload: Slice::from("abcd\r\n"),
                     no_reply: false,
+                    write_opt: WriteOptions::default(),
                 }
             ))
         );
@@ -266,6 +709,23 @@ mod test {
                     bytes: 6,
                     payload: Slice::from("abcd\r\n"),
                     no_reply: false,
+                    write_opt: WriteOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"cas abc 1 0 3 42 noreply\r\nabc\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Setter {
+                    setter: SetterType::Cas(42),
+                    key: Slice::from("abc"),
+                    flags: 1,
+                    ttl: 0,
+                    bytes: 3,
+                    payload: Slice::from("abc"),
+                    no_reply: true,
+                    write_opt: WriteOptions::default(),
                 }
             ))
         );
@@ -276,6 +736,21 @@ mod test {
                 Request::Deleter {
                     key: Slice::from("abc"),
                     no_reply: false,
+                    write_opt: WriteOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"delete abc sync\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Deleter {
+                    key: Slice::from("abc"),
+                    no_reply: false,
+                    write_opt: WriteOptions {
+                        sync: true,
+                        disable_wal: false,
+                    },
                 }
             ))
         );
@@ -286,8 +761,233 @@ mod test {
                 Request::Deleter {
                     key: Slice::from("abc"),
                     no_reply: true,
+                    write_opt: WriteOptions::default(),
                 }
             ))
         );
+        assert_eq!(
+            parse(b"delete abc def noreply nowal\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::MultiDeleter {
+                    keys: vec![Slice::from("abc"), Slice::from("def")],
+                    no_reply: true,
+                    write_opt: WriteOptions {
+                        sync: false,
+                        disable_wal: true,
+                    },
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"delete noreply\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Deleter {
+                    key: Slice::from("noreply"),
+                    no_reply: false,
+                    write_opt: WriteOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"scan a b 10\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Scan {
+                    start: Slice::from("a"),
+                    end: Slice::from("b"),
+                    limit: 10,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"keys user: 0\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Keys {
+                    prefix: Slice::from("user:"),
+                    limit: 0,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"stats prefixes\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Prefixes)))
+        );
+        assert_eq!(
+            parse(b"stats wal\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Wal)))
+        );
+        assert_eq!(
+            parse(b"stats memtable\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Memtable)))
+        );
+        assert_eq!(
+            parse(b"stats compaction\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Compaction)))
+        );
+        assert_eq!(
+            parse(b"stats items\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Items)))
+        );
+        assert_eq!(
+            parse(b"stats sizes\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Sizes)))
+        );
+        assert_eq!(
+            parse(b"stats reset\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::Reset)))
+        );
+        assert_eq!(
+            parse(b"incr abc 5\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Arith {
+                    key: Slice::from("abc"),
+                    decr: false,
+                    delta: 5,
+                    initial: None,
+                    no_reply: false,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"decr abc 18446744073709551615 noreply\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Arith {
+                    key: Slice::from("abc"),
+                    decr: true,
+                    delta: u64::max_value(),
+                    initial: None,
+                    no_reply: true,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"mg abc v c T30 Oxyz\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Meta {
+                    command: MetaCommand::Get,
+                    key: Slice::from("abc"),
+                    flags: MetaFlags(vec![
+                        (b'v', Slice::from("")),
+                        (b'c', Slice::from("")),
+                        (b'T', Slice::from("30")),
+                        (b'O', Slice::from("xyz")),
+                    ]),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"md abc\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Meta {
+                    command: MetaCommand::Delete,
+                    key: Slice::from("abc"),
+                    flags: MetaFlags::default(),
+                }
+            ))
+        );
+        assert_eq!(parse(b"ms abc 3 T0\r\n"), IRResult::Incomplete(3));
+        assert_eq!(
+            parse(b"ms abc 3 MA q\r\nxyz\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Meta {
+                    command: MetaCommand::Set(Slice::from("xyz")),
+                    key: Slice::from("abc"),
+                    flags: MetaFlags(vec![(b'M', Slice::from("A")), (b'q', Slice::from(""))]),
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"mn\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::MetaNoop))
+        );
+        assert_eq!(
+            parse(b"quit\r\nget abc\r\n"),
+            IRResult::Ok(("get abc\r\n".as_bytes(), Request::Quit))
+        );
+        assert_eq!(
+            parse(b"version\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Version))
+        );
+        assert_eq!(
+            parse(b"verbosity 1\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Verbosity {
+                    level: 1,
+                    no_reply: false,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"verbosity 0 noreply\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Verbosity {
+                    level: 0,
+                    no_reply: true,
+                }
+            ))
+        );
+        assert_eq!(
+            parse(b"stats\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Stats(StatsType::General)))
+        );
+        assert_eq!(
+            parse(b"compaction pause\r\n"),
+            IRResult::Ok(("".as_bytes(), Request::Compaction(CompactionCommand::Pause)))
+        );
+        assert_eq!(
+            parse(b"compaction resume\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Compaction(CompactionCommand::Resume)
+            ))
+        );
+        assert_eq!(
+            parse(b"compaction rate_limit 1048576\r\n"),
+            IRResult::Ok((
+                "".as_bytes(),
+                Request::Compaction(CompactionCommand::RateLimit(1_048_576))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_is_known_command() {
+        assert!(is_known_command(b"incr abc x"));
+        assert!(is_known_command(b"get"));
+        assert!(is_known_command(b"mn\r"));
+        assert!(!is_known_command(b"bogus abc"));
+        assert!(!is_known_command(b"getx abc"));
+        assert!(!is_known_command(b""));
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        assert_eq!(None, to_getter_type(b"got"));
+        assert_eq!(None, to_setter_type(b"put"));
+        assert_eq!(None, to_meta_command(b"mx"));
+        assert_eq!(None, to_stats_type(b"bogus"));
+        assert_eq!(None, to_compaction_command(b"stop"));
+        assert_eq!(
+            map_opt!(&b"put abc"[..], key_parser, to_setter_type),
+            IRResult::Err("unknown command")
+        );
+        assert_eq!(
+            map_opt!(&b"set abc"[..], key_parser, to_setter_type),
+            IRResult::Ok((&b" abc"[..], SetterType::Set))
+        );
+        match parse(b"stats bogus\r\n") {
+            IRResult::Err(_) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
     }
 }
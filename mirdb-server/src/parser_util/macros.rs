@@ -158,6 +158,26 @@ macro_rules! split {
     }};
 }
 
+/// Maps what a parser matched with a function returning an Option, failing
+/// where it gives None.
+#[macro_export]
+macro_rules! map_opt {
+    ($i:expr, $mac:ident!($($args:tt)*), $f:expr) => {{
+        use $crate::parser_util::macros::IRResult;
+        match $mac!($i, $($args)*) {
+            IRResult::Ok((i, o)) => match $f(o) {
+                Some(o) => IRResult::Ok((i, o)),
+                None => IRResult::Err("unknown command"),
+            },
+            IRResult::Err(e) => IRResult::Err(e),
+            IRResult::Incomplete(n) => IRResult::Incomplete(n),
+        }
+    }};
+    ($i:expr, $e:ident, $f:expr) => {
+        map_opt!($i, call!($e), $f)
+    };
+}
+
 #[macro_export]
 macro_rules! call (
     ($i:expr, $fun:expr) => ($fun($i));
@@ -360,6 +380,11 @@ pub fn u32_parser(i: &[u8]) -> IRResult<u32> {
     digit::<u32>(i)
 }
 
+#[inline]
+pub fn u64_parser(i: &[u8]) -> IRResult<u64> {
+    digit::<u64>(i)
+}
+
 #[inline]
 pub fn usize_parser(i: &[u8]) -> IRResult<usize> {
     digit::<usize>(i)
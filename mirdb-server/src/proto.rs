@@ -1,21 +1,116 @@
+use std::collections::VecDeque;
 use std::io;
 
 use bytes::buf::IntoBuf;
 use bytes::BytesMut;
+use futures::{try_ready, Async, Poll, Sink, StartSend, Stream};
 #[allow(deprecated)]
 use tokio_io::codec::{Decoder, Encoder, Framed};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_proto::pipeline::ServerProto;
 
+use crate::binary;
+use crate::binary::BinaryHeader;
 use crate::error::MyResult;
 use crate::error::{Status, StatusCode};
-use crate::parser::parse;
+use crate::parser::{is_known_command, parse};
 use crate::parser_util::macros::IRResult;
 use crate::request::Request;
+use crate::resp;
+use crate::resp::RespValue;
 use crate::response::BufferWriter;
 use crate::response::Response;
 
-pub struct ServerCodec;
+/// How the response to a request is written.
+enum Pending {
+    Text,
+    /// A text request with `noreply`, whose response is dropped.
+    NoReply,
+    Binary(BinaryHeader),
+}
+
+/// What a connection speaks, fixed by its first byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// The text and the binary protocol, told apart request by request.
+    Memcached,
+    /// RESP, whose commands all start with `*`.
+    Resp,
+}
+
+/// Speaks the text and the binary protocol, telling them apart by the first
+/// byte of each request, or RESP when a connection starts with an array.
+/// Responses come back in the order of the requests, so how to write each
+/// waits in `pending` for it.
+pub struct ServerCodec {
+    mode: Option<Mode>,
+    pending: VecDeque<Pending>,
+    /// The buffer length a request cut short needs before it is parsed
+    /// again, so a big value is not reparsed on every read.
+    wanted: usize,
+    /// Values over this many bytes are refused with `Request::TooLarge`.
+    max_item_size: usize,
+    /// Bytes of a refused value still to be thrown away as they come.
+    swallow: usize,
+}
+
+impl ServerCodec {
+    pub fn new(max_item_size: usize) -> Self {
+        ServerCodec {
+            mode: None,
+            pending: VecDeque::new(),
+            wanted: 0,
+            max_item_size,
+            swallow: 0,
+        }
+    }
+
+    fn limit_size(&self, req: Request) -> Request {
+        match req.value_len() {
+            Some(len) if len > self.max_item_size => Request::TooLarge,
+            _ => req,
+        }
+    }
+
+    /// Remembers that the request at the start of `src` is `missing` bytes
+    /// short. Only a complete header says how much of the body is missing,
+    /// a partial line could still turn out to be a shorter command. A text
+    /// request whose value is over `max_item_size` is not waited for but
+    /// returned as `Request::TooLarge`, its bytes swallowed as they come, as
+    /// memcached does.
+    fn wait_for(&mut self, src: &mut BytesMut, missing: usize, binary: bool) -> Option<Request> {
+        if !binary {
+            let line_len = match src.windows(2).position(|w| w == b"\r\n") {
+                Some(i) => i + 2,
+                None => return None,
+            };
+            // more than the \r\n after the value is missing only while the
+            // value itself is short
+            if missing > 2 && src.len() - line_len + missing > self.max_item_size {
+                self.swallow = src.len() + missing + 2;
+                return Some(Request::TooLarge);
+            }
+        }
+        self.wanted = src.len() + missing;
+        src.reserve(missing);
+        None
+    }
+
+    /// A malformed RESP command comes out empty, with everything buffered
+    /// thrown away, as on the RESP listener.
+    fn decode_resp(&mut self, src: &mut BytesMut) -> Option<Request> {
+        let src_len = src.len();
+        let (result, src_used) = match resp::parse(src) {
+            IRResult::Ok((remaining, args)) => {
+                (Some(Request::Resp(args)), src_len - remaining.len())
+            }
+            IRResult::Err(_err) => (Some(Request::Resp(vec![])), src_len),
+            IRResult::Incomplete(_) => (None, 0),
+        };
+        src.split_to(src_used);
+        result
+    }
+}
 
 impl Encoder for ServerCodec {
     type Item = Response;
@@ -23,7 +118,23 @@ impl Encoder for ServerCodec {
 
     fn encode(&mut self, item: Response, dst: &mut BytesMut) -> io::Result<()> {
         let mut writer = BufferWriter::new(dst);
-        match item.write(&mut writer) {
+        if self.mode == Some(Mode::Resp) {
+            let value = match item {
+                Response::Resp(value) => value,
+                Response::ClientError(e) | Response::ServerError(e) => RespValue::error(&e),
+                _ => RespValue::error("unexpected reply"),
+            };
+            return match value.write(&mut writer) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+        }
+        let r = match self.pending.pop_front() {
+            Some(Pending::Binary(header)) => binary::write_response(&header, &item, &mut writer),
+            Some(Pending::NoReply) => Ok(()),
+            _ => item.write(&mut writer),
+        };
+        match r {
             Ok(_) => Ok(()),
             Err(e) => Err(e.into()),
         }
@@ -35,11 +146,70 @@ impl Decoder for ServerCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Request>> {
+        if self.swallow > 0 {
+            let n = self.swallow.min(src.len());
+            src.split_to(n);
+            self.swallow -= n;
+            if self.swallow > 0 {
+                return Ok(None);
+            }
+        }
         let src_len = src.len();
-        let (result, src_used) = match { parse(src) } {
-            IRResult::Ok((remaining, req)) => (Ok(Some(req)), src_len - remaining.len()),
-            IRResult::Err(_err) => (Ok(Some(Request::Error)), src_len),
-            IRResult::Incomplete(_) => (Ok(None), 0),
+        if src_len < self.wanted {
+            return Ok(None);
+        }
+        self.wanted = 0;
+        let mode = match (self.mode, src.first()) {
+            (Some(mode), _) => mode,
+            (None, None) => return Ok(None),
+            (None, Some(b'*')) => Mode::Resp,
+            (None, Some(_)) => Mode::Memcached,
+        };
+        self.mode = Some(mode);
+        if mode == Mode::Resp {
+            return Ok(self.decode_resp(src));
+        }
+        let (result, src_used) = if src.first() == Some(&binary::REQUEST_MAGIC) {
+            match binary::parse(src, self.max_item_size) {
+                IRResult::Ok((remaining, (req, header))) => {
+                    self.pending.push_back(Pending::Binary(header));
+                    if req == Request::TooLarge {
+                        // only the header was taken, the body goes as it comes
+                        self.swallow = binary::body_len(src);
+                    }
+                    (Ok(Some(self.limit_size(req))), src_len - remaining.len())
+                }
+                IRResult::Err(_err) => unreachable!(),
+                IRResult::Incomplete(n) => {
+                    self.wait_for(src, n, true);
+                    return Ok(None);
+                }
+            }
+        } else {
+            let r = match { parse(src) } {
+                IRResult::Ok((remaining, req)) => {
+                    (Ok(Some(self.limit_size(req))), src_len - remaining.len())
+                }
+                // only the bad line goes, the requests pipelined after it
+                // are still served
+                IRResult::Err(_err) => match src.windows(2).position(|w| w == b"\r\n") {
+                    Some(i) if is_known_command(&src[..i]) => (Ok(Some(Request::Malformed)), i + 2),
+                    Some(i) => (Ok(Some(Request::Error)), i + 2),
+                    None => (Ok(None), 0),
+                },
+                IRResult::Incomplete(n) => match self.wait_for(src, n, false) {
+                    Some(req) => (Ok(Some(req)), 0),
+                    None => return Ok(None),
+                },
+            };
+            if let (Ok(Some(req)), _) = &r {
+                self.pending.push_back(if req.no_reply() {
+                    Pending::NoReply
+                } else {
+                    Pending::Text
+                });
+            }
+            r
         };
         src.split_to(src_used);
         match result {
@@ -49,16 +219,257 @@ impl Decoder for ServerCodec {
     }
 }
 
-pub struct Proto;
+/// Ends the requests of a connection at a `quit`, after which tokio-proto
+/// writes out the responses still due and closes the connection.
+#[allow(deprecated)]
+pub struct Transport<T> {
+    framed: Framed<T, ServerCodec>,
+    quit: bool,
+}
+
+#[allow(deprecated)]
+impl<T: AsyncWrite + AsyncRead> Stream for Transport<T> {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Request>, io::Error> {
+        if self.quit {
+            return Ok(Async::Ready(None));
+        }
+        match try_ready!(self.framed.poll()) {
+            Some(Request::Quit) => {
+                self.quit = true;
+                Ok(Async::Ready(None))
+            }
+            r => Ok(Async::Ready(r)),
+        }
+    }
+}
+
+#[allow(deprecated)]
+impl<T: AsyncWrite + AsyncRead> Sink for Transport<T> {
+    type SinkItem = Response;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Response) -> StartSend<Response, io::Error> {
+        self.framed.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.framed.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.framed.close()
+    }
+}
+
+pub struct Proto {
+    pub max_item_size: usize,
+}
 
 #[allow(deprecated)]
 impl<T: AsyncWrite + AsyncRead + 'static> ServerProto<T> for Proto {
     type Request = Request;
     type Response = Response;
-    type Transport = Framed<T, ServerCodec>;
-    type BindTransport = io::Result<Framed<T, ServerCodec>>;
+    type Transport = Transport<T>;
+    type BindTransport = io::Result<Transport<T>>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(ServerCodec))
+        Ok(Transport {
+            framed: io.framed(ServerCodec::new(self.max_item_size)),
+            quit: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::options::MB;
+    use crate::request::GetterType;
+    use crate::slice::Slice;
+    use crate::store::TOO_LARGE;
+
+    use super::*;
+
+    #[test]
+    fn test_mixed_protocols() {
+        let mut codec = ServerCodec::new(MB);
+        // a binary noop with opaque 7, then a text get
+        let mut src = BytesMut::from(vec![0x80, 0x0a]);
+        src.extend_from_slice(&[0; 10]);
+        src.extend_from_slice(&7u32.to_be_bytes());
+        src.extend_from_slice(&[0; 8]);
+        src.extend_from_slice(b"get abc\r\n");
+
+        assert_eq!(Some(Request::Noop), codec.decode(&mut src).unwrap());
+        assert_eq!(
+            Some(Request::Getter {
+                getter: GetterType::Get,
+                keys: vec![Slice::from("abc")],
+            }),
+            codec.decode(&mut src).unwrap()
+        );
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+
+        let mut dst = BytesMut::new();
+        codec.encode(Response::Ok, &mut dst).unwrap();
+        assert_eq!(24, dst.len());
+        assert_eq!(&[0x81, 0x0a][..], &dst[..2]);
+        assert_eq!(7u32.to_be_bytes(), dst[12..16]);
+        codec.encode(Response::Get(vec![]), &mut dst).unwrap();
+        assert_eq!(b"END\r\n", &dst[24..]);
+    }
+
+    #[test]
+    fn test_no_reply() {
+        let mut codec = ServerCodec::new(MB);
+        let mut src = BytesMut::from(&b"delete abc noreply\r\ndelete abc\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().unwrap().no_reply());
+        assert!(!codec.decode(&mut src).unwrap().unwrap().no_reply());
+
+        let mut dst = BytesMut::new();
+        codec.encode(Response::Deleted, &mut dst).unwrap();
+        assert!(dst.is_empty());
+        codec.encode(Response::NotFound, &mut dst).unwrap();
+        assert_eq!(b"NOT_FOUND\r\n", &dst[..]);
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let mut codec = ServerCodec::new(MB);
+        let mut src =
+            BytesMut::from(&b"get a\r\nbogus\r\nincr b x\r\nset b 0 0 1\r\nx\r\nget b"[..]);
+        let mut requests = vec![];
+        while let Some(req) = codec.decode(&mut src).unwrap() {
+            requests.push(req);
+        }
+        assert_eq!(4, requests.len());
+        assert_eq!(Request::Error, requests[1]);
+        assert_eq!(Request::Malformed, requests[2]);
+        match &requests[3] {
+            Request::Setter { key, .. } => assert_eq!(Slice::from("b"), *key),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(b"get b", &src[..]);
+
+        // a bad line waits for its end before it is skipped
+        let mut src = BytesMut::from(&b"bogus"[..]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        src.extend_from_slice(b"\r\nget b\r\n");
+        assert_eq!(Some(Request::Error), codec.decode(&mut src).unwrap());
+        assert!(codec.decode(&mut src).unwrap().is_some());
+        assert!(src.is_empty());
+
+        let mut dst = BytesMut::new();
+        codec.encode(Response::Get(vec![]), &mut dst).unwrap();
+        codec.encode(Response::Error, &mut dst).unwrap();
+        codec.encode(Response::Stored, &mut dst).unwrap();
+        assert_eq!(b"END\r\nERROR\r\nSTORED\r\n", &dst[..]);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut codec = ServerCodec::new(MB);
+        let mut src = BytesMut::from(&b"set abc 0 0 10\r\n01234"[..]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert_eq!(src.len() + 5, codec.wanted);
+        assert!(src.capacity() >= codec.wanted);
+
+        // short of the value the request is not parsed again
+        src.extend_from_slice(b"567");
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        src.extend_from_slice(b"89\r\n");
+        match codec.decode(&mut src).unwrap() {
+            Some(Request::Setter { payload, .. }) => assert_eq!(Slice::from("0123456789"), payload),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(0, codec.wanted);
+
+        // a partial line waits on nothing
+        let mut src = BytesMut::from(&b"m"[..]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert_eq!(0, codec.wanted);
+        src.extend_from_slice(b"n\r\n");
+        assert_eq!(Some(Request::MetaNoop), codec.decode(&mut src).unwrap());
+    }
+
+    #[test]
+    fn test_resp_detection() {
+        let mut codec = ServerCodec::new(MB);
+        let mut src = BytesMut::from(&b"*1\r\n$4\r\nPING\r\nGET abc\r\n"[..]);
+        let ping = Request::Resp(vec![Slice::from("PING")]);
+        assert_eq!(Some(ping), codec.decode(&mut src).unwrap());
+        // the connection stays RESP, so the line is an inline command
+        let get = Request::Resp(vec![Slice::from("GET"), Slice::from("abc")]);
+        assert_eq!(Some(get), codec.decode(&mut src).unwrap());
+
+        let mut dst = BytesMut::new();
+        let pong = RespValue::Simple("PONG".to_owned());
+        codec.encode(Response::Resp(pong), &mut dst).unwrap();
+        codec
+            .encode(Response::ServerError("oops".to_owned()), &mut dst)
+            .unwrap();
+        assert_eq!(b"+PONG\r\n-ERR oops\r\n", &dst[..]);
+
+        // a memcached connection does not take RESP later on
+        let mut codec = ServerCodec::new(MB);
+        let mut src = BytesMut::from(&b"mn\r\n*1\r\n"[..]);
+        assert_eq!(Some(Request::MetaNoop), codec.decode(&mut src).unwrap());
+        assert_eq!(Some(Request::Error), codec.decode(&mut src).unwrap());
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut codec = ServerCodec::new(4);
+        let mut src = BytesMut::from(&b"set a 0 0 5\r\nabcde\r\nms a 5\r\nabcde\r\n"[..]);
+        assert_eq!(Some(Request::TooLarge), codec.decode(&mut src).unwrap());
+        assert_eq!(Some(Request::TooLarge), codec.decode(&mut src).unwrap());
+        src.extend_from_slice(b"set a 0 0 4\r\nabcd\r\n");
+        match codec.decode(&mut src).unwrap() {
+            Some(Request::Setter { bytes: 4, .. }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut dst = BytesMut::new();
+        codec
+            .encode(Response::ServerError(TOO_LARGE.to_owned()), &mut dst)
+            .unwrap();
+        assert_eq!(b"SERVER_ERROR object too large for cache\r\n", &dst[..]);
+    }
+
+    #[test]
+    fn test_swallow() {
+        let mut codec = ServerCodec::new(4);
+        let mut src = BytesMut::from(&b"set a 0 0 10\r\n0123"[..]);
+        assert_eq!(Some(Request::TooLarge), codec.decode(&mut src).unwrap());
+        // the value is thrown away rather than waited for
+        assert_eq!(0, codec.wanted);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert!(src.is_empty());
+        src.extend_from_slice(b"456789\r\nmn\r\n");
+        assert_eq!(Some(Request::MetaNoop), codec.decode(&mut src).unwrap());
+        assert_eq!(0, codec.swallow);
+    }
+
+    #[test]
+    fn test_binary_too_large() {
+        let mut codec = ServerCodec::new(4);
+        // a set declaring a 2MB body, with 3 bytes of it sent
+        let mut src = BytesMut::from(vec![0x80, 0x01]);
+        src.extend_from_slice(&[0; 6]);
+        src.extend_from_slice(&(2 * MB as u32).to_be_bytes());
+        src.extend_from_slice(&[0; 12]);
+        src.extend_from_slice(b"abc");
+        assert_eq!(Some(Request::TooLarge), codec.decode(&mut src).unwrap());
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        assert_eq!(2 * MB - 3, codec.swallow);
+        assert!(src.capacity() < MB);
+
+        let mut dst = BytesMut::new();
+        codec
+            .encode(Response::ServerError(TOO_LARGE.to_owned()), &mut dst)
+            .unwrap();
+        assert_eq!(&[0x81, 0x01, 0, 0, 0, 0, 0, 0x03][..], &dst[..8]);
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Token bucket limiting the bytes per second written by background flushes
+/// and compactions. Up to one second worth of bytes can be spent at once.
+/// A rate of 0 turns limiting off.
+pub struct RateLimiter {
+    bytes_per_sec: AtomicUsize,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: usize) -> Self {
+        RateLimiter {
+            bytes_per_sec: AtomicUsize::new(bytes_per_sec),
+            state: Mutex::new(Bucket {
+                available: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> usize {
+        self.bytes_per_sec.load(Relaxed)
+    }
+
+    /// Takes effect from the next request on.
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: usize) {
+        self.bytes_per_sec.store(bytes_per_sec, Relaxed);
+    }
+
+    /// Blocks until `bytes` may be written. Callers queue up behind a caller
+    /// that is waiting, so the limit holds across threads.
+    pub fn request(&self, bytes: usize) {
+        let rate = self.bytes_per_sec();
+        if rate == 0 {
+            return;
+        }
+        let rate = rate as f64;
+        let mut bucket = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.available = (bucket.available + elapsed * rate).min(rate);
+        bucket.last_refill = now;
+        bucket.available -= bytes as f64;
+        if bucket.available < 0. {
+            thread::sleep(Duration::from_secs_f64(-bucket.available / rate));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let limiter = RateLimiter::new(0);
+        let st = Instant::now();
+        limiter.request(1 << 30);
+        assert!(st.elapsed() < Duration::from_millis(100));
+
+        limiter.set_bytes_per_sec(1000);
+        // the bucket starts empty after being disabled, so this waits ~0.2s
+        let st = Instant::now();
+        for _ in 0..4 {
+            limiter.request(50);
+        }
+        let elapsed = st.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use log::info;
+use log::warn;
+
+use crate::blob::blob_file_number;
+use crate::blob::BLOB_EXT;
+use crate::env::list_ext;
+use crate::env::open_table;
+use crate::error::MyResult;
+use crate::manifest::BlobFileMeta;
+use crate::manifest::Manifest;
+use crate::manifest::ManifestBuilder;
+use crate::options::Options;
+use crate::sstable_reader::table_reader_to_file_meta;
+
+/// Tables that fail to open or verify are moved here, in the work dir, as
+/// are orphan files set aside at startup.
+pub(crate) const LOST_DIR: &str = "lost";
+/// The manifest being replaced is kept under this extension.
+const OLD_MANIFEST_EXT: &str = "old";
+
+/// What `repair` found in a work dir.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RepairStats {
+    pub tables: usize,
+    pub entries: u64,
+    pub blob_files: usize,
+    /// Tables moved to `lost/` as unreadable.
+    pub lost: usize,
+}
+
+/// Rebuilds the manifest of the work dir in `opt` from the sstables and blob
+/// files in it, for when the manifest is lost or corrupt. The manifest does
+/// not survive in any form, so the result is best-effort: every table that
+/// verifies goes to level 0, oldest file number first, and compactions sort
+/// them out again. Where tables from different levels overlap, a value
+/// compacted down after a newer one was flushed may come out on top. Blob
+/// files are taken as holding no garbage. The old manifest, if any, is kept
+/// as `MANIFEST.old`. The work dir must not be open.
+pub fn repair(opt: &Options) -> MyResult<RepairStats> {
+    let env = opt.env.as_ref();
+    let work_dir = Path::new(&opt.work_dir);
+    let manifest = Manifest::gen_path(opt);
+    if env.exists(&manifest) {
+        env.rename(&manifest, &manifest.with_extension(OLD_MANIFEST_EXT))?;
+    }
+    let mut builder = ManifestBuilder::new(opt.clone())?;
+    let mut stats = RepairStats::default();
+
+    for path in list_ext(env, work_dir, "sst")? {
+        let verified = open_table(env, &path, opt.get_table_opt().clone())
+            .and_then(|reader| Ok((reader.verify()?, table_reader_to_file_meta(&reader, (0, 0)))));
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        match verified {
+            Ok((entries, meta)) => {
+                stats.tables += 1;
+                stats.entries += entries as u64;
+                builder.add_file_meta(0, meta);
+            }
+            Err(e) => {
+                warn!("moving unreadable sstable {} aside: {:?}", file_name, e);
+                let lost = work_dir.join(LOST_DIR);
+                env.create_dir_all(&lost)?;
+                env.rename(&path, &lost.join(&file_name))?;
+                stats.lost += 1;
+            }
+        }
+    }
+
+    for path in list_ext(env, work_dir, BLOB_EXT)? {
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if let Some(file_number) = blob_file_number(&file_name) {
+            builder.add_blob_file(BlobFileMeta {
+                file_number,
+                total_bytes: env.open(&path)?.size()?,
+                garbage_bytes: 0,
+            });
+            stats.blob_files += 1;
+        }
+    }
+
+    builder.flush()?;
+    info!("repaired manifest of {}: {:?}", opt.work_dir, stats);
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::remove_file;
+    use std::fs::write;
+
+    use crate::data_manager::DataManager;
+    use crate::slice::Slice;
+    use crate::store::StorePayload;
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    #[test]
+    fn test_repair() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.blob_min_size = 4;
+        let keys = vec!["a", "b", "c"];
+        {
+            let dm = DataManager::new(opt.clone())?;
+            for key in &keys {
+                let data = Slice::from(key.repeat(8));
+                let p = StorePayload::new(data, 0, 0, 8, 0);
+                dm.insert(Slice::from(*key), p)?;
+            }
+            dm.close()?;
+        }
+        remove_file(Manifest::gen_path(&opt))?;
+        let bad = Path::new(&opt.work_dir).join("99999999.sst");
+        write(&bad, b"not a table")?;
+
+        let stats = repair(&opt)?;
+        assert!(stats.tables > 0);
+        assert_eq!(keys.len() as u64, stats.entries);
+        assert!(stats.blob_files > 0);
+        assert_eq!(1, stats.lost);
+        assert!(!bad.exists());
+        assert!(Path::new(&opt.work_dir)
+            .join(LOST_DIR)
+            .join("99999999.sst")
+            .exists());
+
+        let dm = DataManager::new(opt.clone())?;
+        for key in &keys {
+            let p = dm.get(&Slice::from(*key))?.unwrap();
+            assert_eq!(Slice::from(key.repeat(8)), p.data);
+        }
+
+        // a second repair keeps the manifest it replaces
+        drop(dm);
+        repair(&opt)?;
+        assert!(Manifest::gen_path(&opt)
+            .with_extension(OLD_MANIFEST_EXT)
+            .exists());
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+use std::str;
+use std::str::FromStr;
+
+use crate::options::WriteOptions;
 use crate::slice::Slice;
 
 pub type Key = Slice;
@@ -10,6 +14,8 @@ pub enum SetterType {
     Replace,
     Append,
     Prepend,
+    /// Stores only if the item's cas unique is still the given one.
+    Cas(u64),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -18,12 +24,76 @@ pub enum GetterType {
     Gets,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatsType {
+    /// A bare `stats`.
+    General,
+    Prefixes,
+    Wal,
+    Memtable,
+    Compaction,
+    /// Item counts by size class.
+    Items,
+    /// Item counts by size.
+    Sizes,
+    /// Zeroes the command counters.
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionCommand {
+    Pause,
+    Resume,
+    /// Bytes per second for flushes and compactions, 0 for no limit.
+    RateLimit(usize),
+}
+
+/// The meta commands, `mg`, `ms`, `md` and `ma`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaCommand {
+    Get,
+    Set(Payload),
+    Delete,
+    Arith,
+}
+
+/// The flags of a meta command, each a letter and maybe a token after it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetaFlags(pub Vec<(u8, Slice)>);
+
+impl MetaFlags {
+    pub fn has(&self, flag: u8) -> bool {
+        self.0.iter().any(|(f, _)| *f == flag)
+    }
+
+    pub fn token(&self, flag: u8) -> Option<&Slice> {
+        self.0.iter().find(|(f, _)| *f == flag).map(|(_, t)| t)
+    }
+
+    /// The token of `flag` as a number, Err if it is not one.
+    pub fn number<T: FromStr>(&self, flag: u8) -> Result<Option<T>, ()> {
+        match self.token(flag) {
+            None => Ok(None),
+            Some(t) => match str::from_utf8(&t[..]).ok().and_then(|s| s.parse().ok()) {
+                Some(n) => Ok(Some(n)),
+                None => Err(()),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Request {
     Getter {
         getter: GetterType,
         keys: Vec<Key>,
     },
+    /// `gat` and `gats`: gets the keys and gives them a new ttl.
+    GetAndTouch {
+        getter: GetterType,
+        ttl: u32,
+        keys: Vec<Key>,
+    },
     Setter {
         setter: SetterType,
         key: Key,
@@ -32,12 +102,117 @@ pub enum Request {
         bytes: usize,
         payload: Payload,
         no_reply: bool,
+        write_opt: WriteOptions,
     },
     Deleter {
         key: Key,
         no_reply: bool,
+        write_opt: WriteOptions,
+    },
+    /// `delete` with several keys, all deleted in one batch.
+    MultiDeleter {
+        keys: Vec<Key>,
+        no_reply: bool,
+        write_opt: WriteOptions,
+    },
+    Meta {
+        command: MetaCommand,
+        key: Key,
+        flags: MetaFlags,
+    },
+    /// `mn`, which clients send after a batch of quiet meta commands.
+    MetaNoop,
+    /// The keys in `[start, end)` of the default keyspace with their values,
+    /// at most `limit` of them, or all with 0.
+    Scan {
+        start: Key,
+        end: Key,
+        limit: usize,
+    },
+    /// Up to `limit` keys starting with `prefix`, or all with 0.
+    Keys {
+        prefix: Key,
+        limit: usize,
+    },
+    /// `incr` and `decr`.
+    Arith {
+        key: Key,
+        decr: bool,
+        delta: u64,
+        /// The number and ttl a missing key is created with, rather than
+        /// answering NOT_FOUND. Only binary requests ask for this.
+        initial: Option<(u64, u32)>,
+        no_reply: bool,
     },
     Info,
+    Version,
+    /// Sets the log level, 0 being the quietest.
+    Verbosity {
+        level: u32,
+        no_reply: bool,
+    },
+    Stats(StatsType),
+    /// An unknown command.
     Error,
+    /// A known command with bad arguments.
+    Malformed,
+    /// A storage command whose value is over `max_item_size`, refused by the
+    /// decoder before the value is buffered.
+    TooLarge,
+    /// Closes the connection, which the transport does before it would
+    /// reach a store.
+    Quit,
+    /// The binary protocol's no-op, answered with an empty success.
+    Noop,
+    /// Asks which SASL mechanisms are offered, binary only.
+    SaslListMechs,
+    /// Authenticates with `mechanism`, binary only.
+    SaslAuth {
+        mechanism: Slice,
+        data: Slice,
+    },
     MajorCompaction,
+    Compaction(CompactionCommand),
+    /// A Redis command, from a connection that spoke RESP first.
+    Resp(Vec<Slice>),
+}
+
+impl Request {
+    /// Whether the client asked for no response with `noreply`.
+    pub fn no_reply(&self) -> bool {
+        match self {
+            Request::Setter { no_reply, .. }
+            | Request::Deleter { no_reply, .. }
+            | Request::MultiDeleter { no_reply, .. }
+            | Request::Arith { no_reply, .. }
+            | Request::Verbosity { no_reply, .. } => *no_reply,
+            _ => false,
+        }
+    }
+
+    /// The length of the value the request stores, if it stores one.
+    pub fn value_len(&self) -> Option<usize> {
+        match self {
+            Request::Setter { bytes, .. } => Some(*bytes),
+            Request::Meta {
+                command: MetaCommand::Set(payload),
+                ..
+            } => Some(payload.len()),
+            _ => None,
+        }
+    }
+
+    /// The keys the request reads or writes.
+    pub fn keys(&self) -> Vec<&Key> {
+        match self {
+            Request::Getter { keys, .. }
+            | Request::GetAndTouch { keys, .. }
+            | Request::MultiDeleter { keys, .. } => keys.iter().collect(),
+            Request::Setter { key, .. }
+            | Request::Deleter { key, .. }
+            | Request::Meta { key, .. }
+            | Request::Arith { key, .. } => vec![key],
+            _ => vec![],
+        }
+    }
 }
@@ -0,0 +1,537 @@
+use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use futures::{future, Future};
+#[allow(deprecated)]
+use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_proto::pipeline::ServerProto;
+use tokio_proto::TcpServer;
+use tokio_service::Service;
+
+use crate::auth::{Authenticator, Credentials};
+use crate::error::MyResult;
+use crate::options::WriteOptions;
+use crate::parser_util::macros::{usize_parser, IRResult};
+use crate::request::{GetterType, Request, SetterType};
+use crate::response::BufferWriter;
+use crate::response::Response;
+use crate::response::Writer;
+use crate::slice::Slice;
+use crate::store::Store;
+use crate::store::MAX_RELATIVE_TTL;
+use crate::store::TOO_LARGE;
+
+/// Keys a `SCAN` walks when not given a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply in RESP, the Redis protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// None is the null bulk string.
+    Bulk(Option<Slice>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    pub(crate) fn error(msg: &str) -> Self {
+        RespValue::Error(format!("ERR {}", msg))
+    }
+
+    pub(crate) fn write(&self, writer: &mut Writer) -> MyResult<()> {
+        match self {
+            RespValue::Simple(s) => writer.write(format!("+{}\r\n", s).as_bytes()),
+            RespValue::Error(e) => writer.write(format!("-{}\r\n", e).as_bytes()),
+            RespValue::Integer(n) => writer.write(format!(":{}\r\n", n).as_bytes()),
+            RespValue::Bulk(None) => writer.write(b"$-1\r\n"),
+            RespValue::Bulk(Some(s)) => {
+                writer.write(format!("${}\r\n", s.len()).as_bytes())?;
+                writer.write(&s[..])?;
+                writer.write(b"\r\n")
+            }
+            RespValue::Array(v) => {
+                writer.write(format!("*{}\r\n", v.len()).as_bytes())?;
+                for value in v {
+                    value.write(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+gen_parser!(
+    length_line<usize>,
+    chain!(n: usize_parser >> tag!(b"\r\n") >> (n))
+);
+
+fn length(src: &[u8]) -> IRResult<usize> {
+    if src.is_empty() {
+        return IRResult::Incomplete(1);
+    }
+    length_line(src)
+}
+
+/// Parses a command: an array of bulk strings, or an inline command, a line
+/// of words as typed into telnet.
+pub fn parse(src: &[u8]) -> IRResult<Vec<Slice>> {
+    if src.first() != Some(&b'*') {
+        return match src.windows(2).position(|w| w == b"\r\n") {
+            Some(i) => {
+                let words = src[..i].split(|b| *b == b' ').filter(|w| !w.is_empty());
+                IRResult::Ok((&src[i + 2..], words.map(Slice::from).collect()))
+            }
+            None => IRResult::Incomplete(1),
+        };
+    }
+    let (mut rest, n) = match length(&src[1..]) {
+        IRResult::Ok(r) => r,
+        IRResult::Err(e) => return IRResult::Err(e),
+        IRResult::Incomplete(n) => return IRResult::Incomplete(n),
+    };
+    let mut args = Vec::with_capacity(n);
+    for _ in 0..n {
+        match rest.first() {
+            None => return IRResult::Incomplete(1),
+            Some(b'$') => {}
+            Some(_) => return IRResult::Err("expected a bulk string"),
+        }
+        let (r, len) = match length(&rest[1..]) {
+            IRResult::Ok(r) => r,
+            IRResult::Err(e) => return IRResult::Err(e),
+            IRResult::Incomplete(n) => return IRResult::Incomplete(n),
+        };
+        if r.len() < len + 2 {
+            return IRResult::Incomplete(len + 2 - r.len());
+        }
+        if &r[len..len + 2] != b"\r\n" {
+            return IRResult::Err("bad bulk string length");
+        }
+        args.push(Slice::from(&r[..len]));
+        rest = &r[len + 2..];
+    }
+    IRResult::Ok((rest, args))
+}
+
+/// Whether `s` matches the glob `pattern`, in which `*` matches any run of
+/// bytes, `?` any one byte and `\` escapes the byte after it.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            s.first() == Some(&rest[0]) && glob_match(&rest[1..], &s[1..])
+        }
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+fn int_arg(arg: &Slice) -> Option<i64> {
+    str::from_utf8(&arg[..]).ok()?.parse().ok()
+}
+
+/// A command or option name, in upper case.
+fn name_arg(arg: &Slice) -> String {
+    String::from_utf8_lossy(&arg[..]).to_ascii_uppercase()
+}
+
+/// A ttl in seconds from now as the store takes it, where the ones longer
+/// than `MAX_RELATIVE_TTL` are unix times.
+fn store_ttl(secs: u64) -> u32 {
+    if secs <= u64::from(MAX_RELATIVE_TTL) {
+        return secs as u32;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    (now + secs).min(u64::from(u32::max_value())) as u32
+}
+
+fn set(store: &Store, args: &[Slice]) -> MyResult<RespValue> {
+    let mut setter = SetterType::Set;
+    let mut ttl = 0;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match &name_arg(option)[..] {
+            "NX" => setter = SetterType::Add,
+            "XX" => setter = SetterType::Replace,
+            unit @ "EX" | unit @ "PX" => match options.next().and_then(int_arg) {
+                Some(n) if n > 0 => {
+                    let secs = if unit == "EX" { n } else { (n + 999) / 1000 };
+                    ttl = store_ttl(secs as u64);
+                }
+                Some(_) => return Ok(RespValue::error("invalid expire time in 'set' command")),
+                None => return Ok(RespValue::error("syntax error")),
+            },
+            _ => return Ok(RespValue::error("syntax error")),
+        }
+    }
+    let payload = args[1].clone();
+    if payload.len() > store.max_item_size() {
+        return Ok(RespValue::error(TOO_LARGE));
+    }
+    let r = store.apply(Request::Setter {
+        setter,
+        key: args[0].clone(),
+        flags: 0,
+        ttl,
+        bytes: payload.len(),
+        payload,
+        no_reply: false,
+        write_opt: WriteOptions::default(),
+    })?;
+    Ok(match r {
+        Response::Stored => RespValue::Simple("OK".to_owned()),
+        Response::ClientError(e) | Response::ServerError(e) => RespValue::error(&e),
+        _ => RespValue::Bulk(None),
+    })
+}
+
+fn delete(store: &Store, key: &Slice) -> MyResult<bool> {
+    let r = store.apply(Request::Deleter {
+        key: key.clone(),
+        no_reply: false,
+        write_opt: WriteOptions::default(),
+    })?;
+    Ok(r == Response::Deleted)
+}
+
+/// Walks `COUNT` keys from the cursor, the number of keys walked before,
+/// and returns those matching `MATCH` with the cursor to go on from, 0 at
+/// the end.
+fn scan(store: &Store, args: &[Slice]) -> MyResult<RespValue> {
+    let cursor = match int_arg(&args[0]) {
+        Some(n) if n >= 0 => n as usize,
+        _ => return Ok(RespValue::error("invalid cursor")),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (&name_arg(option)[..], options.next()) {
+            ("MATCH", Some(p)) => pattern = Some(p),
+            ("COUNT", Some(n)) => match int_arg(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(RespValue::error("syntax error")),
+            },
+            _ => return Ok(RespValue::error("syntax error")),
+        }
+    }
+    let mut keys = vec![];
+    let mut next = 0;
+    for (i, r) in store.scan()?.enumerate().skip(cursor) {
+        if i == cursor + count {
+            next = i;
+            break;
+        }
+        let (key, _) = r?;
+        if pattern.map_or(true, |p| glob_match(&p[..], &key[..])) {
+            keys.push(RespValue::Bulk(Some(key)));
+        }
+    }
+    Ok(RespValue::Array(vec![
+        RespValue::Bulk(Some(Slice::from(next.to_string()))),
+        RespValue::Array(keys),
+    ]))
+}
+
+/// Runs a Redis command against `store`. Keys are addressed to namespaces
+/// as in the text protocol, but `SCAN` only walks the default one.
+pub fn apply(store: &Store, args: Vec<Slice>) -> MyResult<RespValue> {
+    let name = match args.first() {
+        Some(name) => name_arg(name).to_ascii_lowercase(),
+        None => return Ok(RespValue::error("Protocol error")),
+    };
+    let args = &args[1..];
+    let arity_ok = match &name[..] {
+        "ping" => args.len() <= 1,
+        "get" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" => !args.is_empty(),
+        "expire" => args.len() == 2,
+        "scan" => !args.is_empty(),
+        _ => true,
+    };
+    if !arity_ok {
+        let msg = format!("wrong number of arguments for '{}' command", name);
+        return Ok(RespValue::error(&msg));
+    }
+    match &name[..] {
+        "ping" => Ok(match args.first() {
+            Some(msg) => RespValue::Bulk(Some(msg.clone())),
+            None => RespValue::Simple("PONG".to_owned()),
+        }),
+        "get" => {
+            let r = store.apply(Request::Getter {
+                getter: GetterType::Get,
+                keys: vec![args[0].clone()],
+            })?;
+            Ok(match r {
+                Response::Get(mut items) if !items.is_empty() => {
+                    RespValue::Bulk(Some(items.remove(0).data))
+                }
+                _ => RespValue::Bulk(None),
+            })
+        }
+        "set" => set(store, args),
+        "del" => {
+            let mut deleted = 0;
+            for key in args {
+                if delete(store, key)? {
+                    deleted += 1;
+                }
+            }
+            Ok(RespValue::Integer(deleted))
+        }
+        "expire" => {
+            let secs = match int_arg(&args[1]) {
+                Some(secs) => secs,
+                None => return Ok(RespValue::error("value is not an integer or out of range")),
+            };
+            // as in Redis, a ttl that is not positive deletes the key
+            let found = if secs <= 0 {
+                delete(store, &args[0])?
+            } else {
+                let r = store.apply(Request::GetAndTouch {
+                    getter: GetterType::Get,
+                    ttl: store_ttl(secs as u64),
+                    keys: vec![args[0].clone()],
+                })?;
+                r != Response::Get(vec![])
+            };
+            Ok(RespValue::Integer(found as i64))
+        }
+        "scan" => scan(store, args),
+        // sent by redis-cli on connecting
+        "command" => Ok(RespValue::Array(vec![])),
+        _ => Ok(RespValue::error(&format!("unknown command '{}'", name))),
+    }
+}
+
+#[derive(Default)]
+pub struct RespCodec;
+
+impl Encoder for RespCodec {
+    type Item = RespValue;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> io::Result<()> {
+        let mut writer = BufferWriter::new(dst);
+        match item.write(&mut writer) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Slice>;
+    type Error = io::Error;
+
+    /// A malformed command comes out empty, with everything buffered thrown
+    /// away, and is answered with a protocol error.
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<Slice>>> {
+        let src_len = src.len();
+        let (result, src_used) = match parse(src) {
+            IRResult::Ok((remaining, args)) => (Some(args), src_len - remaining.len()),
+            IRResult::Err(_err) => (Some(vec![]), src_len),
+            IRResult::Incomplete(_) => (None, 0),
+        };
+        src.split_to(src_used);
+        Ok(result)
+    }
+}
+
+pub struct RespProto;
+
+#[allow(deprecated)]
+impl<T: AsyncWrite + AsyncRead + 'static> ServerProto<T> for RespProto {
+    type Request = Vec<Slice>;
+    type Response = RespValue;
+    type Transport = Framed<T, RespCodec>;
+    type BindTransport = io::Result<Framed<T, RespCodec>>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(RespCodec))
+    }
+}
+
+pub struct RespServer {
+    store: Arc<Store>,
+    /// None when clients need not authenticate.
+    auth: Option<Authenticator>,
+}
+
+impl Service for RespServer {
+    type Request = Vec<Slice>;
+    type Response = RespValue;
+    type Error = io::Error;
+    type Future = Box<Future<Item = RespValue, Error = io::Error>>;
+
+    fn call(&self, args: Vec<Slice>) -> Self::Future {
+        if let Some(value) = self.auth.as_ref().and_then(|auth| auth.check_resp(&args)) {
+            return Box::new(future::ok(value));
+        }
+        Box::new(future::done(match apply(&self.store, args) {
+            Ok(value) => Ok(value),
+            Err(e) => Ok(RespValue::error(&e.msg)),
+        }))
+    }
+}
+
+/// Serves RESP clients on `addr`, blocking the calling thread. With
+/// `credentials`, clients must `AUTH` first.
+pub fn serve(addr: SocketAddr, store: Arc<Store>, credentials: Option<Arc<Credentials>>) {
+    TcpServer::new(RespProto, addr).serve(move || {
+        Ok(RespServer {
+            store: store.clone(),
+            auth: credentials.clone().map(Authenticator::new),
+        })
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<Slice> {
+        words.iter().map(|w| Slice::from(*w)).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            IRResult::Ok((&b"*1"[..], args(&["GET", "a"]))),
+            parse(b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n*1")
+        );
+        assert_eq!(IRResult::Incomplete(5), parse(b"*2\r\n$3\r\nGET\r\n$3\r\n"));
+        assert_eq!(IRResult::Incomplete(1), parse(b"*2\r\n$3\r\nGET\r\n"));
+        assert_eq!(
+            IRResult::Ok((&b""[..], args(&["SET", "a", "b c"]))),
+            parse(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$3\r\nb c\r\n")
+        );
+        assert_eq!(
+            IRResult::Ok((&b""[..], args(&["get", "a"]))),
+            parse(b"get  a\r\n")
+        );
+        assert_eq!(IRResult::Incomplete(1), parse(b"get a"));
+        match parse(b"*1\r\n:1\r\n") {
+            IRResult::Err(_) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let mut buf = BytesMut::new();
+        let value = RespValue::Array(vec![
+            RespValue::Bulk(Some(Slice::from("0"))),
+            RespValue::Array(vec![RespValue::Bulk(None), RespValue::Integer(-1)]),
+        ]);
+        value.write(&mut BufferWriter::new(&mut buf)).unwrap();
+        assert_eq!(&b"*2\r\n$1\r\n0\r\n*2\r\n$-1\r\n:-1\r\n"[..], &buf[..]);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(!glob_match(b"user:?", b"user:10"));
+    }
+
+    #[test]
+    fn test_auth() -> MyResult<()> {
+        let credentials = Credentials::parse("alice:secret\n")?;
+        let server = RespServer {
+            store: Arc::new(Store::new(get_test_opt())?),
+            auth: Some(Authenticator::new(Arc::new(credentials))),
+        };
+        let call = |words: &[&str]| server.call(args(words)).wait().unwrap();
+        let noauth = RespValue::Error("NOAUTH Authentication required.".to_owned());
+
+        assert_eq!(noauth, call(&["SET", "a", "1"]));
+        assert_eq!(noauth, call(&["GET", "a"]));
+        assert_eq!(
+            RespValue::Simple("OK".to_owned()),
+            call(&["AUTH", "alice", "secret"])
+        );
+        assert_eq!(RespValue::Bulk(None), call(&["GET", "a"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let run = |words: &[&str]| apply(&store, args(words)).unwrap();
+        let ok = RespValue::Simple("OK".to_owned());
+        let bulk = |s: &str| RespValue::Bulk(Some(Slice::from(s)));
+
+        assert_eq!(RespValue::Simple("PONG".to_owned()), run(&["PING"]));
+        assert_eq!(RespValue::Bulk(None), run(&["GET", "a"]));
+        assert_eq!(ok, run(&["SET", "a", "1"]));
+        assert_eq!(bulk("1"), run(&["get", "a"]));
+        assert_eq!(RespValue::Bulk(None), run(&["SET", "a", "2", "NX"]));
+        assert_eq!(RespValue::Bulk(None), run(&["SET", "b", "2", "XX"]));
+        assert_eq!(ok, run(&["SET", "b", "2", "EX", "100"]));
+        assert_eq!(ok, run(&["SET", "c", "3", "px", "1500"]));
+        let ttl = |key: &str| {
+            let mut entries = store.scan().unwrap().map(|r| r.unwrap());
+            let p = entries.find(|(k, _)| *k == Slice::from(key)).unwrap().1;
+            p.expires_at().unwrap() - p.created_at
+        };
+        assert_eq!(2, ttl("c"));
+        match run(&["SET", "c", "3", "EX", "0"]) {
+            RespValue::Error(_) => {}
+            r => panic!("unexpected reply {:?}", r),
+        }
+
+        assert_eq!(RespValue::Integer(1), run(&["EXPIRE", "a", "100"]));
+        assert_eq!(RespValue::Integer(0), run(&["EXPIRE", "x", "100"]));
+        assert_eq!(RespValue::Integer(1), run(&["EXPIRE", "c", "0"]));
+        assert_eq!(RespValue::Bulk(None), run(&["GET", "c"]));
+
+        let scan = |words: &[&str]| match run(words) {
+            RespValue::Array(v) => (v[0].clone(), v[1].clone()),
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert_eq!(
+            (bulk("1"), RespValue::Array(vec![bulk("a")])),
+            scan(&["SCAN", "0", "COUNT", "1"])
+        );
+        assert_eq!(
+            (bulk("0"), RespValue::Array(vec![bulk("b")])),
+            scan(&["SCAN", "1", "COUNT", "1"])
+        );
+        assert_eq!(
+            (bulk("0"), RespValue::Array(vec![bulk("b")])),
+            scan(&["SCAN", "0", "MATCH", "b*"])
+        );
+
+        assert_eq!(RespValue::Integer(2), run(&["DEL", "a", "b", "x"]));
+        match run(&["GET"]) {
+            RespValue::Error(e) => assert!(e.contains("wrong number of arguments")),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        let big = "x".repeat(store.max_item_size() + 1);
+        assert_eq!(RespValue::error(TOO_LARGE), run(&["SET", "a", &big]));
+        match run(&["FLUSHALL"]) {
+            RespValue::Error(e) => assert_eq!("ERR unknown command 'flushall'", e),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
 use bytes::{BufMut, BytesMut};
 
 use crate::error::MyResult;
+use crate::resp::RespValue;
 use crate::slice::Slice;
-use crate::utils::to_str;
 
 #[derive(Debug, PartialEq)]
 pub struct GetRespItem {
@@ -21,6 +21,62 @@ impl GetRespItem {
             bytes,
         }
     }
+
+    fn write(&self, writer: &mut Writer, cas: Option<u64>) -> MyResult<()> {
+        let header = match cas {
+            Some(cas) => format!(" {} {} {}\r\n", self.flags, self.bytes, cas),
+            None => format!(" {} {}\r\n", self.flags, self.bytes),
+        };
+        // the key as it was sent, which need not be utf8
+        writer.write(b"VALUE ")?;
+        writer.write(&self.key[..])?;
+        writer.write(header.as_bytes())?;
+        writer.write(&self.data[..])?;
+        writer.write(b"\r\n")
+    }
+}
+
+/// The answer to a meta command: its two letter code, the flags it returns
+/// and, for `VA`, the value.
+#[derive(Debug, PartialEq)]
+pub struct MetaResponse {
+    pub code: &'static str,
+    pub flags: Vec<String>,
+    pub value: Option<Slice>,
+    /// The `q` flag asked to leave this code out.
+    pub quiet: bool,
+}
+
+impl MetaResponse {
+    pub fn new(code: &'static str) -> Self {
+        MetaResponse {
+            code,
+            flags: vec![],
+            value: None,
+            quiet: false,
+        }
+    }
+
+    fn write(&self, writer: &mut Writer) -> MyResult<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        let mut line = self.code.to_owned();
+        if let Some(value) = &self.value {
+            line.push_str(&format!(" {}", value.len()));
+        }
+        for flag in &self.flags {
+            line.push(' ');
+            line.push_str(flag);
+        }
+        line.push_str("\r\n");
+        writer.write(line.as_bytes())?;
+        if let Some(value) = &self.value {
+            writer.write(&value[..])?;
+            writer.write(b"\r\n")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -30,7 +86,8 @@ pub enum Response {
     Exists,
     NotFound,
     Get(Vec<GetRespItem>),
-    Gets(Vec<GetRespItem>),
+    /// Items with their cas unique.
+    Gets(Vec<(GetRespItem, u64)>),
     Deleted,
     Touched,
     Ok,
@@ -44,6 +101,24 @@ pub enum Response {
     ClientError(String),
     ServerError(String),
     Info(String),
+    Version(String),
+    /// The new value of an `incr` or `decr`.
+    Number(u64),
+    Meta(MetaResponse),
+    Stats(Vec<(String, String)>),
+    /// Answers `stats reset`.
+    Reset,
+    /// Answers `keys`, a `KEY <key>` line each.
+    Keys(Vec<Slice>),
+    /// One response per key of a request on several keys.
+    Multi(Vec<Response>),
+    /// The reply to a Redis command.
+    Resp(RespValue),
+    /// The SASL mechanisms offered, split by spaces.
+    Mechanisms(String),
+    Authenticated,
+    /// A failed authentication, or a request before one.
+    AuthError,
 }
 
 pub trait Writer {
@@ -86,19 +161,15 @@ impl Response {
             Response::NotFound => {
                 writer.write(b"NOT_FOUND\r\n")?;
             }
-            Response::Gets(v) | Response::Get(v) => {
-                for GetRespItem {
-                    key,
-                    data,
-                    flags,
-                    bytes,
-                } in v
-                {
-                    writer.write(
-                        format!("VALUE {} {} {}\r\n", to_str(key), flags, bytes).as_bytes(),
-                    )?;
-                    writer.write(&data[..])?;
-                    writer.write(b"\r\n")?;
+            Response::Get(v) => {
+                for item in v {
+                    item.write(writer, None)?;
+                }
+                writer.write(b"END\r\n")?;
+            }
+            Response::Gets(v) => {
+                for (item, cas) in v {
+                    item.write(writer, Some(*cas))?;
                 }
                 writer.write(b"END\r\n")?;
             }
@@ -123,6 +194,43 @@ impl Response {
             Response::Info(s) => {
                 writer.write(format!("INFO\r\n\r\n{}\r\n\r\nEND\r\n", s).as_bytes())?;
             }
+            Response::Version(s) => {
+                writer.write(format!("VERSION {}\r\n", s).as_bytes())?;
+            }
+            Response::Number(n) => {
+                writer.write(format!("{}\r\n", n).as_bytes())?;
+            }
+            Response::Meta(r) => {
+                r.write(writer)?;
+            }
+            Response::Stats(v) => {
+                for (name, value) in v {
+                    writer.write(format!("STAT {} {}\r\n", name, value).as_bytes())?;
+                }
+                writer.write(b"END\r\n")?;
+            }
+            Response::Reset => {
+                writer.write(b"RESET\r\n")?;
+            }
+            Response::Keys(v) => {
+                for key in v {
+                    writer.write(b"KEY ")?;
+                    writer.write(&key[..])?;
+                    writer.write(b"\r\n")?;
+                }
+                writer.write(b"END\r\n")?;
+            }
+            Response::Multi(v) => {
+                for r in v {
+                    r.write(writer)?;
+                }
+            }
+            Response::Resp(v) => {
+                v.write(writer)?;
+            }
+            Response::AuthError => {
+                writer.write(b"CLIENT_ERROR unauthenticated\r\n")?;
+            }
             _ => {
                 unimplemented!();
             }
@@ -0,0 +1,117 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+use crate::store::StoreKey;
+use crate::store::StorePayload;
+
+struct Inner {
+    lru: LruCache<StoreKey, Option<StorePayload>>,
+    // bumped by every invalidation
+    generation: u64,
+}
+
+/// The results of recent reads, misses included, so reads of hot keys skip
+/// the memtables and sstables altogether. Writers invalidate their keys once
+/// the write is in the memtable. A reader takes the generation before it
+/// looks for the key and only caches what it found if nothing was
+/// invalidated in between, so a value read before a write never lands in
+/// the cache after it.
+pub struct RowCache {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> Self {
+        RowCache {
+            inner: Mutex::new(Inner {
+                lru: LruCache::new(capacity),
+                generation: 0,
+            }),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, k: &StoreKey) -> Option<Option<StorePayload>> {
+        let r = self.inner.lock().unwrap().lru.get(k).cloned();
+        match r {
+            Some(_) => self.hits.fetch_add(1, Relaxed),
+            None => self.misses.fetch_add(1, Relaxed),
+        };
+        r
+    }
+
+    /// To be taken before reading a key that is then passed to `insert`.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    pub fn insert(&self, generation: u64, k: StoreKey, v: Option<StorePayload>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation == generation {
+            inner.lru.put(k, v);
+        }
+    }
+
+    pub fn invalidate(&self, k: &StoreKey) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.lru.pop(k);
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.lru = LruCache::new(self.capacity);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::slice::Slice;
+
+    use super::*;
+
+    #[test]
+    fn test_row_cache() {
+        let cache = RowCache::new(2);
+        let (a, b, c) = (Slice::from("a"), Slice::from("b"), Slice::from("c"));
+        let p = StorePayload::new(Slice::from("1"), 0, 0, 1, 0);
+
+        let g = cache.generation();
+        cache.insert(g, a.clone(), Some(p.clone()));
+        cache.insert(g, b.clone(), None);
+        assert_eq!(Some(Some(p.clone())), cache.get(&a));
+        assert_eq!(Some(None), cache.get(&b));
+        cache.insert(g, c.clone(), None);
+        assert_eq!(None, cache.get(&a));
+
+        // a read that overlapped a write is not cached
+        let g = cache.generation();
+        cache.invalidate(&b);
+        cache.insert(g, a.clone(), Some(p));
+        assert_eq!(None, cache.get(&a));
+        assert_eq!(None, cache.get(&b));
+        assert_eq!(Some(None), cache.get(&c));
+
+        cache.clear();
+        assert_eq!(None, cache.get(&c));
+        assert_eq!(3, cache.hits());
+        assert_eq!(4, cache.misses());
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use bincode::deserialize;
+use log::warn;
+
+use crate::cancel::CancellationToken;
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::manifest::Manifest;
+use crate::memtable::Memtable;
+use crate::memtable::UNSEQUENCED;
+use crate::memtable_rep::Rep;
+use crate::merge::resolve_value;
+use crate::merge::stack_value;
+use crate::options::Options;
+use crate::request::Request;
+use crate::response::Response;
+use crate::slice::Slice;
+use crate::sstable_reader::SstableReader;
+use crate::store::getter_response;
+use crate::store::split_namespace;
+use crate::store::StoreKey;
+use crate::store::StorePayload;
+use crate::store::NAMESPACE_DIR;
+use crate::store::TOO_LARGE;
+use crate::types::Table;
+use crate::utils::read_lock;
+use crate::utils::write_lock;
+use crate::wal::seg_paths;
+use crate::wal::WALSegIter;
+
+// the primary may delete files while they are being opened, or rewrite the
+// manifest while the WAL is read
+const MAX_CATCH_UP_ATTEMPTS: usize = 8;
+
+/// What a follower has read of one keyspace: the levels of its manifest
+/// and a memtable replayed from its writes in the WAL segments.
+struct View {
+    readers: SstableReader,
+    mem: Memtable<Slice, Slice>,
+    /// Where the WAL shared by the keyspaces is.
+    wal_dir: PathBuf,
+    namespace: Slice,
+    /// The segments replayed into `mem`, oldest first.
+    segs: Vec<PathBuf>,
+    /// The sequence of the newest write replayed.
+    last_seq: u64,
+}
+
+impl View {
+    fn new(opt: &Options, wal_dir: PathBuf, namespace: Slice) -> MyResult<Self> {
+        let mut view = View {
+            readers: retry(|| SstableReader::new(opt.clone()))?,
+            mem: new_mem(opt),
+            wal_dir,
+            namespace,
+            segs: vec![],
+            last_seq: 0,
+        };
+        view.catch_up(opt)?;
+        Ok(view)
+    }
+
+    /// Rereads the manifest and then the WAL, until the manifest is the same
+    /// before and after, so that no flush falls between the two.
+    fn catch_up(&mut self, opt: &Options) -> MyResult<()> {
+        retry(|| {
+            self.readers.reload()?;
+            self.replay_wal(opt)?;
+            if Manifest::load(opt)?.live_files() != self.readers.live_files() {
+                return err!(
+                    StatusCode::IOError,
+                    "manifest kept changing while catching up"
+                );
+            }
+            Ok(())
+        })
+    }
+
+    /// Replays the writes made since the last replay: the rest of the last
+    /// segment read and the segments after it. Once a segment replayed
+    /// before is gone, flushed, the memtable is replayed anew from the ones
+    /// left. Reading stops at the first incomplete record, which the primary
+    /// may still be writing.
+    fn replay_wal(&mut self, opt: &Options) -> MyResult<()> {
+        let env = opt.env.as_ref();
+        let paths = seg_paths(env, &self.wal_dir)?;
+        if self.segs.iter().any(|p| !paths.contains(p)) {
+            self.mem = new_mem(opt);
+            self.segs.clear();
+            self.last_seq = 0;
+        }
+        let last = self.segs.last().cloned();
+        for path in paths {
+            if self.segs.contains(&path) && Some(&path) != last.as_ref() {
+                continue;
+            }
+            // created, but its header not written yet
+            if env.open(&path)?.size()? == 0 {
+                continue;
+            }
+            for entry in WALSegIter::with_env(env, &path)? {
+                let entry = entry?;
+                if entry.namespace != self.namespace {
+                    continue;
+                }
+                // entries of a legacy segment carry no sequence number
+                let sequenced = entry.seq != UNSEQUENCED;
+                if sequenced && entry.seq <= self.last_seq {
+                    continue;
+                }
+                if let Some(v) = stack_value(self.mem.get(&entry.key), entry.value)? {
+                    self.mem.insert_shared(entry.seq, entry.key, v);
+                }
+                if sequenced {
+                    self.last_seq = entry.seq;
+                }
+            }
+            if Some(&path) != last.as_ref() {
+                self.segs.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn new_mem(opt: &Options) -> Memtable<Slice, Slice> {
+    Memtable::with_rep(::std::usize::MAX, Rep::with_options(opt))
+}
+
+/// Runs `f` until it succeeds, up to `MAX_CATCH_UP_ATTEMPTS` times.
+fn retry<T, F>(mut f: F) -> MyResult<T>
+where
+    F: FnMut() -> MyResult<T>,
+{
+    let mut r = f();
+    for _ in 1..MAX_CATCH_UP_ATTEMPTS {
+        if r.is_ok() {
+            break;
+        }
+        r = f();
+    }
+    r
+}
+
+/// Follows one keyspace, the default one or a namespace, without writing to
+/// its work dir.
+struct Follower {
+    opt: Options,
+    view: RwLock<View>,
+}
+
+impl Follower {
+    /// `opt.work_dir` is the one of the keyspace, and `wal_dir` the work dir
+    /// of the default keyspace, whose WAL the namespaces share.
+    fn new(opt: Options, wal_dir: PathBuf, namespace: Slice) -> MyResult<Self> {
+        let view = View::new(&opt, wal_dir, namespace)?;
+        Ok(Follower {
+            opt,
+            view: RwLock::new(view),
+        })
+    }
+
+    /// Reads wait for it, but it opens only the tables added since the last
+    /// catch-up and reads only the newest WAL segments.
+    fn catch_up(&self) -> MyResult<()> {
+        write_lock(&self.view).catch_up(&self.opt)
+    }
+
+    fn get(&self, k: &StoreKey) -> MyResult<Option<StorePayload>> {
+        let view = read_lock(&self.view);
+        let r = match view.mem.get(k) {
+            Some(v) => match resolve_value(&v[..], || view.readers.get(k))? {
+                Some(merged) => Some(merged),
+                None => Some(v.clone()),
+            },
+            None => view.readers.get(k)?,
+        };
+        match r {
+            Some(r) => Ok(deserialize::<Option<StorePayload>>(&r[..])?.filter(|p| !p.is_expired())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A read-only instance following the work dir of a primary running in
+/// another process. It takes no LOCK and writes no file; every
+/// `secondary_refresh_interval_ms` it reloads the manifest and replays the
+/// primary's WAL, so reads may be that far behind the primary.
+pub struct Secondary {
+    data: Follower,
+    namespaces: HashMap<Vec<u8>, Follower>,
+    shutdown: CancellationToken,
+    refresher: Mutex<Option<JoinHandle<()>>>,
+}
+
+unsafe impl Sync for Secondary {}
+unsafe impl Send for Secondary {}
+
+impl Secondary {
+    pub fn new(opt: Options) -> MyResult<Arc<Self>> {
+        let path = Path::new(&opt.work_dir);
+        if !opt.env.is_dir(path) {
+            return err!(StatusCode::IOError, "work dir is not a dir");
+        }
+        let mut namespaces = HashMap::new();
+        for name in &opt.namespaces {
+            let mut ns_opt = opt.clone();
+            ns_opt.work_dir = path
+                .join(NAMESPACE_DIR)
+                .join(name)
+                .to_string_lossy()
+                .into_owned();
+            ns_opt.namespaces = vec![];
+            let follower = Follower::new(ns_opt, path.to_path_buf(), Slice::from(name.as_str()))?;
+            namespaces.insert(name.as_bytes().to_vec(), follower);
+        }
+        let secondary = Arc::new(Secondary {
+            data: Follower::new(opt.clone(), path.to_path_buf(), Slice::new())?,
+            namespaces,
+            shutdown: CancellationToken::new(),
+            refresher: Mutex::new(None),
+        });
+        if opt.secondary_refresh_interval_ms > 0 {
+            Self::refresh_thread(secondary.clone(), opt.secondary_refresh_interval_ms);
+        }
+        Ok(secondary)
+    }
+
+    fn refresh_thread(secondary: Arc<Self>, interval_ms: usize) {
+        let s = secondary.clone();
+        let handle = thread::spawn(move || {
+            let d = Duration::from_millis(interval_ms as u64);
+            while !s.shutdown.is_cancelled() {
+                thread::sleep(d);
+                if let Err(e) = s.try_catch_up_with_primary() {
+                    warn!("catching up with primary failed: {:?}", e);
+                }
+            }
+        });
+        *secondary.refresher.lock().unwrap() = Some(handle);
+    }
+
+    /// Brings every keyspace up to date with what the primary has written
+    /// to its WAL and manifest so far.
+    pub fn try_catch_up_with_primary(&self) -> MyResult<()> {
+        self.data.catch_up()?;
+        for follower in self.namespaces.values() {
+            follower.catch_up()?;
+        }
+        Ok(())
+    }
+
+    /// Stops catching up. Reads keep being served from the last catch-up.
+    pub fn close(&self) {
+        self.shutdown.cancel();
+        if let Some(handle) = self.refresher.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+
+    pub fn get(&self, key: Slice) -> MyResult<Option<StorePayload>> {
+        match split_namespace(&self.namespaces, &key) {
+            Some((follower, i)) => follower.get(&key.slice_from(i)),
+            None => self.data.get(&key),
+        }
+    }
+
+    /// Serves gets; anything that writes or needs the primary's state is
+    /// refused.
+    pub fn apply(&self, request: Request) -> MyResult<Response> {
+        match request {
+            Request::Getter { getter, keys } => getter_response(getter, keys, |key| self.get(key)),
+            Request::Error => Ok(Response::Error),
+            Request::Malformed => Ok(Response::ClientError("bad command line format".to_owned())),
+            Request::TooLarge => Ok(Response::ServerError(TOO_LARGE.to_owned())),
+            _ => Ok(Response::ServerError(
+                "not supported by a secondary instance".to_owned(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::options::WriteOptions;
+    use crate::request::GetterType;
+    use crate::request::SetterType;
+    use crate::store::Store;
+    use crate::test_utils::get_test_opt;
+
+    fn set(store: &Store, setter: SetterType, key: &str, data: &str) -> MyResult<Response> {
+        let payload = Slice::from(data);
+        store.apply(Request::Setter {
+            setter,
+            key: Slice::from(key),
+            flags: 0,
+            ttl: 0,
+            bytes: payload.len(),
+            payload,
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })
+    }
+
+    fn data(secondary: &Secondary, key: &str) -> MyResult<Option<Slice>> {
+        Ok(secondary.get(Slice::from(key))?.map(|p| p.data))
+    }
+
+    #[test]
+    fn test_secondary() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        opt.secondary_refresh_interval_ms = 0;
+        let store = Store::new(opt.clone())?;
+        set(&store, SetterType::Set, "a", "1")?;
+        set(&store, SetterType::Set, "app:a", "2")?;
+
+        let secondary = Secondary::new(opt.clone())?;
+        assert_eq!(Some(Slice::from("1")), data(&secondary, "a")?);
+        assert_eq!(Some(Slice::from("2")), data(&secondary, "app:a")?);
+
+        set(&store, SetterType::Set, "b", "3")?;
+        store.apply(Request::Deleter {
+            key: Slice::from("a"),
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        assert!(data(&secondary, "b")?.is_none());
+        secondary.try_catch_up_with_primary()?;
+        assert_eq!(Some(Slice::from("3")), data(&secondary, "b")?);
+        assert!(data(&secondary, "a")?.is_none());
+
+        // flushed into sstables
+        store.close()?;
+        secondary.try_catch_up_with_primary()?;
+        assert_eq!(Some(Slice::from("3")), data(&secondary, "b")?);
+        assert_eq!(Some(Slice::from("2")), data(&secondary, "app:a")?);
+
+        let r = secondary.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("b")],
+        })?;
+        assert_eq!(
+            1,
+            match r {
+                Response::Get(items) => items.len(),
+                _ => 0,
+            }
+        );
+        let r = secondary.apply(Request::Deleter {
+            key: Slice::from("b"),
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        assert!(match r {
+            Response::ServerError(_) => true,
+            _ => false,
+        });
+        assert_eq!(Some(Slice::from("3")), data(&secondary, "b")?);
+
+        secondary.close();
+        Ok(())
+    }
+
+    #[test]
+    fn test_catch_up_appends() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.secondary_refresh_interval_ms = 0;
+        let store = Store::new(opt.clone())?;
+        set(&store, SetterType::Set, "a", "1")?;
+
+        // each append is replayed once, however many catch-ups see it
+        let secondary = Secondary::new(opt.clone())?;
+        for i in 2..5 {
+            set(&store, SetterType::Append, "a", &i.to_string())?;
+            secondary.try_catch_up_with_primary()?;
+            secondary.try_catch_up_with_primary()?;
+            assert_eq!(Some(Slice::from(&"1234"[..i])), data(&secondary, "a")?);
+        }
+        store.close()?;
+        secondary.try_catch_up_with_primary()?;
+        assert_eq!(Some(Slice::from("1234")), data(&secondary, "a")?);
+        secondary.close();
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::vec;
+
+use integer_encoding::FixedInt;
+
+use crate::env::EnvFile;
+use crate::env::SharedEnv;
+use crate::error::MyResult;
+use crate::file_number::FileNumbers;
+use crate::options::Options;
+use crate::slice::Slice;
+use crate::utils::make_file_name;
+
+const SPILL_DIR: &str = "tmp";
+
+pub fn spill_dir(opt: &Options) -> PathBuf {
+    Path::new(&opt.work_dir).join(SPILL_DIR)
+}
+
+/// Collects the (key, value) pairs produced by a scan. Pairs are kept in memory
+/// until `mem_limit` bytes have been buffered, after which everything is moved
+/// into a temporary file so peak memory stays bounded regardless of scan size.
+/// Spill files are numbered from `FileNumbers` so they never reuse the name of
+/// a file left behind by an earlier run.
+pub struct SpillBuffer {
+    env: SharedEnv,
+    file_numbers: FileNumbers,
+    dir: PathBuf,
+    mem_limit: usize,
+    mem_size: usize,
+    mem: Vec<(Slice, Slice)>,
+    file: Option<(PathBuf, BufWriter<Box<dyn EnvFile>>)>,
+    count: usize,
+}
+
+impl SpillBuffer {
+    pub fn new<T: AsRef<Path>>(
+        env: SharedEnv,
+        file_numbers: FileNumbers,
+        dir: T,
+        mem_limit: usize,
+    ) -> Self {
+        SpillBuffer {
+            env,
+            file_numbers,
+            dir: dir.as_ref().to_path_buf(),
+            mem_limit,
+            mem_size: 0,
+            mem: vec![],
+            file: None,
+            count: 0,
+        }
+    }
+
+    pub fn from_opt(opt: &Options, file_numbers: FileNumbers) -> Self {
+        Self::new(
+            opt.env.clone(),
+            file_numbers,
+            spill_dir(opt),
+            opt.scan_spill_threshold,
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn push(&mut self, k: Slice, v: Slice) -> MyResult<()> {
+        self.count += 1;
+
+        if let Some((_, w)) = &mut self.file {
+            return write_pair(w, &k, &v);
+        }
+
+        self.mem_size += k.len() + v.len();
+        self.mem.push((k, v));
+
+        if self.mem_limit > 0 && self.mem_size > self.mem_limit {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> MyResult<()> {
+        if !self.env.exists(&self.dir) {
+            self.env.create_dir_all(&self.dir)?;
+        }
+        let n = self.file_numbers.next();
+        let path = self.dir.join(make_file_name(n, "spill"));
+        let f = self.env.create(&path)?;
+        let mut w = BufWriter::new(f);
+        for (k, v) in self.mem.drain(..) {
+            write_pair(&mut w, &k, &v)?;
+        }
+        self.mem_size = 0;
+        self.file = Some((path, w));
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> MyResult<SpillIter> {
+        match self.file.take() {
+            None => Ok(SpillIter::Memory(
+                ::std::mem::replace(&mut self.mem, vec![]).into_iter(),
+            )),
+            Some((path, mut w)) => {
+                w.flush()?;
+                drop(w);
+                let f = self.env.open(&path)?;
+                Ok(SpillIter::File {
+                    env: self.env.clone(),
+                    reader: BufReader::new(f),
+                    path,
+                    remaining: self.count,
+                })
+            }
+        }
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        if let Some((path, _)) = self.file.take() {
+            let _ = self.env.remove_file(&path);
+        }
+    }
+}
+
+fn write_pair<T: Write>(w: &mut T, k: &[u8], v: &[u8]) -> MyResult<()> {
+    let size_space = u32::required_space();
+    let mut buf = [0; 8];
+    (k.len() as u32).encode_fixed(&mut buf[..size_space]);
+    (v.len() as u32).encode_fixed(&mut buf[size_space..size_space * 2]);
+    w.write_all(&buf)?;
+    w.write_all(k)?;
+    w.write_all(v)?;
+    Ok(())
+}
+
+fn read_pair<T: Read>(r: &mut T) -> MyResult<(Slice, Slice)> {
+    let size_space = u32::required_space();
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    let key_size = u32::decode_fixed(&buf[..size_space]) as usize;
+    let value_size = u32::decode_fixed(&buf[size_space..size_space * 2]) as usize;
+    let mut k = vec![0; key_size];
+    r.read_exact(&mut k)?;
+    let mut v = vec![0; value_size];
+    r.read_exact(&mut v)?;
+    Ok((Slice::from(k), Slice::from(v)))
+}
+
+/// Streams back the pairs collected by a `SpillBuffer` in insertion order,
+/// removing the temporary file once dropped.
+pub enum SpillIter {
+    Memory(vec::IntoIter<(Slice, Slice)>),
+    File {
+        env: SharedEnv,
+        reader: BufReader<Box<dyn EnvFile>>,
+        path: PathBuf,
+        remaining: usize,
+    },
+}
+
+impl Iterator for SpillIter {
+    type Item = MyResult<(Slice, Slice)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SpillIter::Memory(iter) => iter.next().map(Ok),
+            SpillIter::File {
+                reader, remaining, ..
+            } => {
+                if *remaining == 0 {
+                    return None;
+                }
+                *remaining -= 1;
+                Some(read_pair(reader))
+            }
+        }
+    }
+}
+
+impl Drop for SpillIter {
+    fn drop(&mut self) {
+        if let SpillIter::File { env, path, .. } = self {
+            let _ = env.remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::get_test_opt;
+
+    use super::*;
+
+    #[test]
+    fn test_spill() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.scan_spill_threshold = 16;
+        let n = 100;
+
+        let mut buf = SpillBuffer::from_opt(&opt, FileNumbers::new(7));
+        for i in 0..n {
+            buf.push(
+                Slice::from(format!("key{}", i).into_bytes()),
+                Slice::from(format!("value{}", i).into_bytes()),
+            )?;
+        }
+        assert!(buf.is_spilled());
+        assert_eq!(n, buf.len());
+
+        let iter = buf.finish()?;
+        let path = match &iter {
+            SpillIter::File { path, .. } => {
+                assert_eq!(spill_dir(&opt).join(make_file_name(7, "spill")), *path);
+                path.clone()
+            }
+            _ => panic!("expected a spilled iterator"),
+        };
+        let mut count = 0;
+        for (i, r) in iter.enumerate() {
+            let (k, v) = r?;
+            assert_eq!(Slice::from(format!("key{}", i).into_bytes()), k);
+            assert_eq!(Slice::from(format!("value{}", i).into_bytes()), v);
+            count += 1;
+        }
+        assert_eq!(n, count);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory() -> MyResult<()> {
+        let opt = get_test_opt();
+        let mut buf = SpillBuffer::new(
+            opt.env.clone(),
+            FileNumbers::default(),
+            spill_dir(&opt),
+            1024,
+        );
+        buf.push(Slice::from("a"), Slice::from("b"))?;
+        assert!(!buf.is_spilled());
+        let v = buf.finish()?.collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(vec![(Slice::from("a"), Slice::from("b"))], v);
+        Ok(())
+    }
+}
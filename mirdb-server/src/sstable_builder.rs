@@ -1,16 +1,21 @@
 use std::borrow::Borrow;
-use std::fs::File;
 use std::path::Path;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use bincode::{deserialize, serialize};
+use log::debug;
 use serde::Serialize;
 
 use skip_list::SkipList;
-use sstable::TableBuilder;
 use sstable::TableReader;
 
+use crate::blob::blob_file_number;
+use crate::blob::BlobWriter;
+use crate::blob::BLOB_EXT;
+use crate::env::create_table;
+use crate::env::open_table;
+use crate::env::sync_parent_dir;
 use crate::error::MyResult;
 use crate::options::Options;
 use crate::slice::Slice;
@@ -22,21 +27,86 @@ pub fn skiplist_to_sstable(
     opt: &Options,
     path: &Path,
 ) -> MyResult<Option<(String, TableReader)>> {
-    if map.length() == 0 {
+    entries_to_sstable(map.iter(), opt, path)
+}
+
+/// Writes sorted entries to a new sstable at `path`; nothing is written for
+/// no entries.
+pub fn entries_to_sstable<I, K, V>(
+    entries: I,
+    opt: &Options,
+    path: &Path,
+) -> MyResult<Option<(String, TableReader)>>
+where
+    I: Iterator<Item = (K, V)>,
+    K: Borrow<[u8]>,
+    V: Borrow<[u8]>,
+{
+    let mut entries = entries.peekable();
+    if entries.peek().is_none() {
         return Ok(None);
     }
 
     let table_opt = opt.get_table_opt();
-    let mut tb = TableBuilder::new(&path, table_opt.clone())?;
+    let mut tb = create_table(opt.env.as_ref(), path, table_opt.clone())?;
+
+    let mut blob = None;
+
+    for (k, v) in entries {
+        let v: &[u8] = v.borrow();
+        if opt.blob_min_size == 0 || v.len() < opt.blob_min_size {
+            tb.add(k.borrow(), v.borrow())?;
+            continue;
+        }
+        if blob.is_none() {
+            blob = Some(new_blob_writer(opt, path)?);
+        }
+        let idx = blob.as_mut().unwrap().add(v)?;
+        tb.add(k.borrow(), &idx.encode()?)?;
+    }
 
-    for (k, v) in map.iter() {
-        tb.add(k.borrow(), v.borrow())?;
+    if let Some(blob) = blob {
+        blob.finish()?;
     }
 
     tb.flush()?;
 
     Ok(Some((
         path.to_str().unwrap().to_owned(),
-        TableReader::new(path, table_opt.clone())?,
+        open_sstable(path, opt)?,
     )))
 }
+
+/// Creates the blob file that shares its file number with the table at `path`.
+pub fn new_blob_writer(opt: &Options, path: &Path) -> MyResult<BlobWriter> {
+    let file_number = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(blob_file_number)
+        .expect("table file number");
+    BlobWriter::new(opt.env.as_ref(), path.with_extension(BLOB_EXT), file_number)
+}
+
+/// Opens a freshly built table, after syncing its directory so the table and
+/// its blob file are there after a power loss. With `paranoid_checks` the
+/// whole table is read back and verified first; a table that fails is
+/// removed so it never gets installed into the manifest.
+pub fn open_sstable(path: &Path, opt: &Options) -> MyResult<TableReader> {
+    let env = opt.env.as_ref();
+    sync_parent_dir(env, path)?;
+    let reader = open_table(env, path, opt.get_table_opt().clone())?;
+    if !opt.paranoid_checks {
+        return Ok(reader);
+    }
+    match reader.verify() {
+        Ok(n) => {
+            debug!("verified sstable {:?}: {} entries", path, n);
+            Ok(reader)
+        }
+        Err(e) => {
+            drop(reader);
+            env.remove_file(path)?;
+            Err(e.into())
+        }
+    }
+}
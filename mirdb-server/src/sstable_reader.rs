@@ -1,36 +1,120 @@
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::fs::remove_file;
 use std::io::Cursor;
 use std::path::Path;
 
 use bincode::deserialize;
 use serde::Deserialize;
 
+use sstable::PrefixStat;
 use sstable::TableReader;
 
+use crate::blob::blob_file_name;
+use crate::blob::blob_file_number;
+use crate::blob::BlobIndex;
+use crate::blob::BlobStore;
+use crate::env::open_table;
 use crate::error::MyResult;
+use crate::manifest::BlobFileMeta;
 use crate::manifest::FileMeta;
 use crate::manifest::ManifestBuilder;
 use crate::options::Options;
+use crate::options::KB;
 use crate::slice::Slice;
 use crate::store::StoreKey;
 use crate::store::StorePayload;
 use crate::utils::to_str;
 
+/// Bytes of a file that make one more miss allowed before the file is
+/// compacted, as a miss costs about as much as compacting that much.
+const BYTES_PER_MISS: usize = 16 * KB;
+
 pub struct SstableReader {
     opt_: Options,
     readers_: Vec<Vec<TableReader>>,
     manifest_builder_: ManifestBuilder,
+    blobs_: BlobStore,
+}
+
+/// The sstables of a level.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LevelStats {
+    pub files: usize,
+    pub bytes: u64,
+    /// Entries written to the tables, older versions and deletes included.
+    pub entries: u64,
+}
+
+/// Estimated amount of sstable data in a key range.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ApproximateSize {
+    pub bytes: u64,
+    pub keys: u64,
 }
 
-fn table_reader_to_file_meta(reader: &TableReader) -> FileMeta {
+/// `seqs` are the sequences of the oldest and the newest write in the table.
+pub fn table_reader_to_file_meta(reader: &TableReader, seqs: (u64, u64)) -> FileMeta {
     FileMeta {
         file_name: reader.file_name().clone(),
+        smallest_key: reader.min_key().clone(),
+        largest_key: reader.max_key().clone(),
+        file_size: reader.size() as u64,
+        smallest_seq: seqs.0,
+        largest_seq: seqs.1,
     }
 }
 
+/// Sstables to remove and add at once, e.g. the inputs and outputs of a
+/// compaction. Nothing changes until they are passed to
+/// `SstableReader::install`.
+#[derive(Default)]
+pub struct TableChanges {
+    removed: Vec<(usize, HashSet<String>)>,
+    added: Vec<(usize, TableReader)>,
+    blob_garbage: HashMap<usize, u64>,
+}
+
+impl TableChanges {
+    pub fn new() -> Self {
+        TableChanges::default()
+    }
+
+    pub fn remove(&mut self, level: usize, file_names: HashSet<String>) {
+        self.removed.push((level, file_names));
+    }
+
+    pub fn add(&mut self, level: usize, reader: TableReader) {
+        self.added.push((level, reader));
+    }
+
+    /// Garbage the changes leave in blob files, see `add_blob_garbage`.
+    pub fn add_blob_garbage(&mut self, garbage: &HashMap<usize, u64>) {
+        for (file_number, bytes) in garbage {
+            *self.blob_garbage.entry(*file_number).or_insert(0) += bytes;
+        }
+    }
+}
+
+/// The sequences spanned by the files of `metas` in `file_names`, which the
+/// outputs of a compaction of them carry on.
+fn sequence_range<'a>(
+    metas: impl Iterator<Item = &'a FileMeta>,
+    file_names: &HashSet<String>,
+) -> (u64, u64) {
+    metas
+        .filter(|fm| file_names.contains(&fm.file_name))
+        .fold(None, |r, fm| match r {
+            Some((min, max)) => Some((
+                ::std::cmp::min(min, fm.smallest_seq),
+                ::std::cmp::max(max, fm.largest_seq),
+            )),
+            None => Some((fm.smallest_seq, fm.largest_seq)),
+        })
+        .unwrap_or((0, 0))
+}
+
 fn sort_readers(readers: &mut Vec<TableReader>) {
     readers.sort_by(|a, b| a.min_key().cmp(&b.min_key()))
 }
@@ -41,7 +125,8 @@ impl SstableReader {
         let mut r = SstableReader {
             opt_: opt.clone(),
             readers_,
-            manifest_builder_: ManifestBuilder::new(opt)?,
+            manifest_builder_: ManifestBuilder::new(opt.clone())?,
+            blobs_: BlobStore::new(opt.env.clone(), &opt.work_dir),
         };
         r.load()?;
         Ok(r)
@@ -50,7 +135,11 @@ impl SstableReader {
     fn load_reader(&self, file_meta: &FileMeta) -> MyResult<TableReader> {
         let path = Path::new(&self.opt_.work_dir);
         let path = path.join(&file_meta.file_name);
-        Ok(TableReader::new(&path, self.opt_.get_table_opt().clone())?)
+        open_table(
+            self.opt_.env.as_ref(),
+            &path,
+            self.opt_.get_table_opt().clone(),
+        )
     }
 
     pub fn get_readers(&self, level: usize) -> &Vec<TableReader> {
@@ -58,6 +147,20 @@ impl SstableReader {
         &self.readers_[level]
     }
 
+    /// The metas of `level`, in the same order as its readers.
+    pub fn get_file_metas(&self, level: usize) -> &[FileMeta] {
+        self.manifest_builder_
+            .file_metas(level)
+            .map_or(&[], |fms| &fms[..])
+    }
+
+    /// Whether any file of `level` may hold keys in `min_key..=max_key`.
+    pub fn overlaps(&self, level: usize, min_key: &[u8], max_key: &[u8]) -> bool {
+        self.get_file_metas(level)
+            .iter()
+            .any(|fm| fm.smallest_key.as_slice() <= max_key && fm.largest_key.as_slice() >= min_key)
+    }
+
     #[allow(clippy::op_ref)]
     pub fn search_readers<K>(&self, level: usize, key: &K) -> Vec<&TableReader>
     where
@@ -70,21 +173,22 @@ impl SstableReader {
         }
 
         let readers = self.get_readers(level);
+        let metas = self.get_file_metas(level);
         let key = key.borrow();
 
         if level == 0 {
-            for reader in readers.iter().rev() {
-                if &(reader.min_key())[..] <= key && &(reader.max_key())[..] >= key {
+            for (fm, reader) in metas.iter().zip(readers).rev() {
+                if &fm.smallest_key[..] <= key && &fm.largest_key[..] >= key {
                     res.push(reader);
                 }
             }
-        } else if !readers.is_empty() {
+        } else if !metas.is_empty() {
             let mut left = 0;
-            let mut right = readers.len() - 1;
+            let mut right = metas.len() - 1;
 
             while left < right {
                 let middle = (left + right + 1) / 2;
-                if &readers[middle].min_key()[..] < key {
+                if &metas[middle].smallest_key[..] < key {
                     left = middle;
                 } else {
                     right = middle - 1;
@@ -93,12 +197,12 @@ impl SstableReader {
 
             assert_eq!(left, right);
 
-            for reader in readers.iter().skip(left) {
-                if &(reader.min_key())[..] <= key && &(reader.max_key())[..] >= key {
+            for (fm, reader) in metas.iter().zip(readers).skip(left) {
+                if &fm.smallest_key[..] <= key && &fm.largest_key[..] >= key {
                     res.push(reader);
                     continue;
                 }
-                if &(reader.min_key())[..] > key {
+                if &fm.smallest_key[..] > key {
                     break;
                 }
             }
@@ -127,16 +231,80 @@ impl SstableReader {
         Ok(())
     }
 
+    /// Rereads the manifest, for a work dir another process writes. Tables
+    /// still in it stay open and only the new ones are opened; nothing
+    /// changes if one of them fails to open.
+    pub fn reload(&mut self) -> MyResult<()> {
+        let manifest_builder = ManifestBuilder::new(self.opt_.clone())?;
+        let open = self.live_files().into_iter().collect::<HashSet<_>>();
+        let mut readers = HashMap::new();
+        for level in 0..self.opt_.max_level {
+            for fm in manifest_builder.file_metas(level).into_iter().flatten() {
+                if !open.contains(&fm.file_name) {
+                    readers.insert(fm.file_name.clone(), self.load_reader(fm)?);
+                }
+            }
+        }
+        for reader in self.readers_.drain(..).flatten() {
+            readers.insert(reader.file_name().clone(), reader);
+        }
+        for level in 0..self.opt_.max_level {
+            let mut level_readers = manifest_builder
+                .file_metas(level)
+                .into_iter()
+                .flatten()
+                .filter_map(|fm| readers.remove(&fm.file_name))
+                .collect::<Vec<_>>();
+            if level != 0 {
+                sort_readers(&mut level_readers);
+            }
+            self.readers_.push(level_readers);
+        }
+        self.manifest_builder_ = manifest_builder;
+        Ok(())
+    }
+
     pub fn add(&mut self, level: usize, reader: TableReader) -> MyResult<()> {
         self.add_readers(level, vec![reader])
     }
 
+    /// Adds tables flushed from memtables, oldest first, each with the
+    /// sequences of the writes it holds. The newest one becomes the
+    /// manifest's last sequence, which recovery resumes numbering after.
+    pub fn add_flushed(&mut self, readers: Vec<(TableReader, Option<(u64, u64)>)>) -> MyResult<()> {
+        let last = readers
+            .iter()
+            .filter_map(|(_, seqs)| *seqs)
+            .map(|s| s.1)
+            .max();
+        if let Some(last) = last {
+            if last > self.manifest_builder_.manifest().last_sequence {
+                self.manifest_builder_.set_last_sequence(last);
+            }
+        }
+        let readers = readers
+            .into_iter()
+            .map(|(r, seqs)| (r, seqs.unwrap_or((0, 0))))
+            .collect();
+        self.add_with_seqs(0, readers)
+    }
+
     pub fn add_readers(&mut self, level: usize, readers: Vec<TableReader>) -> MyResult<()> {
+        let readers = readers.into_iter().map(|r| (r, (0, 0))).collect();
+        self.add_with_seqs(level, readers)
+    }
+
+    fn add_with_seqs(
+        &mut self,
+        level: usize,
+        readers: Vec<(TableReader, (u64, u64))>,
+    ) -> MyResult<()> {
         assert!(level < self.opt_.max_level);
 
-        for reader in readers {
+        for (reader, seqs) in readers {
+            self.register_blob_file(&reader)?;
             self.manifest_builder_
-                .add_file_meta(level, table_reader_to_file_meta(&reader));
+                .add_file_meta(level, table_reader_to_file_meta(&reader, seqs));
             let readers = &mut self.readers_[level];
             readers.push(reader);
             if level != 0 {
@@ -148,28 +316,178 @@ impl SstableReader {
         Ok(())
     }
 
-    pub fn remove_by_file_names(
+    /// Installs `changes` with a single manifest write, so a crash leaves
+    /// either the old or the new set of files. The removed files are only
+    /// deleted once the manifest no longer lists them.
+    pub fn install(&mut self, changes: TableChanges) -> MyResult<()> {
+        // what replaces the removed files holds the writes they held
+        let removed = changes
+            .removed
+            .iter()
+            .flat_map(|(_, names)| names.iter().cloned())
+            .collect::<HashSet<_>>();
+        let seqs = sequence_range(
+            (0..self.opt_.max_level).flat_map(|l| self.get_file_metas(l).iter()),
+            &removed,
+        );
+
+        for (level, file_names) in &changes.removed {
+            assert!(*level < self.opt_.max_level);
+            self.manifest_builder_
+                .remove_file_meta_by_file_names(*level, file_names);
+            self.readers_[*level].retain(|x| !file_names.contains(x.file_name()));
+        }
+        for (level, reader) in changes.added {
+            assert!(level < self.opt_.max_level);
+            self.register_blob_file(&reader)?;
+            self.manifest_builder_
+                .add_file_meta(level, table_reader_to_file_meta(&reader, seqs));
+            let readers = &mut self.readers_[level];
+            readers.push(reader);
+            if level != 0 {
+                sort_readers(readers);
+            }
+        }
+
+        let obsolete = self.record_blob_garbage(&changes.blob_garbage);
+        self.manifest_builder_.flush()?;
+
+        let work_dir = Path::new(&self.opt_.work_dir);
+        for file_name in &removed {
+            self.opt_.env.remove_file(&work_dir.join(file_name))?;
+        }
+        self.remove_blob_files(obsolete)
+    }
+
+    /// Moves `file_names` from `level` to `level + 1` without rewriting them,
+    /// for inputs that overlap nothing in `level + 1`. Only the manifest
+    /// changes.
+    pub fn move_files(&mut self, level: usize, file_names: &HashSet<String>) -> MyResult<()> {
+        assert!(level + 1 < self.opt_.max_level);
+
+        self.manifest_builder_
+            .move_file_metas(level, level + 1, file_names);
+        let (moved, kept) = self.readers_[level]
+            .drain(..)
+            .partition(|x| file_names.contains(x.file_name()));
+        self.readers_[level] = kept;
+        for reader in &moved {
+            reader.reset_seek_miss_count();
+        }
+        self.readers_[level + 1].extend(moved);
+        sort_readers(&mut self.readers_[level + 1]);
+
+        self.manifest_builder_.flush()
+    }
+
+    /// Swaps files of `level` for their rewritten outputs, which take the
+    /// place of the files, so in level 0 newer runs still shadow them.
+    pub fn replace_files(
         &mut self,
         level: usize,
-        file_names: &HashSet<String>,
+        inputs: &HashSet<String>,
+        outputs: Vec<TableReader>,
+        blob_garbage: &HashMap<usize, u64>,
     ) -> MyResult<()> {
-        assert!(level < self.opt_.max_level);
-
+        for reader in &outputs {
+            self.register_blob_file(reader)?;
+        }
+        let seqs = sequence_range(self.get_file_metas(level).iter(), inputs);
+        let metas = outputs
+            .iter()
+            .map(|r| table_reader_to_file_meta(r, seqs))
+            .collect();
         self.manifest_builder_
-            .remove_file_meta_by_file_names(level, file_names);
-        self.readers_[level].retain(|x| !file_names.contains(x.file_name()));
+            .replace_file_metas(level, inputs, metas);
+
+        let readers = &mut self.readers_[level];
+        let pos = readers
+            .iter()
+            .position(|x| inputs.contains(x.file_name()))
+            .unwrap_or_else(|| readers.len());
+        readers.retain(|x| !inputs.contains(x.file_name()));
+        readers.splice(pos..pos, outputs);
+        if level != 0 {
+            sort_readers(readers);
+        }
 
+        let obsolete = self.record_blob_garbage(blob_garbage);
         self.manifest_builder_.flush()?;
 
         let work_dir = Path::new(&self.opt_.work_dir);
-        for file_name in file_names {
-            let path = work_dir.join(file_name);
-            remove_file(&path)?;
+        for file_name in inputs {
+            self.opt_.env.remove_file(&work_dir.join(file_name))?;
         }
+        self.remove_blob_files(obsolete)
+    }
 
+    /// Large values of a table are written to the blob file sharing its file
+    /// number, which is registered together with the table.
+    fn register_blob_file(&mut self, reader: &TableReader) -> MyResult<()> {
+        let file_number = match blob_file_number(reader.file_name()) {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        if self.manifest_builder_.blob_file(file_number).is_some() {
+            return Ok(());
+        }
+        let path = Path::new(&self.opt_.work_dir).join(blob_file_name(file_number));
+        if !self.opt_.env.exists(&path) {
+            return Ok(());
+        }
+        self.manifest_builder_.add_blob_file(BlobFileMeta {
+            file_number,
+            total_bytes: self.opt_.env.open(&path)?.size()?,
+            garbage_bytes: 0,
+        });
         Ok(())
     }
 
+    /// Adds garbage found by a compaction and deletes blob files that are no
+    /// longer referenced by any table.
+    pub fn add_blob_garbage(&mut self, garbage: &HashMap<usize, u64>) -> MyResult<()> {
+        if garbage.is_empty() {
+            return Ok(());
+        }
+        let obsolete = self.record_blob_garbage(garbage);
+        self.manifest_builder_.flush()?;
+        self.remove_blob_files(obsolete)
+    }
+
+    /// Accounts `garbage` in the manifest, without writing it, and returns
+    /// the blob files that are no longer referenced.
+    fn record_blob_garbage(&mut self, garbage: &HashMap<usize, u64>) -> Vec<usize> {
+        let mut obsolete = vec![];
+        for (file_number, bytes) in garbage {
+            if let Some(meta) = self
+                .manifest_builder_
+                .add_blob_garbage(*file_number, *bytes)
+            {
+                obsolete.push(meta.file_number);
+            }
+        }
+        obsolete
+    }
+
+    fn remove_blob_files(&mut self, file_numbers: Vec<usize>) -> MyResult<()> {
+        let work_dir = Path::new(&self.opt_.work_dir);
+        for file_number in file_numbers {
+            self.blobs_.evict(file_number);
+            self.opt_
+                .env
+                .remove_file(&work_dir.join(blob_file_name(file_number)))?;
+        }
+        Ok(())
+    }
+
+    pub fn blob_file(&self, file_number: usize) -> Option<&BlobFileMeta> {
+        self.manifest_builder_.blob_file(file_number)
+    }
+
+    pub fn get_blob(&self, idx: &BlobIndex) -> MyResult<Vec<u8>> {
+        self.blobs_.get(idx)
+    }
+
     pub fn manifest_builder(&self) -> &ManifestBuilder {
         &self.manifest_builder_
     }
@@ -185,24 +503,89 @@ impl SstableReader {
         for i in 0..self.opt_.max_level {
             let readers = self.search_readers(i, k.borrow());
             for reader in readers {
-                let r = reader.get(k.borrow())?;
-                if r.is_some() {
-                    return Ok(r.map(Slice::from));
+                if let Some(v) = reader.get(k.borrow())? {
+                    if let Some(idx) = BlobIndex::decode(&v)? {
+                        return Ok(Some(Slice::from(self.blobs_.get(&idx)?)));
+                    }
+                    return Ok(Some(Slice::from(v)));
                 }
             }
         }
         Ok(None)
     }
 
+    /// Sums, over the tables overlapping `start..end`, the data bytes between
+    /// the offsets of its ends, and the table's entries in the same
+    /// proportion. No `end` runs to the tables' last keys. Older versions and
+    /// deletes still in the tables are counted too.
+    pub fn approximate_size(&self, start: &[u8], end: Option<&[u8]>) -> MyResult<ApproximateSize> {
+        let mut size = ApproximateSize::default();
+        for readers in &self.readers_ {
+            for reader in readers {
+                if reader.max_key()[..] < *start
+                    || end.map_or(false, |end| reader.min_key()[..] >= *end)
+                {
+                    continue;
+                }
+                // the index only bounds keys by block, so ends past the
+                // table's keys are clamped to the table itself
+                let start = if *start <= reader.min_key()[..] {
+                    0
+                } else {
+                    reader.approximate_offset_of(start)?
+                };
+                let end = match end {
+                    Some(end) if *end <= reader.max_key()[..] => {
+                        reader.approximate_offset_of(end)?
+                    }
+                    _ => reader.data_size(),
+                };
+                let bytes = end.saturating_sub(start) as u64;
+                size.bytes += bytes;
+                if reader.data_size() > 0 {
+                    size.keys +=
+                        reader.properties().num_entries * bytes / reader.data_size() as u64;
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// Names of the sstables and blob files in the manifest.
+    pub fn live_files(&self) -> Vec<String> {
+        self.manifest_builder_.manifest().live_files()
+    }
+
+    pub fn prefix_stats(&self) -> BTreeMap<Vec<u8>, PrefixStat> {
+        let mut stats = BTreeMap::new();
+        for readers in &self.readers_ {
+            for reader in readers {
+                reader.properties().merge_prefix_stats_into(&mut stats);
+            }
+        }
+        stats
+    }
+
+    pub fn level_stats(&self) -> Vec<LevelStats> {
+        self.readers_
+            .iter()
+            .map(|readers| LevelStats {
+                files: readers.len(),
+                bytes: readers.iter().map(|r| r.size() as u64).sum(),
+                entries: readers.iter().map(|r| r.properties().num_entries).sum(),
+            })
+            .collect()
+    }
+
     pub fn compute_compaction_levels(&self) -> Vec<usize> {
+        let max_bytes = self.max_bytes_for_levels();
         let mut scores = Vec::with_capacity(self.opt_.max_level);
         for i in 0..self.opt_.max_level {
             let readers = self.get_readers(i);
             let score = if i == 0 {
                 readers.len() as f64 / self.opt_.l0_compaction_trigger as f64
             } else {
-                readers.iter().map(TableReader::size).sum::<usize>() as f64
-                    / self.max_bytes_for_level(i)
+                self.level_size(i) as f64 / max_bytes[i]
             };
             if score >= 1. {
                 scores.push((i, score))
@@ -212,12 +595,67 @@ impl SstableReader {
         scores.iter().map(|x| x.0).collect()
     }
 
-    fn max_bytes_for_level(&self, level: usize) -> f64 {
-        let mut level = level;
-        let mut result = 10. * 1_048_576.;
-        while level > 1 {
-            result *= 10.;
-            level -= 1;
+    /// The first file, from the top, that gets missed in often enough to be
+    /// worth compacting. Files of the last level have nowhere to go.
+    pub fn seek_compaction_file(&self) -> Option<(usize, String)> {
+        let min_misses = self.opt_.seek_compaction_min_misses;
+        if min_misses == 0 {
+            return None;
+        }
+        for level in 0..self.opt_.max_level - 1 {
+            for reader in self.get_readers(level) {
+                let allowed = ::std::cmp::max(min_misses, reader.size() / BYTES_PER_MISS);
+                if reader.get_seek_miss_count() >= allowed {
+                    return Some((level, reader.file_name().clone()));
+                }
+            }
+        }
+        None
+    }
+
+    /// The oldest file of the first level, from the top, with files written
+    /// before `created_before`, in seconds since the epoch.
+    pub fn periodic_compaction_file(&self, created_before: u64) -> Option<(usize, String)> {
+        for (level, readers) in self.readers_.iter().enumerate() {
+            let oldest = readers
+                .iter()
+                .filter(|r| r.properties().creation_time < created_before)
+                .min_by_key(|r| r.properties().creation_time);
+            if let Some(reader) = oldest {
+                return Some((level, reader.file_name().clone()));
+            }
+        }
+        None
+    }
+
+    fn level_size(&self, level: usize) -> usize {
+        self.get_file_metas(level)
+            .iter()
+            .map(|fm| fm.file_size as usize)
+            .sum()
+    }
+
+    /// How many bytes each level may hold before it is compacted. Level 0
+    /// goes by its number of files instead.
+    pub fn max_bytes_for_levels(&self) -> Vec<f64> {
+        let base = self.opt_.max_bytes_for_level_base as f64;
+        let multiplier = self.opt_.max_bytes_for_level_multiplier;
+        let mut result = vec![base; self.opt_.max_level];
+        for level in 2..self.opt_.max_level {
+            result[level] = result[level - 1] * multiplier;
+        }
+        if !self.opt_.dynamic_level_bytes {
+            return result;
+        }
+        let bottom = (1..self.opt_.max_level)
+            .rev()
+            .find(|l| !self.get_readers(*l).is_empty());
+        if let Some(bottom) = bottom {
+            let mut bytes = self.level_size(bottom) as f64;
+            for level in (1..bottom).rev() {
+                bytes /= multiplier;
+                result[level] = bytes.max(base);
+            }
         }
         result
     }
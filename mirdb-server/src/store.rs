@@ -1,33 +1,80 @@
 use std::collections::HashMap;
 use std::convert::From;
 use std::error::Error;
-use std::fs::create_dir_all;
-use std::io::{Result, Write};
+use std::io::{ErrorKind, Result, Write};
+use std::iter;
+use std::ops::Range;
 use std::path::Path;
+use std::process;
+use std::ptr;
+use std::str;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use bincode::deserialize;
+use bincode::serialize;
+use log::error;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 
 use skip_list::SkipList;
 
+use crate::command_stats::CasOutcome;
+use crate::command_stats::CommandMetrics;
 use crate::data_manager::DataManager;
-use crate::error::{MyResult, StatusCode};
+use crate::env::open_table;
+use crate::env::Env;
+use crate::env::FileLock;
+use crate::error::{err, MyResult, StatusCode};
+use crate::item_stats::ItemStats;
+use crate::manifest::Manifest;
+use crate::merge::{MergeKind, MergeOperand};
 use crate::options::Options;
-use crate::request::{GetterType, Request, SetterType};
+use crate::options::WriteOptions;
+use crate::repair::repair;
+use crate::repair::RepairStats;
+use crate::request::{CompactionCommand, GetterType, Request, SetterType, StatsType};
+use crate::request::{MetaCommand, MetaFlags};
+use crate::resp;
 use crate::response::GetRespItem;
+use crate::response::MetaResponse;
 use crate::response::Response;
 use crate::slice::Slice;
+use crate::sstable_builder::entries_to_sstable;
+use crate::sstable_reader::ApproximateSize;
+use crate::sstable_reader::LevelStats;
+use crate::utils::to_str;
 
 pub type StoreKey = Slice;
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const LOCK_FILE: &str = "LOCK";
+/// Namespaces live in `<work_dir>/ns/<name>`.
+pub(crate) const NAMESPACE_DIR: &str = "ns";
+/// Keys of the form `<namespace>:<key>` are addressed to a namespace.
+const NAMESPACE_SEPARATOR: u8 = b':';
+
+/// Larger ttls are absolute unix times rather than seconds from now, as in
+/// memcached.
+pub(crate) const MAX_RELATIVE_TTL: u32 = 60 * 60 * 24 * 30;
+
+/// Longer keys are refused, as in memcached.
+pub(crate) const MAX_KEY_LEN: usize = 250;
+/// The error for values over `max_item_size`, which the decoders refuse.
+pub(crate) const TOO_LARGE: &str = "object too large for cache";
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct StorePayload {
     pub(crate) data: Slice,
     pub(crate) flags: u32,
-    ttl: u32,
+    pub(crate) ttl: u32,
     pub(crate) bytes: usize,
-    created_at: u64,
+    pub(crate) created_at: u64,
+    /// Changes on every write of the key, for `gets` and `cas`.
+    pub(crate) cas: u64,
 }
 
 impl StorePayload {
@@ -38,63 +85,803 @@ impl StorePayload {
             ttl,
             bytes,
             created_at,
+            cas: 0,
         }
     }
 
-    pub fn is_expired(&self) -> bool {
-        if self.ttl == 0 {
-            return false;
+    /// Gives the value `ttl` as of `now`: 0 for never, seconds from now up
+    /// to `MAX_RELATIVE_TTL` or else a unix time, as in memcached. Relative
+    /// ttls are stored as the unix time they end at, so the expiry is fixed
+    /// at write time.
+    pub fn set_ttl(&mut self, ttl: u32, now: u64) {
+        self.created_at = now;
+        self.ttl = if ttl == 0 || ttl > MAX_RELATIVE_TTL {
+            ttl
+        } else {
+            (now + u64::from(ttl)).min(u64::from(u32::max_value())) as u32
+        };
+    }
+
+    /// Unix time the value expires at, None if it never does. Values
+    /// written before ttls were stored as unix times may still hold
+    /// relative ones, counted from `created_at`.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self.ttl {
+            0 => None,
+            ttl if ttl > MAX_RELATIVE_TTL => Some(u64::from(ttl)),
+            ttl => Some(self.created_at + u64::from(ttl)),
         }
-        self.created_at + u64::from(self.ttl)
-            <= SystemTime::now()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at().map_or(false, |t| {
+            t <= SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs()
+        })
     }
 }
 
 pub struct Store {
     opt: Options,
     data: Arc<DataManager>,
+    /// Keyspaces besides the default one, by name, each with its own
+    /// memtables and levels, sharing the WAL of the default one.
+    namespaces: HashMap<Vec<u8>, Arc<DataManager>>,
+    lock: Mutex<Option<FileLock>>,
+    metrics: CommandMetrics,
+    started: Instant,
+}
+
+/// memcached's verbosity levels as log levels, 0 being the default of
+/// errors only.
+fn verbosity_to_level(level: u32) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn lock_work_dir(env: &dyn Env, path: &Path) -> MyResult<FileLock> {
+    match env.lock_file(&path.join(LOCK_FILE)) {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => err!(
+            StatusCode::IOError,
+            "work dir is locked by another instance"
+        ),
+        r => Ok(r?),
+    }
+}
+
+/// The options of the work dir in `opt` and of the namespaces that have a
+/// dir in it, the default namespace first.
+fn work_dir_opts(opt: &Options) -> Vec<Options> {
+    let env = opt.env.as_ref();
+    let path = Path::new(&opt.work_dir);
+    let mut opts = vec![opt.clone()];
+    for name in &opt.namespaces {
+        let ns_path = path.join(NAMESPACE_DIR).join(name);
+        if !env.is_dir(&ns_path) {
+            continue;
+        }
+        let mut ns_opt = opt.clone();
+        ns_opt.work_dir = ns_path.to_string_lossy().into_owned();
+        ns_opt.namespaces = vec![];
+        opts.push(ns_opt);
+    }
+    opts
+}
+
+/// The namespace in `namespaces` a key is addressed to, and where the key
+/// within it starts.
+pub(crate) fn split_namespace<'a, T>(
+    namespaces: &'a HashMap<Vec<u8>, T>,
+    key: &Slice,
+) -> Option<(&'a T, usize)> {
+    let i = key[..].iter().position(|b| *b == NAMESPACE_SEPARATOR)?;
+    namespaces.get(&key[..i]).map(|ns| (ns, i + 1))
+}
+
+/// `key` of the namespace `namespace`, as addressed from outside, with the
+/// namespace in front.
+fn namespaced_key(namespace: &[u8], key: &[u8]) -> Slice {
+    let mut k = Slice::from(namespace);
+    k.extend(key);
+    k
+}
+
+/// Answers a get or gets of `keys`, looking each one up with `get`.
+pub(crate) fn getter_response<F>(getter: GetterType, keys: Vec<Slice>, get: F) -> MyResult<Response>
+where
+    F: Fn(Slice) -> MyResult<Option<StorePayload>>,
+{
+    let mut v = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(p) = get(key.clone())? {
+            if !p.is_expired() {
+                let item = GetRespItem {
+                    key,
+                    data: p.data,
+                    flags: p.flags,
+                    bytes: p.bytes,
+                };
+                v.push((item, p.cas));
+            }
+        }
+    }
+    Ok(match getter {
+        GetterType::Get => Response::Get(v.into_iter().map(|(item, _)| item).collect()),
+        GetterType::Gets => Response::Gets(v),
+    })
+}
+
+/// Adds `delta` to the number in `current`, the value of `key` in `dm`, or
+/// with `decr` subtracts it, and writes the result. Returns the new number
+/// and value, or the response to answer with when there is no number.
+fn arith_payload(
+    dm: &DataManager,
+    key: Slice,
+    current: Option<StorePayload>,
+    decr: bool,
+    delta: u64,
+    initial: Option<(u64, u32)>,
+) -> MyResult<std::result::Result<(u64, StorePayload), Response>> {
+    let (n, mut p) = match current {
+        Some(p) => {
+            let n = match str::from_utf8(&p.data[..])
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+            {
+                Some(n) => n,
+                None => {
+                    return Ok(Err(Response::ClientError(
+                        "cannot increment or decrement non-numeric value".to_owned(),
+                    )))
+                }
+            };
+            let n = if decr {
+                n.saturating_sub(delta)
+            } else {
+                n.wrapping_add(delta)
+            };
+            (n, p)
+        }
+        None => match initial {
+            Some((n, ttl)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let mut p = StorePayload::new(Slice::new(), 0, 0, 0, now);
+                p.set_ttl(ttl, now);
+                (n, p)
+            }
+            None => return Ok(Err(Response::NotFound)),
+        },
+    };
+    let data = n.to_string();
+    p.bytes = data.len();
+    p.data = Slice::from(data);
+    p.cas = dm.next_cas();
+    dm.insert(key, p.clone())?;
+    Ok(Ok((n, p)))
+}
+
+/// The flags a meta command returns about `p`, the item it read or wrote,
+/// in the order they were asked for. Only the opaque and the key are
+/// returned without an item.
+fn meta_return_flags(
+    flags: &MetaFlags,
+    key: &Slice,
+    p: Option<&StorePayload>,
+    now: u64,
+) -> Vec<String> {
+    let mut v = vec![];
+    for (flag, token) in &flags.0 {
+        match (*flag, p) {
+            (b'O', _) => v.push(format!("O{}", to_str(token))),
+            (b'k', _) => v.push(format!("k{}", to_str(key))),
+            (b'c', Some(p)) => v.push(format!("c{}", p.cas)),
+            (b'f', Some(p)) => v.push(format!("f{}", p.flags)),
+            (b's', Some(p)) => v.push(format!("s{}", p.bytes)),
+            (b't', Some(p)) => match p.expires_at() {
+                Some(t) => v.push(format!("t{}", t.saturating_sub(now))),
+                None => v.push("t-1".to_owned()),
+            },
+            _ => {}
+        }
+    }
+    v
 }
 
 impl Store {
     pub fn new(opt: Options) -> MyResult<Self> {
+        let env = opt.env.as_ref();
         let path = Path::new(&opt.work_dir);
-        if !path.exists() {
-            create_dir_all(path)?;
-        } else if !path.is_dir() {
+        if !env.exists(path) {
+            env.create_dir_all(path)?;
+        } else if !env.is_dir(path) {
             return err!(StatusCode::IOError, "work dir is not a dir");
         }
-        let dm = DataManager::new(opt.clone())?;
-        #[cfg(not(test))]
-        {
+        let lock = lock_work_dir(env, path)?;
+        let dm = DataManager::with_namespaces(opt.clone(), &opt.namespaces)?;
+        if !opt.disable_background_jobs {
             DataManager::background_thread(dm.clone());
         }
-        Ok(Store { data: dm, opt })
+        let mut namespaces = HashMap::new();
+        for name in &opt.namespaces {
+            let ns_path = path.join(NAMESPACE_DIR).join(name);
+            env.create_dir_all(&ns_path)?;
+            let mut ns_opt = opt.clone();
+            ns_opt.work_dir = ns_path.to_string_lossy().into_owned();
+            ns_opt.namespaces = vec![];
+            let ns_dm = DataManager::open_namespace(ns_opt, name, &dm)?;
+            if !opt.disable_background_jobs {
+                DataManager::background_thread(ns_dm.clone());
+            }
+            namespaces.insert(name.as_bytes().to_vec(), ns_dm);
+        }
+        Ok(Store {
+            data: dm,
+            namespaces,
+            opt,
+            lock: Mutex::new(Some(lock)),
+            metrics: CommandMetrics::new(),
+            started: Instant::now(),
+        })
     }
 
-    pub fn apply(&self, request: Request) -> MyResult<Response> {
-        match request {
-            Request::Getter { getter, keys } => {
-                let mut v = Vec::with_capacity(keys.len());
-                for key in keys {
-                    if let Some(p) = self.data.get(&key)? {
-                        if !p.is_expired() {
-                            v.push(GetRespItem {
-                                key,
-                                data: p.data,
-                                flags: p.flags,
-                                bytes: p.bytes,
-                            });
+    /// Rebuilds the manifests of the work dir in `opt` and of its namespaces
+    /// from the files in them, returning what was found in each, the default
+    /// namespace first. The LOCK file is held meanwhile, so this fails while
+    /// a store has the work dir open. See `repair::repair`.
+    pub fn repair(opt: &Options) -> MyResult<Vec<RepairStats>> {
+        let env = opt.env.as_ref();
+        let path = Path::new(&opt.work_dir);
+        if !env.is_dir(path) {
+            return err!(StatusCode::IOError, "work dir is not a dir");
+        }
+        let lock = lock_work_dir(env, path)?;
+        let stats = work_dir_opts(opt)
+            .iter()
+            .map(repair)
+            .collect::<MyResult<Vec<_>>>()?;
+        drop(lock);
+        Ok(stats)
+    }
+
+    /// The manifests of the work dir in `opt` and of its namespaces, the
+    /// default namespace first, each with the options of its work dir. For
+    /// inspection, so a store may have the work dir open meanwhile.
+    pub fn manifests(opt: &Options) -> MyResult<Vec<(Options, Manifest)>> {
+        let env = opt.env.as_ref();
+        if !env.is_dir(Path::new(&opt.work_dir)) {
+            return err!(StatusCode::IOError, "work dir is not a dir");
+        }
+        work_dir_opts(opt)
+            .into_iter()
+            .map(|opt| {
+                let manifest = Manifest::load(&opt)?;
+                Ok((opt, manifest))
+            })
+            .collect()
+    }
+
+    /// The data manager of the namespace `key` is addressed to, and the key
+    /// within it. Keys without a known namespace go to the default one.
+    fn route(&self, key: Slice) -> (&DataManager, Slice) {
+        match split_namespace(&self.namespaces, &key) {
+            Some((dm, i)) => (&**dm, key.slice_from(i)),
+            None => (&*self.data, key),
+        }
+    }
+
+    /// The data manager of the namespace a key range is addressed to, and
+    /// the range within it. A range starting in a namespace ends at its last
+    /// key at the latest; any other range covers the default namespace only.
+    fn route_range<'a>(
+        &self,
+        start: &'a [u8],
+        end: &'a [u8],
+    ) -> (&DataManager, &'a [u8], Option<&'a [u8]>) {
+        match split_namespace(&self.namespaces, &Slice::from(start)) {
+            Some((dm, i)) => {
+                let prefix = &start[..i];
+                let end = if end.starts_with(prefix) {
+                    Some(&end[i..])
+                } else if end > prefix {
+                    None
+                } else {
+                    Some(&b""[..])
+                };
+                (&**dm, &start[i..], end)
+            }
+            None => (&*self.data, start, Some(end)),
+        }
+    }
+
+    fn data_managers(&self) -> impl Iterator<Item = &Arc<DataManager>> {
+        iter::once(&self.data).chain(self.namespaces.values())
+    }
+
+    /// The largest value a set may store.
+    pub fn max_item_size(&self) -> usize {
+        self.opt.max_item_size
+    }
+
+    /// Stops background jobs, flushes or abandons the memtables according to
+    /// `flush_on_close` and releases the LOCK file. Requests made afterwards
+    /// fail without touching any file. Calling it again is a no-op.
+    pub fn close(&self) -> MyResult<()> {
+        let mut lock = self.lock.lock().unwrap();
+        if lock.is_none() {
+            return Ok(());
+        }
+        for dm in self.data_managers() {
+            dm.close()?;
+        }
+        // releases the LOCK file
+        lock.take();
+        Ok(())
+    }
+
+    /// Writes a copy of the store, namespaces included, that another instance
+    /// can open to `dir`. See `DataManager::checkpoint`.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> MyResult<()> {
+        let dir = dir.as_ref();
+        self.data.checkpoint(dir)?;
+        for (name, dm) in &self.namespaces {
+            let name = String::from_utf8_lossy(name);
+            dm.checkpoint(dir.join(NAMESPACE_DIR).join(name.as_ref()))?;
+        }
+        Ok(())
+    }
+
+    /// Estimated bytes and keys in each of `ranges`, routed like in
+    /// `route_range`. See `DataManager::approximate_sizes`.
+    pub fn approximate_sizes(&self, ranges: &[Range<Vec<u8>>]) -> MyResult<Vec<ApproximateSize>> {
+        ranges
+            .iter()
+            .map(|r| {
+                let (dm, start, end) = self.route_range(&r.start, &r.end);
+                dm.approximate_size(start, end)
+            })
+            .collect()
+    }
+
+    /// Installs a pre-built sstable in the default namespace, returning the
+    /// level it went to. A table whose key range reaches into a namespace,
+    /// the keys starting with `<name>:`, is refused, as those keys would be
+    /// out of reach there. See `DataManager::ingest_sstable`.
+    pub fn ingest_sstable<P: AsRef<Path>>(&self, path: P) -> MyResult<usize> {
+        let path = path.as_ref();
+        let reader = open_table(self.opt.env.as_ref(), path, self.opt.get_table_opt())?;
+        for name in self.namespaces.keys() {
+            let mut start = name.clone();
+            start.push(NAMESPACE_SEPARATOR);
+            let mut end = name.clone();
+            end.push(NAMESPACE_SEPARATOR + 1);
+            if *reader.max_key() >= start && *reader.min_key() < end {
+                return err(
+                    StatusCode::InvalidArgument,
+                    format!(
+                        "ingested sstable has keys in namespace {}",
+                        String::from_utf8_lossy(name)
+                    ),
+                );
+            }
+        }
+        drop(reader);
+        self.data.ingest_sstable(path)
+    }
+
+    /// Writes the live keys in `[start, end)` to a new sstable at `path`,
+    /// which `ingest_sstable` can install in another store. A range in a
+    /// namespace, see `route_range`, is written with the keys within it,
+    /// so it is installed in the default namespace there. Values are kept
+    /// inline, so the table needs no blob file. Returns how many keys were
+    /// written; an empty range creates no file.
+    pub fn export_range<P: AsRef<Path>>(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        path: P,
+    ) -> MyResult<usize> {
+        let path = path.as_ref();
+        if self.opt.env.exists(path) {
+            return err!(StatusCode::InvalidArgument, "export path already exists");
+        }
+        let mut opt = self.opt.clone();
+        opt.blob_min_size = 0;
+        let (dm, start, end) = self.route_range(start, end);
+        let mut range = dm.range(start, end)?;
+        let mut failure = None;
+        let mut count = 0;
+        let entries = iter::from_fn(|| {
+            // ingested values are `Option<StorePayload>`s, like in the levels
+            let entry = range.next()?.and_then(|(k, v)| {
+                let p: StorePayload = deserialize(&v[..])?;
+                Ok((k, Slice::from(serialize(&Some(p))?)))
+            });
+            match entry {
+                Ok(entry) => {
+                    count += 1;
+                    Some(entry)
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    None
+                }
+            }
+        });
+        let r = entries_to_sstable(entries, &opt, path);
+        if let Some(e) = failure.or(r.err()) {
+            if self.opt.env.exists(path) {
+                self.opt.env.remove_file(path)?;
+            }
+            return Err(e);
+        }
+        Ok(count)
+    }
+
+    /// Every live key with its value: those of the default namespace in key
+    /// order, then those of each namespace, by name, with the namespace in
+    /// front. Each namespace is read as of one point in time. See
+    /// `DataManager::scan`.
+    pub fn scan(&self) -> MyResult<impl Iterator<Item = MyResult<(StoreKey, StorePayload)>>> {
+        let mut names = self.namespaces.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut iters = vec![(vec![], self.data.scan()?)];
+        for name in names {
+            let mut prefix = name.clone();
+            prefix.push(NAMESPACE_SEPARATOR);
+            iters.push((prefix, self.namespaces[name].scan()?));
+        }
+        Ok(iters.into_iter().flat_map(|(prefix, iter)| {
+            iter.map(move |r| {
+                let (k, v) = r?;
+                let k = if prefix.is_empty() {
+                    k
+                } else {
+                    namespaced_key(&prefix, &k[..])
+                };
+                Ok((k, deserialize(&v[..])?))
+            })
+        }))
+    }
+
+    /// Deletes every key in `[start, end)`, returning how many were live.
+    /// See `route_range` for ranges in a namespace. The keys are found by a
+    /// range scan and deleted one at a time, so a key written into the range
+    /// meanwhile may survive.
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> MyResult<usize> {
+        let (dm, start, end) = self.route_range(start, end);
+        let mut deleted = 0;
+        for r in dm.range(start, end)? {
+            let (key, _) = r?;
+            let _key_lock = dm.lock_key(&key[..]);
+            if dm.remove(&key)?.is_some() {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// The live keys in `[start, end)` with their values, in key order, at
+    /// most `limit` of them or all with 0. See `route_range` for ranges in a
+    /// namespace, whose keys keep its name in front. Only the range is read,
+    /// and the merge stops at `end` or once `limit` keys are found.
+    pub fn scan_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: usize,
+    ) -> MyResult<Vec<(StoreKey, StorePayload)>> {
+        let (dm, rest, end) = self.route_range(start, end);
+        let namespace = &start[..start.len() - rest.len()];
+        let mut v = vec![];
+        dm.scan_range(rest, end, |key, p| {
+            let key = if namespace.is_empty() {
+                key
+            } else {
+                namespaced_key(namespace, &key[..])
+            };
+            v.push((key, p));
+            Ok(limit == 0 || v.len() < limit)
+        })?;
+        Ok(v)
+    }
+
+    /// Gives the value of `key` a new ttl, counted from now, and returns it.
+    /// The cas unique stays, as only the expiry changes.
+    fn get_and_touch(&self, key: Slice, ttl: u32) -> MyResult<Option<StorePayload>> {
+        let (dm, key) = self.route(key);
+        let _key_lock = dm.lock_key(&key[..]);
+        let mut p = match dm.get(&key)? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        p.set_ttl(ttl, now);
+        dm.insert(key, p.clone())?;
+        Ok(Some(p))
+    }
+
+    /// Adds `delta` to the decimal number in the value of `key`, or with
+    /// `decr` subtracts it. As in memcached, increments wrap around at 2^64
+    /// and decrements stop at 0. A missing key is created from `initial`, if
+    /// given.
+    fn arith(
+        &self,
+        key: Slice,
+        decr: bool,
+        delta: u64,
+        initial: Option<(u64, u32)>,
+    ) -> MyResult<Response> {
+        let (dm, key) = self.route(key);
+        let _key_lock = dm.lock_key(&key[..]);
+        let current = dm.get(&key)?;
+        Ok(
+            match arith_payload(dm, key, current, decr, delta, initial)? {
+                Ok((n, _)) => Response::Number(n),
+                Err(r) => r,
+            },
+        )
+    }
+
+    /// Deletes the keys, in one batch per keyspace they are in.
+    fn delete_many(&self, keys: Vec<Slice>, write_opt: WriteOptions) -> MyResult<Response> {
+        let routed: Vec<_> = keys.into_iter().map(|key| self.route(key)).collect();
+        let mut deleted = vec![None; routed.len()];
+        for i in 0..routed.len() {
+            if deleted[i].is_some() {
+                continue;
+            }
+            let dm = routed[i].0;
+            let same: Vec<_> = (i..routed.len())
+                .filter(|j| ptr::eq(routed[*j].0, dm))
+                .collect();
+            let keys: Vec<_> = same.iter().map(|j| routed[*j].1.clone()).collect();
+            for (j, found) in same.into_iter().zip(dm.remove_batch(&keys, write_opt)?) {
+                self.metrics.record_delete(found);
+                deleted[j] = Some(found);
+            }
+        }
+        Ok(Response::Multi(
+            deleted
+                .into_iter()
+                .map(|found| match found {
+                    Some(true) => Response::Deleted,
+                    _ => Response::NotFound,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Runs a meta command. Keys are taken as they are, base64 ones (the
+    /// `b` flag) are not supported, nor is creating missing keys on `mg`.
+    fn meta(&self, command: MetaCommand, key: Slice, flags: MetaFlags) -> MyResult<Response> {
+        let bad_token = || {
+            Ok(Response::ClientError(
+                "bad token in command line format".to_owned(),
+            ))
+        };
+        let (ttl, unique, client_flags) = match (
+            flags.number::<u32>(b'T'),
+            flags.number::<u64>(b'C'),
+            flags.number::<u32>(b'F'),
+        ) {
+            (Ok(ttl), Ok(unique), Ok(client_flags)) => (ttl, unique, client_flags),
+            _ => return bad_token(),
+        };
+        let (vivify, initial, delta) = match (
+            flags.number::<u32>(b'N'),
+            flags.number::<u64>(b'J'),
+            flags.number::<u64>(b'D'),
+        ) {
+            (Ok(vivify), Ok(initial), Ok(delta)) => (vivify, initial, delta),
+            _ => return bad_token(),
+        };
+        let mode = flags.token(b'M').and_then(|m| m[..].first().cloned());
+        // what `q` leaves out
+        let hidden: &[&str] = match command {
+            MetaCommand::Get => &["EN"],
+            MetaCommand::Set(_) => &["HD"],
+            MetaCommand::Delete | MetaCommand::Arith => &["HD", "NF"],
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let (dm, k) = self.route(key.clone());
+        let _key_lock = dm.lock_key(&k[..]);
+        let current = dm.get(&k)?;
+        let mut r = MetaResponse::new("HD");
+        let mismatch = match (unique, &current) {
+            (Some(unique), Some(p)) => p.cas != unique,
+            _ => false,
+        };
+        match command {
+            MetaCommand::Get => {
+                self.metrics.record_get(current.is_some());
+                match current {
+                    None => r.code = "EN",
+                    Some(mut p) => {
+                        if let Some(ttl) = ttl {
+                            p.set_ttl(ttl, now);
+                            dm.insert(k, p.clone())?;
+                        }
+                        if flags.has(b'v') {
+                            r.code = "VA";
+                            r.value = Some(p.data.clone());
                         }
+                        r.flags = meta_return_flags(&flags, &key, Some(&p), now);
                     }
                 }
-                Ok(match getter {
-                    GetterType::Get => Response::Get(v),
-                    GetterType::Gets => Response::Gets(v),
-                })
             }
+            MetaCommand::Set(payload) => {
+                self.metrics.record_set();
+                let mut sp = StorePayload {
+                    bytes: payload.len(),
+                    data: payload,
+                    flags: client_flags.unwrap_or(0),
+                    ttl: 0,
+                    created_at: now,
+                    cas: dm.next_cas(),
+                };
+                sp.set_ttl(ttl.unwrap_or(0), now);
+                let write = match mode.unwrap_or(b'S') {
+                    b'S' | b's' => true,
+                    b'E' | b'e' => current.is_none(),
+                    b'R' | b'r' | b'A' | b'a' | b'P' | b'p' => current.is_some(),
+                    _ => return bad_token(),
+                };
+                if unique.is_some() && current.is_none() {
+                    r.code = "NF";
+                } else if mismatch {
+                    r.code = "EX";
+                } else if !write {
+                    r.code = "NS";
+                } else {
+                    match mode {
+                        Some(b'A') | Some(b'a') => {
+                            dm.merge(k, MergeOperand::new(MergeKind::Append, sp.clone()))?;
+                        }
+                        Some(b'P') | Some(b'p') => {
+                            dm.merge(k, MergeOperand::new(MergeKind::Prepend, sp.clone()))?;
+                        }
+                        _ => {
+                            dm.insert(k, sp.clone())?;
+                        }
+                    }
+                }
+                r.flags = meta_return_flags(&flags, &key, Some(&sp), now);
+            }
+            MetaCommand::Delete => {
+                self.metrics.record_delete(current.is_some());
+                if current.is_none() {
+                    r.code = "NF";
+                } else if mismatch {
+                    r.code = "EX";
+                } else {
+                    dm.remove(&k)?;
+                }
+                r.flags = meta_return_flags(&flags, &key, None, now);
+            }
+            MetaCommand::Arith => {
+                let decr = match mode {
+                    None | Some(b'I') | Some(b'i') | Some(b'+') => false,
+                    Some(b'D') | Some(b'd') | Some(b'-') => true,
+                    _ => return bad_token(),
+                };
+                let initial = vivify.map(|ttl| (initial.unwrap_or(0), ttl));
+                if mismatch {
+                    r.code = "EX";
+                } else {
+                    match arith_payload(dm, k, current, decr, delta.unwrap_or(1), initial)? {
+                        Ok((n, p)) => {
+                            if flags.has(b'v') {
+                                r.code = "VA";
+                                r.value = Some(p.data.clone());
+                            }
+                            r.flags = meta_return_flags(&flags, &key, Some(&p), now);
+                        }
+                        Err(Response::NotFound) => r.code = "NF",
+                        Err(e) => return Ok(e),
+                    }
+                }
+            }
+        }
+        r.quiet = flags.has(b'q') && hidden.contains(&r.code);
+        Ok(Response::Meta(r))
+    }
+
+    /// What a bare `stats` returns: memcached's usual stats, over all the
+    /// namespaces, and the sstables of each level. Items and bytes are what
+    /// the memtables and sstables hold, older versions and deletes included,
+    /// as telling them apart would mean reading everything. Nothing is ever
+    /// evicted, values only expire.
+    fn general_stats(&self) -> Vec<(String, String)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut items = 0;
+        let mut bytes = 0;
+        let mut levels = vec![LevelStats::default(); self.opt.max_level];
+        for dm in self.data_managers() {
+            let memtables = dm.memtable_stats();
+            for t in iter::once(&memtables.mutable).chain(&memtables.immutables) {
+                items += t.entries as u64;
+                bytes += t.bytes as u64;
+            }
+            for (total, level) in levels.iter_mut().zip(dm.level_stats()) {
+                total.files += level.files;
+                total.bytes += level.bytes;
+                total.entries += level.entries;
+            }
+        }
+        items += levels.iter().map(|l| l.entries).sum::<u64>();
+        bytes += levels.iter().map(|l| l.bytes).sum::<u64>();
+
+        let pair = |name: &str, value: u64| (name.to_owned(), value.to_string());
+        let mut v = vec![
+            pair("pid", u64::from(process::id())),
+            pair("uptime", self.started.elapsed().as_secs()),
+            pair("time", now),
+            ("version".to_owned(), VERSION.to_owned()),
+            pair("curr_items", items),
+            pair("bytes", bytes),
+            pair("evictions", 0),
+        ];
+        v.extend(self.metrics.stats().to_pairs());
+        for (i, level) in levels.iter().enumerate() {
+            v.push(pair(&format!("sstables:l{}:files", i), level.files as u64));
+            v.push(pair(&format!("sstables:l{}:bytes", i), level.bytes));
+        }
+        v
+    }
+
+    /// The live items of every keyspace by size. Walks them all.
+    fn item_stats(&self) -> MyResult<ItemStats> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut stats = ItemStats::new(now);
+        for dm in self.data_managers() {
+            for r in dm.scan()? {
+                let (key, v) = r?;
+                let p: StorePayload = deserialize(&v[..])?;
+                stats.add(key.len() + p.bytes, p.created_at);
+            }
+        }
+        Ok(stats)
+    }
+
+    pub fn apply(&self, request: Request) -> MyResult<Response> {
+        if request.keys().iter().any(|key| key.len() > MAX_KEY_LEN) {
+            return Ok(Response::ClientError("bad command line format".to_owned()));
+        }
+        match request {
+            Request::Getter { getter, keys } => getter_response(getter, keys, |key| {
+                let (dm, k) = self.route(key);
+                let r = dm.get(&k)?;
+                self.metrics.record_get(r.is_some());
+                Ok(r)
+            }),
+            Request::GetAndTouch { getter, ttl, keys } => getter_response(getter, keys, |key| {
+                let r = self.get_and_touch(key, ttl)?;
+                self.metrics.record_touch(r.is_some());
+                Ok(r)
+            }),
             Request::Setter {
                 setter,
                 key,
@@ -102,100 +889,217 @@ impl Store {
                 ttl,
                 bytes,
                 payload,
+                write_opt,
                 ..
             } => {
                 if payload.len() > bytes {
                     return Ok(Response::ClientError("bad data chunk".to_owned()));
                 }
+                self.metrics.record_set();
+                let (dm, key) = self.route(key);
                 let data = Slice::from(&payload[..bytes as usize]);
                 let created_at = SystemTime::now()
                     .duration_since(UNIX_EPOCH)
                     .unwrap()
                     .as_secs();
-                let sp = StorePayload {
+                let mut sp = StorePayload {
                     flags,
-                    ttl,
+                    ttl: 0,
                     bytes,
                     data,
                     created_at,
+                    cas: dm.next_cas(),
                 };
+                sp.set_ttl(ttl, created_at);
+                // add, replace, cas and the merges look at the key before writing it
+                let _key_lock = dm.lock_key(&key[..]);
                 match setter {
                     SetterType::Set => {
-                        self.data.insert(key, sp)?;
+                        dm.insert_opt(key, sp, write_opt)?;
                     }
                     SetterType::Add => {
-                        // Cannot use self.data.entry(key).or_insert(sp);
+                        // Cannot use dm.entry(key).or_insert(sp);
                         // because of the NOT_STORED response
-                        if self.data.get(&key)?.is_none() {
-                            self.data.insert(key, sp)?;
+                        if dm.get(&key)?.is_none() {
+                            dm.insert_opt(key, sp, write_opt)?;
                         } else {
                             return Ok(Response::NotStored);
                         }
                     }
                     SetterType::Replace => {
-                        // Cannot use self.data.entry(key).and_modify(|e| *e = sp);
+                        // Cannot use dm.entry(key).and_modify(|e| *e = sp);
                         // because of the NOT_STORED response
-                        if self.data.get(&key)?.is_none() {
+                        if dm.get(&key)?.is_none() {
                             return Ok(Response::NotStored);
                         } else {
-                            self.data.insert(key, sp)?;
+                            dm.insert_opt(key, sp, write_opt)?;
                         }
                     }
+                    SetterType::Cas(unique) => match dm.get(&key)? {
+                        None => {
+                            self.metrics.record_cas(CasOutcome::NotFound);
+                            return Ok(Response::NotFound);
+                        }
+                        Some(p) if p.cas != unique => {
+                            self.metrics.record_cas(CasOutcome::Exists);
+                            return Ok(Response::Exists);
+                        }
+                        Some(_) => {
+                            dm.insert_opt(key, sp, write_opt)?;
+                            self.metrics.record_cas(CasOutcome::Stored);
+                        }
+                    },
                     SetterType::Append => {
-                        if let Some(v) = self.data.get(&key)? {
-                            let mut c = v.clone();
-                            c.data.extend(sp.data);
-                            c.ttl = sp.ttl;
-                            c.created_at = sp.created_at;
-                            c.bytes += sp.bytes;
-                            c.flags = sp.flags;
-                            self.data.insert(key, c)?;
-                        } else {
+                        let op = MergeOperand::new(MergeKind::Append, sp);
+                        if !dm.merge_opt(key, op, write_opt)? {
                             return Ok(Response::NotStored);
                         }
                     }
                     SetterType::Prepend => {
-                        if let Some(v) = self.data.get(&key)? {
-                            let mut tmp: Slice = sp.data.to_owned();
-                            let mut c = v.clone();
-                            tmp.extend(&v.data);
-                            c.data = tmp;
-                            c.ttl = sp.ttl;
-                            c.created_at = sp.created_at;
-                            c.bytes += sp.bytes;
-                            c.flags = sp.flags;
-                            self.data.insert(key, c)?;
-                        } else {
+                        let op = MergeOperand::new(MergeKind::Prepend, sp);
+                        if !dm.merge_opt(key, op, write_opt)? {
                             return Ok(Response::NotStored);
                         }
                     }
                 }
                 Ok(Response::Stored)
             }
-            Request::Deleter { key, .. } => match self.data.remove(&key)? {
-                Some(_) => Ok(Response::Deleted),
-                None => Ok(Response::NotFound),
-            },
+            Request::Deleter { key, write_opt, .. } => {
+                let (dm, key) = self.route(key);
+                let _key_lock = dm.lock_key(&key[..]);
+                let r = dm.remove_opt(&key, write_opt)?;
+                self.metrics.record_delete(r.is_some());
+                match r {
+                    Some(_) => Ok(Response::Deleted),
+                    None => Ok(Response::NotFound),
+                }
+            }
+            Request::Scan { start, end, limit } => {
+                let items = self.scan_range(&start[..], &end[..], limit)?;
+                Ok(Response::Get(
+                    items
+                        .into_iter()
+                        .map(|(key, p)| GetRespItem::new(key, p.data, p.flags, p.bytes))
+                        .collect(),
+                ))
+            }
+            Request::Keys { prefix, limit } => {
+                // keys in a namespace keep its name in front
+                let (dm, rest) = self.route(prefix.clone());
+                let namespace = &prefix[..prefix.len() - rest.len()];
+                let keys = dm.keys_with_prefix(&rest[..], limit)?;
+                Ok(Response::Keys(
+                    keys.into_iter()
+                        .map(|k| namespaced_key(namespace, &k[..]))
+                        .collect(),
+                ))
+            }
+            Request::MultiDeleter {
+                keys, write_opt, ..
+            } => self.delete_many(keys, write_opt),
+            Request::Arith {
+                key,
+                decr,
+                delta,
+                initial,
+                ..
+            } => self.arith(key, decr, delta, initial),
+            Request::Meta {
+                command,
+                key,
+                flags,
+            } => self.meta(command, key, flags),
+            Request::MetaNoop => Ok(Response::Meta(MetaResponse::new("MN"))),
             Request::Info => Ok(Response::Info(self.data.info())),
-            Request::Error => Ok(Response::Error),
+            Request::Noop => Ok(Response::Ok),
+            Request::Version => Ok(Response::Version(VERSION.to_owned())),
+            Request::Verbosity { level, .. } => {
+                log::set_max_level(verbosity_to_level(level));
+                Ok(Response::Ok)
+            }
+            Request::Stats(StatsType::Prefixes) => {
+                let mut v = vec![];
+                for (prefix, stat) in self.data.prefix_stats() {
+                    let prefix = String::from_utf8_lossy(&prefix);
+                    v.push((format!("prefix:{}:keys", prefix), stat.keys.to_string()));
+                    v.push((format!("prefix:{}:bytes", prefix), stat.bytes.to_string()));
+                }
+                Ok(Response::Stats(v))
+            }
+            Request::Stats(StatsType::General) => Ok(Response::Stats(self.general_stats())),
+            Request::Stats(StatsType::Items) => {
+                Ok(Response::Stats(self.item_stats()?.items_pairs()))
+            }
+            Request::Stats(StatsType::Sizes) => {
+                Ok(Response::Stats(self.item_stats()?.sizes_pairs()))
+            }
+            Request::Stats(StatsType::Reset) => {
+                self.metrics.reset();
+                Ok(Response::Reset)
+            }
+            Request::Stats(StatsType::Wal) => Ok(Response::Stats(self.data.wal_stats().to_pairs())),
+            Request::Stats(StatsType::Memtable) => {
+                Ok(Response::Stats(self.data.memtable_stats().to_pairs()))
+            }
+            Request::Stats(StatsType::Compaction) => {
+                Ok(Response::Stats(self.data.compaction_stats().to_pairs()))
+            }
+            // only asked for when there are no credentials, as memcached
+            // without SASL
+            Request::Error | Request::Quit | Request::SaslListMechs | Request::SaslAuth { .. } => {
+                Ok(Response::Error)
+            }
+            Request::Malformed => Ok(Response::ClientError("bad command line format".to_owned())),
+            Request::TooLarge => Ok(Response::ServerError(TOO_LARGE.to_owned())),
+            Request::Resp(args) => Ok(Response::Resp(resp::apply(self, args)?)),
             Request::MajorCompaction => {
-                self.data.major_compaction()?;
+                if self.data.is_compaction_paused() {
+                    return Ok(Response::ServerError("compaction is paused".to_owned()));
+                }
+                for dm in self.data_managers() {
+                    dm.major_compaction()?;
+                }
+                Ok(Response::Ok)
+            }
+            Request::Compaction(CompactionCommand::Pause) => {
+                self.data_managers().for_each(|dm| dm.pause_compaction());
+                Ok(Response::Ok)
+            }
+            Request::Compaction(CompactionCommand::Resume) => {
+                self.data_managers().for_each(|dm| dm.resume_compaction());
+                Ok(Response::Ok)
+            }
+            Request::Compaction(CompactionCommand::RateLimit(bytes_per_sec)) => {
+                self.data_managers()
+                    .for_each(|dm| dm.set_rate_limit(bytes_per_sec));
                 Ok(Response::Ok)
             }
         }
     }
 }
 
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("close store error: {:?}", e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashSet;
     use std::fs::create_dir_all;
     use std::fs::remove_dir_all;
     use std::path::Path;
+    use std::thread;
 
+    use bytes::BytesMut;
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
 
+    use crate::options::WriteOptions;
+    use crate::response::BufferWriter;
     use crate::test_utils::get_test_opt;
     use crate::utils::to_str;
 
@@ -226,6 +1130,7 @@ mod test {
             payload: payload.clone(),
             bytes: payload.len(),
             no_reply: false,
+            write_opt: WriteOptions::default(),
         });
         assert!(r.is_ok(), "stored");
         let r = store.apply(Request::Getter {
@@ -263,6 +1168,7 @@ mod test {
                 bytes: payload.len(),
                 payload,
                 no_reply: false,
+                write_opt: WriteOptions::default(),
             });
             assert_eq!(Ok(Response::Stored), r);
         }
@@ -291,6 +1197,7 @@ mod test {
             let r = store.apply(Request::Deleter {
                 key: key.clone(),
                 no_reply: false,
+                write_opt: WriteOptions::default(),
             });
             assert_eq!(Ok(Response::Deleted), r);
             println!("delete key: {}", to_str(&key));
@@ -337,7 +1244,846 @@ mod test {
             bytes: payload.len() - 1,
             payload,
             no_reply: false,
+            write_opt: WriteOptions::default(),
         });
         assert_eq!(Ok(Response::ClientError("bad data chunk".to_owned())), r);
     }
+
+    #[test]
+    fn test_expired() -> MyResult<()> {
+        let opt = get_test_opt();
+        let store = Store::new(opt)?;
+        let set = |setter, ttl| {
+            let payload = Slice::from("abc");
+            store.apply(Request::Setter {
+                setter,
+                key: Slice::from("a"),
+                flags: 0,
+                ttl,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        let get = || {
+            store.apply(Request::Getter {
+                getter: GetterType::Get,
+                keys: vec![Slice::from("a")],
+            })
+        };
+        // past the relative limit, a ttl is a unix time, here long gone
+        assert_eq!(
+            Ok(Response::Stored),
+            set(SetterType::Set, MAX_RELATIVE_TTL + 1)
+        );
+        assert_eq!(Ok(Response::Get(vec![])), get());
+        assert_eq!(Ok(Response::NotStored), set(SetterType::Replace, 0));
+        assert_eq!(Ok(Response::NotStored), set(SetterType::Append, 0));
+        assert_eq!(
+            Ok(Response::NotFound),
+            store.apply(Request::Deleter {
+                key: Slice::from("a"),
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        );
+        assert_eq!(Ok(Response::Stored), set(SetterType::Add, 100));
+        assert_eq!(1, store.scan()?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expires_at() {
+        let p = |ttl| StorePayload::new(Slice::from("a"), 0, ttl, 1, 1000);
+        assert_eq!(None, p(0).expires_at());
+        assert_eq!(Some(1010), p(10).expires_at());
+        assert_eq!(
+            Some(u64::from(MAX_RELATIVE_TTL + 1)),
+            p(MAX_RELATIVE_TTL + 1).expires_at()
+        );
+        assert!(p(10).is_expired());
+        assert!(!p(0).is_expired());
+    }
+
+    #[test]
+    fn test_set_ttl() {
+        let now = 1_500_000_000;
+        let mut p = StorePayload::new(Slice::from("a"), 0, 0, 1, 0);
+        p.set_ttl(10, now);
+        assert_eq!(now + 10, u64::from(p.ttl));
+        assert_eq!(Some(now + 10), p.expires_at());
+        p.set_ttl(MAX_RELATIVE_TTL + 1, now);
+        assert_eq!(MAX_RELATIVE_TTL + 1, p.ttl);
+        assert!(p.is_expired());
+        p.set_ttl(0, now);
+        assert_eq!(None, p.expires_at());
+    }
+
+    #[test]
+    fn test_flags_round_trip() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let set = |setter, flags, data: &str| {
+            store.apply(Request::Setter {
+                setter,
+                key: Slice::from("a"),
+                flags,
+                ttl: 0,
+                bytes: data.len(),
+                payload: Slice::from(data),
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        set(SetterType::Set, u32::max_value(), "abc")?;
+        // appends leave the flags alone
+        set(SetterType::Append, 0, "d")?;
+        let r = store.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("a")],
+        })?;
+        let mut buf = BytesMut::new();
+        r.write(&mut BufferWriter::new(&mut buf))?;
+        assert_eq!(&b"VALUE a 4294967295 4\r\nabcd\r\nEND\r\n"[..], &buf[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cas() -> MyResult<()> {
+        let opt = get_test_opt();
+        let mut store = Store::new(opt.clone())?;
+        let set = |store: &Store, setter, data: &str| {
+            let payload = Slice::from(data);
+            store.apply(Request::Setter {
+                setter,
+                key: Slice::from("a"),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        let gets = |store: &Store| -> MyResult<Option<(Slice, u64)>> {
+            let r = store.apply(Request::Getter {
+                getter: GetterType::Gets,
+                keys: vec![Slice::from("a")],
+            })?;
+            match r {
+                Response::Gets(mut items) => Ok(items.pop().map(|(item, cas)| (item.data, cas))),
+                r => panic!("unexpected response {:?}", r),
+            }
+        };
+        assert_eq!(Ok(Response::NotFound), set(&store, SetterType::Cas(1), "x"));
+
+        set(&store, SetterType::Set, "abc")?;
+        let (_, cas) = gets(&store)?.unwrap();
+        assert_eq!(
+            Ok(Response::Exists),
+            set(&store, SetterType::Cas(cas + 1), "x")
+        );
+        assert_eq!(
+            Ok(Response::Stored),
+            set(&store, SetterType::Cas(cas), "def")
+        );
+        let (data, new_cas) = gets(&store)?.unwrap();
+        assert_eq!(Slice::from("def"), data);
+        assert_ne!(cas, new_cas);
+        // the token is stale after the write
+        assert_eq!(Ok(Response::Exists), set(&store, SetterType::Cas(cas), "x"));
+
+        // appends change it too
+        set(&store, SetterType::Append, "g")?;
+        let (data, cas) = gets(&store)?.unwrap();
+        assert_eq!(Slice::from("defg"), data);
+        assert_ne!(new_cas, cas);
+
+        // uniques handed out before a restart are not handed out again
+        drop(store);
+        store = Store::new(opt)?;
+        assert_eq!(Some((Slice::from("defg"), cas)), gets(&store)?);
+        set(&store, SetterType::Set, "h")?;
+        assert!(gets(&store)?.unwrap().1 > cas);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_and_touch() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let payload = Slice::from("abc");
+        store.apply(Request::Setter {
+            setter: SetterType::Set,
+            key: Slice::from("a"),
+            flags: 3,
+            ttl: 0,
+            bytes: payload.len(),
+            payload,
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        let gats = |ttl| {
+            store.apply(Request::GetAndTouch {
+                getter: GetterType::Gets,
+                ttl,
+                keys: vec![Slice::from("a"), Slice::from("b")],
+            })
+        };
+        let cas = store.data.get(&Slice::from("a"))?.unwrap().cas;
+
+        match gats(100)? {
+            Response::Gets(items) => {
+                assert_eq!(1, items.len());
+                assert_eq!(Slice::from("abc"), items[0].0.data);
+                assert_eq!(3, items[0].0.flags);
+                assert_eq!(cas, items[0].1);
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        let p = store.data.get(&Slice::from("a"))?.unwrap();
+        assert_eq!(Some(p.created_at + 100), p.expires_at());
+
+        // an absolute time in the past expires the item right away
+        assert_eq!(Response::Gets(vec![]), gats(MAX_RELATIVE_TTL + 1)?);
+        assert_eq!(None, store.data.get(&Slice::from("a"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let meta = |command, flags: &str| {
+            let flags = flags
+                .split_whitespace()
+                .map(|f| (f.as_bytes()[0], Slice::from(&f[1..])))
+                .collect();
+            match store.apply(Request::Meta {
+                command,
+                key: Slice::from("a"),
+                flags: MetaFlags(flags),
+            }) {
+                Ok(Response::Meta(r)) => r,
+                r => panic!("unexpected response {:?}", r),
+            }
+        };
+        let flags = |r: &MetaResponse| r.flags.join(" ");
+
+        let r = meta(MetaCommand::Get, "v q");
+        assert_eq!(("EN", true), (r.code, r.quiet));
+        let r = meta(MetaCommand::Set(Slice::from("abc")), "F3 T0 Oop k");
+        assert_eq!(("HD", "Oop ka"), (r.code, &flags(&r)[..]));
+        let r = meta(MetaCommand::Set(Slice::from("xyz")), "ME");
+        assert_eq!("NS", r.code);
+        let r = meta(MetaCommand::Set(Slice::from("d")), "MA F5");
+        assert_eq!("HD", r.code);
+
+        let r = meta(MetaCommand::Get, "v f s t c");
+        assert_eq!(("VA", Some(Slice::from("abcd"))), (r.code, r.value.clone()));
+        let cas = store.data.get(&Slice::from("a"))?.unwrap().cas;
+        assert_eq!(format!("f3 s4 t-1 c{}", cas), flags(&r));
+
+        let r = meta(MetaCommand::Delete, &format!("C{}", cas + 1));
+        assert_eq!("EX", r.code);
+        let r = meta(MetaCommand::Delete, &format!("C{} q", cas));
+        assert_eq!(("HD", true), (r.code, r.quiet));
+        assert_eq!(None, store.data.get(&Slice::from("a"))?);
+
+        let r = meta(MetaCommand::Arith, "");
+        assert_eq!("NF", r.code);
+        let r = meta(MetaCommand::Arith, "N0 J10 D5 v");
+        assert_eq!(("VA", Some(Slice::from("10"))), (r.code, r.value.clone()));
+        let r = meta(MetaCommand::Arith, "MD D3 v");
+        assert_eq!(Some(Slice::from("7")), r.value);
+
+        match store.apply(Request::Meta {
+            command: MetaCommand::Get,
+            key: Slice::from("a"),
+            flags: MetaFlags(vec![(b'T', Slice::from("x"))]),
+        })? {
+            Response::ClientError(_) => {}
+            r => panic!("unexpected response {:?}", r),
+        }
+        assert_eq!(
+            Response::Meta(MetaResponse::new("MN")),
+            store.apply(Request::MetaNoop)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_arith() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let arith = |key: &str, decr, delta, initial| {
+            store.apply(Request::Arith {
+                key: Slice::from(key),
+                decr,
+                delta,
+                initial,
+                no_reply: false,
+            })
+        };
+        assert_eq!(Response::NotFound, arith("a", false, 1, None)?);
+        assert_eq!(Response::Number(10), arith("a", false, 1, Some((10, 0)))?);
+        assert_eq!(Response::Number(15), arith("a", false, 5, None)?);
+        // increments wrap around, decrements stop at 0
+        assert_eq!(
+            Response::Number(14),
+            arith("a", false, u64::max_value(), Some((10, 0)))?
+        );
+        let p = store.data.get(&Slice::from("a"))?.unwrap();
+        assert_eq!(Slice::from("14"), p.data);
+        assert_eq!(2, p.bytes);
+        assert_eq!(Response::Number(0), arith("a", true, 20, None)?);
+
+        let payload = Slice::from("abc");
+        store.apply(Request::Setter {
+            setter: SetterType::Set,
+            key: Slice::from("b"),
+            flags: 0,
+            ttl: 0,
+            bytes: payload.len(),
+            payload,
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        match arith("b", false, 1, None)? {
+            Response::ClientError(_) => {}
+            r => panic!("unexpected response {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_and_verbosity() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        assert_eq!(
+            Response::Version(env!("CARGO_PKG_VERSION").to_owned()),
+            store.apply(Request::Version)?
+        );
+        let verbosity = |level| Request::Verbosity {
+            level,
+            no_reply: false,
+        };
+        assert_eq!(Response::Ok, store.apply(verbosity(2))?);
+        assert_eq!(LevelFilter::Info, log::max_level());
+        assert_eq!(Response::Ok, store.apply(verbosity(9))?);
+        assert_eq!(LevelFilter::Trace, log::max_level());
+        log::set_max_level(LevelFilter::Off);
+        Ok(())
+    }
+
+    #[test]
+    fn test_general_stats() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        for key in &["a", "b"] {
+            let payload = Slice::from("abc");
+            store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(*key),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })?;
+        }
+        store.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("a"), Slice::from("c")],
+        })?;
+        store.apply(Request::Deleter {
+            key: Slice::from("b"),
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+
+        let stats = match store.apply(Request::Stats(StatsType::General))? {
+            Response::Stats(v) => v.into_iter().collect::<HashMap<_, _>>(),
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!("2", stats["cmd_set"]);
+        assert_eq!("2", stats["cmd_get"]);
+        assert_eq!("1", stats["get_hits"]);
+        assert_eq!("0.50", stats["get_hit_rate"]);
+        assert_eq!("1", stats["delete_hits"]);
+        assert_eq!("0", stats["evictions"]);
+        // tests run no background jobs, so the writes are all in memtables
+        assert_ne!("0", stats["curr_items"]);
+        assert_eq!("0", stats["sstables:l0:files"]);
+        assert!(stats.contains_key("sstables:l6:bytes"));
+
+        // only "a" is left, of 1 + 3 bytes
+        let stats = |stats_type| match store.apply(Request::Stats(stats_type)) {
+            Ok(Response::Stats(v)) => v.into_iter().collect::<HashMap<_, _>>(),
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!("1", stats(StatsType::Items)["items:1:number"]);
+        assert_eq!(1, stats(StatsType::Sizes).len());
+        assert_eq!("1", stats(StatsType::Sizes)["32"]);
+        assert_eq!(
+            Response::Reset,
+            store.apply(Request::Stats(StatsType::Reset))?
+        );
+        assert_eq!("0", stats(StatsType::General)["cmd_set"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_add() -> MyResult<()> {
+        let opt = get_test_opt();
+        let store = Arc::new(Store::new(opt)?);
+        let threads = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let payload = Slice::from(format!("{}", i));
+                    store.apply(Request::Setter {
+                        setter: SetterType::Add,
+                        key: Slice::from("a"),
+                        flags: 0,
+                        ttl: 0,
+                        bytes: payload.len(),
+                        payload,
+                        no_reply: false,
+                        write_opt: WriteOptions::default(),
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let stored = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|r| r == &Ok(Response::Stored))
+            .count();
+        assert_eq!(1, stored);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["c", "app:a", "a", "b"] {
+            let payload = Slice::from(*key);
+            let r = store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(*key),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            });
+            assert_eq!(Ok(Response::Stored), r);
+        }
+        store.apply(Request::Deleter {
+            key: Slice::from("b"),
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        let pairs = store
+            .scan()?
+            .map(|r| r.map(|(k, p)| (k, p.data)))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(
+            vec![
+                (Slice::from("a"), Slice::from("a")),
+                (Slice::from("c"), Slice::from("c")),
+                (Slice::from("app:a"), Slice::from("app:a"))
+            ],
+            pairs
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_range() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["a", "user:1", "user:2", "user:3", "users"] {
+            store.data.insert(
+                Slice::from(*key),
+                StorePayload::new(Slice::from(*key), 0, 0, key.len(), 0),
+            )?;
+        }
+        store.data.remove(&Slice::from("user:2"))?;
+
+        assert_eq!(2, store.delete_range(b"user:", b"user;")?);
+        let keys = store
+            .scan()?
+            .map(|r| r.map(|(k, _)| k))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(vec![Slice::from("a"), Slice::from("users")], keys);
+        assert_eq!(0, store.delete_range(b"user:", b"user;")?);
+
+        for key in &["app:user:1", "app:user:2", "app:x"] {
+            let (dm, key) = store.route(Slice::from(*key));
+            dm.insert(key, StorePayload::new(Slice::from("x"), 0, 0, 1, 0))?;
+        }
+        assert_eq!(0, store.delete_range(b"app", b"apq")?);
+        assert_eq!(2, store.delete_range(b"app:user:", b"app:user;")?);
+        assert_eq!(1, store.delete_range(b"app:", b"app;")?);
+        assert_eq!(0, store.namespaces[&b"app".to_vec()].scan()?.count());
+        assert_eq!(2, store.scan()?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_range() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["a", "b", "c", "d", "e", "app:a", "app:b"] {
+            let (dm, k) = store.route(Slice::from(*key));
+            dm.insert(k, StorePayload::new(Slice::from(*key), 1, 0, key.len(), 0))?;
+        }
+        store.data.remove(&Slice::from("c"))?;
+
+        let scan = |limit| {
+            store.apply(Request::Scan {
+                start: Slice::from("b"),
+                end: Slice::from("e"),
+                limit,
+            })
+        };
+        let item = |key: &str| GetRespItem::new(Slice::from(key), Slice::from(key), 1, 1);
+        assert_eq!(Response::Get(vec![item("b"), item("d")]), scan(0)?);
+        assert_eq!(Response::Get(vec![item("b")]), scan(1)?);
+        assert!(store.scan_range(b"x", b"z", 0)?.is_empty());
+
+        // keys in a namespace keep its name in front
+        let keys = |start: &[u8], end: &[u8]| -> MyResult<Vec<StoreKey>> {
+            let items = store.scan_range(start, end, 0)?;
+            Ok(items.into_iter().map(|(k, _)| k).collect())
+        };
+        assert_eq!(vec![Slice::from("app:b")], keys(b"app:b", b"app;")?);
+        assert_eq!(
+            vec![Slice::from("app:a"), Slice::from("app:b")],
+            keys(b"app:", b"z")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["user:1", "app:user:2", "app:other"] {
+            let (dm, key) = store.route(Slice::from(*key));
+            dm.insert(key, StorePayload::new(Slice::from("x"), 0, 0, 1, 0))?;
+        }
+        let keys = |prefix: &str| {
+            store.apply(Request::Keys {
+                prefix: Slice::from(prefix),
+                limit: 0,
+            })
+        };
+        assert_eq!(Response::Keys(vec![Slice::from("user:1")]), keys("user:")?);
+        assert_eq!(
+            Response::Keys(vec![Slice::from("app:user:2")]),
+            keys("app:user")?
+        );
+        assert_eq!(
+            Response::Keys(vec![Slice::from("app:other"), Slice::from("app:user:2")]),
+            keys("app:")?
+        );
+
+        let mut buf = BytesMut::new();
+        keys("user:")?.write(&mut BufferWriter::new(&mut buf))?;
+        assert_eq!(&b"KEY user:1\r\nEND\r\n"[..], &buf[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_range() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["a", "b", "c", "d", "app:x"] {
+            let (dm, k) = store.route(Slice::from(*key));
+            dm.insert(k, StorePayload::new(Slice::from(*key), 0, 0, key.len(), 0))?;
+        }
+        store.data.remove(&Slice::from("c"))?;
+
+        let other = Store::new(get_test_opt())?;
+        let path = Path::new(&other.opt.work_dir).join("export.sst");
+        assert_eq!(2, store.export_range(b"b", b"e", &path)?);
+        let r = store.export_range(b"b", b"e", &path);
+        assert_eq!(StatusCode::InvalidArgument, r.unwrap_err().code);
+
+        other.ingest_sstable(&path)?;
+        let keys = other
+            .scan()?
+            .map(|r| r.map(|(k, _)| k))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(vec![Slice::from("b"), Slice::from("d")], keys);
+
+        let empty = Path::new(&other.opt.work_dir).join("empty.sst");
+        assert_eq!(0, store.export_range(b"x", b"z", &empty)?);
+        assert!(!empty.exists());
+
+        // a namespace is exported with the keys within it
+        let ns_path = Path::new(&other.opt.work_dir).join("app.sst");
+        assert_eq!(1, store.export_range(b"app:", b"app;", &ns_path)?);
+        other.ingest_sstable(&ns_path)?;
+        assert!(other.data.get(&Slice::from("x"))?.is_some());
+
+        // keys that would be addressed to a namespace aren't ingested
+        let spanning = Path::new(&other.opt.work_dir).join("spanning.sst");
+        assert_eq!(2, store.export_range(b"a", b"c", &spanning)?);
+        let r = store.ingest_sstable(&spanning);
+        assert_eq!(StatusCode::InvalidArgument, r.unwrap_err().code);
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaces() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt.clone())?;
+        let set = |key: &str, data: &str| {
+            let payload = Slice::from(data);
+            store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(key),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        set("k", "default")?;
+        set("app:k", "app")?;
+        set("other:k", "other")?;
+
+        let app = &store.namespaces[&b"app".to_vec()];
+        assert_eq!(
+            Slice::from("app"),
+            app.get(&Slice::from("k"))?.unwrap().data
+        );
+        assert!(app.get(&Slice::from("other:k"))?.is_none());
+        assert_eq!(
+            Slice::from("default"),
+            store.data.get(&Slice::from("k"))?.unwrap().data
+        );
+        assert!(store.data.get(&Slice::from("app:k"))?.is_none());
+
+        store.close()?;
+        drop(store);
+        let store = Store::new(opt)?;
+        let r = store.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("app:k"), Slice::from("k")],
+        })?;
+        let items = match r {
+            Response::Get(items) => items,
+            r => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!(Slice::from("app:k"), items[0].key);
+        assert_eq!(Slice::from("app"), items[0].data);
+        assert_eq!(Slice::from("default"), items[1].data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_approximate_sizes() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        opt.flush_on_close = true;
+        let store = Store::new(opt.clone())?;
+        for key in &["a", "app:a", "app:b"] {
+            let (dm, key) = store.route(Slice::from(*key));
+            dm.insert(key, StorePayload::new(Slice::from("v"), 0, 0, 1, 0))?;
+        }
+        store.close()?;
+        drop(store);
+
+        let store = Store::new(opt)?;
+        let sizes = store.approximate_sizes(&[
+            b"app:".to_vec()..b"app;".to_vec(),
+            b"a".to_vec()..b"b".to_vec(),
+        ])?;
+        assert_eq!(2, sizes[0].keys);
+        assert!(sizes[0].bytes > 0);
+        assert_eq!(1, sizes[1].keys);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt.clone())?;
+        let set = |key: &str| {
+            let payload = Slice::from(key);
+            store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(key),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        set("a")?;
+        set("app:b")?;
+        let dir = format!("{}-checkpoint", opt.work_dir);
+        store.checkpoint(&dir)?;
+        assert!(store.checkpoint(&dir).is_err());
+        set("c")?;
+        drop(store);
+
+        let mut checkpoint_opt = opt.clone();
+        checkpoint_opt.work_dir = dir;
+        let checkpoint = Store::new(checkpoint_opt)?;
+        let r = checkpoint.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("a"), Slice::from("app:b"), Slice::from("c")],
+        })?;
+        match r {
+            Response::Get(items) => {
+                let keys = items.into_iter().map(|item| item.key).collect::<Vec<_>>();
+                assert_eq!(vec![Slice::from("a"), Slice::from("app:b")], keys);
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_close() -> MyResult<()> {
+        let opt = get_test_opt();
+        let store = Store::new(opt.clone())?;
+        assert!(Store::new(opt.clone()).is_err());
+
+        let payload = Slice::from("abc");
+        let set = Request::Setter {
+            setter: SetterType::Set,
+            key: Slice::from("a"),
+            flags: 1,
+            ttl: 0,
+            bytes: payload.len(),
+            payload,
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        };
+        assert_eq!(Ok(Response::Stored), store.apply(set.clone()));
+
+        store.close()?;
+        store.close()?;
+        assert_eq!(
+            StatusCode::Closed,
+            store.apply(set.clone()).unwrap_err().code
+        );
+        drop(store);
+
+        let store = Store::new(opt)?;
+        let r = store.apply(Request::Getter {
+            getter: GetterType::Get,
+            keys: vec![Slice::from("a")],
+        })?;
+        match r {
+            Response::Get(items) => assert_eq!(1, items.len()),
+            _ => panic!("unexpected response"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_limits() -> MyResult<()> {
+        let store = Store::new(get_test_opt())?;
+        let set = |key: String, value: &str, bytes| {
+            store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(key),
+                flags: 0,
+                ttl: 0,
+                bytes,
+                payload: Slice::from(value),
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })
+        };
+        assert_eq!(Response::Stored, set("a".repeat(MAX_KEY_LEN), "abcd", 4)?);
+        assert_eq!(
+            Response::ClientError("bad command line format".to_owned()),
+            set("a".repeat(MAX_KEY_LEN + 1), "abcd", 4)?
+        );
+        assert_eq!(
+            Response::ClientError("bad command line format".to_owned()),
+            store.apply(Request::Getter {
+                getter: GetterType::Get,
+                keys: vec![Slice::from("b"), Slice::from("b".repeat(MAX_KEY_LEN + 1))],
+            })?
+        );
+        assert_eq!(
+            Response::ClientError("bad data chunk".to_owned()),
+            set("a".to_owned(), "abcd", 3)?
+        );
+        assert_eq!(
+            Response::ServerError(TOO_LARGE.to_owned()),
+            store.apply(Request::TooLarge)?
+        );
+        assert_eq!(None, store.data.get(&Slice::from("a"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_many() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.namespaces = vec!["app".to_owned()];
+        let store = Store::new(opt)?;
+        for key in &["a", "b", "app:a"] {
+            let payload = Slice::from("x");
+            store.apply(Request::Setter {
+                setter: SetterType::Set,
+                key: Slice::from(*key),
+                flags: 0,
+                ttl: 0,
+                bytes: payload.len(),
+                payload,
+                no_reply: false,
+                write_opt: WriteOptions::default(),
+            })?;
+        }
+        let r = store.apply(Request::MultiDeleter {
+            keys: ["a", "app:a", "c", "b", "a"]
+                .iter()
+                .map(|k| Slice::from(*k))
+                .collect(),
+            no_reply: false,
+            write_opt: WriteOptions::default(),
+        })?;
+        assert_eq!(
+            Response::Multi(vec![
+                Response::Deleted,
+                Response::Deleted,
+                Response::NotFound,
+                Response::Deleted,
+                Response::NotFound,
+            ]),
+            r
+        );
+        assert_eq!(None, store.data.get(&Slice::from("a"))?);
+        assert_eq!(None, store.data.get(&Slice::from("b"))?);
+        let app = &store.namespaces[&b"app".to_vec()];
+        assert_eq!(None, app.get(&Slice::from("a"))?);
+
+        let mut buf = BytesMut::new();
+        r.write(&mut BufferWriter::new(&mut buf))?;
+        assert_eq!(
+            &b"DELETED\r\nDELETED\r\nNOT_FOUND\r\nDELETED\r\nNOT_FOUND\r\n"[..],
+            &buf[..]
+        );
+        Ok(())
+    }
 }
@@ -19,6 +19,10 @@ pub fn get_test_opt() -> Options {
     }
     create_dir_all(&opt.work_dir).expect("create work dir error!");
     opt.mem_table_max_size = 1;
+    opt.wal_seg_max_size = 1;
     opt.imm_mem_table_max_count = 1;
+    // tests count on an sstable per memtable
+    opt.max_memtables_per_flush = 1;
+    opt.disable_background_jobs = true;
     opt
 }
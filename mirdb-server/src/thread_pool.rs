@@ -1,3 +1,4 @@
+use std::mem;
 use std::ops::Drop;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
@@ -70,6 +71,36 @@ impl ThreadPool {
     {
         self.sender.send(Message::NewJob(Box::new(job))).unwrap();
     }
+
+    /// Runs `jobs` on the pool and returns their results in order. Returns
+    /// only once every job is done, so jobs may borrow from the caller.
+    pub fn run_all<'a, T, F>(&self, jobs: Vec<F>) -> Vec<T>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        let n = jobs.len();
+        let (tx, rx) = mpsc::channel();
+        for (i, job) in jobs.into_iter().enumerate() {
+            let tx = tx.clone();
+            let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+                let _ = tx.send((i, job()));
+            });
+            // each job holds a sender until it is done, and the receiving
+            // below lasts until all senders are gone
+            let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+            self.execute(job);
+        }
+        drop(tx);
+        let mut results = (0..n).map(|_| None).collect::<Vec<_>>();
+        for (i, r) in rx {
+            results[i] = Some(r);
+        }
+        results
+            .into_iter()
+            .map(|r| r.expect("job panicked"))
+            .collect()
+    }
 }
 
 impl Drop for ThreadPool {
@@ -91,3 +122,19 @@ impl Drop for ThreadPool {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_all() {
+        let pool = ThreadPool::new(3);
+        let data = (0..10).collect::<Vec<u64>>();
+        let jobs = data
+            .chunks(3)
+            .map(|chunk| move || chunk.iter().sum::<u64>())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![3, 12, 21, 9], pool.run_all(jobs));
+    }
+}
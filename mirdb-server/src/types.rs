@@ -1,15 +1,77 @@
 use std::borrow::Borrow;
+use std::cmp::min;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem::size_of;
+
+use crate::slice::Slice;
+
+/// Bytes a key or value takes up in a memtable, used to decide when the
+/// memtable is full.
+pub trait ApproximateSize {
+    fn approximate_size(&self) -> usize;
+}
+
+impl ApproximateSize for Slice {
+    fn approximate_size(&self) -> usize {
+        self.len()
+    }
+}
+
+macro_rules! impl_approximate_size {
+    ($($type:ty),*) => {
+        $(
+            impl ApproximateSize for $type {
+                fn approximate_size(&self) -> usize {
+                    size_of::<$type>()
+                }
+            }
+        )*
+    };
+}
+
+impl_approximate_size!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// Hash of the first `len` bytes of a key, which picks its bucket in a hash
+/// memtable. A key and its borrowed forms must hash alike.
+pub trait PrefixHash {
+    fn prefix_hash(&self, len: usize) -> u64;
+}
+
+impl PrefixHash for [u8] {
+    fn prefix_hash(&self, len: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self[..min(len, self.len())].hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl PrefixHash for Slice {
+    fn prefix_hash(&self, len: usize) -> u64 {
+        self[..].prefix_hash(len)
+    }
+}
+
+macro_rules! impl_prefix_hash {
+    ($($type:ty),*) => {
+        $(
+            impl PrefixHash for $type {
+                fn prefix_hash(&self, len: usize) -> u64 {
+                    self.to_be_bytes()[..].prefix_hash(len)
+                }
+            }
+        )*
+    };
+}
+
+impl_prefix_hash!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
 
 pub trait Table<K, V> {
     fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
-        Q: Ord;
-
-    fn get_mut<Q: ?Sized>(&self, k: &Q) -> Option<&mut V>
-    where
-        K: Borrow<Q>,
-        Q: Ord;
+        Q: Ord + PrefixHash;
 
     fn insert(&mut self, k: K, v: V) -> Option<V>;
 
@@ -1,11 +1,16 @@
 use std::borrow::Borrow;
+use std::borrow::Cow;
+use std::cmp::max;
 use std::cmp::min;
 use std::collections::linked_list::Iter as LinkedListIter;
+use std::collections::HashMap;
 use std::collections::LinkedList;
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::fs::copy;
 use std::fs::remove_file;
 use std::fs::File;
-use std::fs::OpenOptions;
+use std::io::BufWriter;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Seek;
@@ -16,11 +21,21 @@ use std::num::Wrapping;
 use std::ops::DerefMut;
 use std::path::Path;
 use std::path::PathBuf;
-use std::ptr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-use glob::glob;
+use crc::crc32;
+use crc::crc32::Hasher32;
 use integer_encoding::FixedInt;
-use memmap::Mmap;
+use log::warn;
 use snap::Decoder;
 use snap::Encoder;
 
@@ -29,116 +44,638 @@ use sstable::RandomAccess;
 use sstable::TableBuilder;
 use sstable::TableReader;
 
+use crate::env::list_ext;
+use crate::env::Env;
+use crate::env::EnvFile;
+use crate::env::FileData;
+use crate::env::PosixEnv;
+use crate::env::SharedEnv;
 use crate::error::err;
 use crate::error::MyResult;
 use crate::error::StatusCode;
+use crate::file_number::FileNumbers;
+use crate::memtable::Memtable;
+use crate::memtable::UNSEQUENCED;
+use crate::memtable_rep::Rep;
+use crate::merge::stack_value;
+use crate::options::int_to_wal_compression;
 use crate::options::Options;
+use crate::options::WALCompression;
+use crate::options::WALRecoveryMode;
+use crate::options::WriteOptions;
 use crate::slice::Slice;
 use crate::sstable_builder::skiplist_to_sstable;
+use crate::types::Table;
 use crate::utils::make_file_name;
 
+// fragment: [u32 size][u32 count][u8 type][u8 compression][u16 0][u32 log number][u32 crc][data][padding]
+// record:   [u32 namespace size][namespace][entry]...
+// entry:    [u64 seq][u32 key size][u32 value size][key][value]
+//
+// All entries of a batch share one record, so a batch is replayed either
+// completely or not at all. A batch is written by one keyspace, which the
+// record names; the default keyspace has an empty name. The entries are compressed together and the
+// compression type is stored per record, so segments written with different
+// settings replay the same way.
+//
+// A record is written as a single FULL fragment, or, when it doesn't fit in
+// a block, split into FIRST, MIDDLE... and LAST fragments that each carry
+// their own crc. A record whose tail fragments are missing is torn and
+// dropped as a whole.
+//
+// The log number is taken from the segment's file name. A recycled segment
+// still holds records of its previous life after the tail; their log number
+// differs, so replay stops there.
+const HEADER_SIZE: usize = 20;
+
+// the segment header and padding keep every fragment 8 byte aligned
+const RECORD_ALIGN: usize = 8;
+
+const BLOCK_SIZE: usize = 32 * 1024;
+
+// segment header: [u32 magic][u32 version][u64 segment number][u64 creation time in ms]
+//
+// Written when a segment is created or recycled and checked whenever one is
+// opened, so a stray file can't be replayed as records.
+const SEG_MAGIC: u32 = 0x6d69_7277;
+const SEG_VERSION: u32 = 2;
+const SEG_HEADER_SIZE: usize = 24;
+
+// version 1 records have no namespace; they belong to the default keyspace
+const SEG_VERSION_UNNAMED: u32 = 1;
+
+// legacy record: [u32 size][u32 key size][snappy key][snappy value][padding]
+//
+// The layout before records had a header and crc, with size covering the
+// compressed key and value. A segment that doesn't start with `SEG_MAGIC`
+// holds these records; they carry no sequence number.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum RecordType {
+    Full = 1,
+    First = 2,
+    Middle = 3,
+    Last = 4,
+}
+
+fn int_to_record_type(i: u8) -> Option<RecordType> {
+    match i {
+        1 => Some(RecordType::Full),
+        2 => Some(RecordType::First),
+        3 => Some(RecordType::Middle),
+        4 => Some(RecordType::Last),
+        _ => None,
+    }
+}
+
+const WAL_EXT: &str = "wal";
+const RECYCLE_EXT: &str = "recycle";
+
+// archived segments are named `<archive time in ms>-<segment file name>`
+const ARCHIVE_SEP: char = '-';
+
+const ZSTD_LEVEL: i32 = 0;
+
+/// Upper bounds, in microseconds, of the sync latency histogram buckets. A
+/// last bucket counts the syncs slower than all of them.
+pub const SYNC_LATENCY_BUCKETS_US: [u64; 8] = [50, 100, 250, 500, 1000, 5000, 10000, 50000];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    /// Global write order, assigned by `WAL` on append.
+    pub seq: u64,
+    pub key: Slice,
+    pub value: Slice,
+    /// The keyspace the entry was written to, empty for the default one.
+    pub namespace: Slice,
+    /// Not written to the WAL.
+    pub write_opt: WriteOptions,
+}
+
+impl LogEntry {
+    pub fn new(key: Slice, value: Slice) -> Self {
+        LogEntry {
+            seq: 0,
+            key,
+            value,
+            namespace: Slice::new(),
+            write_opt: WriteOptions::default(),
+        }
+    }
+
+    pub fn with_namespace(mut self, namespace: Slice) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn with_write_options(mut self, write_opt: WriteOptions) -> Self {
+        self.write_opt = write_opt;
+        self
+    }
+}
+
+/// Write counters of a `WAL`, kept across its segments.
+#[derive(Default)]
+pub struct WALMetrics {
+    bytes_appended: AtomicU64,
+    syncs: AtomicU64,
+    sync_latency: [AtomicU64; SYNC_LATENCY_BUCKETS_US.len() + 1],
+    rotations: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WALStats {
+    pub bytes_appended: u64,
+    pub syncs: u64,
+    /// Sync counts per bucket of `SYNC_LATENCY_BUCKETS_US`, plus the overflow
+    /// bucket.
+    pub sync_latency: Vec<u64>,
+    pub rotations: u64,
+}
+
+impl WALMetrics {
+    fn record_append(&self, bytes: usize) {
+        self.bytes_appended.fetch_add(bytes as u64, Relaxed);
+    }
+
+    fn record_sync(&self, us: u64) {
+        let bucket = SYNC_LATENCY_BUCKETS_US
+            .iter()
+            .position(|b| us <= *b)
+            .unwrap_or(SYNC_LATENCY_BUCKETS_US.len());
+        self.syncs.fetch_add(1, Relaxed);
+        self.sync_latency[bucket].fetch_add(1, Relaxed);
+    }
+
+    fn record_rotation(&self) {
+        self.rotations.fetch_add(1, Relaxed);
+    }
+
+    pub fn stats(&self) -> WALStats {
+        WALStats {
+            bytes_appended: self.bytes_appended.load(Relaxed),
+            syncs: self.syncs.load(Relaxed),
+            sync_latency: self.sync_latency.iter().map(|x| x.load(Relaxed)).collect(),
+            rotations: self.rotations.load(Relaxed),
+        }
+    }
+}
+
+impl WALStats {
+    /// Flattens the stats into `wal:`-prefixed name/value pairs.
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        let mut v = vec![
+            (
+                "wal:bytes_appended".to_owned(),
+                self.bytes_appended.to_string(),
+            ),
+            ("wal:syncs".to_owned(), self.syncs.to_string()),
+        ];
+        for (i, n) in self.sync_latency.iter().enumerate() {
+            let bound = match SYNC_LATENCY_BUCKETS_US.get(i) {
+                Some(b) => b.to_string(),
+                None => "inf".to_owned(),
+            };
+            v.push((format!("wal:sync_latency_us:le_{}", bound), n.to_string()));
+        }
+        v.push(("wal:rotations".to_owned(), self.rotations.to_string()));
+        v
+    }
+}
+
+fn record_crc(meta_buf: &[u8], data: &[u8]) -> u32 {
+    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+    digest.write(meta_buf);
+    digest.write(data);
+    digest.sum32()
+}
+
 fn padding(len: usize) -> usize {
     4usize.wrapping_sub(len) & 7
 }
 
-fn copy_memory(src: &[u8], dst: &mut [u8]) {
-    let len_src = src.len();
-    assert!(dst.len() >= len_src);
+fn compress(compression: WALCompression, data: Vec<u8>) -> MyResult<Vec<u8>> {
+    Ok(match compression {
+        WALCompression::None => data,
+        WALCompression::Snappy => Encoder::new().compress_vec(&data)?,
+        WALCompression::Zstd => zstd::encode_all(&data[..], ZSTD_LEVEL)?,
+    })
+}
+
+fn decompress(compression: WALCompression, data: &[u8]) -> MyResult<Vec<u8>> {
+    Ok(match compression {
+        WALCompression::None => data.to_vec(),
+        WALCompression::Snappy => Decoder::new().decompress_vec(data)?,
+        WALCompression::Zstd => zstd::decode_all(data)?,
+    })
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn encode_seg_header(number: u32, created_ms: u64) -> [u8; SEG_HEADER_SIZE] {
+    let mut buf = [0; SEG_HEADER_SIZE];
+    SEG_MAGIC.encode_fixed(&mut buf[..4]);
+    SEG_VERSION.encode_fixed(&mut buf[4..8]);
+    u64::from(number).encode_fixed(&mut buf[8..16]);
+    created_ms.encode_fixed(&mut buf[16..]);
+    buf
+}
 
-    unsafe {
-        ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), len_src);
+/// Segments written before the segment header existed start right with a
+/// legacy record.
+fn is_legacy_seg(buf: &[u8]) -> bool {
+    buf.len() < 4 || u32::decode_fixed(&buf[..4]) != SEG_MAGIC
+}
+
+/// Checks the segment header and returns its version and creation time.
+fn decode_seg_header(buf: &[u8], number: u32) -> MyResult<(u32, u64)> {
+    if buf.len() < SEG_HEADER_SIZE || u32::decode_fixed(&buf[..4]) != SEG_MAGIC {
+        return err(StatusCode::WALError, "not a wal segment");
+    }
+    let version = u32::decode_fixed(&buf[4..8]);
+    if version != SEG_VERSION && version != SEG_VERSION_UNNAMED {
+        return err(
+            StatusCode::WALError,
+            format!("unsupported wal segment version: {}", version),
+        );
+    }
+    let n = u64::decode_fixed(&buf[8..16]);
+    if n != u64::from(number) {
+        return err(
+            StatusCode::WALError,
+            format!("wal segment {} is named as segment {}", n, number),
+        );
     }
+    Ok((version, u64::decode_fixed(&buf[16..SEG_HEADER_SIZE])))
+}
+
+fn log_number<T: AsRef<Path>>(path: T) -> u32 {
+    path.as_ref()
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.rsplit(ARCHIVE_SEP).next())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(0)
+}
+
+fn encode_record(
+    entries: &[LogEntry],
+    compression: WALCompression,
+    log_number: u32,
+) -> MyResult<Vec<u8>> {
+    let size_space = u32::required_space();
+
+    let seq_space = u64::required_space();
+
+    let namespace = entries.first().map_or(&[][..], |e| &e.namespace[..]);
+    let mut payload = vec![0; size_space];
+    (namespace.len() as u32).encode_fixed(&mut payload[..]);
+    payload.extend_from_slice(namespace);
+    for entry in entries {
+        let mut meta = [0; 16];
+        entry.seq.encode_fixed(&mut meta[..seq_space]);
+        (entry.key.len() as u32).encode_fixed(&mut meta[seq_space..seq_space + size_space]);
+        (entry.value.len() as u32).encode_fixed(&mut meta[seq_space + size_space..]);
+        payload.extend_from_slice(&meta);
+        payload.extend_from_slice(entry.key.as_ref());
+        payload.extend_from_slice(entry.value.as_ref());
+    }
+    let payload = compress(compression, payload)?;
+
+    let chunks = if payload.is_empty() {
+        vec![&payload[..]]
+    } else {
+        payload.chunks(BLOCK_SIZE - HEADER_SIZE).collect()
+    };
+    let mut buf = vec![];
+    for (i, chunk) in chunks.iter().enumerate() {
+        let record_type = match (i == 0, i == chunks.len() - 1) {
+            (true, true) => RecordType::Full,
+            (true, false) => RecordType::First,
+            (false, true) => RecordType::Last,
+            (false, false) => RecordType::Middle,
+        };
+        encode_fragment(
+            &mut buf,
+            record_type,
+            chunk,
+            entries.len(),
+            compression,
+            log_number,
+        );
+    }
+
+    Ok(buf)
+}
+
+fn encode_fragment(
+    buf: &mut Vec<u8>,
+    record_type: RecordType,
+    data: &[u8],
+    count: usize,
+    compression: WALCompression,
+    log_number: u32,
+) {
+    let size_space = u32::required_space();
+    let start = buf.len();
+    buf.resize(start + HEADER_SIZE, 0);
+    buf.extend_from_slice(data);
+
+    let header = &mut buf[start..start + HEADER_SIZE];
+
+    // size
+    (data.len() as u32).encode_fixed(&mut header[..size_space]);
+
+    // count
+    (count as u32).encode_fixed(&mut header[size_space..size_space * 2]);
+
+    // type and compression
+    header[size_space * 2] = record_type as u8;
+    header[size_space * 2 + 1] = compression as u8;
+
+    // log number
+    log_number.encode_fixed(&mut header[size_space * 3..size_space * 4]);
+
+    // crc of everything but the size
+    let crc = record_crc(
+        &buf[start + size_space..start + size_space * 4],
+        &buf[start + HEADER_SIZE..],
+    );
+    crc.encode_fixed(&mut buf[start + size_space * 4..start + HEADER_SIZE]);
+
+    // padding
+    buf.resize(buf.len() + padding(data.len()), 0);
 }
 
 pub struct WALSeg {
-    file: File,
+    env: SharedEnv,
+    file: BufWriter<Box<dyn EnvFile>>,
     size_: usize,
     path: PathBuf,
+    log_number: u32,
+    created_ms: u64,
+    compression: WALCompression,
+    legacy: bool,
+    version: u32,
 }
 
 impl WALSeg {
-    pub fn new<T: AsRef<Path>>(path: T, _capacity: usize) -> MyResult<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path.as_ref())?;
+    /// Appends are buffered in memory up to `buffer_size` bytes; with a
+    /// zero `buffer_size` every append goes straight to the file.
+    pub fn new<T: AsRef<Path>>(path: T, buffer_size: usize) -> MyResult<Self> {
+        Self::with_env(Arc::new(PosixEnv), path, buffer_size)
+    }
+
+    /// Like `new`, with the segment in `env`.
+    pub fn with_env<T: AsRef<Path>>(env: SharedEnv, path: T, buffer_size: usize) -> MyResult<Self> {
+        let mut file = env.open_writable(path.as_ref())?;
+
+        let log_number = log_number(&path);
+        let mut legacy = false;
+        let size = file.size()? as usize;
+        let (version, created_ms) = if size == 0 {
+            let created_ms = now_ms();
+            file.write_all(&encode_seg_header(log_number, created_ms))?;
+            (SEG_VERSION, created_ms)
+        } else {
+            let mut buf = [0; SEG_HEADER_SIZE];
+            let n = min(size, SEG_HEADER_SIZE);
+            file.read_exact(&mut buf[..n])?;
+            if is_legacy_seg(&buf[..n]) {
+                legacy = true;
+                (0, 0)
+            } else if n < SEG_HEADER_SIZE {
+                return err(StatusCode::WALError, "wal segment too short");
+            } else {
+                decode_seg_header(&buf, log_number)?
+            }
+        };
 
         Ok(WALSeg {
-            file,
+            env,
+            file: BufWriter::with_capacity(buffer_size, file),
             size_: 0,
             path: path.as_ref().to_path_buf(),
+            log_number,
+            created_ms,
+            compression: WALCompression::Snappy,
+            legacy,
+            version,
         })
     }
 
+    /// Sets the compression used for records appended from now on.
+    pub fn with_compression(mut self, compression: WALCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub fn iter(&self) -> MyResult<WALSegIter> {
-        WALSegIter::new(&self.path)
+        WALSegIter::with_env(self.env.as_ref(), &self.path)
     }
 
+    /// Bytes of records appended through this handle, not counting the
+    /// segment header.
     pub fn size(&self) -> usize {
         self.size_
     }
 
-    pub fn append(&mut self, key: &Slice, value: &Slice) -> MyResult<()> {
-        let mut encoder = Encoder::new();
-        let key_buf = encoder.compress_vec(key.as_ref())?;
-        let value_buf = encoder.compress_vec(value.as_ref())?;
+    /// Zero for a legacy segment, which has no header to keep it in.
+    pub fn created_ms(&self) -> u64 {
+        self.created_ms
+    }
 
-        let key_size = key_buf.len();
-        let value_size = value_buf.len();
-        let size_space = u32::required_space();
+    /// Whether the segment holds legacy records. It is only replayed, never
+    /// appended to or recycled.
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
 
-        let padding = padding(key_size + value_size);
+    /// Whether records can be appended, which takes a segment of the
+    /// current version.
+    pub fn is_appendable(&self) -> bool {
+        !self.legacy && self.version == SEG_VERSION
+    }
 
-        let mut buf = vec![0; size_space * 2 + key_size + value_size + padding];
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        // size
-        ((key_size + value_size) as u32).encode_fixed(&mut buf[..size_space]);
+    pub fn append(&mut self, key: &Slice, value: &Slice) -> MyResult<()> {
+        self.check_appendable()?;
+        let buf = encode_record(
+            &[LogEntry::new(key.clone(), value.clone())],
+            self.compression,
+            self.log_number,
+        )?;
 
-        // key size
-        (key_size as u32).encode_fixed(&mut buf[size_space..size_space * 2]);
+        self.file.write_all(&buf)?;
 
-        // key
-        copy_memory(&key_buf, &mut buf[size_space * 2..]);
+        self.size_ += buf.len();
 
-        // value
-        copy_memory(&value_buf, &mut buf[size_space * 2 + key_size..]);
+        Ok(())
+    }
 
-        // padding
-        if padding > 0 {
-            let zeros: [u8; 8] = [0; 8];
-            copy_memory(
-                &zeros[..padding],
-                &mut buf[size_space * 2 + key_size + value_size..],
-            );
+    /// Writes all entries as one record with a single write, followed by
+    /// `sync` if `sync` is set. After a crash the batch is either fully
+    /// replayed or dropped.
+    pub fn append_batch(&mut self, entries: &[LogEntry], sync: bool) -> MyResult<()> {
+        if entries.is_empty() {
+            return Ok(());
         }
+        self.check_appendable()?;
+        let buf = encode_record(entries, self.compression, self.log_number)?;
 
         self.file.write_all(&buf)?;
 
-        self.file.flush()?;
-
         self.size_ += buf.len();
 
+        if sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn check_appendable(&self) -> MyResult<()> {
+        if self.legacy {
+            return err(
+                StatusCode::WALError,
+                format!("cannot append to legacy wal {:?}", self.path),
+            );
+        }
+        if self.version != SEG_VERSION {
+            return err(
+                StatusCode::WALError,
+                format!(
+                    "cannot append to version {} wal {:?}",
+                    self.version, self.path
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    /// Hands the buffered records to the OS.
+    pub fn flush(&mut self) -> MyResult<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the buffered records and waits until they are on disk.
+    pub fn sync(&mut self) -> MyResult<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync()?;
         Ok(())
     }
 
     pub fn clone(&self) -> MyResult<Self> {
-        Self::new(&self.path, 0)
+        Ok(Self::with_env(self.env.clone(), &self.path, 0)?.with_compression(self.compression))
     }
 
     pub fn delete(&self) -> MyResult<()> {
-        remove_file(&self.path)?;
+        self.env.remove_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Zero-fills the file up to `size` bytes so appends don't have to grow it.
+    pub fn preallocate(&self, size: usize) -> MyResult<()> {
+        self.file.get_ref().allocate(size as u64)?;
         Ok(())
     }
 
+    /// Renames the segment to `path` and rewinds it, so it is overwritten
+    /// instead of deleted and recreated.
+    pub fn recycle<T: AsRef<Path>>(mut self, path: T) -> MyResult<Self> {
+        self.env.rename(&self.path, path.as_ref())?;
+        self.size_ = 0;
+        self.path = path.as_ref().to_path_buf();
+        self.log_number = log_number(&path);
+        self.version = SEG_VERSION;
+        self.created_ms = now_ms();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file
+            .write_all(&encode_seg_header(self.log_number, self.created_ms))?;
+        self.file.flush()?;
+        Ok(self)
+    }
+
+    /// Replays the segment into a skiplist, handling corrupt records as
+    /// `opt.wal_recovery_mode` says. When replay stops at a corrupt record the
+    /// segment is truncated there, dropping it and every record after it.
     pub fn to_skiplist(&self, opt: &Options) -> MyResult<SkipList<Slice, Slice>> {
         let mut map = SkipList::new(opt.mem_table_max_height);
-        for (k, v) in self.iter()? {
-            map.insert(k, v);
-        }
+        self.replay(opt, |entry| {
+            if let Some(v) = stack_value(map.get(&entry.key), entry.value)? {
+                map.insert(entry.key, v);
+            }
+            Ok(())
+        })?;
         Ok(map)
     }
 
+    /// Like `to_skiplist`, but into a memtable that keeps the sequence
+    /// numbers of the entries, and only of the entries of `namespace` newer
+    /// than `after`.
+    pub fn to_memtable(
+        &self,
+        opt: &Options,
+        namespace: &[u8],
+        after: u64,
+    ) -> MyResult<Memtable<Slice, Slice>> {
+        let table = Memtable::with_rep(::std::usize::MAX, Rep::with_options(opt));
+        self.replay(opt, |entry| {
+            if &entry.namespace[..] != namespace || entry.seq <= after {
+                return Ok(());
+            }
+            if let Some(v) = stack_value(table.get(&entry.key), entry.value)? {
+                table.insert_shared(entry.seq, entry.key, v);
+            }
+            Ok(())
+        })?;
+        Ok(table)
+    }
+
+    fn replay<F>(&self, opt: &Options, mut f: F) -> MyResult<()>
+    where
+        F: FnMut(LogEntry) -> MyResult<()>,
+    {
+        let mode = opt.wal_recovery_mode;
+        let mut iter = self
+            .iter()?
+            .with_skip_corrupted(mode == WALRecoveryMode::SkipCorrupted);
+        for entry in &mut iter {
+            f(entry?)?;
+        }
+        if iter.skipped() > 0 {
+            warn!(
+                "skipped {} corrupt records in wal {:?}",
+                iter.skipped(),
+                self.path
+            );
+        }
+        if let Some(offset) = iter.corrupted_offset() {
+            if mode == WALRecoveryMode::Strict {
+                return err(
+                    StatusCode::WALError,
+                    format!("wal {:?} is corrupted at offset {}", self.path, offset),
+                );
+            }
+            warn!(
+                "wal {:?} is corrupted at offset {}, truncating",
+                self.path, offset
+            );
+            drop(iter);
+            self.file.get_ref().set_len(offset as u64)?;
+        }
+        Ok(())
+    }
+
     pub fn build_sstable(
         &self,
         opt: &Options,
@@ -151,115 +688,509 @@ impl WALSeg {
 
 pub struct WALSegIter {
     offset: usize,
-    mmap: Mmap,
+    mmap: FileData,
     file_size: usize,
+    corrupted: Option<usize>,
+    pending: VecDeque<LogEntry>,
+    log_number: u32,
+    skip_corrupted: bool,
+    skipped: usize,
+    legacy: bool,
+    version: u32,
 }
 
 impl WALSegIter {
     pub fn new<T: AsRef<Path>>(path: T) -> MyResult<Self> {
-        let file = OpenOptions::new().read(true).open(&path)?;
+        Self::with_env(&PosixEnv, path)
+    }
 
-        let file_size = file.metadata()?.len() as usize;
+    /// Like `new`, with the segment in `env`.
+    pub fn with_env<T: AsRef<Path>>(env: &dyn Env, path: T) -> MyResult<Self> {
+        let mmap = env.read_all(path.as_ref())?;
 
-        let mmap = unsafe { Mmap::map(&file)? };
+        let file_size = mmap.len();
+        let log_number = log_number(&path);
+        let legacy = is_legacy_seg(&mmap);
+        let mut version = 0;
+        if !legacy {
+            if file_size < SEG_HEADER_SIZE {
+                return err(StatusCode::WALError, "wal segment too short");
+            }
+            version = decode_seg_header(&mmap[..SEG_HEADER_SIZE], log_number)?.0;
+        }
 
         Ok(WALSegIter {
             file_size,
             mmap,
-            offset: 0,
+            offset: if legacy { 0 } else { SEG_HEADER_SIZE },
+            corrupted: None,
+            pending: VecDeque::new(),
+            log_number,
+            skip_corrupted: false,
+            skipped: 0,
+            legacy,
+            version,
+        })
+    }
+
+    /// Offset of the first corrupt record, once iteration has stopped there.
+    pub fn corrupted_offset(&self) -> Option<usize> {
+        self.corrupted
+    }
+
+    /// Whether the segment holds legacy records.
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    /// Skips corrupt records and goes on with the next intact one, instead of
+    /// stopping at the first one. Legacy records have no crc to find the next
+    /// intact one by, so a legacy segment always stops.
+    pub fn with_skip_corrupted(mut self, skip: bool) -> Self {
+        self.skip_corrupted = skip;
+        self
+    }
+
+    /// Number of corrupt regions skipped so far.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Finds the start of the next intact record after `from`.
+    fn resync(&self, from: usize) -> Option<usize> {
+        let mut offset = from + RECORD_ALIGN;
+        while offset + HEADER_SIZE <= self.file_size {
+            if let Some(fragment) = self.read_fragment(offset) {
+                if fragment.log_number == self.log_number
+                    && (fragment.record_type == RecordType::Full
+                        || fragment.record_type == RecordType::First)
+                {
+                    return Some(offset);
+                }
+            }
+            offset += RECORD_ALIGN;
+        }
+        None
+    }
+
+    /// Returns None for a torn or corrupt fragment.
+    fn read_fragment(&self, offset: usize) -> Option<Fragment> {
+        let size_space = u32::required_space();
+        let header = self.mmap.get(offset..offset + HEADER_SIZE)?;
+        let size = u32::decode_fixed(&header[..size_space]) as usize;
+        let crc = u32::decode_fixed(&header[size_space * 4..]);
+
+        let data = self
+            .mmap
+            .get(offset + HEADER_SIZE..offset + HEADER_SIZE + size)?;
+        if record_crc(&header[size_space..size_space * 4], data) != crc {
+            return None;
+        }
+
+        Some(Fragment {
+            record_type: int_to_record_type(header[size_space * 2])?,
+            count: u32::decode_fixed(&header[size_space..size_space * 2]) as usize,
+            compression: header[size_space * 2 + 1],
+            log_number: u32::decode_fixed(&header[size_space * 3..size_space * 4]),
+            data,
+            next: offset + HEADER_SIZE + size + padding(size),
         })
     }
+
+    /// Returns None for a torn or corrupt record. A record that passes the
+    /// crc check but cannot be decoded is an error.
+    fn read_record(&self) -> MyResult<Option<(Vec<LogEntry>, usize)>> {
+        let first = match self.read_fragment(self.offset) {
+            Some(fragment) => fragment,
+            None => return Ok(None),
+        };
+        let mut data = first.data.to_vec();
+        let mut offset = first.next;
+        match first.record_type {
+            RecordType::Full => {}
+            RecordType::First => loop {
+                let fragment = match self.read_fragment(offset) {
+                    Some(fragment) if fragment.log_number == self.log_number => fragment,
+                    _ => return Ok(None),
+                };
+                data.extend_from_slice(fragment.data);
+                offset = fragment.next;
+                match fragment.record_type {
+                    RecordType::Middle => {}
+                    RecordType::Last => break,
+                    _ => return Ok(None),
+                }
+            },
+            _ => return Ok(None),
+        }
+
+        let compression = match int_to_wal_compression(u32::from(first.compression)) {
+            Some(c) => c,
+            None => {
+                return err(
+                    StatusCode::WALError,
+                    format!("unknown wal compression: {}", first.compression),
+                )
+            }
+        };
+        let data = decompress(compression, &data)?;
+        let entries = decode_entries(&data, first.count, self.version)?;
+
+        Ok(Some((entries, offset)))
+    }
+
+    /// Returns None for a torn or undecodable legacy record.
+    fn read_legacy_record(&self) -> Option<(LogEntry, usize)> {
+        let size_space = u32::required_space();
+        let meta = self.mmap.get(self.offset..self.offset + size_space * 2)?;
+        let size = u32::decode_fixed(&meta[..size_space]) as usize;
+        let key_size = u32::decode_fixed(&meta[size_space..]) as usize;
+        let start = self.offset + size_space * 2;
+        let data = self.mmap.get(start..start + size)?;
+        if key_size > size {
+            return None;
+        }
+        let mut decoder = Decoder::new();
+        let key = decoder.decompress_vec(&data[..key_size]).ok()?;
+        let value = decoder.decompress_vec(&data[key_size..]).ok()?;
+        let entry = LogEntry {
+            seq: UNSEQUENCED,
+            key: Slice::from(key),
+            value: Slice::from(value),
+            namespace: Slice::new(),
+            write_opt: WriteOptions::default(),
+        };
+        Some((entry, start + size + padding(size)))
+    }
+}
+
+struct Fragment<'a> {
+    record_type: RecordType,
+    count: usize,
+    compression: u8,
+    log_number: u32,
+    data: &'a [u8],
+    next: usize,
+}
+
+fn decode_entries(data: &[u8], count: usize, version: u32) -> MyResult<Vec<LogEntry>> {
+    let size_space = u32::required_space();
+    let seq_space = u64::required_space();
+    let get = |from: usize, len: usize| match data.get(from..from + len) {
+        Some(x) => Ok(x),
+        None => err(StatusCode::WALError, "malformed wal record"),
+    };
+
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = 0;
+    let mut namespace = Slice::new();
+    if version != SEG_VERSION_UNNAMED {
+        let size = u32::decode_fixed(get(pos, size_space)?) as usize;
+        pos += size_space;
+        namespace = Slice::from(get(pos, size)?.to_vec());
+        pos += size;
+    }
+    for _ in 0..count {
+        let meta = get(pos, seq_space + size_space * 2)?;
+        let seq = u64::decode_fixed(&meta[..seq_space]);
+        let key_size = u32::decode_fixed(&meta[seq_space..seq_space + size_space]) as usize;
+        let value_size = u32::decode_fixed(&meta[seq_space + size_space..]) as usize;
+        pos += meta.len();
+        let key = get(pos, key_size)?.to_vec();
+        pos += key_size;
+        let value = get(pos, value_size)?.to_vec();
+        pos += value_size;
+        entries.push(LogEntry {
+            seq,
+            key: Slice::from(key),
+            value: Slice::from(value),
+            namespace: namespace.clone(),
+            write_opt: WriteOptions::default(),
+        });
+    }
+    Ok(entries)
 }
 
 impl Iterator for WALSegIter {
-    type Item = (Slice, Slice);
+    type Item = MyResult<LogEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.pending.pop_front() {
+            return Some(Ok(entry));
+        }
+
         if self.offset >= self.file_size {
             return None;
         }
 
-        let size = u32::decode_fixed(&self.mmap[self.offset..self.offset + u32::required_space()])
-            as usize;
-
-        if size == 0 {
+        if self.corrupted.is_some() {
             return None;
         }
 
-        let offset = self.offset + u32::required_space();
-
-        let key_size =
-            u32::decode_fixed(&self.mmap[offset..offset + u32::required_space()]) as usize;
-
-        let offset = offset + u32::required_space();
+        let size_space = u32::required_space();
+        // a zero size, or the zero-filled tail of a preallocated segment
+        let end = min(self.offset + size_space, self.file_size);
+        if self.mmap[self.offset..end].iter().all(|x| *x == 0) {
+            return None;
+        }
 
-        let data = &self.mmap[offset..offset + size];
-        let key_data = &data[..key_size];
-        let value_data = &data[key_size..];
-        let key = Decoder::new()
-            .decompress_vec(&key_data)
-            .expect("snap decompress key in wal file error");
-        let value = Decoder::new()
-            .decompress_vec(&value_data)
-            .expect("snap decompress value in wal file error");
+        if self.legacy {
+            return match self.read_legacy_record() {
+                Some((entry, next_offset)) => {
+                    self.offset = next_offset;
+                    Some(Ok(entry))
+                }
+                None => {
+                    self.corrupted = Some(self.offset);
+                    None
+                }
+            };
+        }
 
-        self.offset = offset + size + padding(size);
+        // a record left over from before the segment was recycled
+        if let Some(n) = self
+            .mmap
+            .get(self.offset + size_space * 3..self.offset + size_space * 4)
+        {
+            if u32::decode_fixed(n) != self.log_number {
+                return None;
+            }
+        }
 
-        Some((Slice::from(key), Slice::from(value)))
+        match self.read_record() {
+            Ok(Some((entries, next_offset))) => {
+                self.offset = next_offset;
+                self.pending.extend(entries);
+                self.next()
+            }
+            Ok(None) => {
+                if self.skip_corrupted {
+                    if let Some(offset) = self.resync(self.offset) {
+                        self.skipped += 1;
+                        self.offset = offset;
+                        return self.next();
+                    }
+                }
+                self.corrupted = Some(self.offset);
+                None
+            }
+            Err(e) => {
+                self.offset = self.file_size;
+                Some(Err(e))
+            }
+        }
     }
 }
 
 pub struct WAL {
     opt: Options,
     pub segs: LinkedList<WALSeg>,
-    current_file_num: usize,
+    recycled: VecDeque<WALSeg>,
+    file_numbers: FileNumbers,
+    last_sequence: u64,
+    /// The first segment holding writes of each keyspace that aren't flushed
+    /// yet, None once all are. Segments before all of them are dropped.
+    keyspaces: HashMap<Slice, Option<usize>>,
+    subscribers: Mutex<Vec<Sender<LogEntry>>>,
+    metrics: WALMetrics,
 }
 
 impl WAL {
     pub fn new(opt: Options) -> MyResult<Self> {
+        Self::with_file_numbers(opt, FileNumbers::default())
+    }
+
+    /// Opens the WAL allocating segment numbers from `file_numbers`, which is
+    /// moved past every existing segment.
+    pub fn with_file_numbers(opt: Options, file_numbers: FileNumbers) -> MyResult<Self> {
+        let env = opt.env.as_ref();
         let path = Path::new(&opt.work_dir);
-        let mut paths = vec![];
-        for entry in glob(path.join("*.wal").to_str().expect("path to str"))? {
-            if let Ok(path) = entry {
-                paths.push(path);
+        // recycled segments are only reused within one run
+        for p in list_ext(env, path, RECYCLE_EXT)? {
+            env.remove_file(&p)?;
+        }
+        let mut segs = LinkedList::new();
+        for p in seg_paths(env, path)? {
+            // crashed before the segment header was written
+            if env.open(&p)?.size()? == 0 {
+                env.remove_file(&p)?;
+                continue;
+            }
+            let seg = WALSeg::with_env(opt.env.clone(), &p, opt.wal_buffer_size)?;
+            file_numbers.advance_to(seg.log_number as usize + 1);
+            segs.push_back(seg.with_compression(opt.wal_compression));
+        }
+        // sequence numbers only grow, so the newest segment with an entry
+        // holds the last one
+        let mut last_sequence = 0;
+        for seg in segs.iter().rev().filter(|x| !x.is_legacy()) {
+            if let Some(entry) = seg.iter()?.last() {
+                last_sequence = entry?.seq;
+                break;
             }
         }
-        paths.sort();
-        let segs = paths
-            .iter()
-            .map(|p| {
-                let seg = WALSeg::new(&p.as_path(), opt.mem_table_max_size).expect("new wal seg");
-                if seg.file.metadata().unwrap().len() == 0 {
-                    remove_file(&seg.path).unwrap();
-                    None
-                } else {
-                    Some(seg)
-                }
-            })
-            .filter(Option::is_some)
-            .map(Option::unwrap)
-            .collect();
         Ok(WAL {
             opt,
             segs,
-            current_file_num: 0,
+            recycled: VecDeque::new(),
+            file_numbers,
+            last_sequence,
+            keyspaces: HashMap::new(),
+            subscribers: Mutex::new(vec![]),
+            metrics: WALMetrics::default(),
         })
     }
 
+    /// Shares the WAL between `keyspaces`, keeping every segment until each
+    /// of them has replayed its writes and called `set_unflushed`.
+    pub fn with_keyspaces(mut self, keyspaces: &[Slice]) -> Self {
+        for keyspace in keyspaces {
+            self.keyspaces.insert(keyspace.clone(), Some(0));
+        }
+        self
+    }
+
+    /// Records the first segment holding writes of `keyspace` that aren't
+    /// flushed yet, None once all are, and drops the segments no keyspace
+    /// needs anymore.
+    pub fn set_unflushed(&mut self, keyspace: &Slice, log_number: Option<usize>) -> MyResult<()> {
+        self.keyspaces.insert(keyspace.clone(), log_number);
+        let needed = self
+            .keyspaces
+            .values()
+            .filter_map(|x| *x)
+            .min()
+            .unwrap_or(::std::usize::MAX);
+        while self
+            .segs
+            .front()
+            .map_or(false, |seg| (seg.log_number as usize) < needed)
+        {
+            self.consume_seg()?;
+        }
+        Ok(())
+    }
+
+    /// The number of the segment appended to, if there is one.
+    pub fn log_number(&self) -> Option<usize> {
+        self.segs.back().map(|seg| seg.log_number as usize)
+    }
+
+    /// The number of the oldest segment, if there is one.
+    pub fn first_log_number(&self) -> Option<usize> {
+        self.segs.front().map(|seg| seg.log_number as usize)
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Continues numbering after `seq`, e.g. when a fresh WAL replaces one
+    /// whose segments have been flushed.
+    pub fn set_last_sequence(&mut self, seq: u64) {
+        self.last_sequence = seq;
+    }
+
     pub fn seg_count(&self) -> usize {
         self.segs.len()
     }
 
-    pub fn append(&mut self, key: &Slice, value: &Slice) -> MyResult<()> {
-        if self.seg_count() == 0 {
+    /// Appends one entry and returns its sequence number.
+    pub fn append(&mut self, key: &Slice, value: &Slice) -> MyResult<u64> {
+        let mut entries = [LogEntry::new(key.clone(), value.clone())];
+        self.append_batch(&mut entries)?;
+        Ok(entries[0].seq)
+    }
+
+    /// Assigns the next sequence numbers to `entries` and appends them as one
+    /// record, which takes them to be of the keyspace of the first one.
+    pub fn append_batch(&mut self, entries: &mut [LogEntry]) -> MyResult<()> {
+        if !self.segs.back().map_or(false, WALSeg::is_appendable) {
             self.new_seg()?;
         }
+        let mut seq = self.last_sequence;
+        for entry in entries.iter_mut() {
+            seq += 1;
+            entry.seq = seq;
+        }
+        // entries left out of the WAL still take a sequence number, so the
+        // memtable orders them with the rest
+        let logged: Cow<[LogEntry]> = if entries.iter().any(|e| e.write_opt.disable_wal) {
+            Cow::Owned(
+                entries
+                    .iter()
+                    .filter(|e| !e.write_opt.disable_wal)
+                    .cloned()
+                    .collect(),
+            )
+        } else {
+            Cow::Borrowed(entries)
+        };
+        let sync = self.opt.wal_sync || logged.iter().any(|e| e.write_opt.sync);
         if let Some(seg) = &mut self.segs.back_mut() {
-            return seg.append(key, value);
+            let size = seg.size();
+            seg.append_batch(&logged, false)?;
+            self.metrics.record_append(seg.size() - size);
+            if sync && !logged.is_empty() {
+                self.sync()?;
+            }
+            self.last_sequence = seq;
+            self.publish(&logged);
+            return Ok(());
         }
         err(StatusCode::WALError, "cannot get the tail wal seg")
     }
 
+    pub fn metrics(&self) -> &WALMetrics {
+        &self.metrics
+    }
+
+    /// Hands the records buffered in the tail segment to the OS.
+    pub fn flush(&mut self) -> MyResult<()> {
+        if let Some(seg) = self.segs.back_mut() {
+            seg.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> MyResult<()> {
+        if let Some(seg) = self.segs.back_mut() {
+            let st = Instant::now();
+            seg.sync()?;
+            self.metrics.record_sync(st.elapsed().as_micros() as u64);
+        }
+        Ok(())
+    }
+
+    fn publish(&self, entries: &[LogEntry]) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        // a failed send means the tail was dropped
+        subscribers.retain(|tx| entries.iter().all(|e| tx.send(e.clone()).is_ok()));
+    }
+
+    /// Follows the log from `from_sequence` on: entries still in the WAL are
+    /// read back first, then new entries are received as they are appended.
+    /// The tail ends when the WAL is dropped.
+    pub fn tail(&mut self, from_sequence: u64) -> MyResult<WALTail> {
+        self.flush()?;
+        let backlog = self
+            .segs
+            .iter()
+            .map(WALSeg::iter)
+            .collect::<MyResult<VecDeque<_>>>()?;
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        Ok(WALTail {
+            backlog,
+            rx,
+            next_seq: max(from_sequence, 1),
+            done: false,
+        })
+    }
+
     pub fn truncate(&mut self, n: usize) -> MyResult<()> {
         for _ in 0..n {
             self.consume_seg()?;
@@ -268,19 +1199,97 @@ impl WAL {
     }
 
     pub fn consume_seg(&mut self) -> MyResult<()> {
-        if let Some(seg) = &mut self.segs.pop_front() {
+        if let Some(mut seg) = self.segs.pop_front() {
+            seg.flush()?;
+            if let Some(dir) = &self.opt.wal_archive_dir {
+                self.archive_seg(seg, Path::new(dir))?;
+            } else if !seg.legacy && self.recycled.len() < self.opt.wal_recycle_num {
+                let path = seg.path.with_extension(RECYCLE_EXT);
+                self.recycled.push_back(seg.recycle(path)?);
+            } else {
+                seg.delete()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn archive_seg(&self, seg: WALSeg, dir: &Path) -> MyResult<()> {
+        let env = self.opt.env.as_ref();
+        env.create_dir_all(dir)?;
+        let now = now_ms();
+        let file_name = seg
+            .path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .expect("wal file name");
+        let path = dir.join(format!("{:013}{}{}", now, ARCHIVE_SEP, file_name));
+        // the archive may live on another file system
+        if env.rename(&seg.path, &path).is_err() {
+            copy(&seg.path, &path)?;
             seg.delete()?;
         }
+        env.sync_dir(dir)?;
+        self.purge_archive(dir)
+    }
+
+    /// Drops the oldest archived segments beyond `wal_archive_max_files` and
+    /// those older than `wal_archive_ttl_secs`.
+    fn purge_archive(&self, dir: &Path) -> MyResult<()> {
+        let paths = seg_paths(self.opt.env.as_ref(), dir)?;
+        let max_files = self.opt.wal_archive_max_files;
+        let excess = if max_files > 0 {
+            paths.len().saturating_sub(max_files)
+        } else {
+            0
+        };
+        let ttl = self.opt.wal_archive_ttl_secs;
+        for (i, path) in paths.iter().enumerate() {
+            let expired = ttl > 0
+                && path
+                    .metadata()?
+                    .modified()?
+                    .elapsed()
+                    .map(|d| d.as_secs() >= ttl)
+                    .unwrap_or(false);
+            if i < excess || expired {
+                self.opt.env.remove_file(path)?;
+            }
+        }
         Ok(())
     }
 
+    /// Archived segments, oldest first. Replaying them in order, up to some
+    /// sequence number, restores the store to that point in time.
+    pub fn archived_segs(&self) -> MyResult<Vec<WALSeg>> {
+        match &self.opt.wal_archive_dir {
+            Some(dir) => seg_paths(self.opt.env.as_ref(), Path::new(dir))?
+                .iter()
+                .map(|p| WALSeg::with_env(self.opt.env.clone(), p, 0))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
     pub fn new_seg(&mut self) -> MyResult<()> {
-        let file_num = self.new_file_num();
-        let file_name = make_file_name(file_num, "wal");
-        let path = Path::new(&self.opt.work_dir);
-        let path = path.join(file_name);
-        let seg = WALSeg::new(path.as_path(), self.opt.mem_table_max_size)?;
-        self.segs.push_back(seg);
+        self.flush()?;
+        let file_num = self.file_numbers.next();
+        let file_name = make_file_name(file_num, WAL_EXT);
+        let dir = Path::new(&self.opt.work_dir);
+        let path = dir.join(file_name);
+        let seg = match self.recycled.pop_front() {
+            Some(seg) => seg.recycle(&path)?,
+            None => {
+                let seg = WALSeg::with_env(self.opt.env.clone(), &path, self.opt.wal_buffer_size)?;
+                if self.opt.wal_preallocate {
+                    seg.preallocate(self.opt.wal_seg_max_size)?;
+                }
+                seg
+            }
+        };
+        self.opt.env.sync_dir(dir)?;
+        self.segs
+            .push_back(seg.with_compression(self.opt.wal_compression));
+        self.metrics.record_rotation();
         Ok(())
     }
 
@@ -291,17 +1300,72 @@ impl WAL {
         Ok(0)
     }
 
-    fn new_file_num(&mut self) -> usize {
-        let n = self.current_file_num;
-        self.current_file_num += 1;
-        n
-    }
-
     pub fn iter(&self) -> MyResult<WALIter> {
         Ok(WALIter::new(&self))
     }
 }
 
+/// The WAL segments in `dir`, oldest first.
+pub fn seg_paths(env: &dyn Env, dir: &Path) -> MyResult<Vec<PathBuf>> {
+    list_ext(env, dir, WAL_EXT)
+}
+
+/// A blocking stream of log entries in sequence order, see `WAL::tail`.
+pub struct WALTail {
+    backlog: VecDeque<WALSegIter>,
+    rx: Receiver<LogEntry>,
+    next_seq: u64,
+    done: bool,
+}
+
+impl WALTail {
+    fn next_entry(&mut self) -> Option<MyResult<LogEntry>> {
+        while let Some(iter) = self.backlog.front_mut() {
+            match iter.next() {
+                Some(entry) => return Some(entry),
+                None => {
+                    self.backlog.pop_front();
+                }
+            }
+        }
+        self.rx.recv().ok().map(Ok)
+    }
+}
+
+impl Iterator for WALTail {
+    type Item = MyResult<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let entry = match self.next_entry() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            // the backlog and the live entries may overlap
+            if entry.seq < self.next_seq {
+                continue;
+            }
+            if entry.seq > self.next_seq {
+                self.done = true;
+                return Some(err(
+                    StatusCode::WALError,
+                    format!("wal entry {} is no longer available", self.next_seq),
+                ));
+            }
+            self.next_seq += 1;
+            return Some(Ok(entry));
+        }
+        None
+    }
+}
+
 pub struct WALIter<'a> {
     segs_iter: LinkedListIter<'a, WALSeg>,
     seg_iter: Option<WALSegIter>,
@@ -317,7 +1381,7 @@ impl<'a> WALIter<'a> {
 }
 
 impl<'a> Iterator for WALIter<'a> {
-    type Item = (Slice, Slice);
+    type Item = MyResult<LogEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some(seg_iter) = &mut self.seg_iter {
@@ -327,7 +1391,10 @@ impl<'a> Iterator for WALIter<'a> {
             }
         }
         if let Some(seg) = self.segs_iter.next() {
-            self.seg_iter = Some(seg.iter().expect("get wal seg iter"));
+            match seg.iter() {
+                Ok(seg_iter) => self.seg_iter = Some(seg_iter),
+                Err(e) => return Some(Err(e)),
+            }
             return self.next();
         }
         None
@@ -336,6 +1403,8 @@ impl<'a> Iterator for WALIter<'a> {
 
 #[cfg(test)]
 mod test {
+    use std::thread;
+
     use crate::test_utils::get_test_opt;
 
     use super::*;
@@ -360,12 +1429,15 @@ mod test {
             seg.append(&Slice::from(k.clone()), &Slice::from(v.clone()))?;
         }
         println!("append cost: {}us", st.elapsed().unwrap().as_micros());
+        // still buffered
+        assert_eq!(None, seg.iter()?.next());
+        seg.flush()?;
         let mut iter = seg.iter()?;
         let st = time::SystemTime::now();
         for (k, v) in &kvs {
             assert_eq!(
                 Some((Slice::from(k.clone()), Slice::from(v.clone()))),
-                iter.next()
+                iter.next().transpose()?.map(|e| (e.key, e.value))
             );
         }
         println!("iter cost: {}us", st.elapsed().unwrap().as_micros());
@@ -393,7 +1465,7 @@ mod test {
         for (k, v) in &kvs {
             assert_eq!(
                 Some((Slice::from(k.clone()), Slice::from(v.clone()))),
-                iter.next()
+                iter.next().transpose()?.map(|e| (e.key, e.value))
             );
         }
         assert_eq!(None, iter.next());
@@ -405,7 +1477,7 @@ mod test {
             }
             assert_eq!(
                 Some((Slice::from(k.clone()), Slice::from(v.clone()))),
-                iter.next()
+                iter.next().transpose()?.map(|e| (e.key, e.value))
             );
         }
         assert_eq!(None, iter.next());
@@ -418,10 +1490,599 @@ mod test {
             }
             assert_eq!(
                 Some((Slice::from(k.clone()), Slice::from(v.clone()))),
-                iter.next()
+                iter.next().transpose()?.map(|e| (e.key, e.value))
             );
         }
         assert_eq!(None, iter.next());
         Ok(())
     }
+
+    #[test]
+    fn test_wal_seg_corruption() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_corruption");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let mut seg = WALSeg::new(&p, 1024)?;
+        for k in &[b"a", b"b", b"c"] {
+            seg.append(&Slice::from(k.to_vec()), &Slice::from(b"value".to_vec()))?;
+        }
+        let record_size = seg.size() / 3;
+
+        // flip a byte in the value of the second record
+        seg.file.seek(SeekFrom::Start(
+            (SEG_HEADER_SIZE + record_size + HEADER_SIZE + 2) as u64,
+        ))?;
+        seg.file.write_all(&[0xff])?;
+        seg.file.flush()?;
+
+        let mut iter = seg.iter()?;
+        assert_eq!(
+            Some(b"a".to_vec()),
+            iter.next().transpose()?.map(|e| e.key[..].to_vec())
+        );
+        assert_eq!(None, iter.next());
+        assert_eq!(Some(SEG_HEADER_SIZE + record_size), iter.corrupted_offset());
+        drop(iter);
+
+        let opt = Options::default();
+        let map = seg.to_skiplist(&opt)?;
+        assert_eq!(1, map.length());
+        assert_eq!(
+            (SEG_HEADER_SIZE + record_size) as u64,
+            seg.file.get_ref().size()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_batch() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_batch");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let mut seg = WALSeg::new(&p, 1024)?;
+        seg.append(&Slice::from(b"a".to_vec()), &Slice::from(b"value".to_vec()))?;
+        let single_size = seg.size();
+        let batch = [b"b", b"c", b"d"]
+            .iter()
+            .map(|k| LogEntry::new(Slice::from(k.to_vec()), Slice::from(b"value".to_vec())))
+            .collect::<Vec<_>>();
+        seg.append_batch(&batch, true)?;
+
+        let keys = seg
+            .iter()?
+            .map(|e| e.map(|e| e.key[..].to_vec()))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()],
+            keys
+        );
+
+        // a torn write loses the whole batch, not just its tail
+        seg.file
+            .get_ref()
+            .set_len((SEG_HEADER_SIZE + seg.size() - 8) as u64)?;
+        let mut iter = seg.iter()?;
+        assert_eq!(
+            Some(b"a".to_vec()),
+            iter.next().transpose()?.map(|e| e.key[..].to_vec())
+        );
+        assert_eq!(None, iter.next());
+        assert_eq!(Some(SEG_HEADER_SIZE + single_size), iter.corrupted_offset());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_compression() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_compression");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let value = Slice::from(b"value".repeat(100));
+        let mut sizes = vec![];
+        let mut seg = WALSeg::new(&p, 1024)?;
+        for c in &[
+            WALCompression::None,
+            WALCompression::Snappy,
+            WALCompression::Zstd,
+        ] {
+            seg = seg.with_compression(*c);
+            let size = seg.size();
+            seg.append(&Slice::from(vec![*c as u8]), &value)?;
+            sizes.push(seg.size() - size);
+        }
+        assert!(sizes[1] < sizes[0]);
+        assert!(sizes[2] < sizes[0]);
+
+        seg.flush()?;
+
+        // records written with any compression replay the same way
+        let entries = seg.iter()?.collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(3, entries.len());
+        for (i, e) in entries.into_iter().enumerate() {
+            assert_eq!(vec![i as u8], e.key[..].to_vec());
+            assert_eq!(value, e.value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_recycle() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.wal_seg_max_size = 4096;
+        opt.wal_recycle_num = 1;
+        let mut wal = WAL::new(opt.clone())?;
+        wal.new_seg()?;
+        for i in 0..10 {
+            wal.append(
+                &Slice::from(format!("key{}", i).into_bytes()),
+                &Slice::from(b"value".to_vec()),
+            )?;
+        }
+        let old_path = wal.segs.front().unwrap().path.clone();
+        assert_eq!(4096, old_path.metadata()?.len());
+        wal.consume_seg()?;
+        assert!(!old_path.exists());
+        assert!(old_path.with_extension(RECYCLE_EXT).exists());
+
+        wal.new_seg()?;
+        let new_path = wal.segs.back().unwrap().path.clone();
+        assert!(!old_path.with_extension(RECYCLE_EXT).exists());
+        assert_eq!(4096, new_path.metadata()?.len());
+        wal.append(&Slice::from(b"a".to_vec()), &Slice::from(b"b".to_vec()))?;
+
+        // the old records after the tail are not replayed
+        let entries = wal
+            .iter()?
+            .map(|e| e.map(|e| (e.key, e.value)))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(
+            vec![(Slice::from(b"a".to_vec()), Slice::from(b"b".to_vec()))],
+            entries
+        );
+        let mut seg_iter = wal.segs.back().unwrap().iter()?;
+        assert_eq!(1, (&mut seg_iter).count());
+        assert_eq!(None, seg_iter.corrupted_offset());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_sequence() -> MyResult<()> {
+        let opt = get_test_opt();
+        let mut wal = WAL::new(opt.clone())?;
+        wal.new_seg()?;
+        assert_eq!(
+            1,
+            wal.append(&Slice::from(b"a".to_vec()), &Slice::from(b"1".to_vec()))?
+        );
+        let mut batch = vec![
+            LogEntry::new(Slice::from(b"b".to_vec()), Slice::from(b"2".to_vec())),
+            LogEntry::new(Slice::from(b"c".to_vec()), Slice::from(b"3".to_vec())),
+        ];
+        wal.append_batch(&mut batch)?;
+        assert_eq!(vec![2, 3], batch.iter().map(|e| e.seq).collect::<Vec<_>>());
+        wal.new_seg()?;
+        wal.new_seg()?;
+        assert_eq!(3, wal.last_sequence());
+
+        // the sequence is recovered from the records on reopen
+        let mut wal = WAL::new(opt.clone())?;
+        assert_eq!(3, wal.last_sequence());
+        assert_eq!(
+            vec![1, 2, 3],
+            wal.iter()?
+                .map(|e| e.map(|e| e.seq))
+                .collect::<MyResult<Vec<_>>>()?
+        );
+        assert_eq!(
+            4,
+            wal.append(&Slice::from(b"d".to_vec()), &Slice::from(b"4".to_vec()))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_metrics() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.wal_sync = true;
+        let mut wal = WAL::new(opt)?;
+        wal.new_seg()?;
+        wal.append(&Slice::from(b"a".to_vec()), &Slice::from(b"1".to_vec()))?;
+        wal.new_seg()?;
+        wal.append(&Slice::from(b"b".to_vec()), &Slice::from(b"2".to_vec()))?;
+
+        let stats = wal.metrics().stats();
+        let size: usize = wal.segs.iter().map(WALSeg::size).sum();
+        assert_eq!(size as u64, stats.bytes_appended);
+        assert_eq!(2, stats.syncs);
+        assert_eq!(2, stats.sync_latency.iter().sum::<u64>());
+        assert_eq!(SYNC_LATENCY_BUCKETS_US.len() + 1, stats.sync_latency.len());
+        assert_eq!(2, stats.rotations);
+        let pairs = stats.to_pairs();
+        assert!(pairs.contains(&("wal:rotations".to_owned(), "2".to_owned())));
+        assert!(pairs.iter().any(|(k, _)| k == "wal:sync_latency_us:le_inf"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_iter_error() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_iter_error");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let mut seg = WALSeg::new(&p, 1024)?;
+        seg.append(&Slice::from(b"a".to_vec()), &Slice::from(b"value".to_vec()))?;
+
+        // a record with a valid crc but an unknown compression type
+        let size_space = u32::required_space();
+        let mut buf = encode_record(
+            &[LogEntry::new(
+                Slice::from(b"b".to_vec()),
+                Slice::from(b"value".to_vec()),
+            )],
+            WALCompression::None,
+            0,
+        )?;
+        buf[size_space * 2 + 1] = 9;
+        let size = u32::decode_fixed(&buf[..size_space]) as usize;
+        let crc = record_crc(
+            &buf[size_space..size_space * 4],
+            &buf[HEADER_SIZE..HEADER_SIZE + size],
+        );
+        crc.encode_fixed(&mut buf[size_space * 4..HEADER_SIZE]);
+        seg.file.write_all(&buf)?;
+        seg.file.flush()?;
+
+        let mut iter = seg.iter()?;
+        assert!(iter.next().unwrap().is_ok());
+        assert_eq!(StatusCode::WALError, iter.next().unwrap().unwrap_err().code);
+        assert_eq!(None, iter.next());
+        assert!(seg.to_skiplist(&Options::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_archive() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        let dir = Path::new(&opt.work_dir).join("archive");
+        opt.wal_archive_dir = Some(dir.to_str().unwrap().to_owned());
+        opt.wal_archive_max_files = 2;
+        let mut wal = WAL::new(opt.clone())?;
+        for k in &[b"a", b"b", b"c"] {
+            wal.new_seg()?;
+            wal.append(&Slice::from(k.to_vec()), &Slice::from(b"value".to_vec()))?;
+        }
+        wal.truncate(3)?;
+        assert_eq!(0, wal.seg_count());
+
+        let segs = wal.archived_segs()?;
+        assert_eq!(2, segs.len());
+        let mut keys = vec![];
+        for seg in &segs {
+            for entry in seg.iter()? {
+                let entry = entry?;
+                keys.push((entry.seq, entry.key[..].to_vec()));
+            }
+        }
+        assert_eq!(vec![(2, b"b".to_vec()), (3, b"c".to_vec())], keys);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_tail() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.wal_seg_max_size = 4096;
+        let mut wal = WAL::new(opt.clone())?;
+        wal.new_seg()?;
+        for i in 0..3 {
+            wal.append(
+                &Slice::from(format!("key{}", i).into_bytes()),
+                &Slice::from(b"value".to_vec()),
+            )?;
+        }
+        let tail = wal.tail(2)?;
+        let t = thread::spawn(move || tail.map(|e| e.map(|e| e.seq)).collect::<MyResult<Vec<_>>>());
+        for i in 3..6 {
+            wal.append(
+                &Slice::from(format!("key{}", i).into_bytes()),
+                &Slice::from(b"value".to_vec()),
+            )?;
+        }
+        drop(wal);
+        assert_eq!(vec![2, 3, 4, 5, 6], t.join().unwrap()?);
+
+        // entries that were already consumed can't be tailed
+        let mut wal = WAL::new(opt.clone())?;
+        wal.truncate(1)?;
+        wal.new_seg()?;
+        wal.set_last_sequence(6);
+        wal.append(&Slice::from(b"a".to_vec()), &Slice::from(b"b".to_vec()))?;
+        let mut tail = wal.tail(1)?;
+        assert_eq!(StatusCode::WALError, tail.next().unwrap().unwrap_err().code);
+        assert_eq!(None, tail.next());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_fragments() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_fragments");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let mut seg = WALSeg::new(&p, 0)?.with_compression(WALCompression::None);
+        seg.append(&Slice::from(b"a".to_vec()), &Slice::from(b"value".to_vec()))?;
+        let small_size = seg.size();
+        let large = Slice::from((0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+        seg.append(&Slice::from(b"b".to_vec()), &large)?;
+        assert!(seg.size() > small_size + BLOCK_SIZE * 3);
+
+        let entries = seg.iter()?.collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(2, entries.len());
+        assert_eq!(large, entries[1].value);
+
+        // a torn large record is dropped as a whole
+        seg.file
+            .get_ref()
+            .set_len((SEG_HEADER_SIZE + small_size + BLOCK_SIZE * 2) as u64)?;
+        let mut iter = seg.iter()?;
+        assert_eq!(
+            Some(b"a".to_vec()),
+            iter.next().transpose()?.map(|e| e.key[..].to_vec())
+        );
+        assert_eq!(None, iter.next());
+        assert_eq!(Some(SEG_HEADER_SIZE + small_size), iter.corrupted_offset());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_header() -> MyResult<()> {
+        let opt = get_test_opt();
+        let dir = Path::new(&opt.work_dir);
+        let mut seg = WALSeg::new(dir.join(make_file_name(7, WAL_EXT)), 0)?;
+        seg.append(&Slice::from(b"a".to_vec()), &Slice::from(b"b".to_vec()))?;
+        let created_ms = seg.created_ms();
+        assert!(created_ms > 0);
+        assert_eq!(
+            created_ms,
+            WALSeg::new(dir.join(make_file_name(7, WAL_EXT)), 0)?.created_ms()
+        );
+
+        // a segment copied under another number
+        copy(
+            dir.join(make_file_name(7, WAL_EXT)),
+            dir.join(make_file_name(8, WAL_EXT)),
+        )?;
+        let r = WALSeg::new(dir.join(make_file_name(8, WAL_EXT)), 0);
+        assert_eq!(StatusCode::WALError, r.err().unwrap().code);
+        remove_file(dir.join(make_file_name(8, WAL_EXT)))?;
+
+        // a file without a header is taken for a legacy segment
+        let mut f = File::create(dir.join(make_file_name(9, WAL_EXT)))?;
+        f.write_all(b"not a wal segment at all")?;
+        let wal = WAL::new(opt.clone())?;
+        assert!(wal.segs.back().unwrap().is_legacy());
+        assert_eq!(1, wal.iter()?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_recovery_mode() -> MyResult<()> {
+        let p = Path::new("/tmp/wal_recovery_mode");
+        if p.exists() {
+            remove_file(p)?;
+        }
+        let mut seg = WALSeg::new(&p, 0)?;
+        for k in &[b"a", b"b", b"c"] {
+            seg.append(&Slice::from(k.to_vec()), &Slice::from(b"value".to_vec()))?;
+        }
+        let record_size = seg.size() / 3;
+        seg.file.seek(SeekFrom::Start(
+            (SEG_HEADER_SIZE + record_size + HEADER_SIZE + 2) as u64,
+        ))?;
+        seg.file.write_all(&[0xff])?;
+        seg.file.flush()?;
+
+        let mut opt = Options::default();
+        opt.wal_recovery_mode = WALRecoveryMode::Strict;
+        assert_eq!(
+            StatusCode::WALError,
+            seg.to_skiplist(&opt).err().unwrap().code
+        );
+
+        opt.wal_recovery_mode = WALRecoveryMode::SkipCorrupted;
+        let map = seg.to_skiplist(&opt)?;
+        assert_eq!(2, map.length());
+        assert!(map.get(&Slice::from(b"c".to_vec())).is_some());
+
+        opt.wal_recovery_mode = WALRecoveryMode::PointInTime;
+        let map = seg.to_skiplist(&opt)?;
+        assert_eq!(1, map.length());
+        assert_eq!(
+            (SEG_HEADER_SIZE + record_size) as u64,
+            seg.file.get_ref().size()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_file_numbers() -> MyResult<()> {
+        let opt = get_test_opt();
+        let mut wal = WAL::new(opt.clone())?;
+        wal.new_seg()?;
+        wal.new_seg()?;
+        wal.append(&Slice::from(b"a".to_vec()), &Slice::from(b"b".to_vec()))?;
+        drop(wal);
+
+        // reopening doesn't reuse the numbers of existing segments
+        let file_numbers = FileNumbers::new(0);
+        let mut wal = WAL::with_file_numbers(opt.clone(), file_numbers.clone())?;
+        assert_eq!(2, file_numbers.peek());
+        wal.new_seg()?;
+        assert_eq!(3, wal.seg_count());
+        assert_eq!(3, file_numbers.peek());
+        assert_eq!(
+            vec![b"a".to_vec()],
+            wal.iter()?
+                .map(|e| e.map(|e| e.key[..].to_vec()))
+                .collect::<MyResult<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    fn encode_legacy_record(key: &[u8], value: &[u8]) -> MyResult<Vec<u8>> {
+        let mut encoder = Encoder::new();
+        let key = encoder.compress_vec(key)?;
+        let value = encoder.compress_vec(value)?;
+        let size = key.len() + value.len();
+        let mut buf = vec![0; 8];
+        (size as u32).encode_fixed(&mut buf[..4]);
+        (key.len() as u32).encode_fixed(&mut buf[4..]);
+        buf.extend_from_slice(&key);
+        buf.extend_from_slice(&value);
+        buf.resize(buf.len() + padding(size), 0);
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_wal_seg_iter_legacy() -> MyResult<()> {
+        let opt = get_test_opt();
+        let path = Path::new(&opt.work_dir).join(make_file_name(3, WAL_EXT));
+        let kvs = vec![
+            (b"a".to_vec(), b"value a".to_vec()),
+            (b"b".to_vec(), vec![]),
+        ];
+        let mut buf = vec![];
+        for (k, v) in &kvs {
+            buf.extend(encode_legacy_record(k, v)?);
+        }
+        let intact = buf.len();
+        let torn = encode_legacy_record(b"c", b"value c")?;
+        buf.extend_from_slice(&torn[..torn.len() / 2]);
+        File::create(&path)?.write_all(&buf)?;
+
+        let mut iter = WALSegIter::new(&path)?;
+        assert!(iter.is_legacy());
+        let entries = (&mut iter).collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(
+            kvs.iter()
+                .map(|(k, v)| (UNSEQUENCED, Slice::from(k.clone()), Slice::from(v.clone())))
+                .collect::<Vec<_>>(),
+            entries
+                .into_iter()
+                .map(|e| (e.seq, e.key, e.value))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(intact), iter.corrupted_offset());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_legacy_seg() -> MyResult<()> {
+        let mut opt = get_test_opt();
+        opt.wal_recycle_num = 1;
+        let dir = Path::new(&opt.work_dir);
+        let path = dir.join(make_file_name(0, WAL_EXT));
+        let mut buf = encode_legacy_record(b"a", b"value a")?;
+        buf.extend(encode_legacy_record(b"b", b"value b")?);
+        File::create(&path)?.write_all(&buf)?;
+
+        let mut wal = WAL::new(opt.clone())?;
+        assert_eq!(1, wal.seg_count());
+        assert!(wal.segs.front().unwrap().is_legacy());
+        assert_eq!(0, wal.last_sequence());
+        let table = wal.segs.front().unwrap().to_memtable(&opt, b"", 0)?;
+        assert_eq!(
+            Some(&Slice::from(b"value b".to_vec())),
+            table.get(&Slice::from(b"b".to_vec()))
+        );
+        assert_eq!(None, table.sequence_range());
+
+        // new writes go to a new segment
+        let seq = wal.append(
+            &Slice::from(b"c".to_vec()),
+            &Slice::from(b"value c".to_vec()),
+        )?;
+        assert_eq!(1, seq);
+        assert_eq!(2, wal.seg_count());
+        assert!(!wal.segs.back().unwrap().is_legacy());
+
+        // once replayed, it is deleted rather than recycled
+        wal.truncate(1)?;
+        assert!(!path.exists());
+        assert!(list_ext(opt.env.as_ref(), dir, RECYCLE_EXT)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_keyspaces() -> MyResult<()> {
+        let opt = get_test_opt();
+        let app = Slice::from(b"app".to_vec());
+        let mut wal = WAL::new(opt)?.with_keyspaces(&[Slice::new(), app.clone()]);
+        let mut entries = [
+            LogEntry::new(Slice::from(b"a".to_vec()), Slice::from(b"1".to_vec()))
+                .with_namespace(app.clone()),
+        ];
+        wal.append_batch(&mut entries)?;
+        wal.new_seg()?;
+        wal.append(&Slice::from(b"b".to_vec()), &Slice::from(b"2".to_vec()))?;
+        let entries = wal
+            .iter()?
+            .map(|e| e.map(|e| (e.namespace, e.key)))
+            .collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(
+            vec![
+                (app.clone(), Slice::from(b"a".to_vec())),
+                (Slice::new(), Slice::from(b"b".to_vec()))
+            ],
+            entries
+        );
+
+        // a segment goes once no keyspace has unflushed writes in it
+        let last = wal.log_number();
+        wal.set_unflushed(&Slice::new(), last)?;
+        assert_eq!(2, wal.seg_count());
+        wal.set_unflushed(&app, None)?;
+        assert_eq!(1, wal.seg_count());
+        wal.set_unflushed(&Slice::new(), None)?;
+        assert_eq!(0, wal.seg_count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_seg_unnamed() -> MyResult<()> {
+        let opt = get_test_opt();
+        let path = Path::new(&opt.work_dir).join(make_file_name(0, WAL_EXT));
+        let mut buf = encode_seg_header(0, now_ms()).to_vec();
+        SEG_VERSION_UNNAMED.encode_fixed(&mut buf[4..8]);
+        let mut payload = vec![0; 16];
+        1u64.encode_fixed(&mut payload[..8]);
+        1u32.encode_fixed(&mut payload[8..12]);
+        1u32.encode_fixed(&mut payload[12..]);
+        payload.extend_from_slice(b"ab");
+        encode_fragment(
+            &mut buf,
+            RecordType::Full,
+            &payload,
+            1,
+            WALCompression::None,
+            0,
+        );
+        File::create(&path)?.write_all(&buf)?;
+
+        // its records belong to the default keyspace
+        let mut wal = WAL::new(opt)?;
+        assert_eq!(1, wal.last_sequence());
+        let entries = wal.iter()?.collect::<MyResult<Vec<_>>>()?;
+        assert_eq!(1, entries.len());
+        assert_eq!(Slice::new(), entries[0].namespace);
+        assert_eq!(Slice::from(b"a".to_vec()), entries[0].key);
+        assert_eq!(Slice::from(b"b".to_vec()), entries[0].value);
+
+        // new writes go to a new segment
+        assert!(!wal.segs.front().unwrap().is_appendable());
+        wal.append(&Slice::from(b"c".to_vec()), &Slice::from(b"d".to_vec()))?;
+        assert_eq!(2, wal.seg_count());
+        Ok(())
+    }
 }
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+
+/// Bump allocator for values of one type. Values are placed side by side in
+/// fixed-size chunks and never moved, so the returned pointers stay valid
+/// until `reset` or drop. The arena never drops the values it holds; owners
+/// drop them in place before giving the memory back.
+pub struct Arena<T> {
+    chunks: RefCell<Vec<Vec<MaybeUninit<T>>>>,
+    chunk_size: usize,
+}
+
+impl<T> Arena<T> {
+    pub fn new(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        Arena {
+            chunks: RefCell::new(vec![]),
+            chunk_size,
+        }
+    }
+
+    /// Takes `&self` so nodes can be allocated while others are borrowed.
+    pub fn alloc(&self, value: T) -> *mut T {
+        let mut chunks = self.chunks.borrow_mut();
+        let full = match chunks.last() {
+            Some(chunk) => chunk.len() == chunk.capacity(),
+            None => true,
+        };
+        if full {
+            chunks.push(Vec::with_capacity(self.chunk_size));
+        }
+        let chunk = chunks.last_mut().unwrap();
+        // never grows past its capacity, so earlier pointers stay put
+        chunk.push(MaybeUninit::new(value));
+        chunk.last_mut().unwrap().as_mut_ptr()
+    }
+
+    /// Places `len` copies of `value` side by side, in a chunk of their own
+    /// when they don't fit in what is left of the last one.
+    pub fn alloc_slice(&self, len: usize, value: T) -> *mut [T]
+    where
+        T: Copy,
+    {
+        let mut chunks = self.chunks.borrow_mut();
+        let fits = match chunks.last() {
+            Some(chunk) => chunk.capacity() - chunk.len() >= len,
+            None => false,
+        };
+        if !fits {
+            chunks.push(Vec::with_capacity(cmp::max(self.chunk_size, len)));
+        }
+        let chunk = chunks.last_mut().unwrap();
+        let start = chunk.len();
+        chunk.resize(start + len, MaybeUninit::new(value));
+        let first = chunk[start..].as_mut_ptr() as *mut T;
+        ptr::slice_from_raw_parts_mut(first, len)
+    }
+
+    /// Frees every value at once, keeping the first chunk for reuse.
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut();
+        chunks.truncate(1);
+        if let Some(chunk) = chunks.first_mut() {
+            chunk.clear();
+        }
+    }
+
+    /// Number of values allocated since the last reset.
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes reserved by the chunks.
+    pub fn capacity_bytes(&self) -> usize {
+        let slots: usize = self.chunks.borrow().iter().map(Vec::capacity).sum();
+        slots * mem::size_of::<T>()
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alloc() {
+        let mut arena = Arena::new(2);
+        let ptrs = (0..5).map(|i| arena.alloc(i)).collect::<Vec<_>>();
+        assert_eq!(5, arena.len());
+        assert_eq!(3, arena.chunks.borrow().len());
+        for (i, p) in ptrs.iter().enumerate() {
+            assert_eq!(i, unsafe { **p });
+        }
+        arena.reset();
+        assert!(arena.is_empty());
+        assert_eq!(1, arena.chunks.borrow().len());
+        let p = arena.alloc(7);
+        assert_eq!(7, unsafe { *p });
+    }
+
+    #[test]
+    fn test_alloc_slice() {
+        let arena = Arena::new(4);
+        let a = arena.alloc_slice(3, 1);
+        // doesn't fit in the one slot left
+        let b = arena.alloc_slice(2, 2);
+        let c = arena.alloc_slice(6, 3);
+        unsafe {
+            (*a)[2] = 5;
+            assert_eq!(&[1, 1, 5], &*a);
+            assert_eq!(&[2, 2], &*b);
+            assert_eq!(&[3; 6], &*c);
+        }
+        assert_eq!(11, arena.len());
+        assert_eq!((4 + 4 + 6) * mem::size_of::<i32>(), arena.capacity_bytes());
+    }
+}
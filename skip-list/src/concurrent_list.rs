@@ -0,0 +1,465 @@
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::ops::Bound;
+use std::ptr;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::Mutex;
+
+use crate::height_generator::GenHeight;
+use crate::height_generator::HeightGenerator;
+
+type Tower<K, V> = [AtomicPtr<Node<K, V>>];
+
+struct Node<K, V> {
+    key: K,
+    value: AtomicPtr<V>,
+    nexts: Vec<AtomicPtr<Node<K, V>>>,
+}
+
+impl<K, V> Node<K, V> {
+    fn allocate(key: K, value: V, height: usize) -> *mut Node<K, V> {
+        Box::into_raw(Box::new(Node {
+            key,
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            nexts: new_tower(height),
+        }))
+    }
+
+    fn value(&self) -> &V {
+        unsafe { &*self.value.load(Acquire) }
+    }
+}
+
+fn new_tower<K, V>(height: usize) -> Vec<AtomicPtr<Node<K, V>>> {
+    (0..=height)
+        .map(|_| AtomicPtr::new(ptr::null_mut()))
+        .collect()
+}
+
+fn next<K, V>(tower: &Tower<K, V>, level: usize) -> Option<&Node<K, V>> {
+    let p = tower[level].load(Acquire);
+    if p.is_null() {
+        None
+    } else {
+        Some(unsafe { &*p })
+    }
+}
+
+/// A skip list that many threads can insert into at once. New nodes are
+/// linked in with compare-and-swap, bottom level first, so readers never
+/// see a half-linked node. Overwritten values are kept until the list is
+/// cleared or dropped, since readers may still hold references to them.
+/// Removing takes `&mut self`.
+pub struct ConcurrentSkipList<K, V> {
+    head_: Vec<AtomicPtr<Node<K, V>>>,
+    length_: AtomicUsize,
+    height_: AtomicUsize,
+    max_height_: usize,
+    retired_: Mutex<Vec<*mut V>>,
+}
+
+unsafe impl<K: Send + Sync, V: Send + Sync> Send for ConcurrentSkipList<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for ConcurrentSkipList<K, V> {}
+
+impl<K, V> ConcurrentSkipList<K, V> {
+    pub fn new(max_height: usize) -> Self {
+        ConcurrentSkipList {
+            head_: new_tower(max_height),
+            length_: AtomicUsize::new(0),
+            height_: AtomicUsize::new(0),
+            max_height_: max_height,
+            retired_: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        self.length_.load(Acquire)
+    }
+
+    pub fn height(&self) -> usize {
+        self.height_.load(Acquire)
+    }
+
+    pub fn max_height(&self) -> usize {
+        self.max_height_
+    }
+
+    pub fn iter(&self) -> ConcurrentSkipListIter<K, V> {
+        ConcurrentSkipListIter(next(&self.head_, 0))
+    }
+
+    fn dispose(&mut self) {
+        let mut current = self.head_[0].load(Relaxed);
+        while !current.is_null() {
+            unsafe {
+                let node = Box::from_raw(current);
+                current = node.nexts[0].load(Relaxed);
+                drop(Box::from_raw(node.value.load(Relaxed)));
+            }
+        }
+        for value in self.retired_.get_mut().unwrap().drain(..) {
+            unsafe {
+                drop(Box::from_raw(value));
+            }
+        }
+        for p in &self.head_ {
+            p.store(ptr::null_mut(), Relaxed);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.dispose();
+        *self.length_.get_mut() = 0;
+        *self.height_.get_mut() = 0;
+    }
+
+    fn raise_height(&self, height: usize) {
+        let mut current = self.height_.load(Acquire);
+        while current < height {
+            match self
+                .height_
+                .compare_exchange(current, height, AcqRel, Acquire)
+            {
+                Ok(_) => return,
+                Err(x) => current = x,
+            }
+        }
+    }
+}
+
+impl<K: Ord, V> ConcurrentSkipList<K, V> {
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let mut tower: &Tower<K, V> = &self.head_;
+        for i in (0..=self.height()).rev() {
+            while let Some(node) = next(tower, i) {
+                match node.key.borrow().cmp(key) {
+                    ::std::cmp::Ordering::Less => tower = &node.nexts,
+                    ::std::cmp::Ordering::Equal => return Some(node.value()),
+                    ::std::cmp::Ordering::Greater => break,
+                }
+            }
+        }
+        None
+    }
+
+    /// Entries with keys between `start` and `end`, in order.
+    pub fn range<'a, Q: ?Sized>(
+        &'a self,
+        start: Bound<&Q>,
+        end: Bound<&'a Q>,
+    ) -> ConcurrentSkipListRange<'a, K, V, Q>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let mut tower: &Tower<K, V> = &self.head_;
+        for i in (0..=self.height()).rev() {
+            while let Some(node) = next(tower, i) {
+                let before_start = match start {
+                    Bound::Included(k) => node.key.borrow() < k,
+                    Bound::Excluded(k) => node.key.borrow() <= k,
+                    Bound::Unbounded => false,
+                };
+                if !before_start {
+                    break;
+                }
+                tower = &node.nexts;
+            }
+        }
+        ConcurrentSkipListRange {
+            iter: ConcurrentSkipListIter(next(tower, 0)),
+            end,
+        }
+    }
+
+    /// For every level, the last tower before `key` and the node after it.
+    fn find_splice<Q: ?Sized>(&self, key: &Q) -> (Vec<&Tower<K, V>>, Vec<*mut Node<K, V>>)
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let mut preds: Vec<&Tower<K, V>> = vec![&self.head_; self.max_height_ + 1];
+        let mut succs = vec![ptr::null_mut(); self.max_height_ + 1];
+        let mut tower: &Tower<K, V> = &self.head_;
+        for i in (0..=self.height()).rev() {
+            loop {
+                let p = tower[i].load(Acquire);
+                if !p.is_null() && unsafe { (*p).key.borrow() < key } {
+                    tower = unsafe { &(*p).nexts };
+                } else {
+                    succs[i] = p;
+                    break;
+                }
+            }
+            preds[i] = tower;
+        }
+        (preds, succs)
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let (preds, succs) = self.find_splice(key);
+        let node_ptr = succs[0];
+        if node_ptr.is_null() || unsafe { (*node_ptr).key.borrow() != key } {
+            return None;
+        }
+        let node = unsafe { Box::from_raw(node_ptr) };
+        for (i, p) in node.nexts.iter().enumerate() {
+            preds[i][i].store(p.load(Relaxed), Relaxed);
+        }
+        self.length_.fetch_sub(1, Relaxed);
+        Some(*unsafe { Box::from_raw(node.value.load(Relaxed)) })
+    }
+}
+
+impl<K: Ord, V: Clone> ConcurrentSkipList<K, V> {
+    /// Inserts through a shared reference. Returns a copy of the value that
+    /// was replaced, if any.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.insert_if(key, value, |_, _| true)
+    }
+
+    /// Like `insert`, but an existing value is only replaced if
+    /// `replace(old, new)` holds. When it doesn't, `value` is dropped and a
+    /// copy of it is returned, as the value that lost.
+    pub fn insert_if<F>(&self, key: K, value: V, replace: F) -> Option<V>
+    where
+        F: Fn(&V, &V) -> bool,
+    {
+        let height = GenHeight::new().gen_height(self.max_height_);
+        let node_ptr = Node::allocate(key, value, height);
+        let node = unsafe { &*node_ptr };
+        // raised before linking, so a search that meets the node also
+        // looks at its upper levels
+        self.raise_height(height);
+
+        // whoever links level 0 first owns the key
+        loop {
+            let (preds, succs) = self.find_splice(&node.key);
+            let succ = succs[0];
+            if !succ.is_null() && unsafe { &(*succ).key } == &node.key {
+                let value = node.value.swap(ptr::null_mut(), Relaxed);
+                unsafe {
+                    drop(Box::from_raw(node_ptr));
+                }
+                return self.replace_value(unsafe { &*succ }, value, replace);
+            }
+            node.nexts[0].store(succ, Release);
+            if preds[0][0]
+                .compare_exchange(succ, node_ptr, AcqRel, Acquire)
+                .is_ok()
+            {
+                break;
+            }
+        }
+        self.length_.fetch_add(1, AcqRel);
+
+        for i in 1..=height {
+            loop {
+                let (preds, succs) = self.find_splice(&node.key);
+                node.nexts[i].store(succs[i], Release);
+                if preds[i][i]
+                    .compare_exchange(succs[i], node_ptr, AcqRel, Acquire)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        None
+    }
+
+    fn replace_value<F>(&self, node: &Node<K, V>, value: *mut V, replace: F) -> Option<V>
+    where
+        F: Fn(&V, &V) -> bool,
+    {
+        let mut old = node.value.load(Acquire);
+        loop {
+            if !replace(unsafe { &*old }, unsafe { &*value }) {
+                let value = unsafe { Box::from_raw(value) };
+                return Some(*value);
+            }
+            match node.value.compare_exchange(old, value, AcqRel, Acquire) {
+                Ok(_) => break,
+                Err(x) => old = x,
+            }
+        }
+        let r = unsafe { (*old).clone() };
+        self.retired_.lock().unwrap().push(old);
+        Some(r)
+    }
+}
+
+impl<K, V> Debug for ConcurrentSkipList<K, V> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(
+            f,
+            "ConcurrentSkipList {{ len = {}, height = {} }}",
+            self.length(),
+            self.height()
+        )
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for ConcurrentSkipList<K, V> {
+    fn clone(&self) -> Self {
+        let copied = ConcurrentSkipList::new(self.max_height_);
+        for (k, v) in self.iter() {
+            copied.insert(k.clone(), v.clone());
+        }
+        copied
+    }
+}
+
+impl<K, V> Drop for ConcurrentSkipList<K, V> {
+    fn drop(&mut self) {
+        self.dispose();
+    }
+}
+
+pub struct ConcurrentSkipListIter<'a, K, V>(Option<&'a Node<K, V>>);
+
+impl<'a, K, V> Iterator for ConcurrentSkipListIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.0?;
+        self.0 = next(&node.nexts, 0);
+        Some((&node.key, node.value()))
+    }
+}
+
+pub struct ConcurrentSkipListRange<'a, K, V, Q: ?Sized> {
+    iter: ConcurrentSkipListIter<'a, K, V>,
+    end: Bound<&'a Q>,
+}
+
+impl<'a, K, V, Q: ?Sized> Iterator for ConcurrentSkipListRange<'a, K, V, Q>
+where
+    K: Borrow<Q>,
+    Q: Ord,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.iter.next()?;
+        let past_end = match self.end {
+            Bound::Included(end) => k.borrow() > end,
+            Bound::Excluded(end) => k.borrow() >= end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.iter = ConcurrentSkipListIter(None);
+            return None;
+        }
+        Some((k, v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut list = ConcurrentSkipList::new(10);
+        assert_eq!(None, list.insert(2, 4));
+        assert_eq!(None, list.insert(0, 1));
+        assert_eq!(None, list.insert(1, 3));
+        assert_eq!(Some(1), list.insert(0, 2));
+        assert_eq!(Some(&2), list.get(&0));
+        assert_eq!(Some(&3), list.get(&1));
+        assert_eq!(Some(&4), list.get(&2));
+        assert_eq!(None, list.get(&3));
+        assert_eq!(3, list.length());
+        assert_eq!(
+            vec![(0, 2), (1, 3), (2, 4)],
+            list.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(3), list.remove(&1));
+        assert_eq!(None, list.remove(&1));
+        assert_eq!(None, list.get(&1));
+        assert_eq!(2, list.length());
+        list.clear();
+        assert_eq!(None, list.get(&0));
+        assert_eq!(0, list.length());
+    }
+
+    #[test]
+    fn test_range() {
+        let list = ConcurrentSkipList::new(10);
+        for i in 0..100 {
+            list.insert(i * 2, i);
+        }
+        let keys = |start, end| list.range(start, end).map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!(
+            vec![10, 12, 14],
+            keys(Bound::Included(&10), Bound::Excluded(&16))
+        );
+        assert_eq!(
+            vec![12, 14, 16],
+            keys(Bound::Excluded(&10), Bound::Included(&16))
+        );
+        assert_eq!(
+            vec![12, 14],
+            keys(Bound::Included(&11), Bound::Included(&15))
+        );
+        assert_eq!(vec![0, 2], keys(Bound::Unbounded, Bound::Excluded(&4)));
+        assert_eq!(
+            vec![196, 198],
+            keys(Bound::Included(&196), Bound::Unbounded)
+        );
+        assert!(keys(Bound::Included(&16), Bound::Excluded(&16)).is_empty());
+        assert!(keys(Bound::Included(&300), Bound::Unbounded).is_empty());
+    }
+
+    #[test]
+    fn test_insert_if() {
+        let list = ConcurrentSkipList::new(10);
+        let newer = |old: &(u64, i32), new: &(u64, i32)| new.0 > old.0;
+        assert_eq!(None, list.insert_if(1, (2, 20), newer));
+        assert_eq!(Some((1, 10)), list.insert_if(1, (1, 10), newer));
+        assert_eq!(Some(&(2, 20)), list.get(&1));
+        assert_eq!(Some((2, 20)), list.insert_if(1, (3, 30), newer));
+        assert_eq!(Some(&(3, 30)), list.get(&1));
+    }
+
+    #[test]
+    fn test_concurrent_insert() {
+        let list = Arc::new(ConcurrentSkipList::new(12));
+        let threads = (0..8)
+            .map(|i| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for j in 0..1000 {
+                        // every thread writes every other thread's keys too
+                        list.insert(j * 8 + i, j);
+                        list.insert((j * 8 + i + 1) % 8000, j);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert_eq!(8000, list.length());
+        let keys = list.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        assert_eq!((0..8000).collect::<Vec<_>>(), keys);
+        for k in 0..8000 {
+            assert!(list.get(&k).is_some());
+        }
+    }
+}
@@ -1,11 +1,16 @@
 #![allow(dead_code)]
 
+mod arena;
+mod concurrent_list;
 mod height_generator;
 mod iter;
 mod list;
 mod node;
 mod util;
 
+pub use crate::concurrent_list::{
+    ConcurrentSkipList, ConcurrentSkipListIter, ConcurrentSkipListRange,
+};
 pub use crate::height_generator::HeightGenerator;
 pub use crate::iter::{SkipListIter, SkipListIterMut};
 pub use crate::list::SkipList;
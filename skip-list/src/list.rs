@@ -1,8 +1,8 @@
 use std::borrow::Borrow;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::mem;
 use std::ops::Drop;
 
+use crate::arena::Arena;
 use crate::height_generator::GenHeight;
 use crate::height_generator::HeightGenerator;
 use crate::iter::SkipListIter;
@@ -10,7 +10,12 @@ use crate::iter::SkipListIterMut;
 use crate::node::SkipListNode;
 use std::fmt::Debug;
 
+/// Nodes and their towers of next pointers live in arenas: removed nodes are
+/// dropped in place, and their memory is only given back all at once by
+/// `clear` or drop.
 pub struct SkipList<K, V> {
+    arena_: Arena<SkipListNode<K, V>>,
+    towers_: Arena<*mut SkipListNode<K, V>>,
     head_: *mut SkipListNode<K, V>,
     length_: usize,
     height_: usize,
@@ -47,8 +52,12 @@ impl<K, V> SkipList<K, V> {
         max_height: usize,
         height_generator: Box<dyn HeightGenerator + Send>,
     ) -> Self {
+        let arena = Arena::default();
+        let towers = Arena::default();
         SkipList {
-            head_: SkipListNode::allocate_dummy(max_height),
+            head_: SkipListNode::allocate_dummy(&arena, &towers, max_height),
+            arena_: arena,
+            towers_: towers,
             length_: 0,
             height_: 0,
             max_height_: max_height,
@@ -72,16 +81,20 @@ impl<K, V> SkipList<K, V> {
             if !is_head {
                 SkipListNode::free(current);
             }
-
-            // drop head nexts ptr
-            let nexts_ptr = &mut (*self.head_).nexts_ as *mut Vec<*mut SkipListNode<K, V>>;
-            nexts_ptr.drop_in_place();
         }
+        self.arena_.reset();
+        self.towers_.reset();
+    }
+
+    /// Bytes reserved for nodes and towers, not counting what keys and
+    /// values own.
+    pub fn arena_size(&self) -> usize {
+        self.arena_.capacity_bytes() + self.towers_.capacity_bytes()
     }
 
     pub fn clear(&mut self) {
         self.dispose();
-        self.head_ = SkipListNode::allocate_dummy(self.max_height_);
+        self.head_ = SkipListNode::allocate_dummy(&self.arena_, &self.towers_, self.max_height_);
         self.length_ = 0;
         self.height_ = 0;
     }
@@ -131,14 +144,15 @@ impl<K: Ord, V> SkipList<K, V> {
             }
         }
 
-        let node_ptr = SkipListNode::allocate(key, value, height);
+        let node_ptr = SkipListNode::allocate(&self.arena_, &self.towers_, key, value, height);
 
         #[allow(clippy::needless_range_loop)]
         for i in 0..=height {
             let update = &mut updates[i];
             unsafe {
-                *((*node_ptr).nexts_.get_unchecked_mut(i)) = *(update.nexts_.get_unchecked_mut(i));
-                *(update.nexts_.get_unchecked_mut(i)) = node_ptr;
+                *((*node_ptr).nexts_mut().get_unchecked_mut(i)) =
+                    *(update.nexts_mut().get_unchecked_mut(i));
+                *(update.nexts_mut().get_unchecked_mut(i)) = node_ptr;
             }
         }
 
@@ -236,12 +250,12 @@ impl<K: Ord, V> SkipList<K, V> {
             for i in 0..=next.height() {
                 let update = &mut updates[i];
                 unsafe {
-                    *(update.nexts_.get_unchecked_mut(i)) = *(next.nexts_.get_unchecked_mut(i));
+                    *(update.nexts_mut().get_unchecked_mut(i)) =
+                        *(next.nexts_mut().get_unchecked_mut(i));
                 }
             }
 
-            let old_value = next.replace_value(unsafe { mem::uninitialized() });
-            SkipListNode::free(next);
+            let old_value = SkipListNode::take_value(next);
 
             self.length_ -= 1;
 
@@ -409,6 +423,30 @@ mod test {
         assert_eq!(2, map.length());
     }
 
+    #[test]
+    fn test_arena() {
+        let mut map: SkipList<Vec<u8>, Vec<u8>> = SkipList::new(10);
+        for i in 0..2000 {
+            map.insert(format!("k{:04}", i).into_bytes(), vec![i as u8]);
+        }
+        let size = map.arena_size();
+        assert!(size > 0);
+        for i in (0..2000).step_by(2) {
+            assert_eq!(
+                Some(vec![i as u8]),
+                map.remove(format!("k{:04}", i).as_bytes())
+            );
+        }
+        assert_eq!(1000, map.length());
+        assert_eq!(Some(&vec![1]), map.get(&b"k0001"[..]));
+        // removed nodes keep their memory until the list is cleared
+        assert_eq!(size, map.arena_size());
+        map.clear();
+        assert!(map.arena_size() < size);
+        map.insert(b"a".to_vec(), b"b".to_vec());
+        assert_eq!(Some(&b"b".to_vec()), map.get(&b"a"[..]));
+    }
+
     #[test]
     fn test_clone() {
         let n = 100;
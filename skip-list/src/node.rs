@@ -1,3 +1,4 @@
+use crate::arena::Arena;
 use crate::util::from_raw;
 use crate::util::from_raw_mut;
 use std::mem;
@@ -5,7 +6,9 @@ use std::ptr;
 
 #[derive(Debug)]
 pub struct SkipListNode<K, V> {
-    pub(crate) nexts_: Vec<*mut SkipListNode<K, V>>,
+    /// Lives in the list's tower arena, next to the towers of the nodes
+    /// allocated before it.
+    pub(crate) nexts_: *mut [*mut SkipListNode<K, V>],
     pub(crate) key_: K,
     pub(crate) value_: V,
 }
@@ -35,29 +38,58 @@ impl<K, V> SkipListNode<K, V> {
         from_raw(node_ptr)
     }
 
-    pub(crate) fn allocate_dummy(max_height: usize) -> *mut SkipListNode<K, V> {
+    pub(crate) fn allocate_dummy(
+        arena: &Arena<SkipListNode<K, V>>,
+        towers: &Arena<*mut SkipListNode<K, V>>,
+        max_height: usize,
+    ) -> *mut SkipListNode<K, V> {
         SkipListNode::allocate(
+            arena,
+            towers,
             unsafe { mem::uninitialized() },
             unsafe { mem::uninitialized() },
             max_height,
         )
     }
 
-    pub(crate) fn allocate(key: K, value: V, height: usize) -> *mut SkipListNode<K, V> {
-        Box::into_raw(Box::new(SkipListNode {
-            nexts_: vec![ptr::null_mut(); height + 1],
+    pub(crate) fn allocate(
+        arena: &Arena<SkipListNode<K, V>>,
+        towers: &Arena<*mut SkipListNode<K, V>>,
+        key: K,
+        value: V,
+        height: usize,
+    ) -> *mut SkipListNode<K, V> {
+        arena.alloc(SkipListNode {
+            nexts_: towers.alloc_slice(height + 1, ptr::null_mut()),
             key_: key,
             value_: value,
-        }))
+        })
+    }
+
+    pub(crate) fn nexts(&self) -> &[*mut SkipListNode<K, V>] {
+        unsafe { &*self.nexts_ }
+    }
+
+    pub(crate) fn nexts_mut(&mut self) -> &mut [*mut SkipListNode<K, V>] {
+        unsafe { &mut *self.nexts_ }
     }
 
     pub(crate) fn height(&self) -> usize {
-        self.nexts_.len() - 1
+        self.nexts().len() - 1
     }
 
+    /// Drops the node in place; its memory goes back with the arena.
     pub(crate) fn free(node_ptr: *mut SkipListNode<K, V>) {
         unsafe {
-            Box::from_raw(node_ptr);
+            ptr::drop_in_place(node_ptr);
+        }
+    }
+
+    /// Drops the node in place except for its value, which is returned.
+    pub(crate) fn take_value(node_ptr: *mut SkipListNode<K, V>) -> V {
+        unsafe {
+            ptr::drop_in_place(&mut (*node_ptr).key_);
+            ptr::read(&(*node_ptr).value_)
         }
     }
 
@@ -66,7 +98,7 @@ impl<K, V> SkipListNode<K, V> {
     }
 
     pub fn next(&self, height: usize) -> Option<&SkipListNode<K, V>> {
-        self.nexts_.get(height).and_then(|ptr| {
+        self.nexts().get(height).and_then(|ptr| {
             if ptr.is_null() {
                 None
             } else {
@@ -76,7 +108,7 @@ impl<K, V> SkipListNode<K, V> {
     }
 
     pub fn next_mut(&mut self, height: usize) -> Option<&mut SkipListNode<K, V>> {
-        self.nexts_.get(height).and_then(|ptr| {
+        self.nexts().get(height).and_then(|ptr| {
             if ptr.is_null() {
                 None
             } else {
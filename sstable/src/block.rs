@@ -1,12 +1,12 @@
-use crc::crc32;
-use crc::crc32::Hasher32;
+use std::sync::Arc;
+
 use integer_encoding::FixedInt;
 use snap::Decoder;
 
-use crate::block_builder::BLOCK_CKSUM_LEN;
 use crate::block_builder::BLOCK_CTYPE_LEN;
 use crate::block_handle::BlockHandle;
 use crate::block_iter::BlockIter;
+use crate::checksum::{verify_checksum, verify_legacy_checksum};
 use crate::error::MyResult;
 use crate::error::StatusCode;
 use crate::options::int_to_compress_type;
@@ -14,12 +14,11 @@ use crate::options::CompressType;
 use crate::options::Options;
 use crate::reader;
 use crate::types::RandomAccess;
-use crate::util::unmask_crc;
 
 #[derive(Clone)]
 pub struct Block {
     opt: Options,
-    pub block: Vec<u8>,
+    pub block: Arc<Vec<u8>>,
 }
 
 unsafe impl Send for Block {}
@@ -33,7 +32,7 @@ impl Block {
     pub fn new_with_buffer<T: Into<Vec<u8>>>(buffer: T, opt: Options) -> Self {
         Block {
             opt,
-            block: buffer.into(),
+            block: Arc::new(buffer.into()),
         }
     }
 
@@ -43,16 +42,16 @@ impl Block {
         opt: Options,
     ) -> MyResult<(Block, usize)> {
         let (data, offset) = reader::read_bytes(r, location)?;
-        let cksum_buf = &data[data.len() - BLOCK_CKSUM_LEN..];
-        if !Block::verify_block(
-            &data[..data.len() - BLOCK_CKSUM_LEN],
-            unmask_crc(u32::decode_fixed(&cksum_buf)),
-        ) {
-            return err!(StatusCode::ChecksumError, "checksum error");
+        let data = if opt.legacy_checksums {
+            verify_legacy_checksum(&data)?
+        } else {
+            verify_checksum(&data)?
+        };
+        let ctype_buf = &data[data.len() - BLOCK_CTYPE_LEN..];
+        let mut buf = data[..data.len() - BLOCK_CTYPE_LEN].to_vec();
+        if let Some(cipher) = &opt.cipher {
+            cipher.apply(location.offset, &mut buf);
         }
-        let ctype_buf =
-            &data[data.len() - BLOCK_CTYPE_LEN - BLOCK_CKSUM_LEN..data.len() - BLOCK_CKSUM_LEN];
-        let buf = &data[..data.len() - BLOCK_CKSUM_LEN - BLOCK_CTYPE_LEN];
         if let Some(ctype) = int_to_compress_type(u32::from(ctype_buf[0])) {
             match ctype {
                 CompressType::None => Ok((Block::new_with_buffer(buf, opt), offset)),
@@ -66,12 +65,6 @@ impl Block {
         }
     }
 
-    fn verify_block(data: &[u8], want: u32) -> bool {
-        let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
-        digest.write(data);
-        digest.sum32() == want
-    }
-
     pub fn restarts_offset(&self) -> usize {
         let restarts = u32::decode_fixed(&self.block[self.block.len() - 4..]);
         self.block.len() - 4 - 4 * restarts as usize
@@ -2,16 +2,14 @@ use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
 
-use crc::crc32;
-use crc::crc32::Hasher32;
 use integer_encoding::{FixedIntWriter, VarIntWriter};
 use snap::Encoder;
 
 use crate::block_handle::BlockHandle;
+use crate::checksum::append_checksum;
 use crate::error::MyResult;
 use crate::options::CompressType;
 use crate::options::Options;
-use crate::util::mask_crc;
 use crate::util::to_str;
 
 pub const BLOCK_CTYPE_LEN: usize = 1;
@@ -127,15 +125,17 @@ impl BlockBuilder {
             self.buffer = encoder.compress_vec(&self.buffer)?;
         }
 
+        // encrypt buffer
+        if let Some(cipher) = &self.opt.cipher {
+            cipher.apply(offset, &mut self.buffer);
+        }
+
         // write ctype
         let ctype_buf = [self.opt.compress_type as u8; BLOCK_CTYPE_LEN];
         self.buffer.write_all(&ctype_buf)?;
 
-        let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
-        digest.write(&self.buffer);
-
-        // write crc
-        self.buffer.write_fixedint(mask_crc(digest.sum32()))?;
+        // write checksum and checksum type
+        append_checksum(&mut self.buffer, self.opt.checksum_type);
 
         w.seek(SeekFrom::Start(offset as u64))?;
         w.write_all(&self.buffer)?;
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+const BLOOM_SEED: u32 = 0xbc9f_1d34;
+
+/// A table-level bloom filter built from every key added to a table. It is
+/// loaded with the table and checked before the index is touched, so lookups
+/// for absent keys rarely cost more than a few hash probes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    k: u32,
+}
+
+impl BloomFilter {
+    pub fn new(key_hashes: &[u32], bits_per_key: usize) -> Self {
+        // 0.69 =~ ln(2), which minimizes the false positive rate
+        let k = ((bits_per_key as f64) * 0.69) as u32;
+        let k = ::std::cmp::min(::std::cmp::max(k, 1), 30);
+
+        let bits = ::std::cmp::max(key_hashes.len() * bits_per_key, 64);
+        let bytes = (bits + 7) / 8;
+        let bits = bytes * 8;
+
+        let mut filter = vec![0; bytes];
+        for h in key_hashes {
+            let mut h = *h;
+            let delta = h.rotate_right(17);
+            for _ in 0..k {
+                let pos = h as usize % bits;
+                filter[pos / 8] |= 1 << (pos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+
+        BloomFilter { bits: filter, k }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let bits = self.bits.len() * 8;
+        if bits == 0 {
+            return true;
+        }
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_right(17);
+        for _ in 0..self.k {
+            let pos = h as usize % bits;
+            if self.bits[pos / 8] & (1 << (pos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+
+    pub fn size(&self) -> usize {
+        self.bits.len()
+    }
+}
+
+pub fn bloom_hash(key: &[u8]) -> u32 {
+    hash(key, BLOOM_SEED)
+}
+
+fn hash(data: &[u8], seed: u32) -> u32 {
+    const M: u32 = 0xc6a4_a793;
+    const R: u32 = 24;
+
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = u32::from(chunk[0])
+            | u32::from(chunk[1]) << 8
+            | u32::from(chunk[2]) << 16
+            | u32::from(chunk[3]) << 24;
+        h = h.wrapping_add(w);
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        if rest.len() == 3 {
+            h = h.wrapping_add(u32::from(rest[2]) << 16);
+        }
+        if rest.len() >= 2 {
+            h = h.wrapping_add(u32::from(rest[1]) << 8);
+        }
+        h = h.wrapping_add(u32::from(rest[0]));
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_bloom() {
+        let n = 10000;
+        let hashes = (0..n).map(|i| bloom_hash(&key(i))).collect::<Vec<_>>();
+        let filter = BloomFilter::new(&hashes, 10);
+
+        for i in 0..n {
+            assert!(filter.may_contain(&key(i)));
+        }
+
+        let false_positives = (n..n * 2).filter(|i| filter.may_contain(&key(*i))).count();
+        let rate = false_positives as f64 / n as f64;
+        assert!(rate < 0.02, "false positive rate {}", rate);
+    }
+
+    #[test]
+    fn test_empty() {
+        let filter = BloomFilter::new(&[], 10);
+        assert!(!filter.may_contain(b"a"));
+        assert_eq!(8, filter.size());
+    }
+}
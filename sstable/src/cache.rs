@@ -1,18 +1,40 @@
+use std::collections::HashMap;
+
 use lru::LruCache;
 
 pub type CacheKey = [u8; 16];
 pub type CacheID = u64;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePriority {
+    Low,
+    High,
+}
+
+/// An LRU cache split into a high priority pool (index blocks) and a low
+/// priority pool (data blocks), so data block churn cannot evict index blocks.
+/// Pinned entries live outside both pools and are never evicted.
 pub struct Cache<T> {
-    inner: LruCache<CacheKey, T>,
+    low: LruCache<CacheKey, T>,
+    high: LruCache<CacheKey, T>,
+    pinned: HashMap<CacheKey, T>,
     id: u64,
 }
 
 impl<T> Cache<T> {
     pub fn new(capacity: usize) -> Cache<T> {
+        Cache::new_with_high_pri_ratio(capacity, 0.)
+    }
+
+    pub fn new_with_high_pri_ratio(capacity: usize, high_pri_ratio: f64) -> Cache<T> {
         assert!(capacity > 0);
+        assert!(high_pri_ratio >= 0. && high_pri_ratio <= 1.);
+        let high_cap = ::std::cmp::max((capacity as f64 * high_pri_ratio) as usize, 1);
+        let low_cap = ::std::cmp::max(capacity.saturating_sub(high_cap), 1);
         Cache {
-            inner: LruCache::new(capacity),
+            low: LruCache::new(low_cap),
+            high: LruCache::new(high_cap),
+            pinned: HashMap::new(),
             id: 0,
         }
     }
@@ -24,19 +46,80 @@ impl<T> Cache<T> {
 
     #[inline]
     pub fn count(&self) -> usize {
-        self.inner.len()
+        self.low.len() + self.high.len() + self.pinned.len()
     }
 
     #[inline]
     pub fn cap(&self) -> usize {
-        self.inner.cap()
+        self.low.cap() + self.high.cap()
+    }
+
+    #[inline]
+    pub fn pinned_count(&self) -> usize {
+        self.pinned.len()
     }
 
     pub fn insert(&mut self, key: CacheKey, elem: T) {
-        self.inner.put(key, elem)
+        self.insert_with_priority(key, elem, CachePriority::Low)
+    }
+
+    pub fn insert_with_priority(&mut self, key: CacheKey, elem: T, priority: CachePriority) {
+        match priority {
+            CachePriority::Low => self.low.put(key, elem),
+            CachePriority::High => self.high.put(key, elem),
+        }
+    }
+
+    pub fn pin(&mut self, key: CacheKey, elem: T) {
+        self.pinned.insert(key, elem);
+    }
+
+    pub fn unpin(&mut self, key: &CacheKey) -> Option<T> {
+        self.pinned.remove(key)
     }
 
     pub fn get(&mut self, key: &CacheKey) -> Option<&T> {
-        self.inner.get(key)
+        if let Some(elem) = self.pinned.get(key) {
+            return Some(elem);
+        }
+        if let Some(elem) = self.high.get(key) {
+            return Some(elem);
+        }
+        self.low.get(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(i: u8) -> CacheKey {
+        [i; 16]
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut cache = Cache::new_with_high_pri_ratio(4, 0.5);
+        cache.insert_with_priority(key(0), 0, CachePriority::High);
+        for i in 1..10 {
+            cache.insert(key(i), i);
+        }
+        assert_eq!(Some(&0), cache.get(&key(0)));
+        assert_eq!(None, cache.get(&key(1)));
+        assert_eq!(Some(&9), cache.get(&key(9)));
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut cache = Cache::new(2);
+        cache.pin(key(0), 0);
+        for i in 1..10 {
+            cache.insert_with_priority(key(i), i, CachePriority::High);
+            cache.insert(key(i + 10), i);
+        }
+        assert_eq!(Some(&0), cache.get(&key(0)));
+        assert_eq!(1, cache.pinned_count());
+        assert_eq!(Some(0), cache.unpin(&key(0)));
+        assert_eq!(None, cache.get(&key(0)));
     }
 }
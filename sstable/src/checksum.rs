@@ -0,0 +1,126 @@
+use std::hash::Hasher;
+
+use crc::crc32;
+use crc::crc32::Hasher32;
+use integer_encoding::FixedInt;
+use twox_hash::XxHash64;
+
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::options::int_to_checksum_type;
+use crate::options::ChecksumType;
+use crate::util::mask_crc;
+
+pub const BLOCK_CKSUM_TYPE_LEN: usize = 1;
+
+pub fn checksum_len(t: ChecksumType) -> usize {
+    match t {
+        ChecksumType::CRC32C => 4,
+        ChecksumType::XXHash64 => 8,
+    }
+}
+
+fn compute(t: ChecksumType, data: &[u8]) -> u64 {
+    match t {
+        ChecksumType::CRC32C => {
+            let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+            digest.write(data);
+            u64::from(mask_crc(digest.sum32()))
+        }
+        ChecksumType::XXHash64 => {
+            let mut hasher = XxHash64::with_seed(0);
+            hasher.write(data);
+            hasher.finish()
+        }
+    }
+}
+
+/// Appends the checksum of `buf` followed by the checksum type byte.
+pub fn append_checksum(buf: &mut Vec<u8>, t: ChecksumType) {
+    let sum = compute(t, buf);
+    let len = checksum_len(t);
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    match t {
+        ChecksumType::CRC32C => (sum as u32).encode_fixed(&mut buf[start..]),
+        ChecksumType::XXHash64 => sum.encode_fixed(&mut buf[start..]),
+    }
+    buf.push(t as u8);
+}
+
+/// Checks the trailer written by `append_checksum` and returns the data it covers.
+pub fn verify_checksum(data: &[u8]) -> MyResult<&[u8]> {
+    if data.len() < BLOCK_CKSUM_TYPE_LEN {
+        return err!(StatusCode::InvalidData, "block too short");
+    }
+    let t = match int_to_checksum_type(u32::from(data[data.len() - 1])) {
+        Some(t) => t,
+        None => return err!(StatusCode::InvalidData, "invalid checksum type"),
+    };
+    let len = checksum_len(t);
+    if data.len() < BLOCK_CKSUM_TYPE_LEN + len {
+        return err!(StatusCode::InvalidData, "block too short");
+    }
+    let end = data.len() - BLOCK_CKSUM_TYPE_LEN;
+    let (payload, cksum_buf) = data[..end].split_at(end - len);
+    let want = match t {
+        ChecksumType::CRC32C => u64::from(u32::decode_fixed(cksum_buf)),
+        ChecksumType::XXHash64 => u64::decode_fixed(cksum_buf),
+    };
+    if compute(t, payload) != want {
+        return err!(StatusCode::ChecksumError, "checksum error");
+    }
+    Ok(payload)
+}
+
+/// Checks the trailer of a block written before checksum types, a bare
+/// CRC32C, and returns the data it covers.
+pub fn verify_legacy_checksum(data: &[u8]) -> MyResult<&[u8]> {
+    let len = checksum_len(ChecksumType::CRC32C);
+    if data.len() < len {
+        return err!(StatusCode::InvalidData, "block too short");
+    }
+    let (payload, cksum_buf) = data.split_at(data.len() - len);
+    if compute(ChecksumType::CRC32C, payload) != u64::from(u32::decode_fixed(cksum_buf)) {
+        return err!(StatusCode::ChecksumError, "checksum error");
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksum() -> MyResult<()> {
+        for t in &[ChecksumType::CRC32C, ChecksumType::XXHash64] {
+            let mut buf = b"some block data".to_vec();
+            append_checksum(&mut buf, *t);
+            assert_eq!(15 + checksum_len(*t) + BLOCK_CKSUM_TYPE_LEN, buf.len());
+            assert_eq!(b"some block data", verify_checksum(&buf)?);
+
+            buf[0] ^= 1;
+            assert_eq!(
+                StatusCode::ChecksumError,
+                verify_checksum(&buf).unwrap_err().code
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_checksum() -> MyResult<()> {
+        // the trailer of old blocks is the CRC32C one without its type byte
+        let mut buf = b"some block data".to_vec();
+        append_checksum(&mut buf, ChecksumType::CRC32C);
+        buf.pop();
+        assert_eq!(b"some block data", verify_legacy_checksum(&buf)?);
+
+        buf[0] ^= 1;
+        assert_eq!(
+            StatusCode::ChecksumError,
+            verify_legacy_checksum(&buf).unwrap_err().code
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use aes::Aes256;
+use ctr::cipher::generic_array::GenericArray;
+use ctr::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use ctr::Ctr128BE;
+use twox_hash::XxHash64;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 16;
+
+pub type EncryptionKey = [u8; KEY_LEN];
+
+/// AES-256-CTR over a table file. Each block is encrypted with the keystream
+/// at its own offset in the file, so no two blocks share keystream, and each
+/// file gets a random nonce of its own.
+#[derive(Clone)]
+pub struct FileCipher {
+    key: Arc<EncryptionKey>,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl FileCipher {
+    pub fn new(key: Arc<EncryptionKey>, nonce: [u8; NONCE_LEN]) -> Self {
+        FileCipher { key, nonce }
+    }
+
+    /// A cipher for a new file.
+    pub fn random(key: Arc<EncryptionKey>) -> Self {
+        FileCipher::new(key, rand::random())
+    }
+
+    pub fn nonce(&self) -> &[u8; NONCE_LEN] {
+        &self.nonce
+    }
+
+    pub fn key_check(&self) -> u64 {
+        key_check(&self.key)
+    }
+
+    /// Encrypts or decrypts `data`, which sits at `offset` in the file.
+    pub fn apply(&self, offset: usize, data: &mut [u8]) {
+        let mut cipher = Ctr128BE::<Aes256>::new(
+            GenericArray::from_slice(&self.key[..]),
+            GenericArray::from_slice(&self.nonce),
+        );
+        cipher.seek(offset as u64);
+        cipher.apply_keystream(data);
+    }
+}
+
+/// Recorded with each encrypted file, to tell a wrong key from corruption.
+pub fn key_check(key: &EncryptionKey) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(key);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let key = Arc::new([7; KEY_LEN]);
+        let cipher = FileCipher::random(key.clone());
+        let plain = b"hello world, hello world".to_vec();
+        let mut data = plain.clone();
+        cipher.apply(100, &mut data);
+        assert_ne!(plain, data);
+
+        // the keystream depends on the offset and the nonce
+        let mut other = plain.clone();
+        cipher.apply(101, &mut other);
+        assert_ne!(data, other);
+        let mut other = plain.clone();
+        FileCipher::random(key.clone()).apply(100, &mut other);
+        assert_ne!(data, other);
+
+        cipher.apply(100, &mut data);
+        assert_eq!(plain, data);
+        assert_ne!(key_check(&key), key_check(&[8; KEY_LEN]));
+    }
+}
@@ -16,6 +16,7 @@ pub enum StatusCode {
     BuildError,
     BincodeError,
     CuckooError,
+    EncryptionError,
 }
 
 #[derive(Debug, PartialEq)]
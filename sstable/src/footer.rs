@@ -1,17 +1,32 @@
 use std::io::Seek;
 use std::io::Write;
 
+use integer_encoding::FixedInt;
+
 use crate::block_handle::BlockHandle;
+use crate::cipher::NONCE_LEN;
 use crate::error::MyResult;
+use crate::error::StatusCode;
 use crate::types::RandomAccess;
 
 pub const FOOTER_LENGTH: usize = 40;
 pub const FULL_FOOTER_LENGTH: usize = FOOTER_LENGTH + 8;
+/// The nonce and key check of an encrypted table, written right before its
+/// footer.
+pub const ENCRYPTION_LENGTH: usize = NONCE_LEN + 8;
 const MAGIC_FOOTER_ENCODED: [u8; 8] = [0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb];
+const MAGIC_ENCRYPTED_FOOTER_ENCODED: [u8; 8] = [0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdc];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Encryption {
+    pub nonce: [u8; NONCE_LEN],
+    pub key_check: u64,
+}
 
 pub struct Footer {
     meta_index_: BlockHandle,
     index_: BlockHandle,
+    encryption_: Option<Encryption>,
 }
 
 impl Footer {
@@ -19,9 +34,20 @@ impl Footer {
         Footer {
             meta_index_: meta_index,
             index_: index,
+            encryption_: None,
         }
     }
 
+    pub fn with_encryption(mut self, encryption: Option<Encryption>) -> Self {
+        self.encryption_ = encryption;
+        self
+    }
+
+    /// Set for tables written with an encryption key.
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption_.as_ref()
+    }
+
     pub fn meta_index(&self) -> &BlockHandle {
         &self.meta_index_
     }
@@ -33,25 +59,50 @@ impl Footer {
     pub fn read(r: &dyn RandomAccess, offset: usize) -> MyResult<Self> {
         let mut buf = [0; FULL_FOOTER_LENGTH];
         r.read_at(offset, &mut buf)?;
-        Ok(Footer::decode(&buf))
+        let mut footer = Footer::decode(&buf);
+        if buf[FOOTER_LENGTH..] == MAGIC_ENCRYPTED_FOOTER_ENCODED {
+            if offset < ENCRYPTION_LENGTH {
+                return err!(StatusCode::InvalidData, "table too short");
+            }
+            let mut enc = [0; ENCRYPTION_LENGTH];
+            r.read_at(offset - ENCRYPTION_LENGTH, &mut enc)?;
+            let mut nonce = [0; NONCE_LEN];
+            nonce.copy_from_slice(&enc[..NONCE_LEN]);
+            footer.encryption_ = Some(Encryption {
+                nonce,
+                key_check: u64::decode_fixed(&enc[NONCE_LEN..]),
+            });
+        }
+        Ok(footer)
     }
 
     pub fn flush<T: Seek + Write>(&self, w: &mut T, offset: usize) -> MyResult<BlockHandle> {
+        let mut size = 0;
+        if let Some(enc) = &self.encryption_ {
+            let mut buf = [0; ENCRYPTION_LENGTH];
+            buf[..NONCE_LEN].copy_from_slice(&enc.nonce);
+            enc.key_check.encode_fixed(&mut buf[NONCE_LEN..]);
+            w.write_all(&buf)?;
+            size += buf.len();
+        }
         let mut buf = [0; FULL_FOOTER_LENGTH];
         self.encode(&mut buf);
         w.write_all(&buf)?;
-        Ok(bh!(offset, buf.len()))
+        size += buf.len();
+        Ok(bh!(offset, size))
     }
 
     pub fn decode(from: &[u8]) -> Footer {
         assert!(from.len() >= FULL_FOOTER_LENGTH);
-        assert_eq!(&from[FOOTER_LENGTH..], &MAGIC_FOOTER_ENCODED);
+        let magic = &from[FOOTER_LENGTH..FULL_FOOTER_LENGTH];
+        assert!(magic == MAGIC_FOOTER_ENCODED || magic == MAGIC_ENCRYPTED_FOOTER_ENCODED);
         let (meta, metalen) = BlockHandle::decode(&from[0..]);
         let (idx, _) = BlockHandle::decode(&from[metalen..]);
 
         Footer {
             meta_index_: meta,
             index_: idx,
+            encryption_: None,
         }
     }
 
@@ -66,8 +117,13 @@ impl Footer {
             to[i] = 0;
         }
 
+        let magic = if self.encryption_.is_some() {
+            &MAGIC_ENCRYPTED_FOOTER_ENCODED
+        } else {
+            &MAGIC_FOOTER_ENCODED
+        };
         to[FOOTER_LENGTH..FULL_FOOTER_LENGTH]
-            .clone_from_slice(&MAGIC_FOOTER_ENCODED[0..(FULL_FOOTER_LENGTH - FOOTER_LENGTH)]);
+            .clone_from_slice(&magic[0..(FULL_FOOTER_LENGTH - FOOTER_LENGTH)]);
     }
 }
 
@@ -93,6 +149,26 @@ mod test {
         assert_eq!(10, footer.meta_index_.size);
         assert_eq!(11, footer.index_.offset);
         assert_eq!(12, footer.index_.size);
+        assert!(footer.encryption().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_footer() -> MyResult<()> {
+        let enc = Encryption {
+            nonce: [3; NONCE_LEN],
+            key_check: 42,
+        };
+        let footer = Footer::new(bh!(0, 10), bh!(11, 12)).with_encryption(Some(enc.clone()));
+        let path = Path::new("/tmp/test_encrypted_footer");
+        let mut f = File::create(path)?;
+        let bh = footer.flush(&mut f, 0)?;
+        f.flush()?;
+        assert_eq!(ENCRYPTION_LENGTH + FULL_FOOTER_LENGTH, bh.size);
+        let f = File::open(path)?;
+        let footer = Footer::read(&f, ENCRYPTION_LENGTH)?;
+        assert_eq!(11, footer.index().offset);
+        assert_eq!(Some(&enc), footer.encryption());
         Ok(())
     }
 }
@@ -9,20 +9,30 @@ mod util;
 mod block;
 mod block_builder;
 mod block_iter;
+mod bloom;
 mod cache;
+mod checksum;
+mod cipher;
 mod footer;
 mod meta_block;
 mod options;
+mod properties;
 mod reader;
 mod table_builder;
 mod table_iter;
 mod table_reader;
 mod types;
+mod value_handle;
 mod writer;
 
+pub use crate::cipher::{EncryptionKey, KEY_LEN};
 pub use crate::error::{MyResult, Status, StatusCode};
-pub use crate::options::Options;
+pub use crate::options::{ChecksumType, Options};
+pub use crate::properties::{
+    FixedPrefixExtractor, PrefixExtractor, PrefixStat, SharedPrefixExtractor, TableProperties,
+};
 pub use crate::table_builder::TableBuilder;
 pub use crate::table_iter::TableIter;
 pub use crate::table_reader::TableReader;
-pub use crate::types::{RandomAccess, SsIterator};
+pub use crate::types::{RandomAccess, SsIterator, WritableFile};
+pub use crate::value_handle::ValueHandle;
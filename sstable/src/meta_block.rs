@@ -9,23 +9,57 @@ use snap::Decoder;
 use snap::Encoder;
 
 use crate::block_handle::BlockHandle;
+use crate::bloom::BloomFilter;
+use crate::cipher::FileCipher;
+use crate::error::StatusCode;
+use crate::properties::TableProperties;
 use crate::reader;
 use crate::types::RandomAccess;
 use crate::MyResult;
 
+/// Starts a meta block followed by its version. Meta blocks written before
+/// there were versions start with the length of `max_key` instead, which is
+/// never this large.
+const VERSIONED: [u8; 8] = [0xff; 8];
+const VERSION: u8 = 1;
+
+/// The meta block of tables written before the bloom filter and the
+/// properties, read as a `MetaBlock` without them.
+#[derive(Serialize, Deserialize)]
+struct MetaBlockV0 {
+    max_key: Vec<u8>,
+    min_key: Vec<u8>,
+    filter: ExportedCuckooFilter,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MetaBlock {
     pub max_key: Vec<u8>,
     pub min_key: Vec<u8>,
     pub filter: ExportedCuckooFilter,
+    pub bloom: Option<BloomFilter>,
+    pub properties: TableProperties,
+    /// Read from a table written before meta block versions, whose blocks
+    /// end in a bare CRC32C.
+    #[serde(skip)]
+    pub legacy: bool,
 }
 
 impl MetaBlock {
-    pub fn new(max_key: Vec<u8>, min_key: Vec<u8>, filter: ExportedCuckooFilter) -> Self {
+    pub fn new(
+        max_key: Vec<u8>,
+        min_key: Vec<u8>,
+        filter: ExportedCuckooFilter,
+        bloom: Option<BloomFilter>,
+        properties: TableProperties,
+    ) -> Self {
         MetaBlock {
             max_key,
             min_key,
             filter,
+            bloom,
+            properties,
+            legacy: false,
         }
     }
 
@@ -34,29 +68,106 @@ impl MetaBlock {
         self.min_key = vec![];
         self.filter.values = vec![];
         self.filter.length = 0;
+        self.bloom = None;
+        self.properties = TableProperties::default();
     }
 
     pub fn new_with_buffer<T: Into<Vec<u8>>>(buffer: T) -> MyResult<Self> {
-        Ok(deserialize(&buffer.into())?)
+        let buffer = buffer.into();
+        if !buffer.starts_with(&VERSIONED) {
+            let v0: MetaBlockV0 = deserialize(&buffer)?;
+            let mut block = MetaBlock::new(
+                v0.max_key,
+                v0.min_key,
+                v0.filter,
+                None,
+                TableProperties::default(),
+            );
+            block.legacy = true;
+            return Ok(block);
+        }
+        match buffer.get(VERSIONED.len()) {
+            Some(&VERSION) => Ok(deserialize(&buffer[VERSIONED.len() + 1..])?),
+            _ => err!(StatusCode::InvalidData, "unknown meta block version"),
+        }
     }
 
     pub fn new_from_location(
         r: &dyn RandomAccess,
         location: &BlockHandle,
+        cipher: Option<&FileCipher>,
     ) -> MyResult<(MetaBlock, usize)> {
-        let (data, offset) = reader::read_bytes(r, location)?;
+        let (mut data, offset) = reader::read_bytes(r, location)?;
+        if let Some(cipher) = cipher {
+            cipher.apply(location.offset, &mut data);
+        }
         let data = Decoder::new().decompress_vec(&data)?;
         let size = data.len();
         Ok((MetaBlock::new_with_buffer(data)?, offset + size))
     }
 
-    pub fn flush<T: Write + Seek>(&mut self, w: &mut T, offset: usize) -> MyResult<BlockHandle> {
-        let buf = serialize(self)?;
+    pub fn flush<T: Write + Seek>(
+        &mut self,
+        w: &mut T,
+        offset: usize,
+        cipher: Option<&FileCipher>,
+    ) -> MyResult<BlockHandle> {
+        let mut buf = VERSIONED.to_vec();
+        buf.push(VERSION);
+        buf.extend_from_slice(&serialize(self)?);
         let mut encoder = Encoder::new();
-        let buf = encoder.compress_vec(&buf)?;
+        let mut buf = encoder.compress_vec(&buf)?;
+        if let Some(cipher) = cipher {
+            cipher.apply(offset, &mut buf);
+        }
         w.seek(SeekFrom::Start(offset as u64))?;
         let size = w.write(&buf)?;
         self.reset();
         Ok(bh!(offset, size))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn empty_filter() -> ExportedCuckooFilter {
+        ExportedCuckooFilter {
+            values: vec![],
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn test_versions() -> MyResult<()> {
+        let v0 = MetaBlockV0 {
+            max_key: b"z".to_vec(),
+            min_key: b"a".to_vec(),
+            filter: empty_filter(),
+        };
+        let block = MetaBlock::new_with_buffer(serialize(&v0)?)?;
+        assert_eq!(b"z".to_vec(), block.max_key);
+        assert_eq!(b"a".to_vec(), block.min_key);
+        assert!(block.bloom.is_none());
+        assert!(block.legacy);
+
+        let block = MetaBlock::new(
+            b"y".to_vec(),
+            b"b".to_vec(),
+            empty_filter(),
+            Some(BloomFilter::new(&[], 10)),
+            TableProperties::default(),
+        );
+        let mut buf = VERSIONED.to_vec();
+        buf.push(VERSION);
+        buf.extend_from_slice(&serialize(&block)?);
+        let block = MetaBlock::new_with_buffer(buf.clone())?;
+        assert_eq!(b"y".to_vec(), block.max_key);
+        assert!(block.bloom.is_some());
+        assert!(!block.legacy);
+
+        buf[VERSIONED.len()] = VERSION + 1;
+        assert!(MetaBlock::new_with_buffer(buf).is_err());
+        Ok(())
+    }
+}
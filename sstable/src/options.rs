@@ -3,6 +3,9 @@ use std::sync::RwLock;
 
 use crate::block::Block;
 use crate::cache::Cache;
+use crate::cipher::EncryptionKey;
+use crate::cipher::FileCipher;
+use crate::properties::SharedPrefixExtractor;
 
 const KB: usize = 1 << 10;
 const MB: usize = KB * KB;
@@ -10,6 +13,7 @@ const MB: usize = KB * KB;
 const BLOCK_MAX_SIZE: usize = 4 * KB;
 const BLOCK_CACHE_CAPACITY: usize = 8 * MB;
 const WRITE_BUFFER_SIZE: usize = 4 * MB;
+const HIGH_PRI_POOL_RATIO: f64 = 0.;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum CompressType {
@@ -25,12 +29,57 @@ pub fn int_to_compress_type(i: u32) -> Option<CompressType> {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChecksumType {
+    CRC32C = 0,
+    XXHash64 = 1,
+}
+
+pub fn int_to_checksum_type(i: u32) -> Option<ChecksumType> {
+    match i {
+        0 => Some(ChecksumType::CRC32C),
+        1 => Some(ChecksumType::XXHash64),
+        _ => None,
+    }
+}
+
 #[derive(Clone)]
 pub struct Options {
     pub block_size: usize,
     pub block_restart_interval: usize,
     pub block_cache: Arc<RwLock<Cache<Block>>>,
     pub compress_type: CompressType,
+    /// Checksum written with new blocks, followed by a byte naming it.
+    /// Blocks of tables written before that byte end in a bare CRC32C and
+    /// are read as such, their meta block telling them apart.
+    pub checksum_type: ChecksumType,
+    pub bloom_bits_per_key: usize,
+    pub prefix_extractor: Option<SharedPrefixExtractor>,
+    /// Fraction of the block cache reserved for index blocks.
+    pub high_pri_pool_ratio: f64,
+    /// Keep index blocks in the shared block cache instead of in each reader.
+    pub cache_index_blocks: bool,
+    /// Pin cached index blocks so they are never evicted; implies `cache_index_blocks`.
+    pub pin_index_blocks: bool,
+    /// Encrypts the blocks of new tables with this AES-256 key. Needed to
+    /// read encrypted tables; plain ones stay readable either way.
+    pub encryption_key: Option<Arc<EncryptionKey>>,
+    /// The cipher of the file being read or written, set up from
+    /// `encryption_key` and the file's nonce.
+    pub(crate) cipher: Option<FileCipher>,
+    /// Set when the file being read is from before checksum types.
+    pub(crate) legacy_checksums: bool,
+}
+
+impl Options {
+    /// Replaces the block cache with a new one of `capacity` bytes, split
+    /// according to `high_pri_pool_ratio`.
+    pub fn reset_block_cache(&mut self, capacity: usize) {
+        self.block_cache = Arc::new(RwLock::new(Cache::new_with_high_pri_ratio(
+            ::std::cmp::max(capacity / self.block_size, 1),
+            self.high_pri_pool_ratio,
+        )));
+    }
 }
 
 impl Default for Options {
@@ -38,10 +87,20 @@ impl Default for Options {
         Options {
             block_size: BLOCK_MAX_SIZE,
             block_restart_interval: 16,
-            block_cache: Arc::new(RwLock::new(Cache::new(
+            block_cache: Arc::new(RwLock::new(Cache::new_with_high_pri_ratio(
                 BLOCK_CACHE_CAPACITY / BLOCK_MAX_SIZE,
+                HIGH_PRI_POOL_RATIO,
             ))),
             compress_type: CompressType::Snappy,
+            checksum_type: ChecksumType::CRC32C,
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+            high_pri_pool_ratio: HIGH_PRI_POOL_RATIO,
+            cache_index_blocks: false,
+            pin_index_blocks: false,
+            encryption_key: None,
+            cipher: None,
+            legacy_checksums: false,
         }
     }
 }
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+/// Decides which prefix of a key its statistics are accounted under.
+pub trait PrefixExtractor: Send + Sync {
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]>;
+}
+
+/// Uses the first `n` bytes of a key as its prefix; shorter keys are accounted
+/// under the whole key.
+pub struct FixedPrefixExtractor(pub usize);
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        Some(&key[..::std::cmp::min(self.0, key.len())])
+    }
+}
+
+pub type SharedPrefixExtractor = Arc<dyn PrefixExtractor>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PrefixStat {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+impl PrefixStat {
+    pub fn merge(&mut self, other: &PrefixStat) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Summary of a table collected while it is built and stored in its meta block.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    pub raw_key_size: u64,
+    pub raw_value_size: u64,
+    pub prefix_stats: BTreeMap<Vec<u8>, PrefixStat>,
+    /// Seconds since the epoch when the table was written.
+    pub creation_time: u64,
+}
+
+impl TableProperties {
+    pub fn add(&mut self, k: &[u8], v: &[u8], extractor: Option<&SharedPrefixExtractor>) {
+        self.num_entries += 1;
+        self.raw_key_size += k.len() as u64;
+        self.raw_value_size += v.len() as u64;
+
+        if let Some(prefix) = extractor.and_then(|e| e.prefix(k)) {
+            let bytes = (k.len() + v.len()) as u64;
+            if let Some(stat) = self.prefix_stats.get_mut(prefix) {
+                stat.keys += 1;
+                stat.bytes += bytes;
+            } else {
+                self.prefix_stats
+                    .insert(prefix.to_vec(), PrefixStat { keys: 1, bytes });
+            }
+        }
+    }
+
+    pub fn merge_prefix_stats_into(&self, dst: &mut BTreeMap<Vec<u8>, PrefixStat>) {
+        for (prefix, stat) in &self.prefix_stats {
+            dst.entry(prefix.clone()).or_default().merge(stat);
+        }
+    }
+}
+
+impl fmt::Display for TableProperties {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entries: {}, raw key size: {}, raw value size: {}, prefixes: {}",
+            self.num_entries,
+            self.raw_key_size,
+            self.raw_value_size,
+            self.prefix_stats.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefix_stats() {
+        let extractor: SharedPrefixExtractor = Arc::new(FixedPrefixExtractor(2));
+        let mut props = TableProperties::default();
+        props.add(b"aa1", b"v", Some(&extractor));
+        props.add(b"aa2", b"vv", Some(&extractor));
+        props.add(b"b", b"v", Some(&extractor));
+        assert_eq!(3, props.num_entries);
+        assert_eq!(7, props.raw_key_size);
+        assert_eq!(
+            Some(&PrefixStat { keys: 2, bytes: 9 }),
+            props.prefix_stats.get(&b"aa".to_vec())
+        );
+        assert_eq!(
+            Some(&PrefixStat { keys: 1, bytes: 2 }),
+            props.prefix_stats.get(&b"b".to_vec())
+        );
+
+        let mut merged = BTreeMap::new();
+        props.merge_prefix_stats_into(&mut merged);
+        props.merge_prefix_stats_into(&mut merged);
+        assert_eq!(
+            Some(&PrefixStat { keys: 4, bytes: 18 }),
+            merged.get(&b"aa".to_vec())
+        );
+    }
+}
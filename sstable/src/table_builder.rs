@@ -4,20 +4,28 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use cuckoofilter::{CuckooFilter, ExportedCuckooFilter};
 
 use crate::block_builder::BlockBuilder;
+use crate::bloom::bloom_hash;
+use crate::bloom::BloomFilter;
+use crate::cipher::FileCipher;
 use crate::error::MyResult;
+use crate::footer::Encryption;
 use crate::footer::Footer;
 use crate::footer::FULL_FOOTER_LENGTH;
 use crate::meta_block::MetaBlock;
 use crate::options::Options;
+use crate::properties::TableProperties;
+use crate::types::WritableFile;
 use crate::util::find_short_succ;
 use crate::util::find_shortest_sep;
 
 pub struct TableBuilder {
-    file: File,
+    file: Box<dyn WritableFile>,
     path_: PathBuf,
     opt: Options,
     offset: usize,
@@ -27,6 +35,8 @@ pub struct TableBuilder {
     min_key: Option<Vec<u8>>,
     max_key: Option<Vec<u8>>,
     filter: CuckooFilter<DefaultHasher>,
+    bloom_hashes: Vec<u32>,
+    properties: TableProperties,
 }
 
 impl TableBuilder {
@@ -36,7 +46,17 @@ impl TableBuilder {
             .truncate(true)
             .write(true)
             .open(path.as_ref())?;
-        Ok(TableBuilder {
+        Ok(Self::from_file(Box::new(file), path, opt))
+    }
+
+    /// Builds the table into `file`, an empty file created at `path`.
+    pub fn from_file<T: AsRef<Path>>(
+        file: Box<dyn WritableFile>,
+        path: T,
+        mut opt: Options,
+    ) -> TableBuilder {
+        opt.cipher = opt.encryption_key.clone().map(FileCipher::random);
+        TableBuilder {
             file,
             path_: path.as_ref().to_path_buf(),
             opt: opt.clone(),
@@ -47,7 +67,9 @@ impl TableBuilder {
             min_key: None,
             max_key: None,
             filter: CuckooFilter::new(),
-        })
+            bloom_hashes: vec![],
+            properties: TableProperties::default(),
+        }
     }
 
     pub fn path(&self) -> &PathBuf {
@@ -75,6 +97,11 @@ impl TableBuilder {
         }
         self.data_block.add(k, v);
         self.filter.add(k)?;
+        if self.opt.bloom_bits_per_key > 0 {
+            self.bloom_hashes.push(bloom_hash(k));
+        }
+        self.properties
+            .add(k, v, self.opt.prefix_extractor.as_ref());
         if self.min_key.is_none() {
             self.min_key = Some(k.to_vec());
         }
@@ -100,18 +127,37 @@ impl TableBuilder {
 
     pub fn flush(&mut self) -> MyResult<()> {
         self.write_data_block(&find_short_succ(&self.data_block.last_key))?;
+        let bloom = if self.opt.bloom_bits_per_key > 0 {
+            Some(BloomFilter::new(
+                &self.bloom_hashes,
+                self.opt.bloom_bits_per_key,
+            ))
+        } else {
+            None
+        };
+        self.properties.creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
         let mut meta_block = MetaBlock::new(
             self.max_key.clone().expect("max key"),
             self.min_key.clone().expect("min key"),
             ExportedCuckooFilter::from(&self.filter),
+            bloom,
+            self.properties.clone(),
         );
-        let meta_bh = meta_block.flush(&mut self.file, self.offset)?;
+        let meta_bh = meta_block.flush(&mut self.file, self.offset, self.opt.cipher.as_ref())?;
         self.offset = meta_bh.offset + meta_bh.size;
         let index_bh = self.index_block.flush(&mut self.file, self.offset)?;
         self.offset = index_bh.offset + index_bh.size;
-        let footer = Footer::new(meta_bh.clone(), index_bh.clone());
+        let encryption = self.opt.cipher.as_ref().map(|cipher| Encryption {
+            nonce: *cipher.nonce(),
+            key_check: cipher.key_check(),
+        });
+        let footer = Footer::new(meta_bh.clone(), index_bh.clone()).with_encryption(encryption);
         footer.flush(&mut self.file, self.offset)?;
         self.file.flush()?;
+        // the table must be on disk before a manifest can list it
+        self.file.sync()?;
         Ok(())
     }
 }
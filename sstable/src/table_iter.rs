@@ -3,23 +3,53 @@ use crate::block_handle::BlockHandle;
 use crate::block_iter::BlockIter;
 use crate::block_iter::BlockIterState;
 use crate::types::SsIterator;
+use crate::value_handle::ValueHandle;
+use crate::MyResult;
 use crate::TableReader;
 
 pub struct TableIter<'a> {
     table: &'a TableReader,
-    pub(crate) index_iter: BlockIter<'a>,
+    index_block: Block,
+    index_iter_state: BlockIterState,
     data_iter_state: BlockIterState,
     data_block: Option<Block>,
+    data_block_handle: Option<BlockHandle>,
 }
 
 impl<'a> TableIter<'a> {
-    pub fn new(table: &'a TableReader) -> Self {
-        Self {
+    pub fn new(table: &'a TableReader) -> MyResult<Self> {
+        let index_block = table.index_block()?;
+        let restarts_offset = index_block.restarts_offset();
+        Ok(Self {
             table,
-            index_iter: table.index_block.iter(),
+            index_block,
+            index_iter_state: BlockIterState::new(restarts_offset),
             data_iter_state: BlockIterState::new(0),
             data_block: None,
+            data_block_handle: None,
+        })
+    }
+
+    /// Returns a handle to the current value without copying it out of the block.
+    pub fn current_value_handle(&self) -> Option<ValueHandle> {
+        if !self.valid() {
+            return None;
         }
+        let block = self.data_block_handle.clone()?;
+        let state = &self.data_iter_state;
+        Some(ValueHandle {
+            block,
+            offset: state.val_offset,
+            size: state.next_offset - state.val_offset,
+        })
+    }
+
+    /// Like `next`, but yields a `ValueHandle` instead of the value.
+    pub fn next_handle(&mut self) -> Option<(Vec<u8>, ValueHandle)> {
+        if !self.advance() {
+            return None;
+        }
+        Some((self.current_k()?, self.current_value_handle()?))
     }
 
     fn data_iter(&self) -> Option<BlockIter> {
@@ -32,11 +62,26 @@ impl<'a> TableIter<'a> {
         }
     }
 
+    fn with_index_iter<R, F>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut BlockIter) -> R,
+    {
+        let mut iter =
+            BlockIter::new_with_state(&self.index_block.block, self.index_iter_state.clone());
+        let r = f(&mut iter);
+        self.index_iter_state = iter.state;
+        r
+    }
+
+    fn index_kv(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.with_index_iter(|iter| iter.current_kv())
+    }
+
     #[cfg(test)]
     fn print_info(&self) {
         println!("------------------------------------");
         println!("data_block: {}", self.data_block.is_some());
-        println!("index_iter: {:?}", self.index_iter.state);
+        println!("index_iter: {:?}", self.index_iter_state);
         println!("data_iter : {:?}", self.data_iter_state);
         println!("------------------------------------");
     }
@@ -57,16 +102,17 @@ impl<'a> SsIterator for TableIter<'a> {
             self.data_iter_state = data_iter.state.clone();
         }
 
-        if !self.index_iter.advance() {
+        if !self.with_index_iter(|iter| iter.advance()) {
             return false;
         }
 
-        if let Some((_k, v)) = self.index_iter.current_kv() {
+        if let Some((_k, v)) = self.index_kv() {
             let (bh, _) = BlockHandle::decode(&v);
             match self.table.read_block(&bh) {
                 Ok(Some(block)) => {
                     self.data_iter_state = BlockIterState::new(block.restarts_offset());
                     self.data_block = Some(block);
+                    self.data_block_handle = Some(bh);
                     return self.advance();
                 }
                 Ok(None) => {
@@ -89,18 +135,19 @@ impl<'a> SsIterator for TableIter<'a> {
             self.data_iter_state = data_iter.state.clone();
         }
 
-        if !self.index_iter.prev() {
+        if !self.with_index_iter(|iter| iter.prev()) {
             self.reset();
             return false;
         }
 
-        if let Some((_k, v)) = self.index_iter.current_kv() {
+        if let Some((_k, v)) = self.index_kv() {
             let (bh, _) = BlockHandle::decode(&v);
             if let Ok(Some(block)) = self.table.read_block(&bh) {
                 let mut iter = block.iter();
                 iter.seek_to_last();
                 self.data_iter_state = iter.state;
                 self.data_block = Some(block);
+                self.data_block_handle = Some(bh);
                 return true;
             }
         }
@@ -118,29 +165,33 @@ impl<'a> SsIterator for TableIter<'a> {
     }
 
     fn reset(&mut self) {
-        self.index_iter.reset();
+        self.with_index_iter(|iter| iter.reset());
         self.data_block = None;
+        self.data_block_handle = None;
         self.data_iter_state.reset();
     }
 
     fn seek(&mut self, key: &[u8]) {
         self.reset();
-        self.index_iter.seek(key);
-        if let Some((_k, v)) = self.index_iter.current_kv() {
+        self.with_index_iter(|iter| iter.seek(key));
+        if let Some((_k, v)) = self.index_kv() {
             let (bh, _) = BlockHandle::decode(&v);
             if let Ok(Some(block)) = self.table.read_block(&bh) {
                 let mut iter = block.iter();
                 iter.seek(key);
                 self.data_iter_state = iter.state;
                 self.data_block = Some(block);
+                self.data_block_handle = Some(bh);
             }
         }
     }
 
     fn seek_to_last(&mut self) {
         self.reset();
-        self.index_iter.seek_to_last();
-        self.index_iter.prev();
+        self.with_index_iter(|iter| {
+            iter.seek_to_last();
+            iter.prev();
+        });
         self.advance();
 
         assert!(self.valid());
@@ -189,7 +240,7 @@ mod test {
 
         let t = TableReader::new(path, opt.clone())?;
 
-        let mut iter = TableIter::new(&t);
+        let mut iter = TableIter::new(&t)?;
         assert_eq!(None, iter.current_kv());
         for i in 0..N {
             let key = &data[i].0;
@@ -220,7 +271,7 @@ mod test {
         t.flush()?;
         let t = TableReader::new(path, opt.clone())?;
 
-        let mut iter = TableIter::new(&t);
+        let mut iter = TableIter::new(&t)?;
         println!("advance before");
         iter.print_info();
         assert!(iter.advance());
@@ -302,7 +353,7 @@ mod test {
         assert!(!iter.prev());
         assert_eq!(None, iter.current_kv());
 
-        let mut iter = TableIter::new(&t);
+        let mut iter = TableIter::new(&t)?;
         iter.seek_to_last();
         assert_eq!(iter.current_k().unwrap(), data[N - 1].0.as_bytes());
         for i in (0..N - 1).into_iter().rev() {
@@ -321,4 +372,31 @@ mod test {
         assert_eq!(None, iter.current_kv());
         Ok(())
     }
+
+    #[test]
+    fn test_value_handle() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_value_handle");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        let data = get_data();
+        for (k, v) in &data {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+        let t = TableReader::new(path, opt.clone())?;
+
+        let mut iter = TableIter::new(&t)?;
+        let mut handles = vec![];
+        while let Some((k, h)) = iter.next_handle() {
+            handles.push((k, h));
+        }
+        assert_eq!(N, handles.len());
+        for (i, (k, h)) in handles.iter().enumerate() {
+            assert_eq!(data[i].0.as_bytes(), &k[..]);
+            assert_eq!(data[i].1.len(), h.size());
+            assert_eq!(data[i].1.as_bytes(), &h.resolve(&t)?[..]);
+        }
+        Ok(())
+    }
 }
@@ -11,12 +11,18 @@ use integer_encoding::FixedIntWriter;
 
 use crate::block::Block;
 use crate::block_handle::BlockHandle;
+use crate::bloom::BloomFilter;
 use crate::cache;
+use crate::cache::CachePriority;
+use crate::cipher::key_check;
+use crate::cipher::FileCipher;
 use crate::error::MyResult;
+use crate::error::StatusCode;
 use crate::footer::Footer;
 use crate::footer::FULL_FOOTER_LENGTH;
 use crate::meta_block::MetaBlock;
 use crate::options::Options;
+use crate::properties::TableProperties;
 use crate::table_iter::TableIter;
 use crate::types::RandomAccess;
 use crate::types::SsIterator;
@@ -29,7 +35,7 @@ pub struct TableReader {
 
     cache_id: cache::CacheID,
     footer: Footer,
-    pub(crate) index_block: Block,
+    index_block: Option<Block>,
     min_key_: Vec<u8>,
     max_key_: Vec<u8>,
     size_: usize,
@@ -38,22 +44,51 @@ pub struct TableReader {
     seek_miss_count_: AtomicUsize,
 
     filter_: CuckooFilter<DefaultHasher>,
+    bloom_: Option<BloomFilter>,
+    properties_: TableProperties,
 }
 
 impl TableReader {
     pub fn new<T: AsRef<Path>>(path: T, opt: Options) -> MyResult<TableReader> {
         let f = File::open(path.as_ref())?;
         let size = f.metadata()?.len() as usize;
+        Self::from_file(Box::new(f), size, path, opt)
+    }
+
+    /// Opens the table of `size` bytes in `file`, which was read from `path`.
+    pub fn from_file<T: AsRef<Path>>(
+        file: Box<dyn RandomAccess>,
+        size: usize,
+        path: T,
+        mut opt: Options,
+    ) -> MyResult<TableReader> {
         if size <= FULL_FOOTER_LENGTH {
             println!("path: {}", path.as_ref().display());
             println!("size: {}", size);
             assert!(size > FULL_FOOTER_LENGTH);
         }
-        let footer = Footer::read(&f, size - FULL_FOOTER_LENGTH)?;
-        let meta_block = MetaBlock::new_from_location(&f, &footer.meta_index())?.0;
-        let index_block = Block::new_from_location(&f, &footer.index(), opt.clone())?.0;
-        let metadata = f.metadata()?;
-        let size_ = metadata.len() as usize;
+        let f = file.as_ref();
+        let footer = Footer::read(f, size - FULL_FOOTER_LENGTH)?;
+        opt.cipher = match (footer.encryption(), &opt.encryption_key) {
+            (None, _) => None,
+            (Some(_), None) => {
+                return err!(
+                    StatusCode::EncryptionError,
+                    "table is encrypted, no key given"
+                );
+            }
+            (Some(enc), Some(key)) => {
+                if enc.key_check != key_check(key) {
+                    return err!(StatusCode::EncryptionError, "wrong encryption key");
+                }
+                Some(FileCipher::new(key.clone(), enc.nonce))
+            }
+        };
+        let meta_block =
+            MetaBlock::new_from_location(f, &footer.meta_index(), opt.cipher.as_ref())?.0;
+        opt.legacy_checksums = meta_block.legacy;
+        let index_block = Block::new_from_location(f, &footer.index(), opt.clone())?.0;
+        let size_ = size;
         let file_name_ = path
             .as_ref()
             .file_name()
@@ -61,12 +96,12 @@ impl TableReader {
             .to_str()
             .expect("file name to str")
             .to_owned();
-        Ok(TableReader {
-            file: Rc::new(Box::new(f)),
+        let mut reader = TableReader {
+            file: Rc::new(file),
             file_size: size,
             cache_id: write_unlock(&opt.block_cache).new_cache_id(),
             footer,
-            index_block,
+            index_block: Some(index_block),
             opt: opt.clone(),
             min_key_: meta_block.min_key.clone(),
             max_key_: meta_block.max_key.clone(),
@@ -74,7 +109,45 @@ impl TableReader {
             file_name_,
             seek_miss_count_: AtomicUsize::new(0),
             filter_: meta_block.filter.into(),
-        })
+            bloom_: meta_block.bloom,
+            properties_: meta_block.properties,
+        };
+        if opt.cache_index_blocks || opt.pin_index_blocks {
+            if let Some(block) = reader.index_block.take() {
+                reader.insert_index_block(block);
+            }
+        }
+        Ok(reader)
+    }
+
+    fn insert_index_block(&self, block: Block) {
+        let cache_key = self.gen_cache_key(self.footer.index());
+        let mut bc = write_unlock(&self.opt.block_cache);
+        if self.opt.pin_index_blocks {
+            bc.pin(cache_key, block);
+        } else {
+            bc.insert_with_priority(cache_key, block, CachePriority::High);
+        }
+    }
+
+    pub(crate) fn index_block(&self) -> MyResult<Block> {
+        if let Some(block) = &self.index_block {
+            return Ok(block.clone());
+        }
+        let cache_key = self.gen_cache_key(self.footer.index());
+        {
+            let mut bc = write_unlock(&self.opt.block_cache);
+            if let Some(block) = bc.get(&cache_key) {
+                return Ok(block.clone());
+            }
+        }
+        let (block, _) = Block::new_from_location(
+            self.file.as_ref().as_ref(),
+            self.footer.index(),
+            self.opt.clone(),
+        )?;
+        self.insert_index_block(block.clone());
+        Ok(block)
     }
 
     fn incr_seek_miss_count(&self) {
@@ -105,6 +178,19 @@ impl TableReader {
         &self.file_name_
     }
 
+    pub fn properties(&self) -> &TableProperties {
+        &self.properties_
+    }
+
+    /// Returns false only if the key is definitely not in this table. The
+    /// key range is left for callers to check.
+    pub fn may_contain(&self, k: &[u8]) -> bool {
+        match &self.bloom_ {
+            Some(bloom) => bloom.may_contain(k),
+            None => true,
+        }
+    }
+
     fn gen_cache_key(&self, bh: &BlockHandle) -> cache::CacheKey {
         let mut dst = [0; 2 * 8];
         (&mut dst[..8])
@@ -131,7 +217,70 @@ impl TableReader {
         Ok(Some(block))
     }
 
-    pub fn iter(&self) -> TableIter {
+    /// Reads every data block from disk, bypassing the block cache, and checks
+    /// block checksums, key order and the key range recorded in the meta block.
+    /// Returns the number of entries in the table.
+    pub fn verify(&self) -> MyResult<usize> {
+        let index_block = self.index_block()?;
+        let mut index_iter = index_block.iter();
+        let mut first_key = None;
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut count = 0;
+
+        while let Some((_, v)) = index_iter.next() {
+            let (bh, _) = BlockHandle::decode(&v);
+            let (block, _) =
+                Block::new_from_location(self.file.as_ref().as_ref(), &bh, self.opt.clone())?;
+            let mut iter = block.iter();
+            while let Some((k, _)) = iter.next() {
+                if let Some(last_key) = &last_key {
+                    if last_key > &k {
+                        return err!(StatusCode::InvalidData, "keys out of order");
+                    }
+                }
+                if first_key.is_none() {
+                    first_key = Some(k.clone());
+                }
+                last_key = Some(k);
+                count += 1;
+            }
+        }
+
+        if count > 0
+            && (first_key.as_ref() != Some(&self.min_key_)
+                || last_key.as_ref() != Some(&self.max_key_))
+        {
+            return err!(StatusCode::InvalidData, "key range mismatch");
+        }
+
+        if count as u64 != self.properties_.num_entries {
+            return err!(StatusCode::InvalidData, "entry count mismatch");
+        }
+
+        Ok(count)
+    }
+
+    /// Bytes taken by the data blocks, the part of the file holding entries.
+    pub fn data_size(&self) -> usize {
+        self.footer.meta_index().offset()
+    }
+
+    /// Offset in the file of the first data block that may hold `k`, or
+    /// `data_size` for keys past the last block. The difference between the
+    /// offsets of two keys approximates the bytes of the entries between them.
+    pub fn approximate_offset_of(&self, k: &[u8]) -> MyResult<usize> {
+        let index_block = self.index_block()?;
+        let mut index_iter = index_block.iter();
+        // index keys are at least the last key of their block
+        while let Some((sep, v)) = index_iter.next() {
+            if &sep[..] >= k {
+                return Ok(BlockHandle::decode(&v).0.offset());
+            }
+        }
+        Ok(self.data_size())
+    }
+
+    pub fn iter(&self) -> MyResult<TableIter> {
         TableIter::new(self)
     }
 
@@ -143,11 +292,11 @@ impl TableReader {
         if k < self.min_key() || k > self.max_key() {
             return Ok(None);
         }
-        if !self.filter_.contains(k) {
+        if !self.may_contain(k) || !self.filter_.contains(k) {
             self.incr_seek_miss_count();
             return Ok(None);
         }
-        let mut iter = self.iter();
+        let mut iter = self.iter()?;
         iter.seek(k);
         if let Some(key) = iter.current_k() {
             if &key[..] == k {
@@ -159,11 +308,26 @@ impl TableReader {
     }
 }
 
+impl Drop for TableReader {
+    fn drop(&mut self) {
+        if self.opt.pin_index_blocks {
+            let cache_key = self.gen_cache_key(self.footer.index());
+            write_unlock(&self.opt.block_cache).unpin(&cache_key);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+    use std::sync::Arc;
     use std::time;
 
+    use crate::cipher::KEY_LEN;
     use crate::table_builder::TableBuilder;
+    use crate::util::read_unlock;
     use crate::util::to_str;
 
     use super::*;
@@ -257,4 +421,129 @@ mod test {
         assert!(first_cost > second_cost);
         Ok(())
     }
+
+    #[test]
+    fn test_verify() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_reader_verify");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        let data = get_data();
+        for (k, v) in &data {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+
+        let t = TableReader::new(path, opt.clone())?;
+        assert_eq!(data.len(), t.verify()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_approximate_offset_of() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_reader_offset");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        let mut data = get_data();
+        data.sort();
+        for (k, v) in &data {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+
+        let t = TableReader::new(path, opt.clone())?;
+        assert_eq!(0, t.approximate_offset_of(b"")?);
+        assert_eq!(0, t.approximate_offset_of(data[0].0.as_bytes())?);
+        assert_eq!(t.data_size(), t.approximate_offset_of(b"z")?);
+        let mid = t.approximate_offset_of(data[data.len() / 2].0.as_bytes())?;
+        assert!(mid > 0 && mid < t.data_size());
+        let mut last = 0;
+        for (k, _) in &data {
+            let offset = t.approximate_offset_of(k.as_bytes())?;
+            assert!(offset >= last);
+            last = offset;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_index_blocks() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_reader_pin");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        opt.pin_index_blocks = true;
+        opt.reset_block_cache(1024);
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        let data = get_data();
+        for (k, v) in &data {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+
+        let t = TableReader::new(path, opt.clone())?;
+        assert_eq!(1, read_unlock(&opt.block_cache).pinned_count());
+        for (k, v) in &data {
+            assert_eq!(Some(v.as_bytes().to_vec()), t.get(k.as_bytes())?);
+        }
+        drop(t);
+        assert_eq!(0, read_unlock(&opt.block_cache).pinned_count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_corrupt_index() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_reader_corrupt_index");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        opt.cache_index_blocks = true;
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        for (k, v) in &get_data() {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+
+        let mut t = TableReader::new(path, opt.clone())?;
+        let mut f = std::fs::OpenOptions::new().write(true).open(path)?;
+        f.seek(SeekFrom::Start(t.footer.index().offset as u64))?;
+        f.write_all(&[0xff; 8])?;
+        // drop the cached copy so the index block is read again
+        t.opt.reset_block_cache(1024);
+        assert!(t.iter().is_err());
+        assert!(t.get(b"prefix_key1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encryption() -> MyResult<()> {
+        let path = Path::new("/tmp/test_table_reader_encryption");
+        let mut opt = Options::default();
+        opt.block_size = 20;
+        opt.encryption_key = Some(Arc::new([1; KEY_LEN]));
+        let mut t = TableBuilder::new(path, opt.clone())?;
+        let data = get_data();
+        for (k, v) in &data {
+            t.add(k.as_bytes(), v.as_bytes())?;
+        }
+        t.flush()?;
+
+        let contents = std::fs::read(path)?;
+        let (k, _) = &data[0];
+        assert!(!contents.windows(k.len()).any(|w| w == k.as_bytes()));
+
+        let t = TableReader::new(path, opt.clone())?;
+        assert_eq!(data.len(), t.verify()?);
+        for (k, v) in &data {
+            assert_eq!(Some(v.as_bytes().to_vec()), t.get(k.as_bytes())?);
+        }
+
+        let mut plain = opt.clone();
+        plain.encryption_key = None;
+        let e = TableReader::new(path, plain.clone()).err().unwrap();
+        assert_eq!(StatusCode::EncryptionError, e.code);
+        plain.encryption_key = Some(Arc::new([2; KEY_LEN]));
+        let e = TableReader::new(path, plain).err().unwrap();
+        assert_eq!(StatusCode::EncryptionError, e.code);
+        Ok(())
+    }
 }
@@ -1,4 +1,7 @@
 use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::Write;
 use std::os::unix::fs::FileExt;
 
 use crate::MyResult;
@@ -33,6 +36,18 @@ impl RandomAccess for File {
     }
 }
 
+/// Where a `TableBuilder` writes the table.
+pub trait WritableFile: Write + Seek + Send {
+    /// Waits until everything written is on disk.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl WritableFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
 pub trait SsIterator {
     fn valid(&self) -> bool;
     fn advance(&mut self) -> bool;
@@ -0,0 +1,32 @@
+use crate::block_handle::BlockHandle;
+use crate::error::MyResult;
+use crate::error::StatusCode;
+use crate::table_reader::TableReader;
+
+/// Points at a value inside a data block of a table. Scans that only need
+/// keys can collect handles and `resolve` the few values they actually need,
+/// saving the copies of the others. Blocks are compressed whole, so the scan
+/// still reads and decompresses every block it passes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueHandle {
+    pub(crate) block: BlockHandle,
+    pub(crate) offset: usize,
+    pub(crate) size: usize,
+}
+
+impl ValueHandle {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Copies the value out of its block, read through the block cache: a
+    /// block evicted since the scan is read and decompressed again.
+    pub fn resolve(&self, table: &TableReader) -> MyResult<Vec<u8>> {
+        match table.read_block(&self.block)? {
+            Some(block) if self.offset + self.size <= block.block.len() => {
+                Ok(block.block[self.offset..self.offset + self.size].to_vec())
+            }
+            _ => err!(StatusCode::InvalidData, "invalid value handle"),
+        }
+    }
+}